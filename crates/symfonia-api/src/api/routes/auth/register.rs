@@ -1,7 +1,6 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use std::collections::HashSet;
 
 use chorus::types::{APIError, AuthError, RegisterSchema, jwt::generate_token};
 use poem::{
@@ -62,19 +61,7 @@ pub async fn register(
 			.expect("Please report this error to the symfonia developers");
 		// Add the user to each role they are in
 		for role_id in role_ids.iter() {
-			// Check if the role exists in the map
-			let maybe_role_entry = role_user_map.get_mut(role_id);
-			match maybe_role_entry {
-				// Role exists - add user
-				Some(role_entry) => {
-					role_entry.insert(id);
-				}
-				// Role doesn't exist - create role and add user to it
-				None => {
-					role_user_map.insert(*role_id, HashSet::new());
-					role_user_map.get_mut(role_id).unwrap().insert(id);
-				}
-			};
+			role_user_map.grant_role(*role_id, id);
 		}
 	});
 
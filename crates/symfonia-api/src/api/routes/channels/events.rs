@@ -0,0 +1,427 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Dispatching of `CHANNEL_CREATE`, `CHANNEL_UPDATE` and `CHANNEL_DELETE`, of
+//! `THREAD_LIST_SYNC` to members gaining access to a channel, of
+//! `MESSAGE_CREATE`, and of other events only members which can see a channel
+//! may receive.
+
+use chorus::types::{ChannelCreate, ChannelDelete, ChannelUpdate, PermissionFlags, Snowflake};
+use sqlx::PgPool;
+use util::{
+	configuration::SymfoniaConfiguration,
+	entities::{Channel, Guild, GuildMember, Message},
+	errors::{Error, GuildError},
+	gateway::{
+		ConnectedUsers, GatewayPayload,
+		dispatchevent::DispatchEvent,
+		event::Event,
+		thread_sync::{thread_list_sync, thread_list_sync_event},
+	},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What happened to a channel.
+pub(crate) enum ChannelEvent {
+	Create,
+	Update,
+	Delete,
+}
+
+impl ChannelEvent {
+	fn to_event(self, channel: chorus::types::Channel) -> Event {
+		Event::Dispatch(match self {
+			ChannelEvent::Create => DispatchEvent::ChannelCreate(GatewayPayload::dispatch(
+				"CHANNEL_CREATE",
+				ChannelCreate { channel, ..Default::default() },
+			)),
+			ChannelEvent::Update => DispatchEvent::ChannelUpdate(GatewayPayload::dispatch(
+				"CHANNEL_UPDATE",
+				ChannelUpdate { channel, ..Default::default() },
+			)),
+			ChannelEvent::Delete => DispatchEvent::ChannelDelete(GatewayPayload::dispatch(
+				"CHANNEL_DELETE",
+				ChannelDelete { channel, ..Default::default() },
+			)),
+		})
+	}
+}
+
+/// Tell the members of the guild of `channel` which can see it that it has
+/// been created, updated or deleted. Channels without permission overwrites are
+/// broadcast to the whole guild. Failing to do so does not undo the change that
+/// has been made, so it is only logged.
+pub(crate) async fn dispatch_channel_event(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	kind: ChannelEvent,
+	channel: &Channel,
+) {
+	let event = kind.to_event(channel.inner.clone());
+	if let Err(e) = dispatch_to_channel(db, connected_users, channel, event).await {
+		log::warn!(target: "symfonia::api::channels", "Failed to dispatch {kind:?} for channel {}: {e}", channel.id);
+	}
+}
+
+/// Send `event` to the members of the guild of `channel` which can see it, as
+/// determined by [channel_recipients].
+pub(crate) async fn dispatch_to_channel(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	channel: &Channel,
+	event: Event,
+) -> Result<(), Error> {
+	// TODO: Private channels notify their recipients instead
+	let Some(guild_id) = channel.guild_id else {
+		return Ok(());
+	};
+	let recipients = channel_recipients(db, channel, guild_id).await?;
+	send_channel_event(connected_users, guild_id, recipients.as_deref(), event).await
+}
+
+/// Announce `message`, which has just been sent in `channel`, with
+/// `MESSAGE_CREATE` to the members which can see `channel`. If
+/// `notify_mentioned_members` is configured, the members mentioned in the
+/// message which have not been sent it, but hold
+/// [VIEW_CHANNEL](PermissionFlags::VIEW_CHANNEL) in the guild, are sent
+/// [Message::mention_notification_event] instead. As with
+/// [dispatch_channel_event], failures are only logged.
+pub(crate) async fn dispatch_message_create(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	channel: &Channel,
+	message: &Message,
+) {
+	if let Err(e) = send_message_create(db, connected_users, channel, message).await {
+		log::warn!(target: "symfonia::api::channels", "Failed to dispatch MESSAGE_CREATE for message {}: {e}", message.id);
+	}
+}
+
+async fn send_message_create(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	channel: &Channel,
+	message: &Message,
+) -> Result<(), Error> {
+	// TODO: Private channels notify their recipients instead
+	let Some(guild_id) = channel.guild_id else {
+		return Ok(());
+	};
+	let recipients = channel_recipients(db, channel, guild_id).await?;
+	send_channel_event(connected_users, guild_id, recipients.as_deref(), message.create_event())
+		.await?;
+	// Without permission overwrites, the whole guild has been sent the message.
+	let Some(recipients) = recipients else {
+		return Ok(());
+	};
+	if !SymfoniaConfiguration::get().gateway.notify_mentioned_members {
+		return Ok(());
+	}
+	notify_mentioned_members(
+		connected_users,
+		guild_id,
+		&message.mentioned_user_ids(),
+		&recipients,
+		message.mention_notification_event(),
+	)
+	.await
+}
+
+/// Send `notification` to the members of the guild `guild_id` among the
+/// `mentioned` users which are not among the `recipients` of the message they
+/// have been mentioned in. Members without the
+/// [VIEW_CHANNEL](PermissionFlags::VIEW_CHANNEL) permission are not notified.
+async fn notify_mentioned_members(
+	connected_users: &ConnectedUsers,
+	guild_id: Snowflake,
+	mentioned: &[Snowflake],
+	recipients: &[Snowflake],
+	notification: Event,
+) -> Result<(), Error> {
+	let notified =
+		mentioned.iter().filter(|user| !recipients.contains(user)).copied().collect::<Vec<_>>();
+	if notified.is_empty() {
+		return Ok(());
+	}
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_user_recipients(&notified).await;
+	// Only members of the guild hold permissions in it.
+	builder.require_permission(guild_id, PermissionFlags::VIEW_CHANNEL).await;
+	builder.set_message(notification).await;
+	builder.send(connected_users.clone()).await
+}
+
+/// Tell the members which can see `channel`, but could not see it as it was
+/// `before` being changed, about its active threads. As with
+/// [dispatch_channel_event], failures are only logged.
+pub(crate) async fn dispatch_thread_list_sync(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	before: &Channel,
+	channel: &Channel,
+) {
+	let Some(guild_id) = channel.guild_id else {
+		return;
+	};
+	if let Err(e) = send_thread_list_syncs(db, connected_users, guild_id, before, channel).await {
+		log::warn!(target: "symfonia::api::channels", "Failed to dispatch THREAD_LIST_SYNC for channel {}: {e}", channel.id);
+	}
+}
+
+async fn send_thread_list_syncs(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	guild_id: Snowflake,
+	before: &Channel,
+	channel: &Channel,
+) -> Result<(), Error> {
+	let guild =
+		Guild::get_by_id(db, guild_id).await?.ok_or(Error::Guild(GuildError::InvalidGuild))?;
+	let members = GuildMember::get_all_by_guild_id_with_permissions(db, guild_id).await?;
+	let gained_access = members
+		.iter()
+		.filter(|member| {
+			channel.is_visible_to(member, guild.owner_id)
+				&& !before.is_visible_to(member, guild.owner_id)
+		})
+		.collect::<Vec<_>>();
+	if gained_access.is_empty() {
+		return Ok(());
+	}
+	let channels = Channel::get_by_guild_id(db, guild_id).await?;
+	for member in gained_access {
+		let sync =
+			thread_list_sync(guild_id, Some(vec![channel.id]), &channels, member, guild.owner_id);
+		send_channel_event(
+			connected_users,
+			guild_id,
+			Some(&[member.id]),
+			thread_list_sync_event(sync),
+		)
+		.await?;
+	}
+	Ok(())
+}
+
+/// The members which can see `channel`, or [None] if the whole guild can.
+pub(crate) async fn channel_recipients(
+	db: &PgPool,
+	channel: &Channel,
+	guild_id: Snowflake,
+) -> Result<Option<Vec<Snowflake>>, Error> {
+	if !channel.has_permission_overwrites() {
+		return Ok(None);
+	}
+	let guild =
+		Guild::get_by_id(db, guild_id).await?.ok_or(Error::Guild(GuildError::InvalidGuild))?;
+	let members = GuildMember::get_all_by_guild_id_with_permissions(db, guild_id).await?;
+	Ok(Some(visible_to(channel, &members, guild.owner_id)))
+}
+
+/// The Snowflake IDs of the `members` which can see `channel`.
+fn visible_to(
+	channel: &Channel,
+	members: &[GuildMember],
+	owner_id: Option<Snowflake>,
+) -> Vec<Snowflake> {
+	members
+		.iter()
+		.filter(|member| channel.is_visible_to(member, owner_id))
+		.map(|member| member.id)
+		.collect()
+}
+
+/// Send `event` to the `recipients` which can see a channel of the guild
+/// `guild_id`, as determined by [channel_recipients].
+pub(crate) async fn send_channel_event(
+	connected_users: &ConnectedUsers,
+	guild_id: Snowflake,
+	recipients: Option<&[Snowflake]>,
+	event: Event,
+) -> Result<(), Error> {
+	let Some(recipients) = recipients else {
+		return connected_users.broadcast_to_guild(guild_id, event).await;
+	};
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_user_recipients(recipients).await;
+	builder.set_message(event).await;
+	builder.send(connected_users.clone()).await
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::collections::HashMap;
+
+	use chorus::types::{PermissionOverwrite, PermissionOverwriteType};
+
+	use super::*;
+
+	fn member(id: u64, roles: Vec<Snowflake>) -> GuildMember {
+		let mut member = GuildMember { id: Snowflake::from(id), ..Default::default() };
+		member.roles = roles;
+		member.permissions = PermissionFlags::VIEW_CHANNEL;
+		member
+	}
+
+	#[tokio::test]
+	async fn channel_create_is_broadcast_to_guild() {
+		let connected_users = ConnectedUsers::new();
+		let guild_id = Snowflake::from(100u64);
+		let member_id = Snowflake::from(1u64);
+		let user = connected_users.new_user(HashMap::new(), member_id, Vec::new());
+		let mut inbox = user.lock().await.inbox.resubscribe();
+		connected_users.role_user_map.lock().await.grant_role(guild_id, member_id);
+
+		let mut channel = Channel::default();
+		channel.guild_id = Some(guild_id);
+		assert!(!channel.has_permission_overwrites());
+		send_channel_event(
+			&connected_users,
+			guild_id,
+			None,
+			ChannelEvent::Create.to_event(channel.inner.clone()),
+		)
+		.await
+		.unwrap();
+
+		assert!(matches!(
+			inbox.try_recv().unwrap(),
+			Event::Dispatch(DispatchEvent::ChannelCreate(_))
+		));
+	}
+
+	#[tokio::test]
+	async fn private_channel_update_only_reaches_permitted_members() {
+		let connected_users = ConnectedUsers::new();
+		let guild_id = Snowflake::from(100u64);
+		let staff = Snowflake::from(200u64);
+		let members = [member(1, vec![guild_id, staff]), member(2, vec![guild_id])];
+		let mut inboxes = Vec::new();
+		for member in members.iter() {
+			let user = connected_users.new_user(HashMap::new(), member.id, Vec::new());
+			inboxes.push(user.lock().await.inbox.resubscribe());
+			connected_users.role_user_map.lock().await.grant_role(guild_id, member.id);
+		}
+
+		let mut channel = Channel::default();
+		channel.guild_id = Some(guild_id);
+		channel.permission_overwrites = Some(sqlx::types::Json(vec![
+			PermissionOverwrite {
+				id: guild_id,
+				overwrite_type: PermissionOverwriteType::Role,
+				allow: PermissionFlags::empty(),
+				deny: PermissionFlags::VIEW_CHANNEL,
+			},
+			PermissionOverwrite {
+				id: staff,
+				overwrite_type: PermissionOverwriteType::Role,
+				allow: PermissionFlags::VIEW_CHANNEL,
+				deny: PermissionFlags::empty(),
+			},
+		]));
+
+		let recipients = visible_to(&channel, &members, None);
+		send_channel_event(
+			&connected_users,
+			guild_id,
+			Some(&recipients),
+			ChannelEvent::Update.to_event(channel.inner.clone()),
+		)
+		.await
+		.unwrap();
+
+		assert!(matches!(
+			inboxes[0].try_recv().unwrap(),
+			Event::Dispatch(DispatchEvent::ChannelUpdate(_))
+		));
+		assert!(inboxes[1].try_recv().is_err());
+	}
+
+	#[tokio::test]
+	async fn mentioned_members_which_cannot_see_the_channel_are_notified() {
+		let connected_users = ConnectedUsers::new();
+		let guild_id = Snowflake::from(100u64);
+		let (viewer, mentioned, bystander, outsider) = (
+			Snowflake::from(1u64),
+			Snowflake::from(2u64),
+			Snowflake::from(3u64),
+			Snowflake::from(4u64),
+		);
+		let mut inboxes = HashMap::new();
+		for user_id in [viewer, mentioned, bystander, outsider] {
+			let user = connected_users.new_user(HashMap::new(), user_id, Vec::new());
+			inboxes.insert(user_id, user.lock().await.inbox.resubscribe());
+		}
+		{
+			let mut role_user_map = connected_users.role_user_map.lock().await;
+			role_user_map.set_role_permissions(guild_id, guild_id, PermissionFlags::VIEW_CHANNEL);
+			for user_id in [viewer, mentioned, bystander] {
+				role_user_map.grant_role(guild_id, user_id);
+			}
+		}
+
+		let notification = Event::Dispatch(DispatchEvent::MessageCreate(GatewayPayload::dispatch(
+			"MESSAGE_CREATE",
+			chorus::types::MessageCreate { guild_id: Some(guild_id), ..Default::default() },
+		)));
+		notify_mentioned_members(
+			&connected_users,
+			guild_id,
+			&[viewer, mentioned, outsider],
+			&[viewer],
+			notification,
+		)
+		.await
+		.unwrap();
+
+		assert!(matches!(
+			inboxes.get_mut(&mentioned).unwrap().try_recv().unwrap(),
+			Event::Dispatch(DispatchEvent::MessageCreate(_))
+		));
+		// The viewer has been sent the message itself already.
+		for user_id in [viewer, bystander, outsider] {
+			assert!(inboxes.get_mut(&user_id).unwrap().try_recv().is_err());
+		}
+	}
+
+	#[tokio::test]
+	async fn mentioned_members_without_view_channel_are_not_notified() {
+		let connected_users = ConnectedUsers::new();
+		let guild_id = Snowflake::from(100u64);
+		let viewers = Snowflake::from(101u64);
+		let (mentioned, hidden) = (Snowflake::from(1u64), Snowflake::from(2u64));
+		let mut inboxes = HashMap::new();
+		for user_id in [mentioned, hidden] {
+			let user = connected_users.new_user(HashMap::new(), user_id, Vec::new());
+			inboxes.insert(user_id, user.lock().await.inbox.resubscribe());
+		}
+		{
+			let mut role_user_map = connected_users.role_user_map.lock().await;
+			role_user_map.set_role_permissions(guild_id, guild_id, PermissionFlags::empty());
+			role_user_map.set_role_permissions(viewers, guild_id, PermissionFlags::VIEW_CHANNEL);
+			for user_id in [mentioned, hidden] {
+				role_user_map.grant_role(guild_id, user_id);
+			}
+			role_user_map.grant_role(viewers, mentioned);
+		}
+
+		let notification = Event::Dispatch(DispatchEvent::MessageCreate(GatewayPayload::dispatch(
+			"MESSAGE_CREATE",
+			chorus::types::MessageCreate { guild_id: Some(guild_id), ..Default::default() },
+		)));
+		notify_mentioned_members(
+			&connected_users,
+			guild_id,
+			&[mentioned, hidden],
+			&[],
+			notification,
+		)
+		.await
+		.unwrap();
+
+		assert!(inboxes.get_mut(&mentioned).unwrap().try_recv().is_ok());
+		assert!(inboxes.get_mut(&hidden).unwrap().try_recv().is_err());
+	}
+}
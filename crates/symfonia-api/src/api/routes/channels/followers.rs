@@ -12,13 +12,17 @@ use sqlx::PgPool;
 use util::{
 	entities::{Channel, Config, Guild, Webhook},
 	errors::{ChannelError, Error, GuildError},
+	gateway::ConnectedUsers,
 };
 
+use super::webhooks::dispatch_webhooks_update;
+
 #[handler]
 pub async fn create_following(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
 	Data(config): Data<&Config>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(channel_id): Path<Snowflake>,
 	Json(payload): Json<AddFollowingChannelSchema>,
 ) -> poem::Result<impl IntoResponse> {
@@ -58,5 +62,8 @@ pub async fn create_following(
 	)
 	.await?;
 
+	dispatch_webhooks_update(db, connected_users, target_guild_id, payload.webhook_channel_id)
+		.await;
+
 	Ok(Json(FollowedChannel { channel_id, webhook_id: webhook.id }))
 }
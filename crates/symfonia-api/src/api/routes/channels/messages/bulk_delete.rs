@@ -1,7 +1,7 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use chorus::types::{Rights, Snowflake};
+use chorus::types::{MessageDeleteBulk, Rights, Snowflake};
 use poem::{
 	IntoResponse, Response, handler,
 	http::StatusCode,
@@ -11,13 +11,21 @@ use sqlx::PgPool;
 use util::{
 	entities::{Channel, Config, Message, User},
 	errors::{ChannelError, Error},
+	gateway::{ConnectedUsers, GatewayPayload, dispatchevent::DispatchEvent, event::Event},
 };
 
+use crate::api::routes::channels::events::{channel_recipients, send_channel_event};
+
+/// Most message IDs carried by a single `MESSAGE_DELETE_BULK`. Deletions of
+/// more messages are split into several events.
+const MAX_IDS_PER_EVENT: usize = 100;
+
 #[handler]
 pub async fn bulk_delete(
 	Data(db): Data<&PgPool>,
 	Data(config): Data<&Config>,
 	Data(user): Data<&User>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(channel_id): Path<Snowflake>,
 	Json(ids): Json<Vec<Snowflake>>,
 ) -> poem::Result<impl IntoResponse> {
@@ -26,10 +34,10 @@ pub async fn bulk_delete(
 		.await?
 		.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
 
-	if channel.guild_id.is_none() {
+	let Some(guild_id) = channel.guild_id else {
 		// No bulk delete for DM channels
 		return Err(Error::Channel(ChannelError::InvalidChannelType).into());
-	}
+	};
 
 	let superuser = user.rights.has(Rights::MANAGE_MESSAGES, true);
 	let max_bulk_delete = config.limits.message.max_bulk_delete;
@@ -38,9 +46,109 @@ pub async fn bulk_delete(
 	}
 
 	// TODO: Check if the user has permission to delete the messages
-	Message::bulk_delete(db, ids).await?;
+	// Only delete messages which actually are in this channel
+	let ids: Vec<Snowflake> =
+		Message::get_by_ids(db, channel_id, &ids).await?.iter().map(|message| message.id).collect();
+	if ids.is_empty() {
+		return Ok(Response::builder().status(StatusCode::NO_CONTENT).finish());
+	}
+	Message::bulk_delete(db, ids.clone()).await?;
 
-	// TODO: Emit event 'MESSAGE_DELETE_BULK'
+	dispatch_message_delete_bulk(db, connected_users, &channel, guild_id, &ids).await;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
+
+/// Tell the members which can see `channel` that the messages with the given
+/// `ids` have been deleted. Failing to do so does not undo the deletion, so it
+/// is only logged.
+async fn dispatch_message_delete_bulk(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	channel: &Channel,
+	guild_id: Snowflake,
+	ids: &[Snowflake],
+) {
+	let result = async {
+		let recipients = channel_recipients(db, channel, guild_id).await?;
+		for event in message_delete_bulk_events(ids, channel.id, guild_id) {
+			send_channel_event(connected_users, guild_id, recipients.as_deref(), event).await?;
+		}
+		Ok::<(), Error>(())
+	}
+	.await;
+	if let Err(e) = result {
+		log::warn!(target: "symfonia::api::channels::messages", "Failed to dispatch MESSAGE_DELETE_BULK for channel {}: {e}", channel.id);
+	}
+}
+
+/// The `MESSAGE_DELETE_BULK` events for the deletion of the messages with the
+/// given `ids`, each carrying at most [MAX_IDS_PER_EVENT] of them.
+fn message_delete_bulk_events(
+	ids: &[Snowflake],
+	channel_id: Snowflake,
+	guild_id: Snowflake,
+) -> Vec<Event> {
+	ids.chunks(MAX_IDS_PER_EVENT)
+		.map(|ids| {
+			Event::Dispatch(DispatchEvent::MessageDeleteBulk(GatewayPayload::dispatch(
+				"MESSAGE_DELETE_BULK",
+				MessageDeleteBulk { ids: ids.to_vec(), channel_id, guild_id: Some(guild_id) },
+			)))
+		})
+		.collect()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::collections::HashMap;
+
+	use super::*;
+
+	fn deleted_ids(event: Event) -> Vec<Snowflake> {
+		match event {
+			Event::Dispatch(DispatchEvent::MessageDeleteBulk(payload)) => {
+				payload.event_data.unwrap().ids
+			}
+			other => panic!("expected a bulk delete, got {other:?}"),
+		}
+	}
+
+	#[tokio::test]
+	async fn bulk_delete_reaches_channel_members_in_one_event() {
+		let connected_users = ConnectedUsers::new();
+		let guild_id = Snowflake::from(100u64);
+		let member = connected_users.new_user(HashMap::new(), Snowflake::from(1u64), Vec::new());
+		let outsider = connected_users.new_user(HashMap::new(), Snowflake::from(2u64), Vec::new());
+		let mut member_inbox = member.lock().await.inbox.resubscribe();
+		let mut outsider_inbox = outsider.lock().await.inbox.resubscribe();
+
+		let ids = [10u64, 11, 12].map(Snowflake::from);
+		for event in message_delete_bulk_events(&ids, Snowflake::from(101u64), guild_id) {
+			send_channel_event(
+				&connected_users,
+				guild_id,
+				Some(&[Snowflake::from(1u64)][..]),
+				event,
+			)
+			.await
+			.unwrap();
+		}
+
+		assert_eq!(deleted_ids(member_inbox.try_recv().unwrap()), ids);
+		assert!(member_inbox.try_recv().is_err());
+		assert!(outsider_inbox.try_recv().is_err());
+	}
+
+	#[test]
+	fn large_bulk_deletes_are_split() {
+		let ids: Vec<Snowflake> =
+			(0..MAX_IDS_PER_EVENT as u64 * 2 + 1).map(Snowflake::from).collect();
+		let events =
+			message_delete_bulk_events(&ids, Snowflake::from(101u64), Snowflake::from(100u64));
+
+		let sizes: Vec<usize> = events.into_iter().map(|event| deleted_ids(event).len()).collect();
+		assert_eq!(sizes, vec![MAX_IDS_PER_EVENT, MAX_IDS_PER_EVENT, 1]);
+	}
+}
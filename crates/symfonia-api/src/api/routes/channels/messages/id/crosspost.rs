@@ -8,15 +8,22 @@ use poem::{
 };
 use sqlx::PgPool;
 use util::{
-	entities::{Channel, Message, User},
-	errors::{ChannelError, Error},
+	entities::{Channel, Config, Guild, GuildMember, Message, Recipient, User},
+	errors::{ChannelError, Error, GuildError},
+	gateway::ConnectedUsers,
+};
+
+use crate::api::routes::channels::{
+	events::dispatch_message_create, messages::validate_message_payload,
 };
 
 #[handler]
 pub async fn create_crosspost_message(
 	Data(db): Data<&PgPool>,
 	Data(_claims): Data<&Claims>,
+	Data(config): Data<&Config>,
 	Data(authed_user): Data<&User>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(channel_id): Path<Snowflake>,
 	Json(payload): Json<MessageSendSchema>,
 ) -> poem::Result<impl IntoResponse> {
@@ -24,10 +31,17 @@ pub async fn create_crosspost_message(
 		.await?
 		.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
 
+	validate_message_payload(&payload, config)?;
+
 	let Some(referenced) = &payload.message_reference else {
 		return Err(Error::Channel(ChannelError::InvalidMessage).into()); // TODO: Maybe a generic bad request error?
 	};
 
+	let referenced_channel = Channel::get_by_id(db, referenced.channel_id)
+		.await?
+		.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
+	check_readable(db, &referenced_channel, authed_user.id).await?;
+
 	let referenced_message = Message::get_by_id(db, referenced.channel_id, referenced.message_id)
 		.await?
 		.ok_or(Error::Channel(ChannelError::InvalidMessage))?;
@@ -40,6 +54,117 @@ pub async fn create_crosspost_message(
 		authed_user.id,
 	)
 	.await?;
+	dispatch_message_create(db, connected_users, &channel, &message).await;
 
 	Ok(Json(message))
 }
+
+/// Make sure the user with the ID `user_id` can read `channel`, so that
+/// crossposting does not leak messages of channels they cannot see. Fails with
+/// [ChannelError::MissingAccess] otherwise.
+async fn check_readable(db: &PgPool, channel: &Channel, user_id: Snowflake) -> Result<(), Error> {
+	let Some(guild_id) = channel.guild_id else {
+		let recipients = Recipient::get_by_channel_id(db, channel.id).await?;
+		return match recipients.iter().any(|recipient| recipient.user_id == user_id) {
+			true => Ok(()),
+			false => Err(Error::Channel(ChannelError::MissingAccess)),
+		};
+	};
+	let guild =
+		Guild::get_by_id(db, guild_id).await?.ok_or(Error::Guild(GuildError::InvalidGuild))?;
+	let mut member = match GuildMember::get_by_id(db, user_id, guild_id).await {
+		Ok(member) => member,
+		Err(Error::Guild(GuildError::MemberNotFound)) => None,
+		Err(e) => return Err(e),
+	};
+	if let Some(member) = member.as_mut() {
+		member.populate_permissions(db).await?;
+	}
+	readable_by(channel, member.as_ref(), guild.owner_id)
+}
+
+/// Whether `member` can see the guild channel `channel`. Users who are not a
+/// member of its guild cannot.
+fn readable_by(
+	channel: &Channel,
+	member: Option<&GuildMember>,
+	owner_id: Option<Snowflake>,
+) -> Result<(), Error> {
+	match member {
+		Some(member) if channel.is_visible_to(member, owner_id) => Ok(()),
+		_ => Err(Error::Channel(ChannelError::MissingAccess)),
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use chorus::types::{PermissionFlags, PermissionOverwrite, PermissionOverwriteType};
+	use util::gateway::testing::{insert_member, insert_role};
+
+	use super::*;
+
+	const GUILD_ID: u64 = 7249086638293258240;
+	const CHANNEL_ID: u64 = 7249086862017433600;
+
+	#[test]
+	fn crossposting_from_unreadable_channel_is_denied() {
+		let guild_id = Snowflake::from(100u64);
+		let mut member = GuildMember { id: Snowflake::from(1u64), ..Default::default() };
+		member.roles = vec![guild_id];
+		member.permissions = PermissionFlags::VIEW_CHANNEL;
+		let mut channel = Channel::default();
+		channel.guild_id = Some(guild_id);
+		assert!(readable_by(&channel, Some(&member), None).is_ok());
+
+		channel.permission_overwrites = Some(sqlx::types::Json(vec![PermissionOverwrite {
+			id: guild_id,
+			overwrite_type: PermissionOverwriteType::Role,
+			allow: PermissionFlags::empty(),
+			deny: PermissionFlags::VIEW_CHANNEL,
+		}]));
+		assert!(matches!(
+			readable_by(&channel, Some(&member), None),
+			Err(Error::Channel(ChannelError::MissingAccess))
+		));
+		// The owner of the guild can read every channel.
+		assert!(readable_by(&channel, Some(&member), Some(member.id)).is_ok());
+		assert!(matches!(
+			readable_by(&Channel::default(), None, None),
+			Err(Error::Channel(ChannelError::MissingAccess))
+		));
+	}
+
+	#[sqlx::test(
+		migrations = "../util/migrations",
+		fixtures(path = "../../../../../../../util/fixtures", scripts("users", "guilds"))
+	)]
+	async fn members_loaded_from_the_database_can_read_channels(db: PgPool) {
+		let guild_id = Snowflake::from(GUILD_ID);
+		let member_id = Snowflake::from(7248639891561517057u64);
+		insert_role(&db, guild_id, guild_id, PermissionFlags::VIEW_CHANNEL).await;
+		insert_member(&db, member_id, guild_id, guild_id).await;
+		let mut channel =
+			Channel::get_by_id(&db, Snowflake::from(CHANNEL_ID)).await.unwrap().unwrap();
+		let guild = Guild::get_by_id(&db, guild_id).await.unwrap().unwrap();
+		assert_ne!(guild.owner_id, Some(member_id));
+
+		check_readable(&db, &channel, member_id).await.unwrap();
+		// Users who are not a member of the guild cannot read it.
+		assert!(matches!(
+			check_readable(&db, &channel, Snowflake::from(7248640296244744192u64)).await,
+			Err(Error::Channel(ChannelError::MissingAccess))
+		));
+
+		channel.permission_overwrites = Some(sqlx::types::Json(vec![PermissionOverwrite {
+			id: guild_id,
+			overwrite_type: PermissionOverwriteType::Role,
+			allow: PermissionFlags::empty(),
+			deny: PermissionFlags::VIEW_CHANNEL,
+		}]));
+		assert!(matches!(
+			check_readable(&db, &channel, member_id).await,
+			Err(Error::Channel(ChannelError::MissingAccess))
+		));
+	}
+}
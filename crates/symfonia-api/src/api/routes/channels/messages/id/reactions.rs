@@ -1,7 +1,10 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use chorus::types::{PartialEmoji, Reaction, ReactionQuerySchema, Snowflake, jwt::Claims};
+use chorus::types::{
+	MessageReactionRemoveAll, MessageReactionRemoveEmoji, PartialEmoji, Reaction,
+	ReactionQuerySchema, Snowflake, jwt::Claims,
+};
 use poem::{
 	IntoResponse, Response, handler,
 	web::{Data, Json, Path, Query},
@@ -9,10 +12,13 @@ use poem::{
 use reqwest::StatusCode;
 use sqlx::PgPool;
 use util::{
-	entities::{Channel, Emoji, GuildMember, Message, User},
+	entities::{Channel, Emoji, GuildMember, Message, Recipient, User},
 	errors::{ChannelError, Error, GuildError, ReactionError, UserError},
+	gateway::{ConnectedUsers, GatewayPayload, dispatchevent::DispatchEvent, event::Event},
 };
 
+use crate::api::routes::channels::events;
+
 #[handler]
 pub async fn add_reaction(
 	Data(db): Data<&PgPool>,
@@ -93,16 +99,27 @@ pub async fn add_reaction(
 #[handler]
 pub async fn delete_all_reactions(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((channel_id, message_id)): Path<(Snowflake, Snowflake)>,
 ) -> poem::Result<impl IntoResponse> {
 	// TODO: Check permissions
+	let channel = Channel::get_by_id(db, channel_id)
+		.await?
+		.ok_or(Error::Channel(ChannelError::InvalidChannel))?;
 	let mut message = Message::get_by_id(db, channel_id, message_id)
 		.await?
 		.ok_or(Error::Channel(ChannelError::InvalidMessage))?;
 
 	message.clear_reactions(db).await?;
 
-	// TODO: Emit event
+	let event = reaction_remove_all_event(MessageReactionRemoveAll {
+		channel_id,
+		message_id,
+		guild_id: channel.guild_id,
+	});
+	if let Err(e) = dispatch_to_channel(db, connected_users, &channel, event).await {
+		log::warn!(target: "symfonia::api::channels::reactions", "Failed to dispatch MESSAGE_REACTION_REMOVE_ALL for message {message_id}: {e}");
+	}
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
@@ -111,6 +128,7 @@ pub async fn delete_all_reactions(
 pub async fn delete_reaction(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((channel_id, message_id)): Path<(Snowflake, Snowflake)>,
 	Path((emoji, user_id)): Path<(String, String)>,
 ) -> poem::Result<impl IntoResponse> {
@@ -133,9 +151,17 @@ pub async fn delete_reaction(
 		// TODO: Check permissions 'MANAGE_MESSAGES'
 	}
 
-	message.remove_reaction(db, partial_emoji).await?;
+	message.remove_reaction(db, partial_emoji.clone()).await?;
 
-	// TODO: Emit event
+	let event = reaction_remove_emoji_event(MessageReactionRemoveEmoji {
+		channel_id: channel.id,
+		message_id,
+		guild_id: channel.guild_id,
+		emoji: into_emoji(partial_emoji),
+	});
+	if let Err(e) = dispatch_to_channel(db, connected_users, &channel, event).await {
+		log::warn!(target: "symfonia::api::channels::reactions", "Failed to dispatch MESSAGE_REACTION_REMOVE_EMOJI for message {message_id}: {e}");
+	}
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
@@ -174,6 +200,54 @@ pub async fn get_reaction(
 	Ok(Json(public_projections))
 }
 
+fn reaction_remove_all_event(remove_all: MessageReactionRemoveAll) -> Event {
+	Event::Dispatch(DispatchEvent::MessageReactionRemoveAll(GatewayPayload::dispatch(
+		"MESSAGE_REACTION_REMOVE_ALL",
+		remove_all,
+	)))
+}
+
+fn reaction_remove_emoji_event(remove_emoji: MessageReactionRemoveEmoji) -> Event {
+	Event::Dispatch(DispatchEvent::MessageReactionRemoveEmoji(GatewayPayload::dispatch(
+		"MESSAGE_REACTION_REMOVE_EMOJI",
+		remove_emoji,
+	)))
+}
+
+/// Send `event` to everyone who can see `channel`: the members of its guild
+/// as determined by [events::channel_recipients], or the recipients of a
+/// private channel.
+async fn dispatch_to_channel(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	channel: &Channel,
+	event: Event,
+) -> Result<(), Error> {
+	if channel.guild_id.is_some() {
+		return events::dispatch_to_channel(db, connected_users, channel, event).await;
+	}
+	let recipients = Recipient::get_by_channel_id(db, channel.id)
+		.await?
+		.into_iter()
+		.map(|recipient| recipient.user_id)
+		.collect::<Vec<_>>();
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_user_recipients(&recipients).await;
+	builder.set_message(event).await;
+	builder.send(connected_users.clone()).await
+}
+
+/// The [chorus::types::Emoji] events refer to `emoji` with. Unicode emojis
+/// have no ID.
+fn into_emoji(emoji: PartialEmoji) -> chorus::types::Emoji {
+	chorus::types::Emoji {
+		id: emoji.id.unwrap_or_default(),
+		name: Some(emoji.name),
+		animated: Some(emoji.animated),
+		..Default::default()
+	}
+}
+
 pub fn get_partial_emoji(emoji: &str) -> Option<PartialEmoji> {
 	let clean_emoji = percent_encoding::percent_decode_str(emoji).decode_utf8().ok()?;
 	if let Some((name, snowflake)) = emoji.split_once(':') {
@@ -184,3 +258,69 @@ pub fn get_partial_emoji(emoji: &str) -> Option<PartialEmoji> {
 		Some(PartialEmoji { name: clean_emoji.to_string(), id: None, animated: false })
 	}
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::collections::HashMap;
+
+	use util::gateway::testing::unreachable_db;
+
+	use super::*;
+
+	/// Dispatches `event` in a guild channel and returns what a member of the
+	/// guild receives.
+	async fn received_by_member(event: Event) -> Event {
+		let connected_users = ConnectedUsers::new();
+		let guild_id = Snowflake::from(100u64);
+		let member_id = Snowflake::from(1u64);
+		let member = connected_users.new_user(HashMap::new(), member_id, Vec::new());
+		let mut inbox = member.lock().await.inbox.resubscribe();
+		connected_users.role_user_map.lock().await.grant_role(guild_id, member_id);
+		let mut channel = Channel::default();
+		channel.guild_id = Some(guild_id);
+		// Guild channels without permission overwrites are dispatched to without
+		// querying the database.
+		let db = unreachable_db();
+
+		dispatch_to_channel(&db, &connected_users, &channel, event).await.unwrap();
+
+		inbox.try_recv().unwrap()
+	}
+
+	#[tokio::test]
+	async fn remove_all_reaches_channel_members() {
+		let event = reaction_remove_all_event(MessageReactionRemoveAll {
+			channel_id: Snowflake::from(101u64),
+			message_id: Snowflake::from(102u64),
+			guild_id: Some(Snowflake::from(100u64)),
+		});
+
+		match received_by_member(event).await {
+			Event::Dispatch(DispatchEvent::MessageReactionRemoveAll(payload)) => {
+				assert_eq!(payload.event_name.as_deref(), Some("MESSAGE_REACTION_REMOVE_ALL"));
+				assert_eq!(payload.event_data.unwrap().message_id, Snowflake::from(102u64));
+			}
+			other => panic!("expected a reaction remove all, got {other:?}"),
+		}
+	}
+
+	#[tokio::test]
+	async fn remove_emoji_reaches_channel_members() {
+		let emoji = get_partial_emoji("%F0%9F%91%8D").unwrap();
+		let event = reaction_remove_emoji_event(MessageReactionRemoveEmoji {
+			channel_id: Snowflake::from(101u64),
+			message_id: Snowflake::from(102u64),
+			guild_id: Some(Snowflake::from(100u64)),
+			emoji: into_emoji(emoji),
+		});
+
+		match received_by_member(event).await {
+			Event::Dispatch(DispatchEvent::MessageReactionRemoveEmoji(payload)) => {
+				assert_eq!(payload.event_name.as_deref(), Some("MESSAGE_REACTION_REMOVE_EMOJI"));
+				assert_eq!(payload.event_data.unwrap().emoji.name.as_deref(), Some("👍"));
+			}
+			other => panic!("expected a reaction remove emoji, got {other:?}"),
+		}
+	}
+}
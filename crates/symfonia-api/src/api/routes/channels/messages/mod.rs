@@ -13,11 +13,43 @@ use sqlx::PgPool;
 use util::{
 	entities::{Channel, Config, Guild, Message, User},
 	errors::{ChannelError, Error, GuildError, RateLimitError, UserError},
+	gateway::ConnectedUsers,
 };
 
+use crate::api::routes::channels::events::dispatch_message_create;
+
 pub mod bulk_delete;
 pub(crate) mod id;
 
+/// Most embeds a single message may have.
+pub(crate) const MAX_EMBEDS: usize = 10;
+/// Most attachments a single message may have.
+pub(crate) const MAX_ATTACHMENTS: usize = 10;
+
+/// Check that `payload` stays within the limits of a single message: its
+/// content may not be longer than configured, and it may not have more than
+/// [MAX_EMBEDS] embeds or [MAX_ATTACHMENTS] attachments.
+pub(crate) fn validate_message_payload(
+	payload: &MessageSendSchema,
+	config: &Config,
+) -> Result<(), Error> {
+	if payload
+		.content
+		.as_ref()
+		.map(|c| c.len() as u32 > config.limits.message.max_characters)
+		.unwrap_or_default()
+	{
+		return Err(Error::Channel(ChannelError::MessageTooLong));
+	}
+	if payload.embeds.as_ref().is_some_and(|embeds| embeds.len() > MAX_EMBEDS) {
+		return Err(Error::Channel(ChannelError::TooManyEmbeds(MAX_EMBEDS)));
+	}
+	if payload.attachments.as_ref().is_some_and(|attachments| attachments.len() > MAX_ATTACHMENTS) {
+		return Err(Error::Channel(ChannelError::TooManyAttachments(MAX_ATTACHMENTS)));
+	}
+	Ok(())
+}
+
 #[handler]
 pub async fn get_messages(
 	Data(db): Data<&PgPool>,
@@ -63,6 +95,7 @@ pub async fn create_message(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
 	Data(config): Data<&Config>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(channel_id): Path<Snowflake>,
 	Json(mut payload): Json<MessageSendSchema>,
 ) -> poem::Result<impl IntoResponse> {
@@ -104,14 +137,7 @@ pub async fn create_message(
 
 	// TODO: Handle file uploads
 
-	if payload
-		.content
-		.as_ref()
-		.map(|c| c.len() as u32 > config.limits.message.max_characters)
-		.unwrap_or_default()
-	{
-		return Err(Error::Channel(ChannelError::MessageTooLong).into());
-	}
+	validate_message_payload(&payload, config)?;
 
 	// TODO: Handle stickers/activity
 
@@ -143,6 +169,7 @@ pub async fn create_message(
 	}
 
 	let message = channel.create_message(db, payload, claims.id).await?;
+	dispatch_message_create(db, connected_users, &channel, &message).await;
 
 	Ok(Json(message))
 }
@@ -158,3 +185,49 @@ pub async fn create_greet_message(
 	unimplemented!();
 	Ok("")
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn over_length_content_is_rejected() {
+		let mut config = Config::default();
+		config.limits.message.max_characters = 4;
+		let payload = |content: &str| MessageSendSchema {
+			content: Some(content.to_string()),
+			..Default::default()
+		};
+
+		assert!(validate_message_payload(&payload("four"), &config).is_ok());
+		assert!(matches!(
+			validate_message_payload(&payload("fives"), &config),
+			Err(Error::Channel(ChannelError::MessageTooLong))
+		));
+	}
+
+	#[test]
+	fn excess_attachments_and_embeds_are_rejected() {
+		let config = Config::default();
+		let attachments = |count| MessageSendSchema {
+			attachments: Some((0..count).map(|_| Default::default()).collect()),
+			..Default::default()
+		};
+		let embeds = |count| MessageSendSchema {
+			embeds: Some((0..count).map(|_| Default::default()).collect()),
+			..Default::default()
+		};
+
+		assert!(validate_message_payload(&attachments(MAX_ATTACHMENTS), &config).is_ok());
+		assert!(matches!(
+			validate_message_payload(&attachments(MAX_ATTACHMENTS + 1), &config),
+			Err(Error::Channel(ChannelError::TooManyAttachments(MAX_ATTACHMENTS)))
+		));
+		assert!(validate_message_payload(&embeds(MAX_EMBEDS), &config).is_ok());
+		assert!(matches!(
+			validate_message_payload(&embeds(MAX_EMBEDS + 1), &config),
+			Err(Error::Channel(ChannelError::TooManyEmbeds(MAX_EMBEDS)))
+		));
+	}
+}
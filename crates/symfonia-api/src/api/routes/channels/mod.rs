@@ -2,6 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 use chorus::types::{ChannelModifySchema, Snowflake, jwt::Claims};
+use events::{ChannelEvent, dispatch_channel_event};
 use invites::{create_invite, get_invites};
 use poem::{
 	IntoResponse, Route, delete, get, handler, post, put,
@@ -11,8 +12,10 @@ use sqlx::PgPool;
 use util::{
 	entities::Channel,
 	errors::{ChannelError, Error},
+	gateway::ConnectedUsers,
 };
 
+pub(crate) mod events;
 mod followers;
 mod invites;
 mod messages;
@@ -89,6 +92,7 @@ pub async fn get_channel(
 pub async fn delete_channel(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(channel_id): Path<Snowflake>,
 ) -> poem::Result<impl IntoResponse> {
 	let channel = Channel::get_by_id(db, channel_id)
@@ -98,6 +102,7 @@ pub async fn delete_channel(
 	// TODO: Check if the user has permission to delete the channel
 	// TODO: Check if the channel is a DM, and handle recipients
 	channel.delete(db).await?;
+	dispatch_channel_event(db, connected_users, ChannelEvent::Delete, &channel).await;
 
 	Ok(Json(channel.into_inner()))
 }
@@ -106,6 +111,7 @@ pub async fn delete_channel(
 pub async fn modify_channel(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(channel_id): Path<Snowflake>,
 	Json(payload): Json<ChannelModifySchema>,
 ) -> poem::Result<impl IntoResponse> {
@@ -117,6 +123,7 @@ pub async fn modify_channel(
 
 	channel.modify(payload);
 	channel.save(db).await?;
+	dispatch_channel_event(db, connected_users, ChannelEvent::Update, &channel).await;
 
 	Ok(Json(channel.into_inner()))
 }
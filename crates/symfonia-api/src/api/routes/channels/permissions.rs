@@ -11,12 +11,16 @@ use sqlx::PgPool;
 use util::{
 	entities::{Channel, GuildMember, Role},
 	errors::{ChannelError, Error, GuildError},
+	gateway::ConnectedUsers,
 };
 
+use super::events::{ChannelEvent, dispatch_channel_event, dispatch_thread_list_sync};
+
 #[handler]
 pub async fn add_overwrite(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((channel_id, overwrite_id)): Path<(Snowflake, Snowflake)>,
 	Json(payload): Json<PermissionOverwrite>,
 ) -> poem::Result<impl IntoResponse> {
@@ -38,6 +42,7 @@ pub async fn add_overwrite(
 		return Err(Error::Guild(GuildError::MemberNotFound).into());
 	}
 
+	let before = channel.clone();
 	if let Some(overwrite) = channel
 		.permission_overwrites
 		.as_mut()
@@ -50,7 +55,8 @@ pub async fn add_overwrite(
 	}
 	channel.save(db).await?;
 
-	// TODO: emit event 'CHANNEL_UPDATE'
+	dispatch_channel_event(db, connected_users, ChannelEvent::Update, &channel).await;
+	dispatch_thread_list_sync(db, connected_users, &before, &channel).await;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
@@ -59,6 +65,7 @@ pub async fn add_overwrite(
 pub async fn remove_overwrite(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((channel_id, overwrite_id)): Path<(Snowflake, Snowflake)>,
 ) -> poem::Result<impl IntoResponse> {
 	let mut channel = Channel::get_by_id(db, channel_id)
@@ -69,12 +76,14 @@ pub async fn remove_overwrite(
 
 	// TODO: Check permissions
 
+	let before = channel.clone();
 	if let Some(overwrites) = channel.permission_overwrites.as_mut() {
 		overwrites.retain(|x| x.id != overwrite_id);
 	}
 	channel.save(db).await?;
 
-	// TODO: emit event 'CHANNEL_UPDATE'
+	dispatch_channel_event(db, connected_users, ChannelEvent::Update, &channel).await;
+	dispatch_thread_list_sync(db, connected_users, &before, &channel).await;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
@@ -1,7 +1,7 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use chorus::types::{Snowflake, jwt::Claims};
+use chorus::types::{Snowflake, TypingStartEvent, jwt::Claims};
 use poem::{
 	IntoResponse, Response, handler,
 	web::{Data, Path},
@@ -11,12 +11,16 @@ use sqlx::PgPool;
 use util::{
 	entities::{Channel, GuildMember},
 	errors::{ChannelError, Error, GuildError},
+	gateway::{ConnectedUsers, GatewayPayload, dispatchevent::DispatchEvent, event::Event},
 };
 
+use crate::api::routes::channels::events::channel_recipients;
+
 #[handler]
 pub async fn typing_indicator(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(channel_id): Path<Snowflake>,
 ) -> poem::Result<impl IntoResponse> {
 	let channel = Channel::get_by_id(db, channel_id)
@@ -32,7 +36,129 @@ pub async fn typing_indicator(
 		.await?
 		.ok_or(Error::Guild(GuildError::MemberNotFound))?;
 
-	// TODO: Emit event 'TYPING_START'
+	let typing_start = TypingStartEvent {
+		channel_id,
+		guild_id: Some(guild_id),
+		user_id: claims.id,
+		// Clients expire typing indicators relative to this timestamp.
+		timestamp: chrono::Utc::now().timestamp(),
+		member: Some(member.into_inner()),
+	};
+	let result = match channel_recipients(db, &channel, guild_id).await {
+		Ok(recipients) => {
+			dispatch_typing_start(connected_users, guild_id, recipients.as_deref(), typing_start)
+				.await
+		}
+		Err(e) => Err(e),
+	};
+	if let Err(e) = result {
+		log::warn!(target: "symfonia::api::channels::typing", "Failed to dispatch TYPING_START in channel {channel_id}: {e}");
+	}
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
+
+/// Send `typing_start` to the `recipients` which can see the channel, as
+/// determined by [channel_recipients], or to all connected members of the guild
+/// with the ID `guild_id` if everyone can. The user who is typing is skipped.
+async fn dispatch_typing_start(
+	connected_users: &ConnectedUsers,
+	guild_id: Snowflake,
+	recipients: Option<&[Snowflake]>,
+	typing_start: TypingStartEvent,
+) -> Result<(), Error> {
+	let mut builder = connected_users.bulk_message_builder();
+	match recipients {
+		Some(recipients) => builder.add_user_recipients(recipients).await,
+		// The @everyone role has the same ID as the guild
+		None => builder.add_role_recipients(&[guild_id]).await,
+	}
+	builder.exclude_user_recipients(&[typing_start.user_id]).await;
+	builder
+		.set_message(Event::Dispatch(DispatchEvent::TypingStart(GatewayPayload::dispatch(
+			"TYPING_START",
+			typing_start,
+		))))
+		.await;
+	builder.send(connected_users.clone()).await
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::collections::HashMap;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn typing_start_skips_the_typer() {
+		let connected_users = ConnectedUsers::new();
+		let guild_id = Snowflake::from(100u64);
+		let (typer_id, other_id) = (Snowflake::from(1u64), Snowflake::from(2u64));
+		let typer = connected_users.new_user(HashMap::new(), typer_id, Vec::new());
+		let other = connected_users.new_user(HashMap::new(), other_id, Vec::new());
+		let mut typer_inbox = typer.lock().await.inbox.resubscribe();
+		let mut other_inbox = other.lock().await.inbox.resubscribe();
+		{
+			let mut role_user_map = connected_users.role_user_map.lock().await;
+			role_user_map.grant_role(guild_id, typer_id);
+			role_user_map.grant_role(guild_id, other_id);
+		}
+
+		let typing_start = TypingStartEvent {
+			channel_id: Snowflake::from(101u64),
+			guild_id: Some(guild_id),
+			user_id: typer_id,
+			timestamp: 1_700_000_000,
+			member: None,
+		};
+		dispatch_typing_start(&connected_users, guild_id, None, typing_start).await.unwrap();
+
+		match other_inbox.try_recv().unwrap() {
+			Event::Dispatch(DispatchEvent::TypingStart(payload)) => {
+				let typing_start = payload.event_data.unwrap();
+				assert_eq!(typing_start.user_id, typer_id);
+				assert_eq!(typing_start.timestamp, 1_700_000_000);
+			}
+			other => panic!("expected a typing start, got {other:?}"),
+		}
+		assert!(typer_inbox.try_recv().is_err());
+	}
+
+	#[tokio::test]
+	async fn typing_start_only_reaches_channel_recipients() {
+		let connected_users = ConnectedUsers::new();
+		let guild_id = Snowflake::from(100u64);
+		let (typer_id, viewer_id, outsider_id) =
+			(Snowflake::from(1u64), Snowflake::from(2u64), Snowflake::from(3u64));
+		let mut inboxes = Vec::new();
+		for user_id in [typer_id, viewer_id, outsider_id] {
+			let user = connected_users.new_user(HashMap::new(), user_id, Vec::new());
+			inboxes.push(user.lock().await.inbox.resubscribe());
+			connected_users.role_user_map.lock().await.grant_role(guild_id, user_id);
+		}
+
+		let typing_start = TypingStartEvent {
+			channel_id: Snowflake::from(101u64),
+			guild_id: Some(guild_id),
+			user_id: typer_id,
+			timestamp: 1_700_000_000,
+			member: None,
+		};
+		dispatch_typing_start(
+			&connected_users,
+			guild_id,
+			Some(&[typer_id, viewer_id]),
+			typing_start,
+		)
+		.await
+		.unwrap();
+
+		assert!(matches!(
+			inboxes[1].try_recv().unwrap(),
+			Event::Dispatch(DispatchEvent::TypingStart(_))
+		));
+		assert!(inboxes[0].try_recv().is_err());
+		assert!(inboxes[2].try_recv().is_err());
+	}
+}
@@ -1,15 +1,16 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use chorus::types::{CreateWebhookSchema, Snowflake, WebhookType};
+use chorus::types::{CreateWebhookSchema, PermissionFlags, Snowflake, WebhookType, WebhooksUpdate};
 use poem::{
 	IntoResponse, handler,
 	web::{Data, Json, Path},
 };
 use sqlx::PgPool;
 use util::{
-	entities::{Channel, Config, User, Webhook},
-	errors::{ChannelError, Error},
+	entities::{Channel, Config, Guild, User, Webhook},
+	errors::{ChannelError, Error, GuildError},
+	gateway::{ConnectedUsers, GatewayPayload, dispatchevent::DispatchEvent, event::Event},
 };
 
 #[handler]
@@ -39,6 +40,7 @@ pub async fn create_webhook(
 	Data(db): Data<&PgPool>,
 	Data(user): Data<&User>,
 	Data(config): Data<&Config>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(channel_id): Path<Snowflake>,
 	Json(payload): Json<CreateWebhookSchema>,
 ) -> poem::Result<impl IntoResponse> {
@@ -77,5 +79,122 @@ pub async fn create_webhook(
 	.await?;
 	hook.user = Some(user.to_inner());
 
+	dispatch_webhooks_update(db, connected_users, guild_id, channel_id).await;
+
 	Ok(Json(hook))
 }
+
+/// Dispatch a `WEBHOOKS_UPDATE` for the channel with the ID `channel_id` to the
+/// members of its guild which may manage webhooks, and a
+/// `GUILD_INTEGRATIONS_UPDATE` to those which may manage the guild, which lists
+/// webhooks among its integrations. Call this whenever a webhook of the channel
+/// is created, updated or deleted. Failing to do so does not undo the change
+/// that has been made, so it is only logged.
+pub(super) async fn dispatch_webhooks_update(
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	guild_id: Snowflake,
+	channel_id: Snowflake,
+) {
+	let guild = match Guild::get_by_id(db, guild_id)
+		.await
+		.and_then(|guild| guild.ok_or(Error::Guild(GuildError::InvalidGuild)))
+	{
+		Ok(guild) => guild,
+		Err(e) => {
+			log::warn!(target: "symfonia::api::channels", "Failed to dispatch WEBHOOKS_UPDATE for channel {channel_id}: {e}");
+			return;
+		}
+	};
+	if let Err(e) =
+		send_webhooks_update(connected_users, guild.owner_id, guild_id, channel_id).await
+	{
+		log::warn!(target: "symfonia::api::channels", "Failed to dispatch WEBHOOKS_UPDATE for channel {channel_id}: {e}");
+	}
+	if let Err(e) =
+		connected_users.dispatch_guild_integrations_update(guild_id, guild.owner_id).await
+	{
+		log::warn!(target: "symfonia::api::channels", "Failed to dispatch GUILD_INTEGRATIONS_UPDATE for guild {guild_id}: {e}");
+	}
+}
+
+/// Send a `WEBHOOKS_UPDATE` to the members of the guild `guild_id` whose roles
+/// grant `MANAGE_WEBHOOKS`, and to its owner, who may always manage webhooks.
+async fn send_webhooks_update(
+	connected_users: &ConnectedUsers,
+	owner_id: Option<Snowflake>,
+	guild_id: Snowflake,
+	channel_id: Snowflake,
+) -> Result<(), Error> {
+	let event = Event::Dispatch(DispatchEvent::WebhooksUpdate(GatewayPayload::dispatch(
+		"WEBHOOKS_UPDATE",
+		WebhooksUpdate { guild_id, channel_id },
+	)));
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_role_recipients(&[guild_id]).await;
+	builder.require_permission(guild_id, PermissionFlags::MANAGE_WEBHOOKS).await;
+	builder.set_message(event.clone()).await;
+	let Some(owner_id) = owner_id else {
+		return builder.send(connected_users.clone()).await;
+	};
+	builder.exclude_user_recipients(&[owner_id]).await;
+	builder.send(connected_users.clone()).await?;
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_user_recipients(&[owner_id]).await;
+	builder.set_message(event).await;
+	builder.send(connected_users.clone()).await
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::collections::HashMap;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn webhooks_update_only_reaches_webhook_managers() {
+		let connected_users = ConnectedUsers::new();
+		let guild_id = Snowflake::from(100u64);
+		let managers = Snowflake::from(102u64);
+		let mut inboxes = Vec::new();
+		for user_id in 1..=3u64 {
+			let user =
+				connected_users.new_user(HashMap::new(), Snowflake::from(user_id), Vec::new());
+			inboxes.push(user.lock().await.inbox.resubscribe());
+		}
+		{
+			let mut role_user_map = connected_users.role_user_map.lock().await;
+			role_user_map.set_role_permissions(guild_id, guild_id, PermissionFlags::SEND_MESSAGES);
+			role_user_map.set_role_permissions(
+				managers,
+				guild_id,
+				PermissionFlags::MANAGE_WEBHOOKS,
+			);
+			for user_id in 1..=3u64 {
+				role_user_map.grant_role(guild_id, Snowflake::from(user_id));
+			}
+			role_user_map.grant_role(managers, Snowflake::from(1u64));
+		}
+
+		send_webhooks_update(
+			&connected_users,
+			Some(Snowflake::from(3u64)),
+			guild_id,
+			Snowflake::from(101u64),
+		)
+		.await
+		.unwrap();
+
+		assert!(matches!(
+			inboxes[0].try_recv().unwrap(),
+			Event::Dispatch(DispatchEvent::WebhooksUpdate(_))
+		));
+		assert!(inboxes[1].try_recv().is_err());
+		// The guild owner may manage webhooks without holding the permission.
+		assert!(inboxes[2].try_recv().is_ok());
+		for inbox in inboxes.iter_mut() {
+			assert!(inbox.try_recv().is_err());
+		}
+	}
+}
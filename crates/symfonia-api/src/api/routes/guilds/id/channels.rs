@@ -13,8 +13,11 @@ use sqlx::PgPool;
 use util::{
 	entities::{Channel, Guild},
 	errors::{Error, GuildError},
+	gateway::ConnectedUsers,
 };
 
+use crate::api::routes::channels::events::{ChannelEvent, dispatch_channel_event};
+
 #[handler]
 pub async fn get_channels(
 	Data(db): Data<&PgPool>,
@@ -30,6 +33,7 @@ pub async fn get_channels(
 pub async fn create_channel(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(guild_id): Path<Snowflake>,
 	Json(payload): Json<ChannelModifySchema>,
 ) -> poem::Result<impl IntoResponse> {
@@ -47,6 +51,7 @@ pub async fn create_channel(
 		payload.permission_overwrites.unwrap_or_else(std::vec::Vec::new),
 	)
 	.await?;
+	dispatch_channel_event(db, connected_users, ChannelEvent::Create, &channel).await;
 
 	Ok(Json(channel.into_inner()).with_status(StatusCode::CREATED))
 }
@@ -55,6 +60,7 @@ pub async fn create_channel(
 pub async fn reorder_channels_route(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path(guild_id): Path<Snowflake>,
 	Json(payload): Json<ModifyChannelPositionsSchema>,
 ) -> poem::Result<impl IntoResponse> {
@@ -70,7 +76,7 @@ pub async fn reorder_channels_route(
 
 		for channel in channels {
 			channel.save(db).await?;
-			// TODO: emit events
+			dispatch_channel_event(db, connected_users, ChannelEvent::Update, &channel).await;
 		}
 	}
 
@@ -1,7 +1,9 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use chorus::types::{EmojiCreateSchema, EmojiModifySchema, Snowflake, jwt::Claims};
+use chorus::types::{
+	EmojiCreateSchema, EmojiModifySchema, GuildEmojisUpdate, Snowflake, jwt::Claims,
+};
 use poem::{
 	IntoResponse, Response, handler,
 	web::{Data, Json, Path},
@@ -11,6 +13,7 @@ use sqlx::PgPool;
 use util::{
 	entities::{Config, Emoji, Guild},
 	errors::{Error, GuildError},
+	gateway::{ConnectedUsers, GatewayPayload, dispatchevent::DispatchEvent, event::Event},
 };
 
 #[handler]
@@ -53,6 +56,7 @@ pub async fn get_emoji(
 pub async fn create_emoji(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(config): Data<&Config>,
 	Path(guild_id): Path<Snowflake>,
 	Json(payload): Json<EmojiCreateSchema>,
@@ -90,7 +94,7 @@ pub async fn create_emoji(
 	)
 	.await?;
 
-	// TODO: Emit event 'GUILD_EMOJIS_UPDATE'
+	dispatch_emojis_update(db, connected_users, &guild).await;
 
 	Ok(Json(emoji).with_status(StatusCode::CREATED))
 }
@@ -99,6 +103,7 @@ pub async fn create_emoji(
 pub async fn modify_emoji(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((guild_id, emoji_id)): Path<(Snowflake, Snowflake)>,
 	Json(payload): Json<EmojiModifySchema>,
 ) -> poem::Result<impl IntoResponse> {
@@ -123,7 +128,7 @@ pub async fn modify_emoji(
 
 	emoji.save(db).await?;
 
-	// TODO: Emit event 'GUILD_EMOJIS_UPDATE'
+	dispatch_emojis_update(db, connected_users, &guild).await;
 
 	Ok(Json(emoji))
 }
@@ -132,6 +137,7 @@ pub async fn modify_emoji(
 pub async fn delete_emoji(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Path((guild_id, emoji_id)): Path<(Snowflake, Snowflake)>,
 ) -> poem::Result<impl IntoResponse> {
 	let guild =
@@ -148,7 +154,73 @@ pub async fn delete_emoji(
 
 	emoji.delete(db).await?;
 
-	// TODO: Emit event 'GUILD_EMOJIS_UPDATE'
+	dispatch_emojis_update(db, connected_users, &guild).await;
 
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
+
+/// Send the current emojis of `guild` to all of its connected members. Failing
+/// to do so does not undo the change that has been made, so it is only logged.
+async fn dispatch_emojis_update(db: &PgPool, connected_users: &ConnectedUsers, guild: &Guild) {
+	let result = match guild.get_emojis(db).await {
+		Ok(emojis) => {
+			let emojis = emojis.into_iter().map(Emoji::into_inner).collect();
+			send_emojis_update(connected_users, guild.id, emojis).await
+		}
+		Err(e) => Err(e),
+	};
+	if let Err(e) = result {
+		log::warn!(target: "symfonia::api::guilds::emoji", "Failed to dispatch GUILD_EMOJIS_UPDATE for guild {}: {e}", guild.id);
+	}
+}
+
+async fn send_emojis_update(
+	connected_users: &ConnectedUsers,
+	guild_id: Snowflake,
+	emojis: Vec<chorus::types::Emoji>,
+) -> Result<(), Error> {
+	connected_users
+		.broadcast_to_guild(
+			guild_id,
+			Event::Dispatch(DispatchEvent::GuildEmojisUpdate(GatewayPayload::dispatch(
+				"GUILD_EMOJIS_UPDATE",
+				GuildEmojisUpdate { guild_id, emojis },
+			))),
+		)
+		.await
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::collections::HashMap;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn emojis_update_reaches_guild_member() {
+		let connected_users = ConnectedUsers::new();
+		let guild_id = Snowflake::from(100u64);
+		let member_id = Snowflake::from(1u64);
+		let member = connected_users.new_user(HashMap::new(), member_id, Vec::new());
+		let mut inbox = member.lock().await.inbox.resubscribe();
+		connected_users.role_user_map.lock().await.grant_role(guild_id, member_id);
+
+		let emoji = chorus::types::Emoji {
+			id: Snowflake::from(200u64),
+			name: Some("party".to_string()),
+			..Default::default()
+		};
+		send_emojis_update(&connected_users, guild_id, vec![emoji]).await.unwrap();
+
+		match inbox.try_recv().unwrap() {
+			Event::Dispatch(DispatchEvent::GuildEmojisUpdate(payload)) => {
+				let update = payload.event_data.unwrap();
+				assert_eq!(update.guild_id, guild_id);
+				assert_eq!(update.emojis.len(), 1);
+				assert_eq!(update.emojis[0].name.as_deref(), Some("party"));
+			}
+			other => panic!("expected an emojis update, got {other:?}"),
+		}
+	}
+}
@@ -11,6 +11,7 @@ use sqlx::PgPool;
 use util::{
 	entities::{Guild, GuildMember, User},
 	errors::{Error, GuildError, UserError},
+	gateway::ConnectedUsers,
 };
 
 pub(crate) mod nick;
@@ -98,6 +99,7 @@ pub async fn modify_member(
 #[handler]
 pub async fn join_guild(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(authed_user): Data<&User>,
 	Path((guild_id, member_id)): Path<(Snowflake, String)>,
 ) -> poem::Result<impl IntoResponse> {
@@ -118,7 +120,7 @@ pub async fn join_guild(
 
 	guild.populate_relations(db).await?;
 
-	guild.add_member(db, member_id).await?;
+	connected_users.add_guild_member(guild.id, member_id, guild.add_member(db, member_id)).await?;
 
 	Ok(Json(guild.into_inner()))
 }
@@ -126,6 +128,7 @@ pub async fn join_guild(
 #[handler]
 pub async fn remove_member(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(authed_user): Data<&User>,
 	Path((guild_id, member_id)): Path<(Snowflake, String)>,
 ) -> poem::Result<impl IntoResponse> {
@@ -153,6 +156,6 @@ pub async fn remove_member(
 	let member =
 		guild.get_member(db, member_id).await?.ok_or(Error::Guild(GuildError::MemberNotFound))?;
 
-	member.delete(db).await?;
+	connected_users.remove_guild_member(guild.id, member_id, member.delete(db)).await?;
 	Ok(Response::builder().status(StatusCode::NO_CONTENT).finish())
 }
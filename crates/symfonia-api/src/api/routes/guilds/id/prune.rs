@@ -11,6 +11,7 @@ use sqlx_pg_uint::PgU16;
 use util::{
 	entities::{Config, Guild, Role, User},
 	errors::{Error, GuildError},
+	gateway::ConnectedUsers,
 };
 
 #[handler]
@@ -56,6 +57,7 @@ pub async fn prune_members_dry_run(
 #[handler]
 pub async fn prune_members(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(authed_user): Data<&User>,
 	Data(config): Data<&Config>,
 	Path(guild_id): Path<Snowflake>,
@@ -93,7 +95,7 @@ pub async fn prune_members(
 	let total_count = members.len();
 	for member in members {
 		// TODO: Emit events?  Maybe write a special query for this?
-		member.delete(db).await?;
+		connected_users.remove_guild_member(guild.id, member.id, member.delete(db)).await?;
 	}
 
 	Ok(Json(GuildPruneResult {
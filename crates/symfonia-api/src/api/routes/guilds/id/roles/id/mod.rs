@@ -11,6 +11,7 @@ use sqlx::PgPool;
 use util::{
 	entities::Guild,
 	errors::{Error, GuildError},
+	gateway::ConnectedUsers,
 };
 
 pub(crate) mod member_ids;
@@ -37,22 +38,27 @@ pub async fn get_role(
 #[handler]
 pub async fn delete_role(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(claims): Data<&Claims>,
 	Path((guild_id, role_id)): Path<(Snowflake, Snowflake)>,
 ) -> poem::Result<impl IntoResponse> {
 	let guild =
 		Guild::get_by_id(db, guild_id).await?.ok_or(Error::Guild(GuildError::InvalidGuild))?;
 
-	let authed_member =
+	let mut authed_member =
 		guild.get_member(db, claims.id).await?.ok_or(Error::Guild(GuildError::MemberNotFound))?;
+	authed_member.populate_permissions(db).await?;
 
-	if authed_member.permissions.has_permission(PermissionFlags::MANAGE_ROLES) {
+	// The guild owner may manage roles without holding the permission.
+	if guild.owner_id != Some(claims.id)
+		&& !authed_member.permissions.has_permission(PermissionFlags::MANAGE_ROLES)
+	{
 		return Err(Error::Guild(GuildError::InsufficientPermissions).into());
 	}
 
 	let role = guild.get_role(db, role_id).await?.ok_or(Error::Guild(GuildError::RoleNotFound))?;
 
-	role.delete(db).await?;
+	connected_users.delete_role(role.id, role.delete(db)).await?;
 
 	// TODO: Emit event 'GUILD_ROLE_DELETE'
 
@@ -122,3 +128,64 @@ pub async fn modify_role(
 
 	Ok(Json(role))
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use poem::{EndpointExt, Route, test::TestClient};
+	use util::gateway::testing::{grant_role, insert_member, insert_role};
+
+	use super::*;
+
+	const GUILD_ID: u64 = 7249086638293258240;
+	const OWNER_ID: u64 = 7248639845155737600;
+	const MEMBER_ID: u64 = 7248639891561517057;
+
+	/// Delete the role with the ID `role_id` as the user with the ID `user_id`.
+	async fn delete_as(db: &PgPool, user_id: u64, role_id: u64) -> StatusCode {
+		let claims = Claims {
+			exp: 0,
+			iat: 0,
+			email: "user@example.com".to_string(),
+			id: Snowflake::from(user_id),
+		};
+		let app = Route::new()
+			.at("/guilds/:guild_id/roles/:role_id", poem::delete(delete_role))
+			.data(db.clone())
+			.data(ConnectedUsers::new())
+			.data(claims);
+		TestClient::new(app)
+			.delete(format!("/guilds/{GUILD_ID}/roles/{role_id}"))
+			.send()
+			.await
+			.0
+			.status()
+	}
+
+	#[sqlx::test(
+		migrations = "../util/migrations",
+		fixtures(path = "../../../../../../../../util/fixtures", scripts("users", "guilds"))
+	)]
+	async fn deleting_roles_requires_manage_roles(db: PgPool) {
+		let guild_id = Snowflake::from(GUILD_ID);
+		insert_role(&db, guild_id, guild_id, PermissionFlags::VIEW_CHANNEL).await;
+		insert_role(&db, Snowflake::from(1u64), guild_id, PermissionFlags::MANAGE_ROLES).await;
+		insert_role(&db, Snowflake::from(2u64), guild_id, PermissionFlags::empty()).await;
+		insert_role(&db, Snowflake::from(3u64), guild_id, PermissionFlags::empty()).await;
+		insert_member(&db, Snowflake::from(OWNER_ID), guild_id, guild_id).await;
+		insert_member(&db, Snowflake::from(MEMBER_ID), guild_id, guild_id).await;
+		sqlx::query("UPDATE guilds SET owner_id = $1 WHERE id = $2")
+			.bind(Snowflake::from(OWNER_ID))
+			.bind(guild_id)
+			.execute(&db)
+			.await
+			.unwrap();
+
+		assert_eq!(delete_as(&db, MEMBER_ID, 2).await, StatusCode::UNAUTHORIZED);
+		// The guild owner does not need to hold the permission.
+		assert_eq!(delete_as(&db, OWNER_ID, 2).await, StatusCode::NO_CONTENT);
+
+		grant_role(&db, Snowflake::from(MEMBER_ID), guild_id, Snowflake::from(1u64)).await;
+		assert_eq!(delete_as(&db, MEMBER_ID, 3).await, StatusCode::NO_CONTENT);
+	}
+}
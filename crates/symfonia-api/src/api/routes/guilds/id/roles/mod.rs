@@ -11,6 +11,7 @@ use util::{
 	SharedEventPublisherMap,
 	entities::{Config, Guild, Role, User},
 	errors::{Error, GuildError},
+	gateway::ConnectedUsers,
 };
 
 pub(crate) mod id;
@@ -38,6 +39,7 @@ pub async fn get_roles(
 pub async fn create_role(
 	Data(db): Data<&PgPool>,
 	Data(publisher_map): Data<&SharedEventPublisherMap>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(authed_user): Data<&User>,
 	Data(config): Data<&Config>,
 	Path(guild_id): Path<Snowflake>,
@@ -56,24 +58,25 @@ pub async fn create_role(
 
 	let name = payload.name.unwrap_or_else(|| format!("Role {}", role_count + 1));
 
-	let role = Role::create(
-		db,
-		publisher_map.clone(),
-		None,
-		guild.id,
-		&name,
-		payload.color.unwrap_or(0.),
-		payload.hoist.unwrap_or_default(),
-		false,
-		true,
-		payload.permissions.unwrap_or_default(),
-		1,
-		None,
-		None,
-	)
-	.await?;
-
-	// TODO: Emit event 'GUILD_ROLE_CREATE'
+	// The role is added to the role user map and dispatched only once it has been
+	// written to the database.
+	let role = connected_users
+		.create_role(Role::create(
+			db,
+			publisher_map.clone(),
+			None,
+			guild.id,
+			&name,
+			payload.color.unwrap_or(0.),
+			payload.hoist.unwrap_or_default(),
+			false,
+			true,
+			payload.permissions.unwrap_or_default(),
+			1,
+			None,
+			None,
+		))
+		.await?;
 
 	Ok(Json(role.into_inner()))
 }
@@ -11,6 +11,7 @@ use util::{
 	SharedEventPublisherMap,
 	entities::{Config, Guild, User},
 	errors::{Error, UserError},
+	gateway::ConnectedUsers,
 };
 
 mod id;
@@ -113,6 +114,7 @@ pub fn setup_routes() -> Route {
 #[handler]
 pub async fn create_guild(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(publisher_map): Data<&SharedEventPublisherMap>,
 	Data(cfg): Data<&Config>,
 	Data(claims): Data<&Claims>,
@@ -138,6 +140,8 @@ pub async fn create_guild(
 		&payload.channels.unwrap_or_default(),
 	)
 	.await?;
+	// The owner has been added as the first member of the new guild.
+	connected_users.role_user_map.lock().await.add_member(guild.id, claims.id);
 
 	Ok(Json(guild))
 }
@@ -13,6 +13,7 @@ use util::{
 	SharedEventPublisherMap,
 	entities::{Config, Guild, GuildTemplate, User},
 	errors::{Error, GuildError},
+	gateway::ConnectedUsers,
 };
 
 #[handler]
@@ -81,6 +82,7 @@ pub async fn get_template(
 #[handler]
 pub async fn create_guild_from_template(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(publisher_map): Data<&SharedEventPublisherMap>,
 	Data(authed_user): Data<&User>,
 	Data(config): Data<&Config>,
@@ -125,7 +127,9 @@ pub async fn create_guild_from_template(
 	)
 	.await?;
 
-	guild.add_member(db, authed_user.id).await?;
+	connected_users
+		.add_guild_member(guild.id, authed_user.id, guild.add_member(db, authed_user.id))
+		.await?;
 
 	Ok(Json(json!({
 		"id": guild.id,
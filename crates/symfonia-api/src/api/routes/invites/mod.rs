@@ -10,6 +10,7 @@ use sqlx::PgPool;
 use util::{
 	entities::{Channel, Invite, User},
 	errors::{ChannelError, Error, InviteError, UserError},
+	gateway::ConnectedUsers,
 };
 
 pub fn setup_routes() -> Route {
@@ -31,6 +32,7 @@ pub async fn get_invite(
 #[handler]
 pub async fn accept_invite(
 	Data(db): Data<&PgPool>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Data(claims): Data<&Claims>,
 	Path(invite_code): Path<String>,
 ) -> poem::Result<impl IntoResponse> {
@@ -40,7 +42,12 @@ pub async fn accept_invite(
 
 	let user = User::get_by_id(db, claims.id).await?.ok_or(Error::User(UserError::InvalidUser))?;
 
-	invite.join(db, &user).await?;
+	match invite.guild_id {
+		Some(guild_id) => {
+			connected_users.add_guild_member(guild_id, user.id, invite.join(db, &user)).await?
+		}
+		None => invite.join(db, &user).await?,
+	}
 
 	Ok(Json(invite.into_inner()))
 }
@@ -1,7 +1,7 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
-use chorus::types::{UserSettings, jwt::Claims};
+use chorus::types::{Snowflake, UserSettings, jwt::Claims};
 use poem::{
 	IntoResponse, handler,
 	web::{Data, Json},
@@ -10,6 +10,7 @@ use sqlx::PgPool;
 use util::{
 	entities::User,
 	errors::{Error, UserError},
+	gateway::{ConnectedUsers, GatewayPayload, dispatchevent::DispatchEvent, event::Event},
 };
 
 #[handler]
@@ -26,13 +27,161 @@ pub async fn get_settings(
 pub async fn update_settings(
 	Data(db): Data<&PgPool>,
 	Data(claims): Data<&Claims>,
+	Data(connected_users): Data<&ConnectedUsers>,
 	Json(settings): Json<UserSettings>,
 ) -> poem::Result<impl IntoResponse> {
 	let mut user =
 		User::get_by_id(db, claims.id).await?.ok_or(Error::User(UserError::InvalidUser))?;
 
+	// Nothing to write or to tell the other sessions about
+	if settings_unchanged(user.settings.as_inner(), &settings) {
+		return Ok(Json(user.settings));
+	}
+
 	user.settings = util::entities::UserSettings::consume(settings, user.settings_index.to_uint());
 	// TODO: user.settings.update(db).await.map_err(Error::Sqlx)?;
+	User::invalidate_cached(claims.id);
+
+	// Notifying the other sessions is best-effort, so a failed dispatch does not
+	// fail the request.
+	if let Err(e) =
+		dispatch_settings_update(connected_users, claims.id, user.settings.as_inner().clone()).await
+	{
+		log::warn!(target: "symfonia::api::users::me::settings", "Failed to dispatch settings update to user {}: {e}", claims.id);
+	}
 
 	Ok(Json(user.settings))
 }
+
+/// Whether `incoming` would leave the `current` settings as they are.
+///
+/// Compares the serialized representations, as not all of the settings' fields
+/// can be compared directly.
+fn settings_unchanged(current: &UserSettings, incoming: &UserSettings) -> bool {
+	match (serde_json::to_value(current), serde_json::to_value(incoming)) {
+		(Ok(current), Ok(incoming)) => current == incoming,
+		_ => false,
+	}
+}
+
+/// Send the new `settings` to all connected sessions of the user, so that a
+/// change made on one device propagates to all others.
+async fn dispatch_settings_update(
+	connected_users: &ConnectedUsers,
+	user_id: Snowflake,
+	settings: UserSettings,
+) -> Result<(), Error> {
+	let mut builder = connected_users.bulk_message_builder();
+	builder.add_user_recipients(&[user_id]).await;
+	builder
+		.set_message(Event::Dispatch(DispatchEvent::UserSettingsUpdate(GatewayPayload::dispatch(
+			"USER_SETTINGS_UPDATE",
+			settings,
+		))))
+		.await;
+	builder.send(connected_users.clone()).await
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::collections::HashMap;
+
+	use poem::{EndpointExt, Route, test::TestClient};
+
+	use super::*;
+
+	const USER_ID: u64 = 7248639845155737600;
+
+	/// Submit `settings` as the user with the ID [USER_ID].
+	async fn update_as_user(
+		db: &PgPool,
+		connected_users: &ConnectedUsers,
+		settings: &UserSettings,
+	) {
+		let claims = Claims {
+			exp: 0,
+			iat: 0,
+			email: "user@example.com".to_string(),
+			id: Snowflake::from(USER_ID),
+		};
+		let app = Route::new()
+			.at("/users/@me/settings", poem::patch(update_settings))
+			.data(db.clone())
+			.data(connected_users.clone())
+			.data(claims);
+		TestClient::new(app)
+			.patch("/users/@me/settings")
+			.body_json(settings)
+			.send()
+			.await
+			.assert_status_is_ok();
+	}
+
+	/// The stored settings of the user with the ID [USER_ID], serialized.
+	async fn stored_settings(db: &PgPool) -> serde_json::Value {
+		let settings: sqlx::types::Json<serde_json::Value> = sqlx::query_scalar(
+			"SELECT to_jsonb(user_settings) FROM user_settings JOIN users ON users.settings_index = user_settings.index WHERE users.id = $1",
+		)
+		.bind(Snowflake::from(USER_ID))
+		.fetch_one(db)
+		.await
+		.unwrap();
+		settings.0
+	}
+
+	#[sqlx::test(
+		migrations = "../util/migrations",
+		fixtures(path = "../../../../../../util/fixtures", scripts("users"))
+	)]
+	async fn only_changed_settings_are_written_and_dispatched(db: PgPool) {
+		let connected_users = ConnectedUsers::new();
+		let user = connected_users.new_user(HashMap::new(), Snowflake::from(USER_ID), Vec::new());
+		let mut other_session = user.lock().await.inbox.resubscribe();
+		let current = User::get_by_id(&db, Snowflake::from(USER_ID)).await.unwrap().unwrap();
+		let stored = stored_settings(&db).await;
+
+		update_as_user(&db, &connected_users, current.settings.as_inner()).await;
+		assert_eq!(stored_settings(&db).await, stored);
+		assert!(other_session.try_recv().is_err());
+
+		let changed = UserSettings {
+			locale: format!("{}-changed", current.settings.locale),
+			..current.settings.as_inner().clone()
+		};
+		update_as_user(&db, &connected_users, &changed).await;
+		match other_session.try_recv().unwrap() {
+			Event::Dispatch(DispatchEvent::UserSettingsUpdate(payload)) => {
+				assert_eq!(payload.event_data.unwrap().locale, changed.locale)
+			}
+			other => panic!("expected a settings update, got {other:?}"),
+		}
+	}
+
+	#[test]
+	fn unchanged_settings_are_detected() {
+		let current = UserSettings { locale: "en-US".to_string(), ..Default::default() };
+		assert!(settings_unchanged(&current, &current.clone()));
+		let changed = UserSettings { locale: "de".to_string(), ..Default::default() };
+		assert!(!settings_unchanged(&current, &changed));
+	}
+
+	#[tokio::test]
+	async fn other_session_receives_settings_update() {
+		let connected_users = ConnectedUsers::new();
+		let user_id = Snowflake::from(1u64);
+		let user = connected_users.new_user(HashMap::new(), user_id, Vec::new());
+		// Every session of a user subscribes to the user's inbox.
+		let mut other_session = user.lock().await.inbox.resubscribe();
+
+		let settings = UserSettings { locale: "de".to_string(), ..Default::default() };
+		dispatch_settings_update(&connected_users, user_id, settings).await.unwrap();
+
+		match other_session.try_recv().unwrap() {
+			Event::Dispatch(DispatchEvent::UserSettingsUpdate(payload)) => {
+				assert_eq!(payload.event_data.unwrap().locale, "de")
+			}
+			other => panic!("expected a settings update, got {other:?}"),
+		}
+	}
+}
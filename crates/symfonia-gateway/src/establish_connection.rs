@@ -2,33 +2,48 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use chorus::types::{GatewayHeartbeat, GatewayHello, GatewayReady};
+use chorus::types::{GatewayHeartbeat, GatewayReady, GatewayResume, Opcode, Snowflake};
 use futures::{SinkExt, StreamExt};
 use log::{debug, trace};
-use serde_json::json;
 use sqlx::PgPool;
-use tokio::{net::TcpStream, sync::Mutex, task::JoinHandle};
+use tokio::{net::TcpStream, sync::Mutex, task::JoinHandle, time::Instant};
 use tokio_tungstenite::{
-	accept_async,
-	tungstenite::{
-		Message,
-		protocol::{CloseFrame, frame::coding::CloseCode},
-	},
+	accept_hdr_async_with_config,
+	tungstenite::handshake::server::{Request, Response},
 };
 use util::{
-	entities::Config,
+	configuration::{
+		CompressionAlgorithm, GatewayConfiguration, HeartbeatConfiguration, SymfoniaConfiguration,
+	},
+	entities::{Application, User},
 	errors::{Error, GatewayError, UserError},
-	gateway::{GatewayPayload, NewWebSocketConnection, WebSocketConnection, event::Event},
-	util::token::check_token,
+	gateway::{
+		DisconnectInfo, GatewayClient, GatewayPayload, GatewayUser, NewWebSocketConnection,
+		WebSocketConnection,
+		auth::GatewayAuthenticator,
+		codec::PayloadCompression,
+		connection_state::ConnectionState,
+		event::Event,
+		intents,
+		kill_reason::KillReason,
+		resume::{ResumeBuffer, ResumeSnapshot, SequencedEvent},
+		session_info::ClientProperties,
+		session_token::SessionToken,
+		shard::validate_shard,
+		stream_compression::{ZlibStream, requested_compression},
+		websocket_config,
+	},
+	util::token::strip_bot_prefix,
 };
 
 use super::ConnectedUsers;
 use crate::{
 	gateway_task::{self},
-	heartbeat::HeartbeatHandler,
-	ready::create_ready,
+	heartbeat::{self, HeartbeatHandler},
+	identify_limit::IdentifyLimiter,
+	ready::{create_ready, send_initial_guild_creates},
 };
 
 /// Internal use only state struct to pass around data to the
@@ -36,7 +51,6 @@ use crate::{
 struct State {
 	connection: WebSocketConnection,
 	db: PgPool,
-	config: Config,
 	connected_users: ConnectedUsers,
 	sequence_number: Arc<Mutex<u64>>,
 	/// Receiver for heartbeat messages. The `HeartbeatHandler` will receive
@@ -45,8 +59,48 @@ struct State {
 	/// Sender for heartbeat messages. The main gateway task will send messages
 	/// to this channel for the `HeartbeatHandler` to receive and handle.
 	heartbeat_send: tokio::sync::broadcast::Sender<GatewayHeartbeat>,
-	session_id_send: tokio::sync::broadcast::Sender<String>,
-	session_id_receive: tokio::sync::broadcast::Receiver<String>,
+	session_id_send: tokio::sync::broadcast::Sender<(Snowflake, SessionToken)>,
+	session_id_receive: tokio::sync::broadcast::Receiver<(Snowflake, SessionToken)>,
+	heartbeat_config: HeartbeatConfiguration,
+	identify_limiter: Arc<IdentifyLimiter>,
+	/// Checks the tokens clients identify and resume with.
+	authenticator: Arc<dyn GatewayAuthenticator>,
+	/// Number of dispatches kept for replaying them when a session resumes.
+	resume_buffer_size: usize,
+	/// Number of dispatches kept for busy sessions, see
+	/// [ResumeBuffer::adaptive].
+	max_resume_buffer_size: usize,
+	default_user_intents: u64,
+	default_bot_intents: u64,
+	/// Compression used if the client identifies with `compress` set.
+	payload_compression: PayloadCompression,
+}
+
+/// The parts of the [GatewayConfiguration] a [handshake] depends on.
+struct HandshakeConfig {
+	identify_timeout: Duration,
+	resume_buffer_size: usize,
+	max_resume_buffer_size: usize,
+	default_user_intents: u64,
+	default_bot_intents: u64,
+	heartbeat: HeartbeatConfiguration,
+	payload_compression: PayloadCompression,
+}
+
+impl From<&GatewayConfiguration> for HandshakeConfig {
+	fn from(config: &GatewayConfiguration) -> Self {
+		Self {
+			identify_timeout: Duration::from_secs(config.identify_timeout),
+			resume_buffer_size: config.resume_buffer_size,
+			max_resume_buffer_size: config.max_resume_buffer_size,
+			default_user_intents: config.default_user_intents,
+			default_bot_intents: config.default_bot_intents,
+			heartbeat: config.heartbeat.clone(),
+			payload_compression: PayloadCompression::new(
+				config.compression_level(CompressionAlgorithm::Zlib),
+			),
+		}
+	}
 }
 
 /// `establish_connection` is the entrypoint method that gets called when a
@@ -58,16 +112,64 @@ struct State {
 pub(super) async fn establish_connection(
 	stream: TcpStream,
 	db: PgPool,
-	config: Config,
 	connected_users: ConnectedUsers,
+	identify_limiter: Arc<IdentifyLimiter>,
+	authenticator: Arc<dyn GatewayAuthenticator>,
 ) -> Result<NewWebSocketConnection, Error> {
 	trace!(target: "symfonia::gateway::establish_connection::establish_connection", "Beginning process to establish connection (handshake)");
-	// Accept the connection and split it into its sender and receiver halves.
-	let ws_stream = accept_async(stream).await?.split();
-	let connection = WebSocketConnection::new(ws_stream.0, ws_stream.1);
+	let gateway_config = &SymfoniaConfiguration::get().gateway;
+	// Accept the connection and split it into its sender and receiver halves,
+	// noting the transport compression the client asked for in the URL.
+	let mut compression = None;
+	let ws_stream = accept_hdr_async_with_config(
+		stream,
+		|request: &Request, response: Response| {
+			compression = request.uri().query().and_then(requested_compression);
+			Ok(response)
+		},
+		Some(websocket_config(gateway_config.max_payload_size)),
+	)
+	.await?
+	.split();
+	let mut connection = WebSocketConnection::with_limits(
+		ws_stream.0,
+		ws_stream.1,
+		gateway_config.max_payload_size,
+		gateway_config.buffer_capacity(),
+		Duration::from_secs(gateway_config.write_timeout),
+	);
+	if let Some(algorithm) = compression {
+		let level = gateway_config.compression_level(algorithm);
+		connection = connection
+			.with_stream_compression(ZlibStream::new(level), gateway_config.buffer_capacity());
+	}
+	handshake(
+		connection,
+		db,
+		connected_users,
+		identify_limiter,
+		authenticator,
+		HandshakeConfig::from(gateway_config),
+	)
+	.await
+}
+
+/// Perform the handshake with the client on the other end of `connection`:
+/// Send it a `Hello`, wait for it to identify or resume, register the session
+/// with `connected_users` and spawn the tasks serving it.
+async fn handshake(
+	connection: WebSocketConnection,
+	db: PgPool,
+	connected_users: ConnectedUsers,
+	identify_limiter: Arc<IdentifyLimiter>,
+	authenticator: Arc<dyn GatewayAuthenticator>,
+	handshake_config: HandshakeConfig,
+) -> Result<NewWebSocketConnection, Error> {
+	// The client has to identify within the identify timeout, starting now.
+	let identify_deadline = Instant::now() + handshake_config.identify_timeout;
 	trace!(target: "symfonia::gateway::establish_connection::establish_connection", "Sending hello message");
 	// Hello message
-	match connection.sender.send(Message::Text(json!(GatewayHello::default()).to_string().into())) {
+	match connection.send_encoded(&heartbeat::hello(&handshake_config.heartbeat)) {
 		Ok(_) => (),
 		Err(e) => {
 			log::debug!(target: "symfonia::gateway::establish_connection", "Error when sending hello message. Aborting connection: {e}");
@@ -78,29 +180,38 @@ pub(super) async fn establish_connection(
 
 	let received_identify_or_resume = false;
 
-	let (kill_send, kill_receive) = tokio::sync::broadcast::channel::<()>(1);
+	let (kill_send, kill_receive) = tokio::sync::broadcast::channel::<KillReason>(1);
 	// Inter-task communication channels. The main gateway task will send received
 	// heartbeat related messages to the `HeartbeatHandler` task via the
 	// `message_send` channel, which the `HeartbeatHandler` task will then receive
 	// and handle.
 	let (message_send, message_receive) = tokio::sync::broadcast::channel::<GatewayHeartbeat>(4);
 
-	let sequence_number = Arc::new(Mutex::new(0u64)); // TODO: Actually use this, as in: Increment it when needed. Currently, this is not being done.
+	// Incremented by the main gateway task for every dispatch sent to the client.
+	let sequence_number = Arc::new(Mutex::new(0u64));
 
-	// Used to inform the `HeartbeatHandler` task of the session_id of the client,
-	// if we receive it after a heartbeat handler task has been spawned.
-	let (session_id_send, session_id_receive) = tokio::sync::broadcast::channel::<String>(1);
+	// Used to inform the `HeartbeatHandler` task of the user and session_id of the
+	// client, if we receive them after a heartbeat handler task has been spawned.
+	let (session_id_send, session_id_receive) =
+		tokio::sync::broadcast::channel::<(Snowflake, SessionToken)>(1);
 
 	let state = State {
 		connection: connection.clone(),
 		db: db.clone(),
-		config: config.clone(),
 		connected_users: connected_users.clone(),
 		sequence_number: sequence_number.clone(),
 		heartbeat_receive: message_receive.resubscribe(),
 		heartbeat_send: message_send.clone(),
 		session_id_send: session_id_send.clone(),
 		session_id_receive: session_id_receive.resubscribe(),
+		heartbeat_config: handshake_config.heartbeat,
+		identify_limiter,
+		authenticator,
+		resume_buffer_size: handshake_config.resume_buffer_size,
+		max_resume_buffer_size: handshake_config.max_resume_buffer_size,
+		default_user_intents: handshake_config.default_user_intents,
+		default_bot_intents: handshake_config.default_bot_intents,
+		payload_compression: handshake_config.payload_compression,
 	};
 
 	// This JoinHandle `.is_some()` if we receive a heartbeat message *before* we
@@ -116,14 +227,9 @@ pub(super) async fn establish_connection(
 			debug!(target: "symfonia::gateway::establish_connection::establish_connection", "Connection was closed before we could establish it");
 			Err(GatewayError::Closed.into())
 		}
-		// If we do not receive an identifying or resuming message within 30 seconds, we close the connection.
-		_ = tokio::time::sleep(std::time::Duration::from_secs(30)) => {
-			debug!(target: "symfonia::gateway::establish_connection::establish_connection", "Connection timed out: No message received within 30 seconds");
-			Err(GatewayError::Timeout.into())
-		}
 		// Since async closures are not yet stable, we have to use a dedicated function to handle the
 		// connection establishment process. :(
-		new_connection = finish_connecting(heartbeat_handler_handle, state)
+		new_connection = until_identified(&connection, identify_deadline, finish_connecting(heartbeat_handler_handle, state))
 		 => {
 			log::trace!(target: "symfonia::gateway::establish_connection", "Connection established.");
 			new_connection
@@ -131,6 +237,33 @@ pub(super) async fn establish_connection(
 	}
 }
 
+/// Kill `connection` with `reason`, after the handshake has failed. Fails
+/// only if the connection is already gone, in which case there is nothing
+/// left to kill, so that is only logged.
+fn kill_connection(connection: &WebSocketConnection, reason: KillReason) {
+	if let Err(e) = connection.kill(reason) {
+		log::debug!(target: "symfonia::gateway::establish_connection::kill_connection", "Failed to kill connection: {e}");
+	}
+}
+
+/// Drives `connecting` to completion, unless `deadline` passes first. In that
+/// case, the client has not identified in time, and the connection is closed
+/// with close code 4009.
+async fn until_identified<T>(
+	connection: &WebSocketConnection,
+	deadline: Instant,
+	connecting: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+	tokio::select! {
+		_ = tokio::time::sleep_until(deadline) => {
+			debug!(target: "symfonia::gateway::establish_connection::until_identified", "Connection timed out: Client did not identify in time");
+			let _ = connection.kill(KillReason::Timeout);
+			Err(GatewayError::Timeout.into())
+		}
+		result = connecting => result,
+	}
+}
+
 /// `finish_connecting` is the second part of the connection establishment
 /// process. It picks up after the initial `Hello` message has been sent to the
 /// client. It then waits on the next message from the client, which should be
@@ -147,11 +280,7 @@ async fn finish_connecting(
 			Ok(next) => next,
 			Err(_) => {
 				log::debug!(target: "symfonia::gateway::finish_connecting", "Encountered error when trying to receive message. Sending kill signal...");
-				state.connection.sender.send(Message::Close(Some(CloseFrame {
-					code: CloseCode::Library(4002),
-					reason: "Failed to decode payload".into(),
-				})));
-				state.connection.kill_send.send(()).expect("Failed to send kill_send");
+				kill_connection(&state.connection, KillReason::InvalidPayload);
 				return Err(GatewayError::Timeout.into());
 			}
 		};
@@ -184,6 +313,7 @@ async fn finish_connecting(
 							state.heartbeat_receive.resubscribe(),
 							state.sequence_number.clone(),
 							state.session_id_receive.resubscribe(),
+							state.heartbeat_config.clone(),
 						);
 						async move {
 							heartbeat_handler.run().await;
@@ -196,86 +326,134 @@ async fn finish_connecting(
 			}
 		} else if let Event::Identify(identify) = event {
 			log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Received identify payload");
-			let claims = match check_token(
+			if !state.identify_limiter.try_acquire() {
+				log::debug!(target: "symfonia::gateway::establish_connection::finish_connecting", "Too many concurrent identifies. Rejecting identify");
+				kill_connection(&state.connection, KillReason::RateLimited);
+				return Err(GatewayError::RateLimited.into());
+			}
+			// An identify payload without data cannot be authenticated, just like one with
+			// an invalid token.
+			let (token, shard, large_threshold, requested_intents, properties, compress) = identify
+				.event_data
+				.map(|data| {
+					(
+						data.token,
+						data.shard,
+						data.large_threshold,
+						data.intents,
+						ClientProperties::from_identify(&data.properties),
+						data.compress,
+					)
+				})
+				.unwrap_or_default();
+			let large_threshold = util::gateway::large_threshold(large_threshold);
+			let requested_intents = requested_intents.map(|intents| intents as u64);
+			let token = strip_bot_prefix(&token).map(str::to_string).unwrap_or(token);
+			let user_id = match state.authenticator.authenticate(&token).await {
+				Ok(user_id) => {
+					trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Token verified");
+					user_id
+				}
+				Err(e) => {
+					log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Failed to verify token: {e}");
+					kill_connection(&state.connection, KillReason::AuthFailed);
+					return Err(UserError::InvalidToken.into());
+				}
+			};
+			let intents = match session_intents(
 				&state.db,
-				&identify.event_data.as_ref().unwrap().token,
-				&state.config.security.jwt_secret,
+				user_id,
+				requested_intents,
+				state.default_user_intents,
+				state.default_bot_intents,
 			)
 			.await
 			{
-				Ok(claims) => {
-					trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Token verified");
-					claims
-				}
-				Err(_) => {
-					log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Failed to verify token");
-					state.connection.sender.send(Message::Close(Some(CloseFrame {
-						code: CloseCode::Library(4004),
-						reason: "The token you sent in your identify payload is incorrect.".into(),
-					})));
-					state.connection.kill_send.send(()).expect("Failed to send kill signal");
-					return Err(UserError::InvalidToken.into());
+				Ok(intents) => intents,
+				Err(e) => {
+					log::debug!(target: "symfonia::gateway::establish_connection::finish_connecting", "Rejecting identify of user {}: {e}", user_id);
+					let reason = match e {
+						Error::Gateway(GatewayError::DisallowedIntents(_)) => {
+							KillReason::DisallowedIntents
+						}
+						_ => KillReason::AuthFailed,
+					};
+					kill_connection(&state.connection, reason);
+					return Err(e);
 				}
 			};
 			log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Getting gateway_user");
-			let gateway_user = state.connected_users.get_user_or_new(claims.id);
-			log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Creating main gateway task handle");
-			let main_task_handle = tokio::spawn(gateway_task::gateway_task(
-				state.connection.clone(),
-				gateway_user.lock().await.inbox.resubscribe(),
-				state.heartbeat_send.clone(),
-				state.sequence_number.clone(),
-				state.connected_users.clone(),
-				claims.id,
-			));
-			log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Creating gateway_client");
-			let gateway_client = state
-                .connected_users
-                .new_client(
-                    gateway_user.clone(),
-                    state.connection.clone(),
-                    main_task_handle,
-                    match heartbeat_handler_handle {
-                        Some(handle) => handle,
-                        None => tokio::spawn({
-                            log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "No heartbeat_handler yet. Creating one...");
-                            let mut heartbeat_handler = HeartbeatHandler::new(
-                                state.connection.clone(),
-                                state.heartbeat_receive.resubscribe(),
-                                state.sequence_number.clone(),
-                                state.session_id_receive.resubscribe(),
-                            );
-                            async move {
-                                heartbeat_handler.run().await;
-                            }
-                        }),
-                    },
-                    &identify.event_data.as_ref().unwrap().token,
-                    state.sequence_number.clone(),
-                )
-                .await;
-			match state.session_id_send.send(identify.event_data.unwrap().token) {
-				Ok(_) => (),
-				Err(_) => {
-					log::error!(target: "symfonia::gateway::establish_connection::finish_connecting", "Failed to send session_id to heartbeat handler");
-					state.connection.sender.send(Message::Close(Some(CloseFrame {
-						code: CloseCode::Library(4000),
-						reason: "Internal server error".into(),
-					})));
-					state.connection.kill_send.send(()).expect("Failed to send kill signal");
-					return Err(GatewayError::Internal.into());
-				}
+			let gateway_user = state.connected_users.get_user_or_new(user_id);
+			if let Err(e) = state.connected_users.load_blocks(&state.db, user_id).await {
+				log::warn!(target: "symfonia::gateway::establish_connection::finish_connecting", "Failed to load the users blocked by user {}: {e}", user_id);
 			}
-			let formatted_payload = GatewayPayload::<GatewayReady> {
-				op_code: 0,
-				event_data: Some(create_ready(claims.id, &state.db).await?),
-				sequence_number: None,
-				event_name: Some("READY".to_string()),
-			};
-			state
-				.connection
-				.sender
-				.send(Message::Text(json!(formatted_payload).to_string().into()))?;
+			let existing_shards = GatewayUser::shards(&gateway_user).await;
+			if let Err(e) = validate_shard(shard, existing_shards) {
+				log::debug!(target: "symfonia::gateway::establish_connection::finish_connecting", "Rejecting shard {shard:?}: {e}");
+				let reason = match e {
+					GatewayError::InvalidShard => KillReason::InvalidShard,
+					_ => KillReason::ShardingRequired,
+				};
+				kill_connection(&state.connection, reason);
+				return Err(e.into());
+			}
+			// Live dispatches wait until the READY and the guild creates have been sent.
+			state.connection.start_sync();
+			// The session is registered under a token of its own, sent to the client as
+			// the session ID of the READY, rather than under the token it identified with.
+			let session_token = SessionToken::generate();
+			let gateway_client = start_session(
+				&state,
+				heartbeat_handler_handle,
+				gateway_user.clone(),
+				user_id,
+				&session_token,
+				ResumeSnapshot::default(),
+				shard,
+			)
+			.await?;
+			set_up_session(&state, &gateway_client, async {
+				{
+					let mut gateway_client = gateway_client.lock().await;
+					gateway_client.set_large_threshold(large_threshold);
+					gateway_client.set_intents(intents);
+					gateway_client.set_properties(properties);
+					gateway_client.transition_to(ConnectionState::Identified)?;
+				}
+				let formatted_payload = GatewayPayload::<GatewayReady>::dispatch(
+					"READY",
+					create_ready(user_id, &session_token, &state.db).await?,
+				);
+				// Payloads of connections using transport compression, negotiated through
+				// the gateway URL, are not compressed a second time. Otherwise, payload
+				// compression applies to the READY already.
+				if compress.unwrap_or_default() {
+					state.connection.enable_payload_compression(state.payload_compression);
+				}
+				state.connection.send_encoded(&formatted_payload)?;
+				send_initial_guild_creates(
+					&state.connection,
+					&state.db,
+					&state.connected_users,
+					user_id,
+					shard,
+					large_threshold,
+				)
+				.await?;
+				gateway_client.lock().await.transition_to(ConnectionState::Ready)?;
+				state.connection.finish_sync();
+				// Interactions kept while a bot was offline are delivered once it is ready.
+				let pending_interactions = state.connected_users.take_pending_interactions(user_id);
+				if !pending_interactions.is_empty() {
+					if let Some(inbox) = state.connected_users.inbox(user_id).await {
+						for interaction in pending_interactions {
+							inbox.send(interaction).map_err(GatewayError::from)?;
+						}
+					}
+				}
+				Ok(())
+			})
+			.await?;
 			log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Done!");
 			return Ok(NewWebSocketConnection {
 				user: gateway_user,
@@ -283,16 +461,669 @@ async fn finish_connecting(
 			});
 		} else if let Event::Resume(resume) = event {
 			log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Received resume payload");
-			log::warn!(target: "symfonia::gateway::establish_connection::finish_connecting", "Resuming connections is not yet implemented. Telling client to identify instead.");
-			state.connection.sender.send(Message::Close(Some(CloseFrame {
-				code: CloseCode::from(4000),
-				reason: "Resuming connections is not yet implemented. Please identify instead."
-					.into(),
-			})))?;
-			state.connection.kill_send.send(()).expect("Failed to send kill signal");
+			let prepared = match resume.event_data {
+				Some(resume) => prepare_resume(&state, resume).await,
+				None => Err(GatewayError::SessionNotResumable.into()),
+			};
+			let (user_id, disconnect_info, replay) = match prepared {
+				Ok(prepared) => prepared,
+				Err(e) => {
+					log::debug!(target: "symfonia::gateway::establish_connection::finish_connecting", "Cannot resume session, telling client to identify instead: {e}");
+					state.connection.send_encoded(&GatewayPayload {
+						op_code: Opcode::InvalidSession as u8,
+						event_data: Some(false),
+						sequence_number: None,
+						event_name: None,
+					})?;
+					continue;
+				}
+			};
+			let gateway_user = state.connected_users.get_user_or_new(user_id);
+			// The resumed session continues with the sequence numbers of the old one.
+			*state.sequence_number.lock().await = disconnect_info.disconnected_at_sequence;
+			// Live dispatches wait until the missed ones have been replayed.
+			state.connection.start_sync();
+			let gateway_client = start_session(
+				&state,
+				heartbeat_handler_handle,
+				gateway_user.clone(),
+				user_id,
+				&disconnect_info.session_token,
+				disconnect_info.recent_dispatches,
+				disconnect_info.shard,
+			)
+			.await?;
+			set_up_session(&state, &gateway_client, async {
+				{
+					let mut gateway_client = gateway_client.lock().await;
+					gateway_client.set_intents(disconnect_info.intents);
+					gateway_client.set_properties(disconnect_info.properties.clone());
+					gateway_client.transition_to(ConnectionState::Resuming)?;
+				}
+				// The resumed session is compressed as the disconnected one was, including
+				// the replayed dispatches.
+				if disconnect_info.compress {
+					state.connection.enable_payload_compression(state.payload_compression);
+				}
+				for event in replay {
+					state.connection.send_encoded(&event.payload)?;
+				}
+				state.connection.send_encoded(&GatewayPayload::<()> {
+					op_code: Opcode::Dispatch as u8,
+					event_data: None,
+					sequence_number: None,
+					event_name: Some("RESUMED".to_string()),
+				})?;
+				gateway_client.lock().await.transition_to(ConnectionState::Ready)?;
+				state.connection.finish_sync();
+				Ok(())
+			})
+			.await?;
+			log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Resumed session");
+			return Ok(NewWebSocketConnection { user: gateway_user, client: gateway_client });
 		} else {
 			debug!(target: "symfonia::gateway::establish_connection::finish_connecting", "Message could not be decoded as resume, heartbeat or identify: {}", raw_message);
 			return Err(GatewayError::UnexpectedMessage("Received payload other than Heartbeat, Identify or Resume before the connection was established".to_string()).into());
 		}
 	}
 }
+
+/// Finish setting up the session of `gateway_client`, which [start_session]
+/// has registered already, by awaiting `setup`. If that fails, the session is
+/// killed with [KillReason::InternalError] and removed again, instead of being
+/// left registered without a client receiving its dispatches.
+async fn set_up_session(
+	state: &State,
+	gateway_client: &Arc<Mutex<GatewayClient>>,
+	setup: impl Future<Output = Result<(), Error>>,
+) -> Result<(), Error> {
+	let Err(e) = setup.await else {
+		return Ok(());
+	};
+	log::debug!(target: "symfonia::gateway::establish_connection::set_up_session", "Failed to set up session, killing it: {e}");
+	// Kills the connection, too.
+	if let Err(e) = gateway_client
+		.lock()
+		.await
+		.die(state.connected_users.clone(), KillReason::InternalError)
+		.await
+	{
+		log::debug!(target: "symfonia::gateway::establish_connection::set_up_session", "Failed to remove session: {e}");
+	}
+	Err(e)
+}
+
+/// Spawn the main gateway task of a session, register the session as a
+/// [GatewayClient] of `gateway_user` and hand its token to the
+/// `HeartbeatHandler`. If no `HeartbeatHandler` has been spawned yet, because
+/// the client has not sent a heartbeat so far, one is spawned.
+///
+/// The dispatches retained for resuming the session start out with
+/// `recent_dispatches`, those of the session being resumed, if any.
+async fn start_session(
+	state: &State,
+	heartbeat_handler_handle: Option<JoinHandle<()>>,
+	gateway_user: Arc<Mutex<GatewayUser>>,
+	user_id: Snowflake,
+	token: &SessionToken,
+	recent_dispatches: ResumeSnapshot,
+	shard: Option<(u64, u64)>,
+) -> Result<Arc<Mutex<GatewayClient>>, Error> {
+	log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Creating main gateway task handle");
+	let shard = Arc::new(Mutex::new(shard));
+	let mut resume_buffer =
+		ResumeBuffer::adaptive(state.resume_buffer_size, state.max_resume_buffer_size)
+			.with_metrics(state.connected_users.metrics.clone());
+	resume_buffer.restore(recent_dispatches);
+	let recent_dispatches = Arc::new(Mutex::new(resume_buffer));
+	let main_task_handle = tokio::spawn(gateway_task::gateway_task(
+		state.connection.clone(),
+		state.db.clone(),
+		gateway_user.lock().await.inbox.resubscribe(),
+		state.heartbeat_send.clone(),
+		state.sequence_number.clone(),
+		recent_dispatches.clone(),
+		state.connected_users.clone(),
+		user_id,
+		token.clone(),
+		shard.clone(),
+	));
+	log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "Creating gateway_client");
+	let heartbeat_handler_handle = match heartbeat_handler_handle {
+		Some(handle) => handle,
+		None => tokio::spawn({
+			log::trace!(target: "symfonia::gateway::establish_connection::finish_connecting", "No heartbeat_handler yet. Creating one...");
+			let mut heartbeat_handler = HeartbeatHandler::new(
+				state.connection.clone(),
+				state.heartbeat_receive.resubscribe(),
+				state.sequence_number.clone(),
+				state.session_id_receive.resubscribe(),
+				state.heartbeat_config.clone(),
+			);
+			async move {
+				heartbeat_handler.run().await;
+			}
+		}),
+	};
+	let gateway_client = state
+		.connected_users
+		.new_client(
+			gateway_user.clone(),
+			state.connection.clone(),
+			main_task_handle,
+			heartbeat_handler_handle,
+			token,
+			state.sequence_number.clone(),
+			recent_dispatches,
+			shard,
+		)
+		.await;
+	match state.session_id_send.send((user_id, token.clone())) {
+		Ok(_) => (),
+		Err(_) => {
+			log::error!(target: "symfonia::gateway::establish_connection::finish_connecting", "Failed to send session_id to heartbeat handler");
+			kill_connection(&state.connection, KillReason::InternalError);
+			return Err(GatewayError::Internal.into());
+		}
+	}
+	Ok(gateway_client)
+}
+
+/// Look up the session a client wants to resume, by the session ID it has been
+/// sent in the READY, and the events it missed, along with the Snowflake ID of
+/// the user resuming it. The session is removed
+/// from the resumeable sessions in the process, even if the reason it has been
+/// disconnected for does not allow resuming it. Sessions of other users than
+/// the one resuming cannot be resumed.
+async fn prepare_resume(
+	state: &State,
+	resume: GatewayResume,
+) -> Result<(Snowflake, DisconnectInfo, Vec<SequencedEvent>), Error> {
+	let token = strip_bot_prefix(&resume.token).unwrap_or(&resume.token);
+	let session_token: SessionToken = resume.session_id.parse()?;
+	let user_id = state.authenticator.authenticate(token).await?;
+	let resume_sequence = resume.seq.parse::<u64>().map_err(|_| {
+		GatewayError::UnexpectedMessage("Resume payload has an invalid sequence number".to_string())
+	})?;
+	// The session stays resumable until the resume has been validated, so that
+	// a resume with the wrong token or sequence number does not discard it.
+	let disconnect_info = state
+		.connected_users
+		.resumable_clients
+		.get(&session_token)
+		.await
+		.filter(|disconnect_info| {
+			disconnect_info.is_resumable() && disconnect_info.user_id == user_id
+		})
+		.ok_or(GatewayError::SessionNotResumable)?;
+	let replay = disconnect_info.recent_dispatches.replay_after(resume_sequence)?;
+	// Only one of several concurrent resumes of the session gets to remove it.
+	let disconnect_info = state
+		.connected_users
+		.resumable_clients
+		.remove(&session_token)
+		.await
+		.ok_or(GatewayError::SessionNotResumable)?;
+	Ok((user_id, disconnect_info, replay))
+}
+
+/// The intents of a session of the user with the ID `user_id`, which requested
+/// the intents `requested`. Users which do not request any intents get
+/// `default_user`, bots are handled by [bot_session_intents]. Whether a session
+/// belongs to a bot is decided by its user rather than by the `Bot ` prefix of
+/// its token, so that bots cannot skip the checks of their intents by leaving
+/// the prefix out.
+///
+/// ## Errors
+///
+/// Fails with [UserError::InvalidToken] if the user does not exist, and as
+/// described in [bot_session_intents] for bots.
+async fn session_intents(
+	db: &PgPool,
+	user_id: Snowflake,
+	requested: Option<u64>,
+	default_user: u64,
+	default_bot: u64,
+) -> Result<u64, Error> {
+	let user = User::get_by_id(db, user_id).await?.ok_or(UserError::InvalidToken)?;
+	if user.bot != Some(true) {
+		return Ok(requested.unwrap_or(default_user));
+	}
+	bot_session_intents(db, user_id, requested, default_bot).await
+}
+
+/// The intents of a session of the bot user with the ID `bot_user_id`. Bots
+/// which do not request any intents get the `default` ones the application of
+/// the bot may use.
+///
+/// ## Errors
+///
+/// Fails with [UserError::InvalidToken] if the user is not the bot user of any
+/// application, and with [GatewayError::DisallowedIntents] if it requested
+/// privileged intents its application may not use.
+async fn bot_session_intents(
+	db: &PgPool,
+	bot_user_id: Snowflake,
+	requested: Option<u64>,
+	default: u64,
+) -> Result<u64, Error> {
+	let application =
+		Application::get_by_bot_user_id(db, bot_user_id).await?.ok_or(UserError::InvalidToken)?;
+	match requested {
+		Some(requested) => Ok(intents::validate_bot_intents(requested, application.flags)?),
+		None => Ok(intents::bot_intents(default, application.flags)),
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::time::SystemTime;
+
+	use chorus::types::{GatewayIdentifyPayload, jwt::generate_token};
+	use futures::future::BoxFuture;
+	use serde_json::json;
+	use tokio_tungstenite::tungstenite::{Message, protocol::frame::coding::CloseCode};
+	use util::{
+		entities::{Config, UserCache},
+		gateway::{
+			auth::{AuthError, JwtAuthenticator},
+			session_info::ClientProperties,
+			testing::unreachable_db,
+		},
+	};
+
+	use super::*;
+
+	/// Accepts the token `accepted` of the user with the ID 1 only.
+	struct MockAuthenticator;
+
+	impl GatewayAuthenticator for MockAuthenticator {
+		fn authenticate<'a>(
+			&'a self,
+			token: &'a str,
+		) -> BoxFuture<'a, Result<Snowflake, AuthError>> {
+			Box::pin(async move {
+				match token {
+					"accepted" => Ok(Snowflake::from(1u64)),
+					_ => Err(AuthError::InvalidToken),
+				}
+			})
+		}
+	}
+
+	/// Accepts the token `bot-token`, without a `Bot ` prefix, of the user with
+	/// the ID held.
+	struct BotAuthenticator(Snowflake);
+
+	impl GatewayAuthenticator for BotAuthenticator {
+		fn authenticate<'a>(
+			&'a self,
+			token: &'a str,
+		) -> BoxFuture<'a, Result<Snowflake, AuthError>> {
+			Box::pin(async move {
+				match token {
+					"bot-token" => Ok(self.0),
+					_ => Err(AuthError::InvalidToken),
+				}
+			})
+		}
+	}
+
+	fn resumable_session(session_token: &SessionToken, user_id: Snowflake) -> DisconnectInfo {
+		DisconnectInfo {
+			session_token: session_token.clone(),
+			user_id,
+			disconnected_at_sequence: 0,
+			shard: None,
+			recent_dispatches: ResumeSnapshot::default(),
+			intents: 0,
+			compress: false,
+			properties: ClientProperties::default(),
+			reason: KillReason::ConnectionLost,
+			disconnected_at: SystemTime::now(),
+		}
+	}
+
+	fn handshake_config() -> HandshakeConfig {
+		HandshakeConfig {
+			identify_timeout: Duration::from_secs(5),
+			resume_buffer_size: 16,
+			max_resume_buffer_size: 64,
+			default_user_intents: 0,
+			default_bot_intents: 0,
+			heartbeat: HeartbeatConfiguration::default(),
+			payload_compression: PayloadCompression::new(6),
+		}
+	}
+
+	#[tokio::test]
+	async fn resuming_handshake_yields_new_connection() {
+		let user_id = Snowflake::from(1u64);
+		// The token is checked against the user, which is served from the cache so
+		// that no database is needed.
+		UserCache::init(16, Duration::from_secs(60));
+		let mut user = User::default();
+		user.id = user_id;
+		UserCache::global().unwrap().insert(user);
+		let config = Config::default();
+		let token = generate_token(&user_id, "user@example.com", &config.security.jwt_secret);
+
+		let connected_users = ConnectedUsers::new();
+		let session_token = SessionToken::generate();
+		connected_users
+			.resumable_clients
+			.insert(session_token.clone(), resumable_session(&session_token, user_id))
+			.await;
+		let db = unreachable_db();
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		let handshake = tokio::spawn(handshake(
+			connection,
+			db.clone(),
+			connected_users.clone(),
+			Arc::new(IdentifyLimiter::new(1, Duration::from_secs(5))),
+			Arc::new(JwtAuthenticator::new(db, config.security.jwt_secret.clone())),
+			handshake_config(),
+		));
+
+		let Message::Text(hello) = client.outgoing.recv().await.unwrap() else {
+			panic!("expected a hello");
+		};
+		assert_eq!(serde_json::from_str::<serde_json::Value>(&hello).unwrap()["op"], 10);
+		let resume = json!({
+			"op": Opcode::Resume as u8,
+			"d": { "token": token, "session_id": session_token.as_str(), "seq": "0" },
+		});
+		client.incoming.send(Message::Text(resume.to_string().into())).unwrap();
+
+		let new_connection = handshake.await.unwrap().unwrap();
+		assert_eq!(new_connection.client.lock().await.session_token, session_token);
+		assert!(new_connection.client.lock().await.parent.upgrade().is_some());
+		assert!(connected_users.client_by_token(&session_token).await.is_some());
+		let Message::Text(resumed) = client.outgoing.recv().await.unwrap() else {
+			panic!("expected RESUMED");
+		};
+		assert_eq!(serde_json::from_str::<serde_json::Value>(&resumed).unwrap()["t"], "RESUMED");
+	}
+
+	#[tokio::test]
+	async fn resume_is_authenticated_by_the_configured_authenticator() {
+		let connected_users = ConnectedUsers::new();
+		for token in ["accepted", "rejected"] {
+			let session_token = SessionToken::from(token);
+			connected_users
+				.resumable_clients
+				.insert(
+					session_token.clone(),
+					resumable_session(&session_token, Snowflake::from(1u64)),
+				)
+				.await;
+		}
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		let handshake = tokio::spawn(handshake(
+			connection,
+			unreachable_db(),
+			connected_users.clone(),
+			Arc::new(IdentifyLimiter::new(1, Duration::from_secs(5))),
+			Arc::new(MockAuthenticator),
+			handshake_config(),
+		));
+		client.outgoing.recv().await.unwrap();
+
+		let resume = |token| {
+			let resume = json!({
+				"op": Opcode::Resume as u8,
+				"d": { "token": token, "session_id": token, "seq": "0" },
+			});
+			Message::Text(resume.to_string().into())
+		};
+		client.incoming.send(resume("rejected")).unwrap();
+		let Message::Text(invalid_session) = client.outgoing.recv().await.unwrap() else {
+			panic!("expected an invalid session");
+		};
+		assert_eq!(
+			serde_json::from_str::<serde_json::Value>(&invalid_session).unwrap()["op"],
+			Opcode::InvalidSession as u8
+		);
+		// The session of the rejected token has not been handed out.
+		assert!(
+			connected_users.resumable_clients.contains_key(&SessionToken::from("rejected")).await
+		);
+
+		client.incoming.send(resume("accepted")).unwrap();
+		let new_connection = handshake.await.unwrap().unwrap();
+		assert_eq!(new_connection.user.lock().await.id, Snowflake::from(1u64));
+		assert_eq!(
+			new_connection.client.lock().await.session_token,
+			SessionToken::from("accepted")
+		);
+	}
+
+	#[tokio::test]
+	async fn resumed_session_keeps_payload_compression() {
+		let connected_users = ConnectedUsers::new();
+		let session_token = SessionToken::from("accepted");
+		let mut disconnected = resumable_session(&session_token, Snowflake::from(1u64));
+		disconnected.compress = true;
+		connected_users.resumable_clients.insert(session_token, disconnected).await;
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		let resumed_connection = connection.clone();
+		let handshake = tokio::spawn(handshake(
+			connection,
+			unreachable_db(),
+			connected_users.clone(),
+			Arc::new(IdentifyLimiter::new(1, Duration::from_secs(5))),
+			Arc::new(MockAuthenticator),
+			handshake_config(),
+		));
+		client.outgoing.recv().await.unwrap();
+		assert!(!resumed_connection.compresses_payloads());
+
+		let resume = json!({
+			"op": Opcode::Resume as u8,
+			"d": { "token": "accepted", "session_id": "accepted", "seq": "0" },
+		});
+		client.incoming.send(Message::Text(resume.to_string().into())).unwrap();
+		handshake.await.unwrap().unwrap();
+
+		assert!(resumed_connection.compresses_payloads());
+	}
+
+	#[tokio::test]
+	async fn sessions_of_other_users_cannot_be_resumed() {
+		let connected_users = ConnectedUsers::new();
+		let session_token = SessionToken::from("accepted");
+		connected_users
+			.resumable_clients
+			.insert(session_token.clone(), resumable_session(&session_token, Snowflake::from(2u64)))
+			.await;
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		tokio::spawn(handshake(
+			connection,
+			unreachable_db(),
+			connected_users.clone(),
+			Arc::new(IdentifyLimiter::new(1, Duration::from_secs(5))),
+			Arc::new(MockAuthenticator),
+			handshake_config(),
+		));
+		client.outgoing.recv().await.unwrap();
+
+		let resume = json!({
+			"op": Opcode::Resume as u8,
+			"d": { "token": "accepted", "session_id": "accepted", "seq": "0" },
+		});
+		client.incoming.send(Message::Text(resume.to_string().into())).unwrap();
+
+		let Message::Text(invalid_session) = client.outgoing.recv().await.unwrap() else {
+			panic!("expected an invalid session");
+		};
+		assert_eq!(
+			serde_json::from_str::<serde_json::Value>(&invalid_session).unwrap()["op"],
+			Opcode::InvalidSession as u8
+		);
+		assert!(connected_users.client_by_token(&session_token).await.is_none());
+		// The session can still be resumed by its own user.
+		assert!(connected_users.resumable_clients.contains_key(&session_token).await);
+	}
+
+	#[tokio::test]
+	async fn connection_without_identify_is_closed_after_timeout() {
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		let deadline = Instant::now() + Duration::from_millis(50);
+
+		let result =
+			until_identified(&connection, deadline, std::future::pending::<Result<(), Error>>())
+				.await;
+
+		assert!(matches!(result, Err(Error::Gateway(GatewayError::Timeout))));
+		match client.outgoing.recv().await.unwrap() {
+			Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Library(4009)),
+			other => panic!("expected a close frame, got {other:?}"),
+		}
+	}
+
+	/// Make the user with the ID `bot_user_id` the bot user of an application
+	/// which may not use any privileged intents.
+	async fn insert_bot(db: &PgPool, bot_user_id: Snowflake) {
+		sqlx::query("UPDATE users SET bot = true WHERE id = $1")
+			.bind(bot_user_id)
+			.execute(db)
+			.await
+			.unwrap();
+		sqlx::query(
+			"INSERT INTO applications (id, name, hook, bot_public, bot_require_code_grant, verify_key, flags, owner_id, bot_user_id) VALUES (1, 'My Bot', true, true, false, 1, 0, $1, $1)",
+		)
+		.bind(bot_user_id)
+		.execute(db)
+		.await
+		.unwrap();
+	}
+
+	#[sqlx::test(
+		migrations = "../util/migrations",
+		fixtures(path = "../../util/fixtures", scripts("users"))
+	)]
+	async fn bots_are_recognized_by_their_user(db: PgPool) {
+		let bot_user_id = Snowflake::from(7248639845155737600u64);
+		let user_id = Snowflake::from(7248639891561517057u64);
+		insert_bot(&db, bot_user_id).await;
+		let defaults = intents::GUILDS | intents::GUILD_MEMBERS;
+
+		// The session of the bot gets the intents of a bot, whether it identified with
+		// a `Bot ` prefix or not.
+		assert_eq!(
+			session_intents(&db, bot_user_id, None, defaults, defaults).await.unwrap(),
+			intents::GUILDS
+		);
+		assert_eq!(
+			session_intents(&db, user_id, None, defaults, defaults).await.unwrap(),
+			defaults
+		);
+	}
+
+	#[sqlx::test(
+		migrations = "../util/migrations",
+		fixtures(path = "../../util/fixtures", scripts("users"))
+	)]
+	async fn bot_identifying_without_prefix_is_denied_privileged_intents(db: PgPool) {
+		let bot_user_id = Snowflake::from(7248639845155737600u64);
+		insert_bot(&db, bot_user_id).await;
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		let handshake = tokio::spawn(handshake(
+			connection,
+			db,
+			ConnectedUsers::new(),
+			Arc::new(IdentifyLimiter::new(1, Duration::from_secs(5))),
+			Arc::new(BotAuthenticator(bot_user_id)),
+			handshake_config(),
+		));
+		client.outgoing.recv().await.unwrap();
+
+		let mut identify = GatewayIdentifyPayload::common();
+		identify.token = "bot-token".to_string();
+		identify.intents = Some(intents::GUILD_MEMBERS as _);
+		let identify = GatewayPayload {
+			op_code: Opcode::Identify as u8,
+			event_data: Some(identify),
+			sequence_number: None,
+			event_name: None,
+		};
+		client.incoming.send(Message::Text(json!(identify).to_string().into())).unwrap();
+
+		assert!(matches!(
+			handshake.await.unwrap(),
+			Err(Error::Gateway(GatewayError::DisallowedIntents(_)))
+		));
+	}
+
+	#[tokio::test]
+	async fn session_failing_to_get_ready_is_removed() {
+		let user_id = Snowflake::from(1u64);
+		// The user is served from the cache, so that identifying only fails once the
+		// READY is built from the unreachable database.
+		UserCache::init(16, Duration::from_secs(60));
+		let mut user = User::default();
+		user.id = user_id;
+		UserCache::global().unwrap().insert(user);
+		let connected_users = ConnectedUsers::new();
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		let handshake = tokio::spawn(handshake(
+			connection,
+			unreachable_db(),
+			connected_users.clone(),
+			Arc::new(IdentifyLimiter::new(1, Duration::from_secs(5))),
+			Arc::new(MockAuthenticator),
+			handshake_config(),
+		));
+		client.outgoing.recv().await.unwrap();
+
+		let mut identify = GatewayIdentifyPayload::common();
+		identify.token = "accepted".to_string();
+		let identify = GatewayPayload {
+			op_code: Opcode::Identify as u8,
+			event_data: Some(identify),
+			sequence_number: None,
+			event_name: None,
+		};
+		client.incoming.send(Message::Text(json!(identify).to_string().into())).unwrap();
+
+		assert!(handshake.await.unwrap().is_err());
+		assert!(connected_users.list_sessions(user_id).await.is_empty());
+		match client.outgoing.recv().await.unwrap() {
+			Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Library(4000)),
+			other => panic!("expected a close frame, got {other:?}"),
+		}
+	}
+
+	#[tokio::test]
+	async fn throttled_identify_is_rate_limited() {
+		let identify_limiter = Arc::new(IdentifyLimiter::new(1, Duration::from_secs(60)));
+		assert!(identify_limiter.try_acquire());
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		let handshake = tokio::spawn(handshake(
+			connection,
+			unreachable_db(),
+			ConnectedUsers::new(),
+			identify_limiter,
+			Arc::new(MockAuthenticator),
+			handshake_config(),
+		));
+		client.outgoing.recv().await.unwrap();
+
+		let mut identify = GatewayIdentifyPayload::common();
+		identify.token = "accepted".to_string();
+		let identify = GatewayPayload {
+			op_code: Opcode::Identify as u8,
+			event_data: Some(identify),
+			sequence_number: None,
+			event_name: None,
+		};
+		client.incoming.send(Message::Text(json!(identify).to_string().into())).unwrap();
+
+		assert!(matches!(handshake.await.unwrap(), Err(Error::Gateway(GatewayError::RateLimited))));
+		match client.outgoing.recv().await.unwrap() {
+			Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Library(4009)),
+			other => panic!("expected a close frame, got {other:?}"),
+		}
+	}
+}
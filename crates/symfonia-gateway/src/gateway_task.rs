@@ -2,34 +2,54 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::sync::Arc;
+use std::{collections::VecDeque, sync::Arc};
 
 use chorus::types::{GatewayHeartbeat, Snowflake};
 use log::debug;
-use serde_json::json;
+use sqlx::PgPool;
 use tokio::sync::Mutex;
-use tokio_tungstenite::tungstenite::{
-	Message,
-	protocol::{CloseFrame, frame::coding::CloseCode},
-};
+use tokio_tungstenite::tungstenite::Message;
 use util::{
 	errors::{Error, GatewayError},
-	gateway::{WebSocketConnection, event::Event},
+	gateway::{
+		GatewayPayload, WebSocketConnection,
+		connection_state::ConnectionState,
+		dispatchevent::DispatchEvent,
+		event::Event,
+		kill_reason::KillReason,
+		resume::{ResumeBuffer, SequencedEvent},
+		session_token::SessionToken,
+		shard::receives_guild,
+	},
 };
 
 use super::ConnectedUsers;
 
 /// Handles all messages a client sends to the gateway post-handshake.
+#[allow(clippy::too_many_arguments)]
 pub(super) async fn gateway_task(
 	mut connection: WebSocketConnection,
+	db: PgPool,
 	inbox: tokio::sync::broadcast::Receiver<Event>,
 	heartbeat_send: tokio::sync::broadcast::Sender<GatewayHeartbeat>,
 	last_sequence_number: Arc<Mutex<u64>>,
+	recent_dispatches: Arc<Mutex<ResumeBuffer>>,
 	connected_users: ConnectedUsers,
 	user_id: Snowflake,
+	session_token: SessionToken,
+	shard: Arc<Mutex<Option<(u64, u64)>>>,
 ) {
 	log::trace!(target: "symfonia::gateway::gateway_task", "Started a new gateway task!");
-	let inbox_processor = tokio::spawn(process_inbox(connection.clone(), inbox.resubscribe()));
+	let inbox_processor = tokio::spawn(process_inbox(
+		connection.clone(),
+		inbox.resubscribe(),
+		last_sequence_number.clone(),
+		recent_dispatches,
+		shard,
+		connected_users.clone(),
+		user_id,
+		session_token.clone(),
+	));
 
 	/*
 	Before we can respond to any gateway event we receive, we need to figure out what kind of event
@@ -40,33 +60,36 @@ pub(super) async fn gateway_task(
 
 	loop {
 		tokio::select! {
-			_ = connection.kill_receive.recv() => {
+			reason = connection.kill_receive.recv() => {
 				// Since callsites handle closing the connection, we don't need to do that here.
 				// Perform cleanup and return
-				let mut store_lock = connected_users.store.write();
-				store_lock.users.remove(&user_id);
-				store_lock.inboxes.remove(&user_id);
-				// TODO(bitfl0wer) Add the user to the disconnected sessions
-				drop(store_lock);
+				let reason = reason.unwrap_or(KillReason::InternalError);
+				end_session(&connected_users, &session_token, reason).await;
 				return;
 			},
 			message_result = connection.receiver.recv() => {
-				if message_result.is_err() {
-					connection.sender.send(Message::Close(Some(CloseFrame { code: tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Library(4000), reason: "INTERNAL_SERVER_ERROR".into() })));
-					connection.kill_send.send(()).expect("Failed to send kill_send");
-				}
-				let message_of_unknown_type = message_result.unwrap();
+				// The session ends once the kill signal has been received.
+				let Ok(message_of_unknown_type) = message_result else {
+					kill_connection(&connection, KillReason::InternalError);
+					continue;
+				};
 				match message_of_unknown_type {
 					Message::Text(_) => {
 						log::trace!(target: "symfonia::gateway::gateway_task", "Received raw message {:?}", message_of_unknown_type);
-						let event = unwrap_event(Event::try_from(message_of_unknown_type), connection.clone(), connection.kill_send.clone());
-						handle_event(event, connection.clone(), heartbeat_send.clone());
-					},
-					Message::Close(close_frame) => {
-						// Closing is initiated by the client - we don't need to send a
-						// close message back.
-						debug!("Client is closing connection. Signaling gateway_task to shut down");
-						connection.kill_send.send(());
+						connected_users.record_activity(user_id).await;
+						let Some(event) = unwrap_event(Event::try_from(message_of_unknown_type), &connection) else {
+							continue;
+						};
+						handle_event(
+							event,
+							connection.clone(),
+							&db,
+							heartbeat_send.clone(),
+							&connected_users,
+							user_id,
+							&session_token,
+						)
+						.await;
 					},
 					_ => continue
 				}
@@ -75,35 +98,101 @@ pub(super) async fn gateway_task(
 	}
 }
 
+/// Kill `connection` for the given `reason`. This only fails if the tasks of
+/// the connection have already stopped, so failures are only logged.
+fn kill_connection(connection: &WebSocketConnection, reason: KillReason) {
+	if let Err(e) = connection.kill(reason) {
+		log::debug!(target: "symfonia::gateway::gateway_task", "Failed to kill connection: {e}");
+	}
+}
+
+/// Remove the session identified by `session_token`, which has been killed for
+/// the given `reason`, from the connected users, keeping it around to be
+/// resumed. Does nothing if the session has already been removed.
+async fn end_session(
+	connected_users: &ConnectedUsers,
+	session_token: &SessionToken,
+	reason: KillReason,
+) {
+	let Some(client) = connected_users.client_by_token(session_token).await else {
+		return;
+	};
+	if let Err(e) = client.lock().await.die(connected_users.clone(), reason).await {
+		log::debug!(target: "symfonia::gateway::gateway_task", "Error when ending session: {e}");
+	}
+}
+
 /// Handle an event received from the gateway.
-fn handle_event(
+async fn handle_event(
 	event: Event,
 	connection: WebSocketConnection,
+	db: &PgPool,
 	heartbeat_send: tokio::sync::broadcast::Sender<GatewayHeartbeat>,
+	connected_users: &ConnectedUsers,
+	user_id: Snowflake,
+	session_token: &SessionToken,
 ) {
 	log::trace!(target: "symfonia::gateway::gateway_task", "Event type of received message: {:?}", event);
 	match event {
 		Event::Dispatch(_) => {
 			// Receiving a dispatch event from a client is never correct
 			log::debug!(target: "symfonia::gateway::gateway_task", "Received an unexpected message: {:?}", event);
-			connection.sender.send(Message::Close(Some(CloseFrame {
-				code: tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Library(
-					4002,
-				),
-				reason: "DECODE_ERROR".into(),
-			})));
-			connection.kill_send.send(()).expect("Failed to send kill_send");
-		}
-		Event::Heartbeat(hearbeat_event) => {
-			match heartbeat_send.send(hearbeat_event) {
-				Err(e) => {
-					log::debug!(target: "symfonia::gateway::gateway_task", "Received Heartbeat but HeartbeatHandler seems to be dead?");
-					connection.sender.send(Message::Close(Some(CloseFrame { code: tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Library(4002), reason: "DECODE_ERROR".into() })));
-					connection.kill_send.send(()).expect("Failed to send kill_send");
-				}
-				Ok(_) => {
-					log::trace!(target: "symfonia::gateway::gateway_task", "Forwarded heartbeat message to HeartbeatHandler!");
-				}
+			kill_connection(&connection, KillReason::InvalidPayload);
+		}
+		Event::Identify(_) | Event::Resume(_) => {
+			// Sessions identify or resume once, while `establish_connection` sets them up.
+			let to = match event {
+				Event::Identify(_) => ConnectionState::Identified,
+				_ => ConnectionState::Resuming,
+			};
+			let mut state = match connected_users.client_by_token(session_token).await {
+				Some(client) => client.lock().await.state(),
+				None => ConnectionState::Dead,
+			};
+			if let Err(e) = state.transition(to) {
+				log::debug!(target: "symfonia::gateway::gateway_task", "Rejecting identify or resume of established session: {e}");
+				kill_connection(&connection, KillReason::AlreadyAuthenticated);
+			}
+		}
+		Event::Heartbeat(hearbeat_event) => match heartbeat_send.send(hearbeat_event) {
+			Err(e) => {
+				log::debug!(target: "symfonia::gateway::gateway_task", "Received Heartbeat but HeartbeatHandler seems to be dead?");
+				kill_connection(&connection, KillReason::InternalError);
+			}
+			Ok(_) => {
+				log::trace!(target: "symfonia::gateway::gateway_task", "Forwarded heartbeat message to HeartbeatHandler!");
+			}
+		},
+		Event::PresenceUpdate(presence_update) => {
+			let Some(presence_update) = presence_update.event_data else {
+				return;
+			};
+			if let Err(e) = connected_users
+				.update_presence(user_id, session_token, presence_update.status)
+				.await
+			{
+				log::warn!(target: "symfonia::gateway::gateway_task", "Failed to broadcast presence of user {user_id}: {e}");
+			}
+		}
+		Event::GuildSubscriptions(lazy_request) => {
+			let Some(lazy_request) = lazy_request.event_data else {
+				return;
+			};
+			if let Err(e) = connected_users
+				.subscribe_to_presences(db, user_id, session_token, lazy_request)
+				.await
+			{
+				log::warn!(target: "symfonia::gateway::gateway_task", "Failed to subscribe user {user_id} to presences: {e}");
+			}
+		}
+		Event::VoiceStateUpdate(voice_state_update) => {
+			let Some(voice_state_update) = voice_state_update.event_data else {
+				return;
+			};
+			if let Err(e) =
+				connected_users.update_voice_state(user_id, voice_state_update.state).await
+			{
+				log::warn!(target: "symfonia::gateway::gateway_task", "Failed to broadcast voice state of user {user_id}: {e}");
 			}
 		}
 		_ => {
@@ -112,73 +201,88 @@ fn handle_event(
 	}
 }
 
-/// Unwraps an event from a Result<Event, Error> and handles the error if there
-/// is one. Errors will shut down all tasks belonging to this session and will
-/// kill the gateway task through a panic.
-fn unwrap_event(
-	result: Result<Event, Error>,
-	connection: WebSocketConnection,
-	kill_send: tokio::sync::broadcast::Sender<()>,
-) -> Event {
-	match result {
-		Err(e) => {
-			match e {
-				Error::Gateway(g) => match g {
-					GatewayError::UnexpectedOpcode(o) => {
-						log::debug!(target: "symfonia::gateway::gateway_task::unwrap_event", "Received an unexpected opcode: {:?}", o);
-						connection.sender.send(Message::Close(Some(CloseFrame { code: tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Library(4001), reason: "UNKNOWN_OPCODE".into() })));
-						kill_send.send(()).expect("Failed to send kill_send");
-						panic!("Killing gateway task: Received an unexpected opcode");
-					}
-					GatewayError::UnexpectedMessage(m) => {
-						log::debug!(target: "symfonia::gateway::gateway_task::unwrap_event", "Received an unexpected message: {:?}", m);
-						connection.sender.send(Message::Close(Some(CloseFrame { code: tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Library(4002), reason: "DECODE_ERROR".into() })));
-						kill_send.send(()).expect("Failed to send kill_send");
-						panic!("Killing gateway task: Received an unexpected message");
-					}
-					_ => {
-						log::debug!(target: "symfonia::gateway::gateway_task::unwrap_event", "Received an unexpected error: {:?}", g);
-						connection.sender.send(Message::Close(Some(CloseFrame { code: tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Library(4000), reason: "INTERNAL_SERVER_ERROR".into() })));
-						kill_send.send(()).expect("Failed to send kill_send");
-						panic!("Killing gateway task: Received an unexpected error");
-					}
-				},
-				_ => {
-					log::debug!(target: "symfonia::gateway::gateway_task::unwrap_event", "Received an unexpected error: {:?}", e);
-					connection.sender.send(Message::Close(Some(CloseFrame { code: tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Library(4000), reason: "INTERNAL_SERVER_ERROR".into() })));
-					kill_send.send(()).expect("Failed to send kill_send");
-					panic!("Killing gateway task: Received an unexpected error");
-				}
-			}
+/// Unwraps an event from a Result<Event, Error>, or kills `connection` if
+/// the message could not be turned into an event. The gateway task then ends
+/// the session once it receives the kill signal.
+fn unwrap_event(result: Result<Event, Error>, connection: &WebSocketConnection) -> Option<Event> {
+	let e = match result {
+		Ok(event) => return Some(event),
+		Err(e) => e,
+	};
+	let reason = match e {
+		Error::Gateway(GatewayError::UnexpectedOpcode(o)) => {
+			log::debug!(target: "symfonia::gateway::gateway_task::unwrap_event", "Received an unexpected opcode: {:?}", o);
+			KillReason::UnknownOpcode
 		}
-		Ok(event) => event,
-	}
+		Error::Gateway(GatewayError::UnexpectedMessage(m)) => {
+			log::debug!(target: "symfonia::gateway::gateway_task::unwrap_event", "Received an unexpected message: {:?}", m);
+			KillReason::InvalidPayload
+		}
+		e => {
+			log::debug!(target: "symfonia::gateway::gateway_task::unwrap_event", "Received an unexpected error: {:?}", e);
+			KillReason::InternalError
+		}
+	};
+	kill_connection(connection, reason);
+	None
 }
 
-/// Process events triggered by the HTTP API.
+/// Process events triggered by the HTTP API. Guild events are only forwarded if
+/// the guild belongs to the `shard` this session is currently on, presences
+/// only if [ConnectedUsers::receives_presence] says so. Every
+/// forwarded dispatch event gets the next sequence number of this session and
+/// is retained in `recent_dispatches`, so that it can be replayed on resume.
+/// While the session is being synced, see [WebSocketConnection::start_sync],
+/// dispatch events are held back and forwarded in order afterwards.
 async fn process_inbox(
 	mut connection: WebSocketConnection,
 	mut inbox: tokio::sync::broadcast::Receiver<Event>,
+	sequence_number: Arc<Mutex<u64>>,
+	recent_dispatches: Arc<Mutex<ResumeBuffer>>,
+	shard: Arc<Mutex<Option<(u64, u64)>>>,
+	connected_users: ConnectedUsers,
+	user_id: Snowflake,
+	session_token: SessionToken,
 ) {
+	let mut syncing = connection.syncing();
+	let mut held_back = VecDeque::new();
+	let forwarder = InboxForwarder {
+		connection: connection.clone(),
+		sequence_number,
+		recent_dispatches,
+		shard,
+		connected_users,
+		user_id,
+		session_token,
+	};
 	loop {
 		tokio::select! {
 			_ = connection.kill_receive.recv() => {
 				return;
 			}
-			event = inbox.recv() => {
-				match event {
-					Ok(event) => {
-						let send_result = connection.sender.send(Message::Text(json!(event).to_string().into()));
-						match send_result {
-							Ok(_) => (), // TODO: Increase sequence number here
-							Err(_) => {
-								debug!("Failed to send event to WebSocket. Closing connection and killing tasks");
-								connection.sender.send(Message::Close(Some(CloseFrame { code: CloseCode::Library(4000), reason: "WebSocket error".into() })));
-								connection.kill_send.send(()).expect("Failed to send kill_send");
-							},
-						}
+			Ok(()) = syncing.changed() => {
+				if *syncing.borrow_and_update() {
+					continue;
+				}
+				while let Some(event) = held_back.pop_front() {
+					if forwarder.forward(event).await.is_err() {
+						return;
 					}
-					Err(_) => {
+				}
+			}
+			event = inbox.recv() => {
+				let Ok(event) = event else {
+					return;
+				};
+				if matches!(event, Event::Dispatch(_)) && *syncing.borrow() {
+					held_back.push_back(event);
+					continue;
+				}
+				// Events held back during the sync go first, even if the end of the sync
+				// has not been noticed yet.
+				held_back.push_back(event);
+				while let Some(event) = held_back.pop_front() {
+					if forwarder.forward(event).await.is_err() {
 						return;
 					}
 				}
@@ -186,3 +290,381 @@ async fn process_inbox(
 		}
 	}
 }
+
+/// Forwards the events of the inbox of a user to one of their sessions, see
+/// [process_inbox].
+struct InboxForwarder {
+	connection: WebSocketConnection,
+	sequence_number: Arc<Mutex<u64>>,
+	recent_dispatches: Arc<Mutex<ResumeBuffer>>,
+	shard: Arc<Mutex<Option<(u64, u64)>>>,
+	connected_users: ConnectedUsers,
+	user_id: Snowflake,
+	session_token: SessionToken,
+}
+
+impl InboxForwarder {
+	/// Send `event` to the client, unless the session does not receive it.
+	///
+	/// ## Errors
+	///
+	/// Returns [GatewayError::Closed] if the connection no longer forwards
+	/// messages to the client, after killing its tasks.
+	async fn forward(&self, event: Event) -> Result<(), GatewayError> {
+		if let Some(guild_id) = event.guild_id() {
+			if !receives_guild(*self.shard.lock().await, guild_id) {
+				return Ok(());
+			}
+		}
+		if let Event::Dispatch(DispatchEvent::PresenceUpdate(GatewayPayload {
+			event_data: Some(presence),
+			..
+		})) = &event
+		{
+			if !self
+				.connected_users
+				.receives_presence(self.user_id, &self.session_token, presence.user.id)
+				.await
+			{
+				return Ok(());
+			}
+		}
+		let message = match event {
+			Event::Dispatch(_) => {
+				let mut sequence = self.sequence_number.lock().await;
+				*sequence += 1;
+				match SequencedEvent::new(*sequence, &event) {
+					Ok(event) => {
+						let message = self.connection.encode(&event.payload);
+						self.recent_dispatches.lock().await.push(event);
+						message
+					}
+					Err(e) => Err(e),
+				}
+			}
+			event => self.connection.encode(&event),
+		};
+		let message = match message {
+			Ok(message) => message,
+			Err(e) => {
+				debug!("Failed to serialize event: {e}");
+				return Ok(());
+			}
+		};
+		if let Err(e) = self.connection.try_send(message) {
+			debug!("Failed to send event to WebSocket: {e}. Closing connection and killing tasks");
+			// Nobody listening for the kill signal means that the tasks have already
+			// stopped.
+			let _ = self.connection.kill(KillReason::InternalError);
+			return Err(e);
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::collections::HashMap;
+
+	use chorus::types::{MessageCreate, UserStatus};
+	use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+	use util::gateway::{
+		intents,
+		testing::{TestSession, unreachable_db},
+	};
+
+	use super::*;
+
+	fn message_in_guild(guild_id: Snowflake) -> Event {
+		Event::Dispatch(DispatchEvent::MessageCreate(GatewayPayload::dispatch(
+			"MESSAGE_CREATE",
+			MessageCreate { guild_id: Some(guild_id), ..Default::default() },
+		)))
+	}
+
+	#[tokio::test]
+	async fn killed_session_is_deregistered_and_resumable() {
+		let connected_users = ConnectedUsers::default();
+		let user_id = Snowflake::from(1u64);
+		let user = connected_users.new_user(HashMap::new(), user_id, Vec::new());
+		let (connection, _client) = WebSocketConnection::from_channels();
+		let (heartbeat_send, _) = tokio::sync::broadcast::channel(4);
+		let sequence = Arc::new(Mutex::new(0));
+		let recent_dispatches = Arc::new(Mutex::new(ResumeBuffer::new(10)));
+		let shard = Arc::new(Mutex::new(None));
+		TestSession::new(connection.clone())
+			.last_sequence(sequence.clone())
+			.recent_dispatches(recent_dispatches.clone())
+			.shard(shard.clone())
+			.connect(&connected_users, &user, "token")
+			.await;
+		let task = tokio::spawn(gateway_task(
+			connection.clone(),
+			unreachable_db(),
+			user.lock().await.inbox.resubscribe(),
+			heartbeat_send,
+			sequence,
+			recent_dispatches,
+			connected_users.clone(),
+			user_id,
+			SessionToken::from("token"),
+			shard,
+		));
+
+		// This is what the connection does when the client closes it abnormally.
+		connection.kill_send.send(KillReason::ConnectionLost).unwrap();
+		task.await.unwrap();
+
+		assert!(connected_users.inbox(user_id).await.is_none());
+		let disconnect_info =
+			connected_users.resumable_clients.get(&SessionToken::from("token")).await.unwrap();
+		assert!(disconnect_info.is_resumable());
+		assert_eq!(connected_users.metrics.snapshot().client_initiated_closes(), 1);
+	}
+
+	#[tokio::test]
+	async fn identify_after_ready_is_rejected() {
+		let connected_users = ConnectedUsers::default();
+		let user = connected_users.new_user(HashMap::new(), Snowflake::from(1u64), Vec::new());
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		let gateway_client =
+			TestSession::new(connection.clone()).connect(&connected_users, &user, "token").await;
+		{
+			let mut gateway_client = gateway_client.lock().await;
+			gateway_client.transition_to(ConnectionState::Identified).unwrap();
+			gateway_client.transition_to(ConnectionState::Ready).unwrap();
+		}
+		let mut kill_receive = connection.kill_receive.resubscribe();
+		let (heartbeat_send, _) = tokio::sync::broadcast::channel(4);
+
+		let identify = Event::try_from(Message::Text(r#"{"op":2}"#.into())).unwrap();
+		handle_event(
+			identify,
+			connection.clone(),
+			&unreachable_db(),
+			heartbeat_send,
+			&connected_users,
+			Snowflake::from(1u64),
+			&SessionToken::from("token"),
+		)
+		.await;
+
+		match client.outgoing.recv().await.unwrap() {
+			Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Library(4005)),
+			other => panic!("expected a close frame, got {other:?}"),
+		}
+		assert_eq!(kill_receive.try_recv().unwrap(), KillReason::AlreadyAuthenticated);
+		assert_eq!(gateway_client.lock().await.state(), ConnectionState::Ready);
+	}
+
+	#[tokio::test]
+	async fn unknown_opcode_kills_connection_without_panicking() {
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		let mut kill_receive = connection.kill_receive.resubscribe();
+
+		let event = Event::try_from(Message::Text(r#"{"op":255}"#.into()));
+		assert!(unwrap_event(event, &connection).is_none());
+
+		match client.outgoing.recv().await.unwrap() {
+			Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Library(4001)),
+			other => panic!("expected a close frame, got {other:?}"),
+		}
+		assert_eq!(kill_receive.try_recv().unwrap(), KillReason::UnknownOpcode);
+	}
+
+	#[tokio::test]
+	async fn received_message_updates_last_activity() {
+		let connected_users = ConnectedUsers::default();
+		let user_id = Snowflake::from(1u64);
+		let user = connected_users.new_user(HashMap::new(), user_id, Vec::new());
+		let (connection, client) = WebSocketConnection::from_channels();
+		let (heartbeat_send, mut heartbeat_receive) = tokio::sync::broadcast::channel(4);
+		let sequence = Arc::new(Mutex::new(0));
+		let recent_dispatches = Arc::new(Mutex::new(ResumeBuffer::new(10)));
+		let shard = Arc::new(Mutex::new(None));
+		// The user has another session, which stays quiet.
+		for token in ["quiet", "active"] {
+			let (connection, _) = WebSocketConnection::from_channels();
+			TestSession::new(connection)
+				.last_sequence(sequence.clone())
+				.recent_dispatches(recent_dispatches.clone())
+				.shard(shard.clone())
+				.connect(&connected_users, &user, token)
+				.await;
+		}
+		tokio::spawn(gateway_task(
+			connection,
+			unreachable_db(),
+			user.lock().await.inbox.resubscribe(),
+			heartbeat_send,
+			sequence,
+			recent_dispatches,
+			connected_users.clone(),
+			user_id,
+			SessionToken::from("active"),
+			shard,
+		));
+		let connected_at = user.lock().await.last_activity();
+		tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+
+		client.incoming.send(Message::Text(r#"{"op":1,"d":0}"#.into())).unwrap();
+		heartbeat_receive.recv().await.unwrap();
+
+		assert!(user.lock().await.last_activity() > connected_at);
+	}
+
+	#[tokio::test]
+	async fn moved_session_receives_events_of_its_new_shard() {
+		let connected_users = ConnectedUsers::default();
+		let user_id = Snowflake::from(1u64);
+		let user = connected_users.new_user(HashMap::new(), user_id, Vec::new());
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		let sequence = Arc::new(Mutex::new(0));
+		let recent_dispatches = Arc::new(Mutex::new(ResumeBuffer::new(10)));
+		let shard = Arc::new(Mutex::new(Some((0, 2))));
+		let gateway_client = TestSession::new(connection.clone())
+			.last_sequence(sequence.clone())
+			.recent_dispatches(recent_dispatches.clone())
+			.shard(shard.clone())
+			.connect(&connected_users, &user, "token")
+			.await;
+		tokio::spawn(process_inbox(
+			connection,
+			user.lock().await.inbox.resubscribe(),
+			sequence,
+			recent_dispatches,
+			shard,
+			connected_users.clone(),
+			user_id,
+			SessionToken::from("token"),
+		));
+		let inbox = connected_users.inbox(user_id).await.unwrap();
+		let first_shard_guild = Snowflake::from(2u64 << 22);
+		let second_shard_guild = Snowflake::from(1u64 << 22);
+
+		inbox.send(message_in_guild(second_shard_guild)).unwrap();
+		inbox.send(message_in_guild(first_shard_guild)).unwrap();
+		// Only the event of the guild on shard 0 has been delivered.
+		assert!(matches!(client.outgoing.recv().await.unwrap(), Message::Text(_)));
+		assert!(client.outgoing.try_recv().is_err());
+
+		let moved = connected_users.move_user_between_shards(user_id, (0, 2), (1, 2)).await;
+		assert_eq!(moved.unwrap(), 1);
+		assert_eq!(gateway_client.lock().await.shard().await, Some((1, 2)));
+
+		inbox.send(message_in_guild(first_shard_guild)).unwrap();
+		inbox.send(message_in_guild(second_shard_guild)).unwrap();
+		let Message::Text(text) = client.outgoing.recv().await.unwrap() else {
+			panic!("expected a text message");
+		};
+		assert!(text.contains(&u64::from(second_shard_guild).to_string()));
+		assert!(client.outgoing.try_recv().is_err());
+		assert!(matches!(
+			connected_users.move_user_between_shards(user_id, (1, 2), (2, 2)).await,
+			Err(GatewayError::InvalidShard)
+		));
+	}
+
+	#[tokio::test]
+	async fn only_subscribed_presences_are_delivered() {
+		let connected_users = ConnectedUsers::default();
+		let user_id = Snowflake::from(1u64);
+		let subscribed_id = Snowflake::from(2u64);
+		let unsubscribed_id = Snowflake::from(3u64);
+		let guild_id = Snowflake::from(4u64);
+		let mut users = Vec::new();
+		for id in [user_id, subscribed_id, unsubscribed_id] {
+			let user = connected_users.new_user(HashMap::new(), id, Vec::new());
+			let (connection, _) = WebSocketConnection::from_channels();
+			TestSession::new(connection)
+				.connect(&connected_users, &user, format!("token {id}"))
+				.await
+				.lock()
+				.await
+				.set_intents(intents::GUILDS);
+			users.push(user);
+		}
+		// Sharing a guild does not matter without the GUILD_PRESENCES intent.
+		let mut role_user_map = connected_users.role_user_map.lock().await;
+		for id in [user_id, subscribed_id, unsubscribed_id] {
+			role_user_map.grant_role(guild_id, id);
+		}
+		drop(role_user_map);
+		let session_token = SessionToken::from(format!("token {user_id}"));
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		tokio::spawn(process_inbox(
+			connection.clone(),
+			users[0].lock().await.inbox.resubscribe(),
+			Arc::new(Mutex::new(0)),
+			Arc::new(Mutex::new(ResumeBuffer::new(10))),
+			Arc::new(Mutex::new(None)),
+			connected_users.clone(),
+			user_id,
+			session_token.clone(),
+		));
+		let (heartbeat_send, _) = tokio::sync::broadcast::channel(4);
+
+		let lazy_request =
+			format!(r#"{{"op":14,"d":{{"guild_id":"{guild_id}","members":["{subscribed_id}"]}}}}"#);
+		let lazy_request = Event::try_from(Message::Text(lazy_request.into())).unwrap();
+		handle_event(
+			lazy_request,
+			connection,
+			&unreachable_db(),
+			heartbeat_send,
+			&connected_users,
+			user_id,
+			&session_token,
+		)
+		.await;
+		for id in [unsubscribed_id, subscribed_id] {
+			connected_users
+				.update_presence(id, &SessionToken::from(format!("token {id}")), UserStatus::Idle)
+				.await
+				.unwrap();
+		}
+
+		let Message::Text(text) = client.outgoing.recv().await.unwrap() else {
+			panic!("expected a text message");
+		};
+		assert!(text.contains(&format!(r#""id":"{subscribed_id}""#)));
+		assert!(client.outgoing.try_recv().is_err());
+	}
+
+	#[tokio::test]
+	async fn dispatch_during_sync_follows_the_sync_burst() {
+		let connected_users = ConnectedUsers::default();
+		let user_id = Snowflake::from(1u64);
+		let user = connected_users.new_user(HashMap::new(), user_id, Vec::new());
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		connection.start_sync();
+		tokio::spawn(process_inbox(
+			connection.clone(),
+			user.lock().await.inbox.resubscribe(),
+			Arc::new(Mutex::new(0)),
+			Arc::new(Mutex::new(ResumeBuffer::new(10))),
+			Arc::new(Mutex::new(None)),
+			connected_users.clone(),
+			user_id,
+			SessionToken::from("token"),
+		));
+
+		let inbox = connected_users.inbox(user_id).await.unwrap();
+		inbox.send(message_in_guild(Snowflake::from(2u64))).unwrap();
+		// Give the live event the chance to overtake the burst.
+		tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+		for burst in ["READY", "GUILD_CREATE"] {
+			connection.try_send(Message::Text(burst.into())).unwrap();
+		}
+		connection.finish_sync();
+
+		for burst in ["READY", "GUILD_CREATE"] {
+			assert_eq!(client.outgoing.recv().await.unwrap(), Message::Text(burst.into()));
+		}
+		let Message::Text(text) = client.outgoing.recv().await.unwrap() else {
+			panic!("expected a text message");
+		};
+		assert!(text.contains("MESSAGE_CREATE"));
+	}
+}
@@ -4,24 +4,61 @@
 
 use std::sync::Arc;
 
-use chorus::types::{GatewayHeartbeat, GatewayHeartbeatAck};
+use chorus::types::{
+	GatewayHeartbeat, GatewayHeartbeatAck, GatewayHello, HelloData, Opcode, Snowflake,
+};
 use futures::SinkExt;
 use log::*;
-use serde_json::json;
+use rand::Rng;
 use tokio::sync::Mutex;
-use tokio_tungstenite::tungstenite::{Message, protocol::CloseFrame};
-use util::gateway::WebSocketConnection;
+use util::{
+	configuration::HeartbeatConfiguration,
+	gateway::{
+		GatewayPayload, WebSocketConnection, kill_reason::KillReason,
+		log_context::SessionLogContext, session_token::SessionToken,
+	},
+	session_log,
+};
 
 static HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(45);
 static LATENCY_BUFFER: std::time::Duration = std::time::Duration::from_secs(5);
+/// Upper bound for [HeartbeatConfiguration::hello_jitter_percent].
+const MAX_HELLO_JITTER_PERCENT: u8 = 50;
+
+/// The `Hello` sent to a newly connected client. Its `heartbeat_interval`
+/// deviates from [HEARTBEAT_INTERVAL] by up to the configured jitter.
+pub(super) fn hello(config: &HeartbeatConfiguration) -> GatewayHello {
+	let jitter = i64::from(config.hello_jitter_percent.min(MAX_HELLO_JITTER_PERCENT));
+	let percent = 100 + rand::thread_rng().gen_range(-jitter..=jitter);
+	let heartbeat_interval = HEARTBEAT_INTERVAL.as_millis() as u64 * percent as u64 / 100;
+	GatewayHello { op: Opcode::Hello as i32, d: HelloData { heartbeat_interval } }
+}
+
+/// The longest `heartbeat_interval` [hello] may advertise with `config`.
+fn max_heartbeat_interval(config: &HeartbeatConfiguration) -> std::time::Duration {
+	let jitter = u32::from(config.hello_jitter_percent.min(MAX_HELLO_JITTER_PERCENT));
+	HEARTBEAT_INTERVAL * (100 + jitter) / 100
+}
+
+/// How long a client may go without heartbeating before its session is closed.
+/// Covers the longest interval it may have been told to heartbeat in.
+fn heartbeat_timeout(config: &HeartbeatConfiguration) -> std::time::Duration {
+	max_heartbeat_interval(config) + LATENCY_BUFFER
+}
 
 pub(super) struct HeartbeatHandler {
 	connection: WebSocketConnection,
 	message_receive: tokio::sync::broadcast::Receiver<GatewayHeartbeat>,
-	last_heartbeat: std::time::Instant,
+	last_heartbeat: tokio::time::Instant,
 	/// The current sequence number of the gateway connection.
 	sequence_number: Arc<Mutex<u64>>,
-	session_id_receive: tokio::sync::broadcast::Receiver<String>,
+	session_id_receive: tokio::sync::broadcast::Receiver<(Snowflake, SessionToken)>,
+	/// Identifies the session of this handler in log lines, once known.
+	log_context: SessionLogContext,
+	/// Operator-provided tuning for this handler.
+	config: HeartbeatConfiguration,
+	/// Tracks how many heartbeats in a row had a "way off" sequence number.
+	way_off_counter: WayOffCounter,
 }
 
 impl HeartbeatHandler {
@@ -36,11 +73,14 @@ impl HeartbeatHandler {
 	/// - `message_receive`: An MPSC (Multiple Producer Single Consumer) channel
 	///   receiver for receiving heartbeat messages.
 	/// - `session_id_receive`: A oneshot channel receiver for receiving the
-	///   session ID. The heartbeat handler may start running before an identify
-	///   or resume message with a session ID is received, so this channel is
-	///   used to wait for the session ID. If a session ID has been received,
-	///   the heartbeat handler can use it to store a DisconnectInfo object in
-	///   the appropriate `GatewayClient` when the connection is closed.
+	///   Snowflake ID of the user and the session ID. The heartbeat handler may
+	///   start running before an identify or resume message with a session ID
+	///   is received, so this channel is used to wait for the session ID. If a
+	///   session ID has been received, the heartbeat handler can use it to
+	///   store a DisconnectInfo object in the appropriate `GatewayClient` when
+	///   the connection is closed.
+	/// - `config`: The [HeartbeatConfiguration] to use, e.g. for deciding when
+	///   a heartbeat sequence number is "way off".
 	///
 	/// # Returns
 	/// The newly created `HeartbeatHandler` instance.
@@ -70,15 +110,19 @@ impl HeartbeatHandler {
 		connection: WebSocketConnection,
 		message_receive: tokio::sync::broadcast::Receiver<GatewayHeartbeat>,
 		last_sequence_number: Arc<Mutex<u64>>,
-		session_id_receive: tokio::sync::broadcast::Receiver<String>,
+		session_id_receive: tokio::sync::broadcast::Receiver<(Snowflake, SessionToken)>,
+		config: HeartbeatConfiguration,
 	) -> Self {
 		trace!(target: "symfonia::gateway::heartbeat_handler", "New heartbeat handler created");
 		Self {
 			connection,
 			message_receive,
-			last_heartbeat: std::time::Instant::now(),
+			last_heartbeat: tokio::time::Instant::now(),
 			sequence_number: last_sequence_number,
 			session_id_receive,
+			log_context: SessionLogContext::unidentified(),
+			way_off_counter: WayOffCounter::new(config.way_off_tolerance),
+			config,
 		}
 	}
 
@@ -89,7 +133,8 @@ impl HeartbeatHandler {
 	/// signals to either receive a new heartbeat message or check if it should
 	/// terminate. It updates the last heartbeat time upon receiving a new
 	/// heartbeat, sends a ping over the WebSocket connection periodically, and
-	/// terminates itself if no heartbeats are received within 45 seconds.
+	/// terminates itself if no heartbeats are received within the heartbeat
+	/// timeout, which covers the longest interval [hello] may advertise.
 	/// Because this method is running an "infinite" loop, the
 	/// [HeartbeatHandler] should be moved to a separate task using
 	/// `tokio::spawn`, where the method should be executed.
@@ -107,7 +152,7 @@ impl HeartbeatHandler {
 	/// this is being done to close the [GatewayTask].
 	/// ```
 	pub(super) async fn run(&mut self) {
-		trace!(target: "symfonia::gateway::heartbeat_handler", "Heartbeat handler started");
+		session_log!(target: "symfonia::gateway::heartbeat_handler", Level::Trace, &self.log_context, "Heartbeat handler started");
 		// TODO: On death of this task, create and store disconnect info in gateway
 		// client object
 		let sequence = 0u64;
@@ -119,109 +164,375 @@ impl HeartbeatHandler {
 			// - Heartbeat sequence number is way off, likely because the connection has
 			//   high latency or is unstable
 			//
-			// I would consider "way off" to be a difference of more than or equal to 3.
+			// What is considered "way off" is configurable through
+			// `HeartbeatConfiguration::way_off_threshold` and defaults to a difference of
+			// more than or equal to 3.
 			tokio::select! {
+				Ok((user_id, session_id)) = self.session_id_receive.recv() => {
+					self.log_context = SessionLogContext::new(user_id, &session_id);
+					session_log!(target: "symfonia::gateway::heartbeat_handler", Level::Trace, &self.log_context, "Received session ID");
+				}
 				_ = self.connection.kill_receive.recv() => {
-					trace!("Received kill signal in heartbeat_handler. Stopping heartbeat handler");
+					session_log!(target: "symfonia::gateway::heartbeat_handler", Level::Trace, &self.log_context, "Received kill signal in heartbeat_handler. Stopping heartbeat handler");
 					break;
 				}
 				Ok(heartbeat) = self.message_receive.recv() => {
-					trace!("Received heartbeat message in heartbeat_handler");
+					session_log!(target: "symfonia::gateway::heartbeat_handler", Level::Trace, &self.log_context, "Received heartbeat message in heartbeat_handler");
 					if let Some(received_sequence_number) = heartbeat.d {
-						let sequence = self.sequence_number.lock().await;
-						// TODO: As long as sequence numbers are not increased server-side, this code
-						// is not useful.
-						/* match Self::compare_sequence_numbers(*sequence, received_sequence_number) {
-							SequenceNumberComparison::Correct => {
-								self.send_ack().await;
-							}
+						let sequence = *self.sequence_number.lock().await;
+						let comparison = Self::compare_sequence_numbers(sequence, received_sequence_number, self.config.way_off_threshold);
+						match &comparison {
+							SequenceNumberComparison::Correct => (),
 							SequenceNumberComparison::SlightlyOff(diff) => {
-								trace!(target: "symfonia::gateway::heartbeat_handler", "Received heartbeat sequence number is slightly off by {}. This may be due to latency or a new packet being sent before the current one got received.", diff);
-								self.send_ack().await;
+								session_log!(target: "symfonia::gateway::heartbeat_handler", Level::Trace, &self.log_context, "Received heartbeat sequence number is slightly off by {}. This may be due to latency or a new packet being sent before the current one got received.", diff);
 							}
 							SequenceNumberComparison::WayOff(diff) => {
-								// TODO: We could potentially send a heartbeat to the client, prompting it to send a new heartbeat.
-								// This would require more logic though.
-								trace!(target: "symfonia::gateway::heartbeat_handler", "Received heartbeat sequence number is way off by {}. This may be due to latency.", diff);
-								match self.connection.sender.send(Message::Text(json!(GatewayReconnect::default()).to_string())) {
-									Ok(_) => (),
-									Err(e) => {
-										trace!("Failed to send reconnect message in heartbeat_handler. Stopping gateway_task and heartbeat_handler");
-										self.connection.kill_send.send(()).expect("Failed to send kill signal in heartbeat_handler");
-									}
-								};
-								self.connection.kill_send.send(()).expect("Failed to send kill signal in heartbeat_handler");
+								session_log!(target: "symfonia::gateway::heartbeat_handler", Level::Trace, &self.log_context, "Received heartbeat sequence number is way off by {}. This may be due to latency.", diff);
 							}
-						} */
+						}
+						if self.should_request_reconnect(&comparison) {
+							// TODO: We could potentially send a heartbeat to the client, prompting it to send a new heartbeat.
+							// This would require more logic though. It must not happen with `passive_heartbeat` set.
+							session_log!(target: "symfonia::gateway::heartbeat_handler", Level::Trace, &self.log_context, "Received {} consecutive heartbeats with a way off sequence number. Requesting reconnect", self.way_off_counter.consecutive);
+							let reconnect = GatewayPayload::<()> {
+								op_code: Opcode::Reconnect as u8,
+								event_data: None,
+								sequence_number: None,
+								event_name: None,
+							};
+							if let Err(e) = self.connection.send_encoded(&reconnect) {
+								session_log!(target: "symfonia::gateway::heartbeat_handler", Level::Trace, &self.log_context, "Failed to send reconnect message in heartbeat_handler: {e}. Stopping gateway_task and heartbeat_handler");
+							}
+							self.signal_kill(KillReason::Reconnect);
+							break;
+						}
 					}
-					self.last_heartbeat = std::time::Instant::now();
-					match self.connection.sender.send(Message::Text(
-						json!(GatewayHeartbeatAck::default()).to_string().into(),
-					)) {
+					self.last_heartbeat = tokio::time::Instant::now();
+					match self.connection.send_encoded(&GatewayHeartbeatAck::default()) {
 						Ok(_) => (),
-						Err(_) => {
-							trace!("Failed to send heartbeat ack in heartbeat_handler. Stopping gateway_task and heartbeat_handler");
-							self.connection.sender.send(Message::Close(Some(CloseFrame { code: tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Library(4000), reason: "WebSocket error".into() })));
-							self.connection.kill_send.send(()).expect("Failed to send kill signal in heartbeat_handler");
+						Err(e) => {
+							session_log!(target: "symfonia::gateway::heartbeat_handler", Level::Trace, &self.log_context, "Failed to send heartbeat ack in heartbeat_handler: {e}. Stopping gateway_task and heartbeat_handler");
+							self.signal_kill(KillReason::InternalError);
 						},
 					}
 
-					;
 				}
-				else => {
-					// TODO: We could potentially send a heartbeat if we haven't received one in ~40 seconds,
-					// to try and keep the session from disconnecting.
-					let elapsed = std::time::Instant::now() - self.last_heartbeat;
-					if elapsed > std::time::Duration::from_secs(45) {
-						trace!("Heartbeat timed out in heartbeat_handler. Stopping gateway_task and heartbeat_handler");
-						self.connection.sender.send(Message::Close(Some(CloseFrame { code: tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Library(4009), reason: "Heartbeat timeout".into() })));
-						self.connection.kill_send.send(()).expect("Failed to send kill signal in heartbeat_handler");;
-						break;
-					}
+				// TODO: We could potentially send a heartbeat if we haven't received one in ~40 seconds,
+				// to try and keep the session from disconnecting.
+				_ = tokio::time::sleep_until(self.heartbeat_deadline()) => {
+					session_log!(target: "symfonia::gateway::heartbeat_handler", Level::Trace, &self.log_context, "Heartbeat timed out in heartbeat_handler. Stopping gateway_task and heartbeat_handler");
+					self.signal_kill(KillReason::Timeout);
+					break;
 				}
 			}
 		}
 	}
 
+	/// The point in time at which the session is closed, unless the client
+	/// heartbeats before.
+	fn heartbeat_deadline(&self) -> tokio::time::Instant {
+		self.last_heartbeat + heartbeat_timeout(&self.config)
+	}
+
 	/// Compares two sequence numbers and returns a comparison result of type
-	/// [SequenceNumberComparison].
-	fn compare_sequence_numbers(one: u64, two: u64) -> SequenceNumberComparison {
+	/// [SequenceNumberComparison]. Differences greater than or equal to
+	/// `way_off_threshold` are considered [SequenceNumberComparison::WayOff].
+	fn compare_sequence_numbers(
+		one: u64,
+		two: u64,
+		way_off_threshold: u64,
+	) -> SequenceNumberComparison {
 		let max = std::cmp::max(one, two);
 		let min = std::cmp::min(one, two);
 		match max - min {
 			0 => SequenceNumberComparison::Correct,
-			1..=2 => SequenceNumberComparison::SlightlyOff(max - min),
-			_ => SequenceNumberComparison::WayOff(max - min),
+			diff if diff < way_off_threshold => SequenceNumberComparison::SlightlyOff(diff),
+			diff => SequenceNumberComparison::WayOff(diff),
 		}
 	}
 
+	/// Records the result of a sequence number comparison, returning whether
+	/// the client should be asked to reconnect. With
+	/// [HeartbeatConfiguration::passive_heartbeat] set, this is never the case.
+	fn should_request_reconnect(&mut self, comparison: &SequenceNumberComparison) -> bool {
+		let way_off_limit_reached = self.way_off_counter.record(comparison);
+		way_off_limit_reached && !self.config.passive_heartbeat
+	}
+
 	/// Shorthand for sending a heartbeat ack message.
 	async fn send_ack(&self) {
-		match self
-			.connection
-			.sender
-			.send(Message::Text(json!(GatewayHeartbeatAck::default()).to_string().into()))
-		{
-			Ok(_) => (),
-			Err(_) => {
-				trace!(
-					"Failed to send heartbeat ack in heartbeat_handler. Stopping gateway_task and heartbeat_handler"
-				);
-				self.connection
-					.kill_send
-					.send(())
-					.expect("Failed to send kill signal in heartbeat_handler");
-			}
-		};
+		if let Err(e) = self.connection.send_encoded(&GatewayHeartbeatAck::default()) {
+			session_log!(target: "symfonia::gateway::heartbeat_handler", Level::Trace, &self.log_context, "Failed to send heartbeat ack in heartbeat_handler: {e}. Stopping gateway_task and heartbeat_handler");
+			self.signal_kill(KillReason::InternalError);
+		}
+	}
+
+	/// Closes the connection for the given `reason` and signals all of its
+	/// tasks to shut down. Failing to do so means that they have already shut
+	/// down, which is logged and otherwise ignored.
+	fn signal_kill(&self, reason: KillReason) {
+		if let Err(e) = self.connection.kill(reason) {
+			session_log!(target: "symfonia::gateway::heartbeat_handler", Level::Trace, &self.log_context, "Failed to send kill signal: {e}");
+		}
 	}
 }
 
 /// Granular comparison of two sequence numbers.
+#[derive(Debug, PartialEq, Eq)]
 enum SequenceNumberComparison {
 	/// The sequence numbers are identical.
 	Correct,
-	/// The sequence numbers have a difference of more than 0 and less than 3.
+	/// The sequence numbers have a difference of more than 0 and less than the
+	/// configured "way off" threshold.
 	SlightlyOff(u64),
-	// The sequence numbers have a difference of 3 or more.
+	/// The sequence numbers have a difference of at least the configured "way
+	/// off" threshold.
 	WayOff(u64),
 }
+
+/// Counts consecutive [SequenceNumberComparison::WayOff] heartbeats, so that a
+/// single reordered packet does not immediately force a client to reconnect.
+struct WayOffCounter {
+	/// Number of "way off" heartbeats received in a row.
+	consecutive: u8,
+	/// Number of consecutive "way off" heartbeats after which a reconnect is
+	/// requested.
+	tolerance: u8,
+}
+
+impl WayOffCounter {
+	fn new(tolerance: u8) -> Self {
+		Self { consecutive: 0, tolerance }
+	}
+
+	/// Records the result of a sequence number comparison. Returns `true`, if
+	/// the client should be asked to reconnect.
+	///
+	/// Any [SequenceNumberComparison::Correct] or
+	/// [SequenceNumberComparison::SlightlyOff] comparison resets the counter.
+	fn record(&mut self, comparison: &SequenceNumberComparison) -> bool {
+		match comparison {
+			SequenceNumberComparison::WayOff(_) => {
+				self.consecutive = self.consecutive.saturating_add(1);
+				self.consecutive >= self.tolerance
+			}
+			_ => {
+				self.consecutive = 0;
+				false
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use tokio_tungstenite::tungstenite::Message;
+	use util::{errors::GatewayError, gateway::codec::PayloadCodec};
+
+	use super::*;
+
+	#[test]
+	fn hello_interval_stays_within_jitter_band() {
+		let config = HeartbeatConfiguration { hello_jitter_percent: 10, ..Default::default() };
+		let timeout = heartbeat_timeout(&config);
+		for _ in 0..2 {
+			let hello = hello(&config);
+			assert_eq!(hello.op, Opcode::Hello as i32);
+			let interval = std::time::Duration::from_millis(hello.d.heartbeat_interval);
+			assert!(interval >= HEARTBEAT_INTERVAL * 90 / 100, "{interval:?} is too short");
+			assert!(interval <= HEARTBEAT_INTERVAL * 110 / 100, "{interval:?} is too long");
+			assert!(interval < timeout);
+		}
+		assert_eq!(max_heartbeat_interval(&config), std::time::Duration::from_millis(49_500));
+	}
+
+	#[test]
+	fn hello_without_jitter_advertises_default_interval() {
+		let config = HeartbeatConfiguration::default();
+		assert_eq!(hello(&config).d.heartbeat_interval, HEARTBEAT_INTERVAL.as_millis() as u64);
+		assert_eq!(heartbeat_timeout(&config), HEARTBEAT_INTERVAL + LATENCY_BUFFER);
+	}
+
+	#[test]
+	fn default_way_off_threshold_boundary() {
+		let threshold = HeartbeatConfiguration::default().way_off_threshold;
+		assert_eq!(
+			HeartbeatHandler::compare_sequence_numbers(10, 10, threshold),
+			SequenceNumberComparison::Correct
+		);
+		assert_eq!(
+			HeartbeatHandler::compare_sequence_numbers(10, 12, threshold),
+			SequenceNumberComparison::SlightlyOff(2)
+		);
+		assert_eq!(
+			HeartbeatHandler::compare_sequence_numbers(13, 10, threshold),
+			SequenceNumberComparison::WayOff(3)
+		);
+	}
+
+	#[test]
+	fn raised_way_off_threshold_boundary() {
+		assert_eq!(
+			HeartbeatHandler::compare_sequence_numbers(10, 12, 5),
+			SequenceNumberComparison::SlightlyOff(2)
+		);
+		assert_eq!(
+			HeartbeatHandler::compare_sequence_numbers(13, 10, 5),
+			SequenceNumberComparison::SlightlyOff(3)
+		);
+		assert_eq!(
+			HeartbeatHandler::compare_sequence_numbers(10, 15, 5),
+			SequenceNumberComparison::WayOff(5)
+		);
+	}
+
+	#[test]
+	fn single_way_off_heartbeat_does_not_reconnect() {
+		let mut counter = WayOffCounter::new(HeartbeatConfiguration::default().way_off_tolerance);
+		assert!(!counter.record(&SequenceNumberComparison::WayOff(4)));
+		assert!(!counter.record(&SequenceNumberComparison::Correct));
+		assert!(!counter.record(&SequenceNumberComparison::WayOff(4)));
+	}
+
+	#[test]
+	fn consecutive_way_off_heartbeats_reconnect() {
+		let mut counter = WayOffCounter::new(HeartbeatConfiguration::default().way_off_tolerance);
+		assert!(!counter.record(&SequenceNumberComparison::WayOff(4)));
+		assert!(counter.record(&SequenceNumberComparison::WayOff(5)));
+	}
+
+	#[test]
+	fn slightly_off_heartbeat_resets_way_off_counter() {
+		let mut counter = WayOffCounter::new(2);
+		assert!(!counter.record(&SequenceNumberComparison::WayOff(4)));
+		assert!(!counter.record(&SequenceNumberComparison::SlightlyOff(1)));
+		assert!(!counter.record(&SequenceNumberComparison::WayOff(4)));
+	}
+
+	#[tokio::test]
+	async fn passive_heartbeat_never_prompts_client() {
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		let (heartbeat_send, heartbeat_receive) = tokio::sync::broadcast::channel(4);
+		let (_session_id_send, session_id_receive) = tokio::sync::broadcast::channel(1);
+		let config = HeartbeatConfiguration { passive_heartbeat: true, ..Default::default() };
+		let mut handler = HeartbeatHandler::new(
+			connection.clone(),
+			heartbeat_receive,
+			Arc::new(Mutex::new(0)),
+			session_id_receive,
+			config,
+		);
+		let handler = tokio::spawn(async move { handler.run().await });
+
+		// Way off heartbeats are acknowledged, without asking for a reconnect.
+		for _ in 0..4 {
+			heartbeat_send
+				.send(GatewayHeartbeat { op: Opcode::Heartbeat as u8, d: Some(10) })
+				.unwrap();
+		}
+		// The connection then goes quiet.
+		tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+		let mut op_codes = Vec::new();
+		while let Ok(Message::Text(text)) = client.outgoing.try_recv() {
+			op_codes.push(serde_json::from_str::<serde_json::Value>(&text).unwrap()["op"].as_u64());
+		}
+		assert_eq!(op_codes, vec![Some(Opcode::HeartbeatAck as u64); 4]);
+
+		connection.kill_send.send(KillReason::ServerShutdown).unwrap();
+		handler.await.unwrap();
+	}
+
+	/// Encodes every payload as a binary message holding just its opcode.
+	struct OpCodeCodec;
+
+	impl PayloadCodec for OpCodeCodec {
+		fn encode(&self, payload: &serde_json::Value) -> Result<Message, GatewayError> {
+			let op_code = payload["op"].as_u64().ok_or(GatewayError::Internal)? as u8;
+			Ok(Message::Binary(vec![op_code].into()))
+		}
+	}
+
+	#[tokio::test]
+	async fn heartbeat_ack_is_encoded_with_connection_codec() {
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		let connection = connection.with_codec(Arc::new(OpCodeCodec));
+		let (heartbeat_send, heartbeat_receive) = tokio::sync::broadcast::channel(4);
+		let (_session_id_send, session_id_receive) = tokio::sync::broadcast::channel(1);
+		let mut handler = HeartbeatHandler::new(
+			connection.clone(),
+			heartbeat_receive,
+			Arc::new(Mutex::new(0)),
+			session_id_receive,
+			HeartbeatConfiguration::default(),
+		);
+		let handler = tokio::spawn(async move { handler.run().await });
+
+		heartbeat_send.send(GatewayHeartbeat { op: Opcode::Heartbeat as u8, d: Some(0) }).unwrap();
+
+		assert_eq!(
+			client.outgoing.recv().await.unwrap(),
+			Message::Binary(vec![Opcode::HeartbeatAck as u8].into())
+		);
+		connection.kill_send.send(KillReason::ServerShutdown).unwrap();
+		handler.await.unwrap();
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn silent_client_is_killed_with_timeout() {
+		let (connection, _client) = WebSocketConnection::from_channels();
+		let mut kill_receive = connection.kill_send.subscribe();
+		let (_heartbeat_send, heartbeat_receive) = tokio::sync::broadcast::channel(4);
+		let (_session_id_send, session_id_receive) = tokio::sync::broadcast::channel(1);
+		let mut handler = HeartbeatHandler::new(
+			connection,
+			heartbeat_receive,
+			Arc::new(Mutex::new(0)),
+			session_id_receive,
+			HeartbeatConfiguration::default(),
+		);
+		let handler = tokio::spawn(async move { handler.run().await });
+
+		// With the clock paused, time skips ahead to the deadline once nothing else
+		// is left to do.
+		handler.await.unwrap();
+
+		assert_eq!(kill_receive.try_recv().unwrap(), KillReason::Timeout);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn deadline_covers_the_longest_advertised_interval() {
+		let (connection, _client) = WebSocketConnection::from_channels();
+		let mut kill_receive = connection.kill_send.subscribe();
+		let (heartbeat_send, heartbeat_receive) = tokio::sync::broadcast::channel(4);
+		let (_session_id_send, session_id_receive) = tokio::sync::broadcast::channel(1);
+		let config = HeartbeatConfiguration { hello_jitter_percent: 50, ..Default::default() };
+		let mut handler = HeartbeatHandler::new(
+			connection,
+			heartbeat_receive,
+			Arc::new(Mutex::new(0)),
+			session_id_receive,
+			config.clone(),
+		);
+		assert!(
+			handler.heartbeat_deadline()
+				>= handler.last_heartbeat + max_heartbeat_interval(&config)
+		);
+		let handler = tokio::spawn(async move { handler.run().await });
+
+		// A client told to heartbeat at the longest interval stays connected.
+		for _ in 0..3 {
+			tokio::time::sleep(max_heartbeat_interval(&config)).await;
+			heartbeat_send
+				.send(GatewayHeartbeat { op: Opcode::Heartbeat as u8, d: Some(0) })
+				.unwrap();
+		}
+		tokio::task::yield_now().await;
+		assert!(kill_receive.try_recv().is_err());
+		assert!(!handler.is_finished());
+
+		handler.await.unwrap();
+		assert_eq!(kill_receive.try_recv().unwrap(), KillReason::Timeout);
+	}
+}
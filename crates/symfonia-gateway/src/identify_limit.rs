@@ -0,0 +1,78 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// Limits how many identifies the gateway processes per time window, across
+/// all connections. After a restart, every client reconnects at once; without
+/// a limit, all of their identifies would be processed at the same time.
+/// Identifies exceeding the limit are rejected, and the clients retry later.
+pub(crate) struct IdentifyLimiter {
+	/// Identifies allowed per window.
+	max_concurrency: usize,
+	/// Length of a window.
+	interval: Duration,
+	/// Start of the current window and number of identifies allowed in it.
+	window: Mutex<(Instant, usize)>,
+}
+
+impl IdentifyLimiter {
+	/// Create an [IdentifyLimiter] allowing `max_concurrency` identifies every
+	/// `interval`. A `max_concurrency` of zero is treated as one.
+	pub(crate) fn new(max_concurrency: usize, interval: Duration) -> Self {
+		Self {
+			max_concurrency: max_concurrency.max(1),
+			interval,
+			window: Mutex::new((Instant::now(), 0)),
+		}
+	}
+
+	/// Whether an identify received now may be processed. If so, it counts
+	/// towards the limit of the current window.
+	pub(crate) fn try_acquire(&self) -> bool {
+		self.try_acquire_at(Instant::now())
+	}
+
+	fn try_acquire_at(&self, now: Instant) -> bool {
+		let mut window = self.window.lock();
+		if now.duration_since(window.0) >= self.interval {
+			*window = (now, 0);
+		}
+		if window.1 >= self.max_concurrency {
+			return false;
+		}
+		window.1 += 1;
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn bursts_are_throttled_to_the_configured_rate() {
+		let interval = Duration::from_secs(5);
+		let limiter = IdentifyLimiter::new(2, interval);
+		let start = Instant::now();
+
+		let burst: Vec<bool> = (0..5).map(|_| limiter.try_acquire_at(start)).collect();
+		assert_eq!(burst, vec![true, true, false, false, false]);
+		assert!(!limiter.try_acquire_at(start + interval / 2));
+
+		// The next window allows as many identifies again.
+		let next_window = start + interval;
+		let burst: Vec<bool> = (0..3).map(|_| limiter.try_acquire_at(next_window)).collect();
+		assert_eq!(burst, vec![true, true, false]);
+	}
+
+	#[test]
+	fn zero_concurrency_still_allows_identifies() {
+		let limiter = IdentifyLimiter::new(0, Duration::from_secs(5));
+		assert!(limiter.try_acquire());
+		assert!(!limiter.try_acquire());
+	}
+}
@@ -1,21 +1,25 @@
 mod establish_connection;
 mod gateway_task;
 mod heartbeat;
+mod identify_limit;
 mod ready;
 
-static RESUME_RECONNECT_WINDOW_SECONDS: u8 = 90;
 static DEFAULT_GATEWAY_BIND: &str = "0.0.0.0:3003";
 
-use std::{collections::HashMap, thread::sleep, time::Duration};
+use std::{sync::Arc, time::Duration};
 
+use identify_limit::IdentifyLimiter;
 use log::info;
 use sqlx::PgPool;
-use tokio::net::TcpListener;
+use tokio::{net::TcpListener, time::sleep};
 use util::{
 	configuration::SymfoniaConfiguration,
 	entities::Config,
 	errors::Error,
-	gateway::{ConnectedUsers, ResumableClientsStore},
+	gateway::{
+		ConnectedUsers,
+		auth::{self, GatewayAuthenticator},
+	},
 };
 
 // This Source Code Form is subject to the terms of the Mozilla Public
@@ -59,10 +63,28 @@ Handling a connection involves the following steps:
 Handling disconnects and session resumes is for late
 */
 
+/// Start the gateway server, checking tokens with the authenticator selected in
+/// the configuration.
 pub async fn start_gateway(
 	db: PgPool,
 	connected_users: ConnectedUsers,
 	config: Config,
+) -> Result<(), Error> {
+	let authenticator = auth::from_config(
+		&SymfoniaConfiguration::get().gateway.authentication,
+		db.clone(),
+		config.security.jwt_secret.clone(),
+	);
+	start_gateway_with_authenticator(db, connected_users, authenticator).await
+}
+
+/// Start the gateway server, checking the tokens clients identify and resume
+/// with using `authenticator`. Use this to plug in a custom
+/// [GatewayAuthenticator].
+pub async fn start_gateway_with_authenticator(
+	db: PgPool,
+	connected_users: ConnectedUsers,
+	authenticator: Arc<dyn GatewayAuthenticator>,
 ) -> Result<(), Error> {
 	// TODO(bitfl0wer): Add log messages throughout the method for debugging the
 	// gateway
@@ -77,17 +99,21 @@ pub async fn start_gateway(
 
 	info!(target: "symfonia::gateway", "Gateway server listening on {host}:{port}");
 
-	let resumeable_clients: ResumableClientsStore = HashMap::new();
 	let connected_users_clone = connected_users.clone();
 	tokio::task::spawn(async { purge_expired_disconnects(connected_users_clone).await });
+	let identify_limiter = Arc::new(IdentifyLimiter::new(
+		SymfoniaConfiguration::get().gateway.max_concurrent_identifies,
+		Duration::from_secs(SymfoniaConfiguration::get().gateway.identify_interval),
+	));
 	while let Ok((stream, _)) = listener.accept().await {
 		log::trace!(target: "symfonia::gateway", "New connection received");
 		let connection_result =
 			match tokio::task::spawn(establish_connection::establish_connection(
 				stream,
 				db.clone(),
-				config.clone(),
 				connected_users.clone(),
+				identify_limiter.clone(),
+				authenticator.clone(),
 			))
 			.await
 			{
@@ -108,44 +134,21 @@ pub async fn start_gateway(
 	Ok(())
 }
 
-/// A disconnected, resumable session can only be resumed within
-/// `RESUME_RECONNECT_WINDOW_SECONDS` seconds after a disconnect occurs.
-/// Sessions that can be resumed are stored in a `Map`. The purpose of this
-/// method is to periodically throw out expired sessions from that map.
+/// A disconnected, resumable session can only be resumed within the TTL of the
+/// [ResumableClientsStore](util::gateway::resumable_store::ResumableClientsStore)
+/// it is kept in. Expired sessions are never handed out again, but a store
+/// may still hold on to them. The purpose of this method is to periodically
+/// throw them out of the store, along with the users whose sessions have
+/// stopped without ending, see [ConnectedUsers::sweep_stale_users].
 async fn purge_expired_disconnects(connected_users: ConnectedUsers) {
 	let mut minutely_log_timer = 0;
 	let mut removed_elements_last_minute: u128 = 0;
 	loop {
-		sleep(Duration::from_secs(5));
-		// log::trace!(target: "symfonia::gateway::purge_expired_disconnects", "Removing
-		// stale disconnected sessions from list of resumeable sessions");
-		let current_unix_timestamp = std::time::SystemTime::now()
-			.duration_since(std::time::SystemTime::UNIX_EPOCH)
-			.expect("Check the clock/time settings on the host machine")
-			.as_secs();
-		let mut to_remove = Vec::new();
-		let mut _inner = connected_users.inner();
-		let read_lock = _inner.read();
-		for (disconnected_session_id, disconnected_session) in
-			read_lock.resumeable_clients_store.iter()
-		{
-			// TODO(bitfl0wer): What are we calculating here? At least, this should be
-			// commented
-			if current_unix_timestamp - disconnected_session.disconnected_at_sequence
-				> RESUME_RECONNECT_WINDOW_SECONDS as u64
-			{
-				to_remove.push(disconnected_session_id.clone());
-			}
-		}
-		drop(read_lock);
-		let len = to_remove.len();
+		sleep(Duration::from_secs(5)).await;
+		connected_users.sweep_stale_users().await;
+		let len = connected_users.resumable_clients.purge_expired().await;
 		removed_elements_last_minute =
 			removed_elements_last_minute.checked_add(len as u128).unwrap_or(u128::MAX);
-		let mut write_lock = _inner.write();
-		for session_id in to_remove.iter() {
-			write_lock.resumeable_clients_store.remove(session_id);
-		}
-		drop(write_lock);
 		minutely_log_timer += 1;
 		if minutely_log_timer == 12 {
 			log::debug!(target: "symfonia::gateway::purge_expired_disconnects", "Removed {} stale sessions in the last 60 seconds", removed_elements_last_minute);
@@ -156,28 +159,14 @@ async fn purge_expired_disconnects(connected_users: ConnectedUsers) {
 }
 
 /// Tells every user-/client specific tokio task spawned by the symfonia binary
-/// to yield so that the server may shut down in an orderly fashion.
+/// to yield so that the server may shut down in an orderly fashion. Clients are
+/// asked to reconnect, see [ConnectedUsers::shutdown].
 ///
-/// ## #\[allow(clippy::await_holding_lock)]
-///
-/// We hold the lock of `inner.write()` across an await point
-/// `user_mutex.lock().await`. The lock is held across the await point right
-/// before shutting down the application. It should be okay to do this. The
-/// inevitable shutdown of the application should guarantee no contention.
-///
-/// TODO: This is currently unused. We cannot use this, as the future created by
-/// this function is not `Send`. This is because of the whole "holding mutex
-/// across await" thing. We need to find a better solution for this.
-#[allow(clippy::await_holding_lock)]
+/// TODO: This is currently unused.
 pub async fn tokio_task_killer(connected_users: ConnectedUsers) {
 	exit_signal_detected().await;
 	log::debug!("Exit signal detected!");
-	let inner = connected_users.inner();
-	let users = &mut inner.write().users;
-	for (_, user_mutex) in users.iter() {
-		let mut user = user_mutex.lock().await;
-		user.kill().await;
-	}
+	connected_users.shutdown().await;
 }
 
 /// Detects when an exit signal is sent by the operating system. The future will
@@ -5,16 +5,30 @@
 use std::collections::HashMap;
 
 use chorus::types::{
-	ClientInfo, GatewayReady, ReadState, Session, Snowflake, UserNote, VersionedReadStateOrEntries,
+	ClientInfo, GatewayReady, GuildCreate, GuildCreateDataOption, ReadState, Session, Snowflake,
+	ThreadListSync, UserNote, VersionedReadStateOrEntries,
 };
 use serde_json::json;
 use sqlx::PgPool;
 use util::{
-	entities::{Channel, Guild, Note, Relationship, User},
+	entities::{Channel, Guild, GuildMember, Note, Relationship, User},
 	errors::Error,
+	gateway::{
+		ConnectedUsers, GatewayPayload, WebSocketConnection,
+		session_token::SessionToken,
+		shard::receives_guild,
+		thread_sync::{thread_list_sync, thread_list_sync_payload},
+	},
 };
 
-pub async fn create_ready(user_id: Snowflake, db: &PgPool) -> Result<GatewayReady, Error> {
+/// Create the `READY` of a session of the user with the ID `user_id`, which
+/// has been registered under `session_token`. Its session ID is the token, so
+/// that the client can resume the session with it.
+pub async fn create_ready(
+	user_id: Snowflake,
+	session_token: &SessionToken,
+	db: &PgPool,
+) -> Result<GatewayReady, Error> {
 	let user = match User::get_by_id(db, user_id).await? {
 		Some(uwuser) => uwuser,
 		None => {
@@ -52,9 +66,7 @@ pub async fn create_ready(user_id: Snowflake, db: &PgPool) -> Result<GatewayRead
 		notes.insert(note.target_id, note.content);
 	}
 
-	// TODO: The session ID needs to be stored in the database and also removed on
-	// session disconnect. This is a temporary solution.
-	let session_id = Snowflake::generate().to_string();
+	let session_id = session_token.as_str().to_string();
 
 	// TODO: This is also just temporary.
 	let session = Session {
@@ -86,3 +98,253 @@ pub async fn create_ready(user_id: Snowflake, db: &PgPool) -> Result<GatewayRead
 	log::debug!(target: "symfonia::gateway::ready::create_ready", "Created READY json payload: {:#?}", json!(ready));
 	Ok(ready)
 }
+
+/// Sends a `GUILD_CREATE` for each guild of the user to `connection`, after
+/// the `READY` has been sent. Only the connecting client receives these, as
+/// the other sessions of the user already know about the guilds. If the
+/// session identified with a `shard`, only the guilds of that shard are sent.
+/// Guilds with more than `large_threshold` members are sent without their
+/// offline members, which are not loaded from the database either.
+pub async fn send_initial_guild_creates(
+	connection: &WebSocketConnection,
+	db: &PgPool,
+	connected_users: &ConnectedUsers,
+	user_id: Snowflake,
+	shard: Option<(u64, u64)>,
+	large_threshold: u64,
+) -> Result<(), Error> {
+	let user = match User::get_by_id(db, user_id).await? {
+		Some(user) => user,
+		None => {
+			return Err(Error::Custom(format!(
+				"The user specified by user_id '{user_id}' does not exist in the database"
+			)));
+		}
+	};
+	let mut guilds = Vec::new();
+	for guild_id in user.get_guild_ids(db).await? {
+		if !receives_guild(shard, guild_id) {
+			continue;
+		}
+		if let Some(guild) = Guild::get_by_id(db, guild_id).await? {
+			let member_ids = GuildMember::get_ids_by_guild_id(db, guild_id).await?;
+			let member_count = member_ids.len();
+			// The member of the connecting user is needed for the thread list sync.
+			let (large, member_ids) = initial_member_ids(member_ids, large_threshold, |id| {
+				id == user_id || connected_users.is_online(id)
+			});
+			let members =
+				GuildMember::get_by_ids_with_permissions(db, guild_id, &member_ids).await?;
+			// The channels are sent with the guild, and the threads among them are
+			// synced from the same list.
+			let channels = Channel::get_by_guild_id(db, guild_id).await?;
+			let thread_list_sync = members
+				.iter()
+				.find(|member| member.id == user_id)
+				.map(|member| thread_list_sync(guild_id, None, &channels, member, guild.owner_id));
+			let mut guild = guild.into_inner();
+			guild.channels = channels.into_iter().map(Channel::into_inner).collect();
+			guilds.push(InitialGuild {
+				guild,
+				large,
+				member_count,
+				members: members.into_iter().map(GuildMember::into_inner).collect(),
+				thread_list_sync,
+			});
+		}
+	}
+	send_guild_creates(connection, guilds)
+}
+
+/// A guild as sent in an initial `GUILD_CREATE`, along with the members sent
+/// with it.
+struct InitialGuild {
+	guild: chorus::types::Guild,
+	/// Whether the guild has more members than the `large_threshold` of the
+	/// session.
+	large: bool,
+	member_count: usize,
+	members: Vec<chorus::types::GuildMember>,
+	/// The active threads of the guild the user can see, sent in a
+	/// `THREAD_LIST_SYNC` following the `GUILD_CREATE` if there are any.
+	thread_list_sync: Option<ThreadListSync>,
+}
+
+/// Whether a guild with the members `member_ids` is large, and the IDs of the
+/// members sent with its initial `GUILD_CREATE`. If it has more than
+/// `large_threshold` members, only those for which `is_online` holds are
+/// sent. Clients fetch the others with a `GUILD_MEMBERS_REQUEST`.
+fn initial_member_ids(
+	member_ids: Vec<Snowflake>,
+	large_threshold: u64,
+	is_online: impl Fn(Snowflake) -> bool,
+) -> (bool, Vec<Snowflake>) {
+	let large = member_ids.len() as u64 > large_threshold;
+	if !large {
+		return (false, member_ids);
+	}
+	(true, member_ids.into_iter().filter(|id| is_online(*id)).collect())
+}
+
+/// Sends a `GUILD_CREATE` for each of the `guilds` to `connection`, each
+/// followed by the `THREAD_LIST_SYNC` of the guild.
+fn send_guild_creates(
+	connection: &WebSocketConnection,
+	guilds: Vec<InitialGuild>,
+) -> Result<(), Error> {
+	for InitialGuild { guild, large, member_count, members, thread_list_sync } in guilds {
+		let payload = GatewayPayload::dispatch(
+			"GUILD_CREATE",
+			GuildCreate { d: GuildCreateDataOption::Guild(guild), ..Default::default() },
+		);
+		let mut payload = json!(payload);
+		if let Some(data) = payload.get_mut("d").and_then(|data| data.as_object_mut()) {
+			data.insert("large".to_string(), json!(large));
+			data.insert("member_count".to_string(), json!(member_count));
+			data.insert("members".to_string(), json!(members));
+		}
+		connection.send_encoded(&payload)?;
+
+		if let Some(thread_list_sync) =
+			thread_list_sync.filter(|thread_list_sync| !thread_list_sync.threads.is_empty())
+		{
+			let payload = json!(thread_list_sync_payload(thread_list_sync));
+			connection.send_encoded(&payload)?;
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use chorus::types::GatewayHeartbeatAck;
+	use tokio_tungstenite::tungstenite::Message;
+	use util::gateway::codec::PayloadCompression;
+
+	use super::*;
+
+	fn initial_guild(id: u64) -> InitialGuild {
+		InitialGuild {
+			guild: chorus::types::Guild { id: Snowflake::from(id), ..Default::default() },
+			large: false,
+			member_count: 0,
+			members: Vec::new(),
+			thread_list_sync: None,
+		}
+	}
+
+	fn member_ids(count: u64) -> Vec<Snowflake> {
+		(1..=count).map(Snowflake::from).collect()
+	}
+
+	/// A guild with `count` members, of which those kept by
+	/// [initial_member_ids] are sent.
+	fn guild_with_members(count: u64, large_threshold: u64) -> InitialGuild {
+		let is_online = |id: Snowflake| id == Snowflake::from(1u64);
+		let (large, ids) = initial_member_ids(member_ids(count), large_threshold, is_online);
+		InitialGuild {
+			large,
+			member_count: count as usize,
+			members: ids.iter().map(|_| chorus::types::GuildMember::default()).collect(),
+			..initial_guild(1)
+		}
+	}
+
+	#[tokio::test]
+	async fn one_guild_create_per_guild() {
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		let guilds = vec![initial_guild(1), initial_guild(2)];
+
+		send_guild_creates(&connection, guilds).unwrap();
+
+		for _ in 0..2 {
+			let Message::Text(text) = client.outgoing.try_recv().unwrap() else {
+				panic!("expected a text message");
+			};
+			let payload: serde_json::Value = serde_json::from_str(&text).unwrap();
+			assert_eq!(payload["t"], "GUILD_CREATE");
+		}
+		assert!(client.outgoing.try_recv().is_err());
+	}
+
+	#[tokio::test]
+	async fn large_guilds_omit_offline_members() {
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		let guilds = vec![guild_with_members(3, 3), guild_with_members(4, 3)];
+
+		send_guild_creates(&connection, guilds).unwrap();
+
+		let mut sent = Vec::new();
+		for _ in 0..2 {
+			let Message::Text(text) = client.outgoing.try_recv().unwrap() else {
+				panic!("expected a text message");
+			};
+			sent.push(serde_json::from_str::<serde_json::Value>(&text).unwrap()["d"].clone());
+		}
+		assert_eq!(sent[0]["large"], false);
+		assert_eq!(sent[0]["members"].as_array().unwrap().len(), 3);
+		assert_eq!(sent[1]["large"], true);
+		assert_eq!(sent[1]["member_count"], 4);
+		assert_eq!(sent[1]["members"].as_array().unwrap().len(), 1);
+	}
+
+	#[tokio::test]
+	async fn thread_list_sync_follows_guild_create() {
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		let guild_id = Snowflake::from(1u64);
+		let mut with_threads = initial_guild(1);
+		with_threads.thread_list_sync = Some(ThreadListSync {
+			guild_id,
+			threads: vec![chorus::types::Channel {
+				id: Snowflake::from(11u64),
+				..Default::default()
+			}],
+			..Default::default()
+		});
+		let mut without_threads = initial_guild(2);
+		without_threads.thread_list_sync =
+			Some(ThreadListSync { guild_id: Snowflake::from(2u64), ..Default::default() });
+
+		send_guild_creates(&connection, vec![with_threads, without_threads]).unwrap();
+
+		let mut events = Vec::new();
+		while let Ok(Message::Text(text)) = client.outgoing.try_recv() {
+			events.push(serde_json::from_str::<serde_json::Value>(&text).unwrap()["t"].clone());
+		}
+		assert_eq!(events, ["GUILD_CREATE", "THREAD_LIST_SYNC", "GUILD_CREATE"]);
+	}
+
+	#[tokio::test]
+	async fn large_guild_create_is_compressed_if_requested() {
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		connection.enable_payload_compression(PayloadCompression::new(6));
+		let guild = guild_with_members(50, 250);
+
+		send_guild_creates(&connection, vec![guild]).unwrap();
+		connection.send_encoded(&GatewayHeartbeatAck::default()).unwrap();
+
+		assert!(matches!(client.outgoing.try_recv().unwrap(), Message::Binary(_)));
+		assert!(matches!(client.outgoing.try_recv().unwrap(), Message::Text(_)));
+	}
+
+	#[test]
+	fn offline_members_of_large_guilds_are_not_loaded() {
+		let is_online = |id: Snowflake| u64::from(id) % 2 == 0;
+
+		assert_eq!(initial_member_ids(member_ids(4), 4, is_online), (false, member_ids(4)));
+		assert_eq!(
+			initial_member_ids(member_ids(5), 4, is_online),
+			(true, vec![Snowflake::from(2u64), Snowflake::from(4u64)])
+		);
+	}
+
+	#[tokio::test]
+	async fn no_guilds_no_guild_create() {
+		let (connection, mut client) = WebSocketConnection::from_channels();
+
+		send_guild_creates(&connection, Vec::new()).unwrap();
+
+		assert!(client.outgoing.try_recv().is_err());
+	}
+}
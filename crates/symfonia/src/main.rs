@@ -2,7 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::{path::PathBuf, str::FromStr};
+use std::{path::PathBuf, str::FromStr, time::Duration};
 
 use clap::Parser;
 use lazy_static::lazy_static;
@@ -26,7 +26,9 @@ use symfonia_api::api::start_api;
 use symfonia_gateway::start_gateway;
 use tokio::sync::OnceCell;
 use util::{
-	configuration::SymfoniaConfiguration, database::Connection, entities::Config,
+	configuration::SymfoniaConfiguration,
+	database::Connection,
+	entities::{Config, UserCache},
 	gateway::ConnectedUsers,
 };
 
@@ -62,6 +64,11 @@ async fn main() -> Result<(), AnyError> {
 		),
 	);
 	trace!("Read config!");
+	SymfoniaConfiguration::get().validate_gateway()?;
+	let user_cache = &SymfoniaConfiguration::get().general.user_cache;
+	if user_cache.capacity > 0 {
+		UserCache::init(user_cache.capacity, Duration::from_secs(user_cache.ttl));
+	}
 	let stdout = ConsoleAppender::builder()
 		.target(Target::Stdout)
 		.encoder(Box::new(PatternEncoder::new(
@@ -91,8 +98,12 @@ async fn main() -> Result<(), AnyError> {
 		)
 		.unwrap();
 
+	// Log lines of gateway sessions carry the session as key-values, see
+	// util::gateway::log_context::SessionLogContext.
 	let gateway_log = RollingFileAppender::builder()
-		.encoder(Box::new(PatternEncoder::new("{d} {l} - {m}{n}")))
+		.encoder(Box::new(PatternEncoder::new(
+			"{d} {l} - user_id={K(user_id)} session={K(session)} - {m}{n}",
+		)))
 		.build(
 			"log/gateway.log",
 			Box::new(CompoundPolicy::new(
@@ -197,8 +208,16 @@ async fn main() -> Result<(), AnyError> {
 	let symfonia_config = Config::init(db.pool()).await.unwrap_or_default();
 
 	let connected_users = ConnectedUsers::default();
+	connected_users
+		.set_dispatch_concurrency(SymfoniaConfiguration::get().gateway.dispatch_concurrency);
 	log::debug!(target: "symfonia", "Initializing Role->User map...");
-	connected_users.init_role_user_map(db.pool()).await.expect("Failed to init role user map");
+	connected_users
+		.init_role_user_map(
+			db.pool(),
+			SymfoniaConfiguration::get().gateway.role_user_map_init_attempts,
+		)
+		.await
+		.expect("Failed to init role user map");
 	log::trace!(target: "symfonia", "Role->User map initialized with {} entries", connected_users.role_user_map.lock().await.len());
 
 	let mut tasks = [
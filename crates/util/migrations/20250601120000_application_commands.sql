@@ -0,0 +1,12 @@
+create table application_commands
+(
+    id             numeric(20, 0) not null constraint chk_id_range check (id >= 0 AND id <= 18446744073709551615) primary key,
+    application_id numeric(20, 0) not null constraint chk_application_id_range check (application_id >= 0 AND application_id <= 18446744073709551615),
+    name           varchar(32)    not null,
+    data           jsonb          not null,
+    constraint application_commands_application_id_name_uindex
+        unique (application_id, name),
+    constraint application_commands_applications_id_fk
+        foreign key (application_id) references applications (id)
+            on delete cascade
+);
@@ -0,0 +1,2 @@
+alter table members
+    alter column settings type jsonb using settings::jsonb;
@@ -0,0 +1,19 @@
+-- Keep in sync with MAX_COMMANDS in crates/util/src/entities/application.rs.
+create or replace function check_application_commands_count()
+returns TRIGGER as $$
+begin
+    -- Registrations for the same application wait for each other here, so that
+    -- they cannot both pass the check below.
+    perform 1 from applications where id = NEW.application_id for update;
+    if (select count(*) from application_commands where application_id = NEW.application_id) >= 100 then
+        raise exception 'application % has reached the maximum number of commands', NEW.application_id
+            using errcode = 'check_violation', constraint = 'application_commands_max_count';
+    end if;
+    return NEW;
+end;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER application_commands_max_count
+BEFORE INSERT ON application_commands
+FOR EACH ROW
+EXECUTE FUNCTION check_application_commands_count();
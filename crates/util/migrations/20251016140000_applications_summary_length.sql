@@ -0,0 +1,2 @@
+-- Keep in sync with MAX_SUMMARY_LENGTH in crates/util/src/entities/application.rs.
+ALTER TABLE applications ALTER COLUMN summary TYPE varchar(400);
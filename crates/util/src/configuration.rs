@@ -5,6 +5,7 @@
 use std::{
 	default,
 	fmt::{Display, Formatter},
+	ops::RangeInclusive,
 	path::PathBuf,
 	str::FromStr,
 	sync::OnceLock,
@@ -13,7 +14,7 @@ use std::{
 use serde::{Deserialize, Serialize};
 use serde_with::{DisplayFromStr, serde_as};
 
-use crate::errors::Error;
+use crate::errors::{ConfigurationError, Error};
 
 const TLS_CONFIG_DISABLE: &str = "disable";
 const TLS_CONFIG_ALLOW: &str = "allow";
@@ -121,6 +122,26 @@ pub struct GeneralConfiguration {
 	pub node_id: u64,
 	#[serde(rename = "database")]
 	pub database_configuration: DatabaseConfiguration,
+	#[serde(default)]
+	pub user_cache: UserCacheConfiguration,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+/// Sizing of the in-memory cache in front of `User::get_by_id`.
+pub struct UserCacheConfiguration {
+	/// Number of users kept in the cache. `0` disables the cache.
+	pub capacity: usize,
+	/// Seconds a user is served from the cache before being read from the
+	/// database again. Bounds how long changes made by other nodes take to
+	/// become visible.
+	pub ttl: u64,
+}
+
+impl Default for UserCacheConfiguration {
+	fn default() -> Self {
+		Self { capacity: 0, ttl: 60 }
+	}
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -136,6 +157,342 @@ pub enum LogLevel {
 pub struct GatewayConfiguration {
 	#[serde(flatten)]
 	pub cfg: ComponentConfiguration,
+	#[serde(default)]
+	pub heartbeat: HeartbeatConfiguration,
+	/// Seconds a client has to send an identify payload after connecting,
+	/// before the connection is closed.
+	#[serde(default = "default_identify_timeout")]
+	pub identify_timeout: u64,
+	/// Level to compress gateway streams at. Trades compression ratio for CPU
+	/// time. Out-of-range values are clamped to the range supported by the
+	/// [CompressionAlgorithm] in use; if unset, the default level of that
+	/// algorithm is used. Payloads compressed on their own, as requested by
+	/// clients identifying with `compress`, use the zlib level.
+	#[serde(default)]
+	pub compression_level: Option<i32>,
+	/// Number of recent dispatches retained per session, so that they can be
+	/// replayed when the session is resumed. Sessions which missed more
+	/// dispatches than this have to identify anew. Must be at least 1.
+	#[serde(default = "default_resume_buffer_size")]
+	pub resume_buffer_size: usize,
+	/// Number of recent dispatches retained for sessions receiving many
+	/// dispatches. The resume buffer of a session grows from
+	/// `resume_buffer_size` up to this under load, and shrinks back once the
+	/// session is quiet again. Must be at least `resume_buffer_size`.
+	#[serde(default = "default_max_resume_buffer_size")]
+	pub max_resume_buffer_size: usize,
+	/// What to do with interactions for bots which are not connected to the
+	/// gateway.
+	#[serde(default)]
+	pub offline_interactions: OfflineInteractionPolicy,
+	/// Largest message, in bytes, accepted from a client. It also limits the
+	/// size of single WebSocket frames. Connections sending larger messages
+	/// are closed.
+	#[serde(default = "default_max_payload_size")]
+	pub max_payload_size: usize,
+	/// Number of messages buffered per connection in each direction. Clients
+	/// which fall further behind than this miss messages, so bursty workloads
+	/// may need a larger buffer. See [Self::buffer_capacity].
+	#[serde(default = "default_buffer_capacity")]
+	pub buffer_capacity: usize,
+	/// Seconds a client has to take a message sent to it. Clients which stop
+	/// reading are disconnected after this, so that they do not hold up the
+	/// delivery to their connection forever.
+	#[serde(default = "default_write_timeout")]
+	pub write_timeout: u64,
+	/// Number of identifies processed every `identify_interval` seconds,
+	/// across all connections. Excess identifies are rejected with close code
+	/// 4009, so that reconnecting clients retry with backoff instead of
+	/// overwhelming the server after a restart.
+	#[serde(default = "default_max_concurrent_identifies")]
+	pub max_concurrent_identifies: usize,
+	/// Length, in seconds, of the window `max_concurrent_identifies` applies
+	/// to.
+	#[serde(default = "default_identify_interval")]
+	pub identify_interval: u64,
+	/// Intents of user sessions which do not send any when identifying.
+	#[serde(default = "default_user_intents")]
+	pub default_user_intents: u64,
+	/// Intents of bot sessions which do not send any when identifying.
+	/// Privileged intents the application of a bot may not use are left out.
+	/// Bots explicitly requesting such intents are disconnected instead.
+	#[serde(default)]
+	pub default_bot_intents: u64,
+	/// Number of times the queries loading which users have which roles are
+	/// attempted at startup, if they fail with transient errors such as a lost
+	/// connection.
+	#[serde(default = "default_role_user_map_init_attempts")]
+	pub role_user_map_init_attempts: u32,
+	/// Number of inboxes an event sent to many users, such as all members of
+	/// a guild, is delivered to at once. Larger values deliver faster, smaller
+	/// ones let other tasks run more often during huge fan-outs.
+	#[serde(default = "default_dispatch_concurrency")]
+	pub dispatch_concurrency: usize,
+	/// Whether members mentioned in a message of a channel they cannot see
+	/// are sent a notification about it anyway. The notification is a
+	/// `MESSAGE_CREATE` without the content, embeds and attachments of the
+	/// message.
+	#[serde(default)]
+	pub notify_mentioned_members: bool,
+	/// How the tokens clients identify and resume with are checked.
+	#[serde(default)]
+	pub authentication: GatewayAuthentication,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+/// Backend checking the tokens clients identify and resume with. Deployments
+/// may also plug in their own
+/// [GatewayAuthenticator](crate::gateway::auth::GatewayAuthenticator).
+pub enum GatewayAuthentication {
+	/// Accept the JWTs issued by this instance.
+	#[default]
+	Jwt,
+	/// Ask an external service, see
+	/// [HttpAuthenticator](crate::gateway::auth::HttpAuthenticator).
+	Http { url: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+/// Handling of interactions whose target bot has no live gateway session.
+pub enum OfflineInteractionPolicy {
+	/// Discard the interaction.
+	#[default]
+	Drop,
+	/// Keep the interaction and deliver it once the bot connects.
+	Store,
+}
+
+fn default_identify_timeout() -> u64 {
+	30
+}
+
+fn default_resume_buffer_size() -> usize {
+	100
+}
+
+fn default_max_resume_buffer_size() -> usize {
+	1000
+}
+
+fn default_max_payload_size() -> usize {
+	crate::gateway::DEFAULT_MAX_PAYLOAD_SIZE
+}
+
+fn default_buffer_capacity() -> usize {
+	crate::gateway::DEFAULT_BUFFER_CAPACITY
+}
+
+fn default_write_timeout() -> u64 {
+	crate::gateway::DEFAULT_WRITE_TIMEOUT.as_secs()
+}
+
+fn default_max_concurrent_identifies() -> usize {
+	1
+}
+
+fn default_identify_interval() -> u64 {
+	5
+}
+
+fn default_user_intents() -> u64 {
+	crate::gateway::intents::ALL
+}
+
+fn default_role_user_map_init_attempts() -> u32 {
+	5
+}
+
+fn default_dispatch_concurrency() -> usize {
+	crate::gateway::DEFAULT_DISPATCH_CONCURRENCY
+}
+
+impl GatewayConfiguration {
+	/// The configured buffer capacity, raised to at least
+	/// [MIN_BUFFER_CAPACITY](crate::gateway::MIN_BUFFER_CAPACITY).
+	pub fn buffer_capacity(&self) -> usize {
+		let minimum = crate::gateway::MIN_BUFFER_CAPACITY;
+		if self.buffer_capacity < minimum {
+			log::warn!(target: "symfonia::configuration", "Buffer capacity {} is too small. Raising it to {minimum}", self.buffer_capacity);
+		}
+		self.buffer_capacity.max(minimum)
+	}
+
+	/// The configured compression level, made valid for `algorithm`.
+	pub fn compression_level(&self, algorithm: CompressionAlgorithm) -> i32 {
+		match self.compression_level {
+			Some(level) => algorithm.clamp_level(level),
+			None => algorithm.default_level(),
+		}
+	}
+
+	/// Check that the values of this configuration make sense, both on their
+	/// own and in relation to each other.
+	///
+	/// ## Errors
+	///
+	/// Returns [ConfigurationError::Invalid] describing the first value which
+	/// does not.
+	pub fn validate(&self) -> Result<(), ConfigurationError> {
+		at_least("gateway.identify_timeout", self.identify_timeout, 1)?;
+		if let Some(level) = self.compression_level {
+			// Levels are clamped to the range of the algorithm in use, but levels no
+			// algorithm supports are a mistake.
+			let lowest = *CompressionAlgorithm::Zlib.level_range().start();
+			let highest = *CompressionAlgorithm::Zstd.level_range().end();
+			within("gateway.compression_level", level, lowest..=highest)?;
+		}
+		at_least("gateway.resume_buffer_size", self.resume_buffer_size, 1)?;
+		at_least(
+			"gateway.max_resume_buffer_size",
+			self.max_resume_buffer_size,
+			self.resume_buffer_size,
+		)
+		.map_err(|e| e.relative_to("gateway.resume_buffer_size"))?;
+		at_least("gateway.max_payload_size", self.max_payload_size, MIN_MAX_PAYLOAD_SIZE)?;
+		at_least(
+			"gateway.buffer_capacity",
+			self.buffer_capacity,
+			crate::gateway::MIN_BUFFER_CAPACITY,
+		)?;
+		at_least("gateway.write_timeout", self.write_timeout, 1)?;
+		at_least("gateway.max_concurrent_identifies", self.max_concurrent_identifies, 1)?;
+		at_least("gateway.identify_interval", self.identify_interval, 1)?;
+		at_least("gateway.role_user_map_init_attempts", self.role_user_map_init_attempts, 1)?;
+		at_least("gateway.dispatch_concurrency", self.dispatch_concurrency, 1)?;
+		self.heartbeat.validate()
+	}
+}
+
+/// Smallest `max_payload_size` accepted by [GatewayConfiguration::validate].
+/// Clients could not even identify with less.
+const MIN_MAX_PAYLOAD_SIZE: usize = 1024;
+
+/// Check that `value` of the setting `field` is at least `minimum`.
+fn at_least<T: PartialOrd + Display>(
+	field: &'static str,
+	value: T,
+	minimum: T,
+) -> Result<(), ConfigurationError> {
+	if value < minimum {
+		return Err(ConfigurationError::Invalid {
+			field,
+			value: value.to_string(),
+			requirement: format!("at least {minimum}"),
+		});
+	}
+	Ok(())
+}
+
+/// Check that `value` of the setting `field` lies within `range`.
+fn within<T: PartialOrd + Display>(
+	field: &'static str,
+	value: T,
+	range: RangeInclusive<T>,
+) -> Result<(), ConfigurationError> {
+	if !range.contains(&value) {
+		return Err(ConfigurationError::Invalid {
+			field,
+			value: value.to_string(),
+			requirement: format!("within {}..={}", range.start(), range.end()),
+		});
+	}
+	Ok(())
+}
+
+impl ConfigurationError {
+	/// Name the setting `other` the requirement of this error derives from,
+	/// for errors about the relation between two settings.
+	fn relative_to(self, other: &str) -> Self {
+		let ConfigurationError::Invalid { field, value, requirement } = self;
+		ConfigurationError::Invalid {
+			field,
+			value,
+			requirement: format!("{requirement}, the value of {other}"),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+/// Algorithms gateway streams can be compressed with.
+pub enum CompressionAlgorithm {
+	Zlib,
+	Zstd,
+}
+
+impl CompressionAlgorithm {
+	/// Range of compression levels supported by this algorithm.
+	pub fn level_range(&self) -> RangeInclusive<i32> {
+		match self {
+			CompressionAlgorithm::Zlib => 0..=9,
+			CompressionAlgorithm::Zstd => 1..=22,
+		}
+	}
+
+	/// The compression level used if none is configured.
+	pub fn default_level(&self) -> i32 {
+		match self {
+			CompressionAlgorithm::Zlib => 6,
+			CompressionAlgorithm::Zstd => 3,
+		}
+	}
+
+	/// Clamps `level` into [Self::level_range].
+	pub fn clamp_level(&self, level: i32) -> i32 {
+		let range = self.level_range();
+		if !range.contains(&level) {
+			log::warn!(target: "symfonia::configuration", "Compression level {level} is out of range for {self:?}. Clamping it to {}..={}", range.start(), range.end());
+		}
+		level.clamp(*range.start(), *range.end())
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+/// Tuning knobs for the gateway's `HeartbeatHandler`.
+pub struct HeartbeatConfiguration {
+	/// Difference between the server-side sequence number and the sequence
+	/// number reported in a heartbeat, at or above which the heartbeat is
+	/// considered to be "way off". Differences below this value are
+	/// tolerated as "slightly off".
+	pub way_off_threshold: u64,
+	/// Number of consecutive "way off" heartbeats after which the client is
+	/// asked to reconnect. A single reordered packet should not be enough to
+	/// end a session.
+	pub way_off_tolerance: u8,
+	/// Never prompt clients or ask them to reconnect based on their
+	/// heartbeats; only close sessions which stop heartbeating altogether.
+	/// Useful for clients behind proxies which drop opcodes they do not
+	/// expect.
+	pub passive_heartbeat: bool,
+	/// Percentage by which the `heartbeat_interval` advertised in each `Hello`
+	/// may randomly deviate from the default, so that clients connecting at
+	/// the same time do not heartbeat in lockstep. At most 50.
+	pub hello_jitter_percent: u8,
+}
+
+impl HeartbeatConfiguration {
+	/// Check that the values of this configuration make sense. See
+	/// [GatewayConfiguration::validate].
+	pub fn validate(&self) -> Result<(), ConfigurationError> {
+		// Every heartbeat would be "way off" otherwise.
+		at_least("gateway.heartbeat.way_off_threshold", self.way_off_threshold, 1)?;
+		at_least("gateway.heartbeat.way_off_tolerance", self.way_off_tolerance, 1)?;
+		within("gateway.heartbeat.hello_jitter_percent", self.hello_jitter_percent, 0..=50)
+	}
+}
+
+impl Default for HeartbeatConfiguration {
+	fn default() -> Self {
+		Self {
+			way_off_threshold: 3,
+			way_off_tolerance: 2,
+			passive_heartbeat: false,
+			hello_jitter_percent: 0,
+		}
+	}
 }
 
 impl Display for GatewayConfiguration {
@@ -197,6 +554,13 @@ impl SymfoniaConfiguration {
 		Ok(config)
 	}
 
+	/// Check the [GatewayConfiguration], so that nonsensical values are
+	/// reported at startup instead of failing in obscure ways later. See
+	/// [GatewayConfiguration::validate].
+	pub fn validate_gateway(&self) -> Result<(), ConfigurationError> {
+		self.gateway.validate()
+	}
+
 	pub fn init(file_path: &PathBuf) {
 		let config =
 			SymfoniaConfiguration::from_file(file_path).expect("Couldn't parse configuration");
@@ -245,4 +609,84 @@ mod test {
 			.unwrap()
 		);
 	}
+
+	/// A [GatewayConfiguration] with `settings` and defaults for everything
+	/// else.
+	fn gateway(settings: &str) -> GatewayConfiguration {
+		toml::from_str(&format!(
+			"enabled = true\nport = 3002\nhost = \"0.0.0.0\"\ntls = false\n{settings}\n\
+			 [database]\nmax_connections = 20\n"
+		))
+		.unwrap()
+	}
+
+	#[test]
+	fn shipped_gateway_configuration_is_valid() {
+		let config = SymfoniaConfiguration::from_file(
+			&PathBuf::from_str(env!("CARGO_MANIFEST_DIR"))
+				.unwrap()
+				.join("../../")
+				.join("symfonia.toml"),
+		)
+		.unwrap();
+		assert_eq!(config.validate_gateway(), Ok(()));
+		assert_eq!(gateway("").validate(), Ok(()));
+	}
+
+	#[test]
+	fn invalid_gateway_settings_are_reported() {
+		let cases = [
+			("identify_timeout = 0", "gateway.identify_timeout", "at least 1"),
+			("compression_level = -1", "gateway.compression_level", "within 0..=22"),
+			("compression_level = 23", "gateway.compression_level", "within 0..=22"),
+			("resume_buffer_size = 0", "gateway.resume_buffer_size", "at least 1"),
+			(
+				"resume_buffer_size = 100\nmax_resume_buffer_size = 50",
+				"gateway.max_resume_buffer_size",
+				"at least 100, the value of gateway.resume_buffer_size",
+			),
+			("max_payload_size = 10", "gateway.max_payload_size", "at least 1024"),
+			("buffer_capacity = 0", "gateway.buffer_capacity", "at least 16"),
+			("write_timeout = 0", "gateway.write_timeout", "at least 1"),
+			("max_concurrent_identifies = 0", "gateway.max_concurrent_identifies", "at least 1"),
+			("identify_interval = 0", "gateway.identify_interval", "at least 1"),
+			(
+				"role_user_map_init_attempts = 0",
+				"gateway.role_user_map_init_attempts",
+				"at least 1",
+			),
+			("dispatch_concurrency = 0", "gateway.dispatch_concurrency", "at least 1"),
+			(
+				"heartbeat = { way_off_threshold = 0 }",
+				"gateway.heartbeat.way_off_threshold",
+				"at least 1",
+			),
+			(
+				"heartbeat = { way_off_tolerance = 0 }",
+				"gateway.heartbeat.way_off_tolerance",
+				"at least 1",
+			),
+			(
+				"heartbeat = { hello_jitter_percent = 60 }",
+				"gateway.heartbeat.hello_jitter_percent",
+				"within 0..=50",
+			),
+		];
+		for (settings, expected_field, expected_requirement) in cases {
+			let error = gateway(settings).validate().unwrap_err();
+			let ConfigurationError::Invalid { field, requirement, .. } = &error;
+			assert_eq!((*field, requirement.as_str()), (expected_field, expected_requirement));
+			assert!(error.to_string().contains(expected_field), "{error}");
+		}
+	}
+
+	#[test]
+	fn compression_level_is_clamped() {
+		assert_eq!(CompressionAlgorithm::Zlib.clamp_level(4), 4);
+		assert_eq!(CompressionAlgorithm::Zlib.clamp_level(15), 9);
+		assert_eq!(CompressionAlgorithm::Zlib.clamp_level(-1), 0);
+		assert_eq!(CompressionAlgorithm::Zstd.clamp_level(15), 15);
+		assert_eq!(CompressionAlgorithm::Zstd.clamp_level(0), 1);
+		assert_eq!(CompressionAlgorithm::Zstd.clamp_level(30), 22);
+	}
 }
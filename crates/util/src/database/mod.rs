@@ -10,7 +10,9 @@ use secrecy::{
 };
 use sqlx::{PgPool, Row, postgres::PgConnectOptions};
 
+mod retry;
 mod seed_config;
+pub use retry::*;
 pub use seed_config::*;
 
 use crate::errors::Error;
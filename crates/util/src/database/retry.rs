@@ -0,0 +1,101 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{future::Future, time::Duration};
+
+/// Delay before the first retry of [retry_transient]. Doubled after every
+/// further failed attempt.
+pub const INITIAL_RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Whether `error` may go away on its own, such as a lost connection or a
+/// timeout, so that the failed query is worth retrying. Errors in the query
+/// itself, such as a missing table, are not.
+pub fn is_transient(error: &sqlx::Error) -> bool {
+	match error {
+		sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::WorkerCrashed => true,
+		sqlx::Error::Database(error) => error.code().is_some_and(|code| {
+			// Class 08 covers connection exceptions. 53300 is too_many_connections,
+			// 57P01 admin_shutdown and 57P03 cannot_connect_now.
+			code.starts_with("08") || matches!(code.as_ref(), "53300" | "57P01" | "57P03")
+		}),
+		_ => false,
+	}
+}
+
+/// Run `operation` up to `attempts` times, as long as it fails with
+/// [transient](is_transient) errors. The first retry happens after
+/// `initial_backoff`, and the delay doubles with each retry after that. Other
+/// errors, and the error of the last attempt, are returned right away.
+pub async fn retry_transient<T, F, Fut>(
+	attempts: u32,
+	initial_backoff: Duration,
+	mut operation: F,
+) -> Result<T, sqlx::Error>
+where
+	F: FnMut() -> Fut,
+	Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+	let mut backoff = initial_backoff;
+	let mut attempt = 1;
+	loop {
+		match operation().await {
+			Err(e) if attempt < attempts && is_transient(&e) => {
+				log::warn!(target: "symfonia::db", "Transient database error on attempt {attempt} of {attempts}, retrying in {backoff:?}: {e}");
+				tokio::time::sleep(backoff).await;
+				backoff = backoff.saturating_mul(2);
+				attempt += 1;
+			}
+			result => return result,
+		}
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::sync::atomic::{AtomicU32, Ordering};
+
+	use super::*;
+
+	#[tokio::test]
+	async fn transient_error_is_retried() {
+		let calls = AtomicU32::new(0);
+		let result = retry_transient(3, Duration::ZERO, || async {
+			match calls.fetch_add(1, Ordering::Relaxed) {
+				0 => Err(sqlx::Error::PoolTimedOut),
+				_ => Ok(42),
+			}
+		})
+		.await;
+
+		assert_eq!(result.unwrap(), 42);
+		assert_eq!(calls.load(Ordering::Relaxed), 2);
+	}
+
+	#[tokio::test]
+	async fn logic_error_fails_fast() {
+		let calls = AtomicU32::new(0);
+		let result: Result<(), _> = retry_transient(3, Duration::ZERO, || async {
+			calls.fetch_add(1, Ordering::Relaxed);
+			Err(sqlx::Error::RowNotFound)
+		})
+		.await;
+
+		assert!(matches!(result, Err(sqlx::Error::RowNotFound)));
+		assert_eq!(calls.load(Ordering::Relaxed), 1);
+	}
+
+	#[tokio::test]
+	async fn attempts_are_bounded() {
+		let calls = AtomicU32::new(0);
+		let result: Result<(), _> = retry_transient(3, Duration::ZERO, || async {
+			calls.fetch_add(1, Ordering::Relaxed);
+			Err(sqlx::Error::PoolTimedOut)
+		})
+		.await;
+
+		assert!(matches!(result, Err(sqlx::Error::PoolTimedOut)));
+		assert_eq!(calls.load(Ordering::Relaxed), 3);
+	}
+}
@@ -4,16 +4,36 @@
 
 use std::{
 	ops::{Deref, DerefMut},
+	path::PathBuf,
 	sync::Arc,
 };
 
-use chorus::types::{ApplicationFlags, Snowflake};
+use chorus::types::{ApplicationCommand, ApplicationFlags, InteractionCreate, Snowflake};
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::PgPool;
 
 use super::{Config, user::User, *};
-use crate::errors::Error;
+use crate::{
+	configuration::SymfoniaConfiguration,
+	errors::{ApplicationError, Error},
+	gateway::{ConnectedUsers, GatewayPayload, dispatchevent::DispatchEvent, event::Event},
+};
+
+/// Largest application icon, in bytes, that will be accepted.
+pub const MAX_ICON_SIZE: usize = 10 * 1024 * 1024;
+
+/// Most commands a single application can have registered. Enforced by the
+/// `application_commands_max_count` trigger, which has to be kept in sync.
+pub const MAX_COMMANDS: usize = 100;
+
+/// Shortest and longest name, in characters, an application may have.
+pub const NAME_LENGTH: (usize, usize) = (1, 100);
+
+/// Longest summary, in characters, an application may have. Keep in sync with
+/// the length of the `summary` column of `applications`.
+pub const MAX_SUMMARY_LENGTH: usize = 400;
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Application {
@@ -41,6 +61,13 @@ impl DerefMut for Application {
 }
 
 impl Application {
+	/// Create a new application, along with its bot user if
+	/// `create_bot_user` is set.
+	///
+	/// ## Errors
+	///
+	/// Fails with an [ApplicationError] if `name` or `summary` are not within
+	/// [NAME_LENGTH] or [MAX_SUMMARY_LENGTH].
 	pub async fn create(
 		db: &PgPool,
 		cfg: &Config,
@@ -51,6 +78,8 @@ impl Application {
 		flags: ApplicationFlags,
 		create_bot_user: bool,
 	) -> Result<Self, Error> {
+		check_name_and_summary(name, Some(summary))?;
+
 		let bot_user_id = if create_bot_user {
 			let bot_user = User::create(db, cfg, name, None, None, None, None, true).await?;
 
@@ -65,6 +94,7 @@ impl Application {
 				summary: Some(summary.to_string()),
 				verify_key: verify_key.to_string(),
 				flags,
+				bot_public: true,
 				..Default::default()
 			},
 			owner_id: owner_id.to_owned(),
@@ -73,10 +103,11 @@ impl Application {
 			publisher: Arc::new(RwLock::new(pubserve::Publisher::new())),
 		};
 
-		let _res = sqlx::query("INSERT INTO applications (id, name, summary, hook, bot_public, verify_key, owner_id, flags, integration_public, discoverability_state, discovery_eligibility_flags) VALUES (?, ?, ?, true, true, ?, ?, ?, true, 1, 2240)")
+		let _res = sqlx::query("INSERT INTO applications (id, name, summary, hook, bot_public, verify_key, owner_id, flags, integration_public, discoverability_state, discovery_eligibility_flags) VALUES (?, ?, ?, true, ?, ?, ?, ?, true, 1, 2240)")
             .bind(application.id)
             .bind(name)
             .bind(summary)
+            .bind(application.bot_public)
             .bind(verify_key)
             .bind(owner_id)
             .bind(flags)
@@ -87,13 +118,25 @@ impl Application {
 	}
 
 	pub async fn get_by_id(db: &PgPool, id: &Snowflake) -> Result<Option<Self>, Error> {
-		sqlx::query_as("SELECT * FROM applications WHERE id = ?")
+		sqlx::query_as("SELECT * FROM applications WHERE id = $1")
 			.bind(id)
 			.fetch_optional(db)
 			.await
 			.map_err(Error::Sqlx)
 	}
 
+	/// Retrieve the application whose bot user has the given ID.
+	pub async fn get_by_bot_user_id(
+		db: &PgPool,
+		bot_user_id: Snowflake,
+	) -> Result<Option<Self>, Error> {
+		sqlx::query_as("SELECT * FROM applications WHERE bot_user_id = $1")
+			.bind(bot_user_id)
+			.fetch_optional(db)
+			.await
+			.map_err(Error::Sqlx)
+	}
+
 	pub async fn get_by_owner(db: &PgPool, owner_id: &Snowflake) -> Result<Vec<Self>, Error> {
 		sqlx::query_as("SELECT * FROM applications WHERE owner_id = ?")
 			.bind(owner_id)
@@ -102,12 +145,482 @@ impl Application {
 			.map_err(Error::Sqlx)
 	}
 
+	/// Retrieve the applications owned by the team with the ID `team_id`.
+	/// Applications owned by a single user are never included.
+	pub async fn get_by_team(db: &PgPool, team_id: &Snowflake) -> Result<Vec<Self>, Error> {
+		sqlx::query_as("SELECT * FROM applications WHERE team_id = $1")
+			.bind(team_id)
+			.fetch_all(db)
+			.await
+			.map_err(Error::Sqlx)
+	}
+
 	pub async fn get_owner(&self, db: &PgPool) -> Result<User, Error> {
 		let u = User::get_by_id(db, self.owner_id).await?.unwrap(); // Unwrap the option since this should absolutely never fail
 		Ok(u)
 	}
 
+	/// Persist the editable fields of this application, including its
+	/// [ApplicationFlags] and whether its bot is public, and send an
+	/// `APPLICATION_UPDATE` event to the inbox of its owner, if they are
+	/// connected.
+	///
+	/// ## Errors
+	///
+	/// Fails with an [ApplicationError] if the name or summary of this
+	/// application are not within [NAME_LENGTH] or [MAX_SUMMARY_LENGTH].
+	pub async fn update(&self, db: &PgPool, connected_users: &ConnectedUsers) -> Result<(), Error> {
+		check_name_and_summary(&self.name, self.summary.as_deref())?;
+		sqlx::query(
+			"UPDATE applications SET name = $1, description = $2, summary = $3, bot_public = $4, bot_require_code_grant = $5, flags = $6 WHERE id = $7",
+		)
+		.bind(&self.name)
+		.bind(&self.description)
+		.bind(&self.summary)
+		.bind(self.bot_public)
+		.bind(self.bot_require_code_grant)
+		.bind(self.flags)
+		.bind(self.id)
+		.execute(db)
+		.await?;
+
+		let mut message = connected_users.bulk_message_builder();
+		message.add_user_recipients(&[self.owner_id]).await;
+		message.set_message(self.update_event()).await;
+		message.send(connected_users.clone()).await
+	}
+
+	/// Make the bot of this application public, so that anyone may add it to
+	/// their guilds, or private, so that only its owner may. This application
+	/// is only changed once the change has been persisted.
+	pub async fn set_bot_public(
+		&mut self,
+		db: &PgPool,
+		connected_users: &ConnectedUsers,
+		public: bool,
+	) -> Result<(), Error> {
+		let mut updated = self.clone();
+		updated.inner.bot_public = public;
+		updated.update(db, connected_users).await?;
+		*self = updated;
+		Ok(())
+	}
+
+	/// The `APPLICATION_UPDATE` event announcing the current state of this
+	/// application.
+	fn update_event(&self) -> Event {
+		Event::Dispatch(DispatchEvent::ApplicationUpdate(GatewayPayload::dispatch(
+			"APPLICATION_UPDATE",
+			self.inner.clone(),
+		)))
+	}
+
+	/// Validate, store and set a new icon for this application. The icon is
+	/// stored under `app-icons/<application id>/<hash>.<extension>` in the
+	/// directory given by the `STORAGE_LOCATION` environment variable, or
+	/// `files` if it is not set.
+	///
+	/// Returns the hash of the new icon, which is also what the `icon` field
+	/// is set to. Hashes of animated (GIF) icons are prefixed with `a_`.
+	///
+	/// ## Errors
+	///
+	/// Fails with an [ApplicationError] if `image_bytes` is larger than
+	/// [MAX_ICON_SIZE] or not a PNG, JPEG or GIF image.
+	pub async fn set_icon(&mut self, db: &PgPool, image_bytes: &[u8]) -> Result<String, Error> {
+		let extension = icon_extension(image_bytes)?;
+		let mut hash = hex::encode(&Sha256::digest(image_bytes)[..16]);
+		if extension == "gif" {
+			hash = format!("a_{hash}");
+		}
+
+		let directory = PathBuf::from(
+			std::env::var("STORAGE_LOCATION").unwrap_or_else(|_| "files".to_string()),
+		)
+		.join("app-icons")
+		.join(self.id.to_string());
+		tokio::fs::create_dir_all(&directory).await?;
+		tokio::fs::write(directory.join(format!("{hash}.{extension}")), image_bytes).await?;
+
+		sqlx::query("UPDATE applications SET icon = $1 WHERE id = $2")
+			.bind(&hash)
+			.bind(self.id)
+			.execute(db)
+			.await?;
+		self.inner.icon = Some(hash.clone());
+
+		Ok(hash)
+	}
+
+	/// Register a new command for this application. The ID and application ID
+	/// of `command` are assigned by this method.
+	///
+	/// ## Errors
+	///
+	/// Fails with [ApplicationError::MaxCommandsReached] if the application
+	/// already has [MAX_COMMANDS] commands, and with
+	/// [ApplicationError::DuplicateCommandName] if it already has a command
+	/// with the name of `command`. Both are enforced by the database, so that
+	/// concurrent registrations cannot get around them.
+	pub async fn create_command(
+		&self,
+		db: &PgPool,
+		mut command: ApplicationCommand,
+	) -> Result<ApplicationCommand, Error> {
+		command.id = Snowflake::generate();
+		command.application_id = self.id;
+		sqlx::query(
+			"INSERT INTO application_commands (id, application_id, name, data) VALUES ($1, $2, $3, $4)",
+		)
+		.bind(command.id)
+		.bind(self.id)
+		.bind(&command.name)
+		.bind(sqlx::types::Json(&command))
+		.execute(db)
+		.await
+		.map_err(command_constraint_error)?;
+
+		Ok(command)
+	}
+
+	/// All commands registered for this application.
+	pub async fn get_commands(&self, db: &PgPool) -> Result<Vec<ApplicationCommand>, Error> {
+		let commands: Vec<sqlx::types::Json<ApplicationCommand>> = sqlx::query_scalar(
+			"SELECT data FROM application_commands WHERE application_id = $1 ORDER BY id",
+		)
+		.bind(self.id)
+		.fetch_all(db)
+		.await?;
+		Ok(commands.into_iter().map(|command| command.0).collect())
+	}
+
+	/// Replace the command with the ID `command.id` of this application with
+	/// `command`.
+	///
+	/// ## Errors
+	///
+	/// Fails with [ApplicationError::UnknownCommand] if this application has no
+	/// command with that ID, and with [ApplicationError::DuplicateCommandName]
+	/// if it has another command with the name of `command`.
+	pub async fn update_command(
+		&self,
+		db: &PgPool,
+		mut command: ApplicationCommand,
+	) -> Result<ApplicationCommand, Error> {
+		command.application_id = self.id;
+		let result = sqlx::query(
+			"UPDATE application_commands SET name = $1, data = $2 WHERE id = $3 AND application_id = $4",
+		)
+		.bind(&command.name)
+		.bind(sqlx::types::Json(&command))
+		.bind(command.id)
+		.bind(self.id)
+		.execute(db)
+		.await
+		.map_err(command_constraint_error)?;
+		if result.rows_affected() == 0 {
+			return Err(ApplicationError::UnknownCommand.into());
+		}
+
+		Ok(command)
+	}
+
+	/// Delete the command with the ID `command_id` of this application.
+	///
+	/// ## Errors
+	///
+	/// Fails with [ApplicationError::UnknownCommand] if this application has no
+	/// command with that ID.
+	pub async fn delete_command(&self, db: &PgPool, command_id: Snowflake) -> Result<(), Error> {
+		let result =
+			sqlx::query("DELETE FROM application_commands WHERE id = $1 AND application_id = $2")
+				.bind(command_id)
+				.bind(self.id)
+				.execute(db)
+				.await?;
+		if result.rows_affected() == 0 {
+			return Err(ApplicationError::UnknownCommand.into());
+		}
+		Ok(())
+	}
+
+	/// Deliver `interaction`, which invokes one of the commands of this
+	/// application, to the bot user of this application. See
+	/// [ConnectedUsers::dispatch_interaction].
+	///
+	/// ## Errors
+	///
+	/// Fails with [ApplicationError::NoBotUser] if this application has no bot
+	/// user.
+	pub async fn dispatch_interaction(
+		&self,
+		connected_users: &ConnectedUsers,
+		interaction: InteractionCreate,
+	) -> Result<bool, Error> {
+		let bot_user_id = self.bot_user_id.ok_or(ApplicationError::NoBotUser)?;
+		connected_users
+			.dispatch_interaction(
+				bot_user_id,
+				interaction,
+				SymfoniaConfiguration::get().gateway.offline_interactions,
+			)
+			.await
+	}
+
 	pub fn public_json(&self) -> String {
 		serde_json::to_string(&self.inner).unwrap()
 	}
 }
+
+/// Determine the file extension of an icon from its contents, rejecting icons
+/// which are too large or of an unsupported format.
+fn icon_extension(image_bytes: &[u8]) -> Result<&'static str, ApplicationError> {
+	if image_bytes.len() > MAX_ICON_SIZE {
+		return Err(ApplicationError::IconTooLarge(MAX_ICON_SIZE));
+	}
+	if image_bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+		Ok("png")
+	} else if image_bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+		Ok("jpg")
+	} else if image_bytes.starts_with(b"GIF87a") || image_bytes.starts_with(b"GIF89a") {
+		Ok("gif")
+	} else {
+		Err(ApplicationError::InvalidIcon)
+	}
+}
+
+/// Turn violations of the constraints on `application_commands` into the
+/// [ApplicationError]s they stand for.
+fn command_constraint_error(error: sqlx::Error) -> Error {
+	let constraint = match &error {
+		sqlx::Error::Database(database_error) => database_error.constraint(),
+		_ => None,
+	};
+	match constraint {
+		Some("application_commands_application_id_name_uindex") => {
+			ApplicationError::DuplicateCommandName.into()
+		}
+		Some("application_commands_max_count") => {
+			ApplicationError::MaxCommandsReached(MAX_COMMANDS).into()
+		}
+		_ => error.into(),
+	}
+}
+
+/// Check whether `name` and `summary` are within the lengths Discord allows
+/// for applications.
+fn check_name_and_summary(name: &str, summary: Option<&str>) -> Result<(), ApplicationError> {
+	let (min, max) = NAME_LENGTH;
+	if !(min..=max).contains(&name.chars().count()) {
+		return Err(ApplicationError::InvalidNameLength(min, max));
+	}
+	if summary.is_some_and(|summary| summary.chars().count() > MAX_SUMMARY_LENGTH) {
+		return Err(ApplicationError::SummaryTooLong(MAX_SUMMARY_LENGTH));
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::collections::HashMap;
+
+	use super::*;
+	use crate::gateway::testing::unreachable_db;
+
+	const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+	#[test]
+	fn png_icon_is_accepted() {
+		let mut icon = PNG_SIGNATURE.to_vec();
+		icon.extend_from_slice(&[0; 64]);
+		assert_eq!(icon_extension(&icon).unwrap(), "png");
+	}
+
+	#[test]
+	fn oversized_icon_is_rejected() {
+		let mut icon = PNG_SIGNATURE.to_vec();
+		icon.resize(MAX_ICON_SIZE + 1, 0);
+		assert!(matches!(icon_extension(&icon), Err(ApplicationError::IconTooLarge(_))));
+	}
+
+	#[test]
+	fn non_image_icon_is_rejected() {
+		assert!(matches!(icon_extension(b"not an image"), Err(ApplicationError::InvalidIcon)));
+	}
+
+	#[test]
+	fn empty_name_is_rejected() {
+		assert!(matches!(
+			check_name_and_summary("", None),
+			Err(ApplicationError::InvalidNameLength(1, 100))
+		));
+		assert!(check_name_and_summary(&"a".repeat(101), None).is_err());
+	}
+
+	#[test]
+	fn overlong_summary_is_rejected() {
+		let summary = "a".repeat(MAX_SUMMARY_LENGTH + 1);
+		assert!(matches!(
+			check_name_and_summary("My Bot", Some(&summary)),
+			Err(ApplicationError::SummaryTooLong(MAX_SUMMARY_LENGTH))
+		));
+	}
+
+	#[test]
+	fn valid_name_and_summary_are_accepted() {
+		assert!(check_name_and_summary("My Bot", Some("")).is_ok());
+		assert!(check_name_and_summary(&"é".repeat(100), Some(&"a".repeat(400))).is_ok());
+	}
+
+	#[test]
+	fn update_event_carries_bot_public() {
+		let mut application = Application::default();
+		application.bot_public = false;
+
+		let Event::Dispatch(DispatchEvent::ApplicationUpdate(payload)) = application.update_event()
+		else {
+			panic!("expected an APPLICATION_UPDATE event");
+		};
+		assert_eq!(payload.event_name.as_deref(), Some("APPLICATION_UPDATE"));
+		assert!(!payload.event_data.unwrap().bot_public);
+	}
+
+	#[tokio::test]
+	async fn failed_bot_public_change_is_not_applied() {
+		let db = unreachable_db();
+		let mut application = Application::default();
+		application.name = "My Bot".to_string();
+		application.bot_public = true;
+
+		assert!(application.set_bot_public(&db, &ConnectedUsers::new(), false).await.is_err());
+		assert!(application.bot_public);
+	}
+
+	#[sqlx::test(fixtures(path = "../../fixtures", scripts("users")))]
+	async fn only_team_applications_are_listed_for_a_team(db: PgPool) {
+		let owner_id = Snowflake::from(7248639845155737600u64);
+		let team_id = Snowflake::from(1u64);
+		sqlx::query("INSERT INTO teams (id, name, owner_user_id) VALUES ($1, 'My Team', $2)")
+			.bind(team_id)
+			.bind(owner_id)
+			.execute(&db)
+			.await
+			.unwrap();
+		for (id, team_id) in [(10u64, Some(team_id)), (11, Some(team_id)), (12, None)] {
+			sqlx::query(
+				"INSERT INTO applications (id, name, hook, bot_public, bot_require_code_grant, verify_key, flags, owner_id, team_id) VALUES ($1, 'My App', true, true, false, 1, 0, $2, $3)",
+			)
+			.bind(Snowflake::from(id))
+			.bind(owner_id)
+			.bind(team_id)
+			.execute(&db)
+			.await
+			.unwrap();
+		}
+
+		let mut applications = Application::get_by_team(&db, &team_id).await.unwrap();
+		applications.sort_by_key(|application| application.id);
+
+		assert_eq!(
+			applications.iter().map(|application| application.id).collect::<Vec<_>>(),
+			vec![Snowflake::from(10u64), Snowflake::from(11u64)]
+		);
+		assert!(applications.iter().all(|application| application.team_id == Some(team_id)));
+		assert!(Application::get_by_team(&db, &Snowflake::from(2u64)).await.unwrap().is_empty());
+	}
+
+	#[test]
+	fn command_survives_storage_roundtrip() {
+		let command = ApplicationCommand {
+			id: Snowflake::from(1u64),
+			application_id: Snowflake::from(2u64),
+			name: "ping".to_string(),
+			description: "Replies with pong".to_string(),
+			..Default::default()
+		};
+
+		// Commands are stored as JSON in the `data` column.
+		let stored = serde_json::to_value(&command).unwrap();
+		let loaded: ApplicationCommand = serde_json::from_value(stored).unwrap();
+
+		assert_eq!(loaded.id, command.id);
+		assert_eq!(loaded.application_id, command.application_id);
+		assert_eq!(loaded.name, "ping");
+		assert_eq!(loaded.description, "Replies with pong");
+	}
+
+	async fn insert_application(db: &PgPool) -> Application {
+		let id = Snowflake::from(10u64);
+		sqlx::query(
+			"INSERT INTO applications (id, name, hook, bot_public, bot_require_code_grant, verify_key, flags, owner_id) VALUES ($1, 'My App', true, true, false, 1, 0, $2)",
+		)
+		.bind(id)
+		.bind(Snowflake::from(7248639845155737600u64))
+		.execute(db)
+		.await
+		.unwrap();
+		Application::get_by_id(db, &id).await.unwrap().unwrap()
+	}
+
+	fn command(name: &str) -> ApplicationCommand {
+		ApplicationCommand { name: name.to_string(), ..Default::default() }
+	}
+
+	#[sqlx::test(fixtures(path = "../../fixtures", scripts("users")))]
+	async fn duplicate_command_names_are_rejected(db: PgPool) {
+		let application = insert_application(&db).await;
+		application.create_command(&db, command("ping")).await.unwrap();
+		let mut pong = application.create_command(&db, command("pong")).await.unwrap();
+
+		assert!(matches!(
+			application.create_command(&db, command("ping")).await,
+			Err(Error::Application(ApplicationError::DuplicateCommandName))
+		));
+		pong.name = "ping".to_string();
+		assert!(matches!(
+			application.update_command(&db, pong).await,
+			Err(Error::Application(ApplicationError::DuplicateCommandName))
+		));
+		assert_eq!(application.get_commands(&db).await.unwrap().len(), 2);
+	}
+
+	#[sqlx::test(fixtures(path = "../../fixtures", scripts("users")))]
+	async fn concurrent_registrations_respect_the_command_limit(db: PgPool) {
+		let application = insert_application(&db).await;
+		for i in 0..MAX_COMMANDS - 1 {
+			application.create_command(&db, command(&format!("command-{i}"))).await.unwrap();
+		}
+
+		let (first, second) = tokio::join!(
+			application.create_command(&db, command("first")),
+			application.create_command(&db, command("second"))
+		);
+
+		assert_eq!([&first, &second].iter().filter(|result| result.is_ok()).count(), 1);
+		assert!([first, second].into_iter().any(|result| matches!(
+			result,
+			Err(Error::Application(ApplicationError::MaxCommandsReached(MAX_COMMANDS)))
+		)));
+		assert_eq!(application.get_commands(&db).await.unwrap().len(), MAX_COMMANDS);
+	}
+
+	#[sqlx::test(fixtures(path = "../../fixtures", scripts("users")))]
+	async fn bot_public_change_is_persisted_and_sent_to_the_owner(db: PgPool) {
+		let connected_users = ConnectedUsers::new();
+		let mut application = insert_application(&db).await;
+		let owner = connected_users.new_user(HashMap::new(), application.owner_id, Vec::new());
+		let mut inbox = owner.lock().await.inbox.resubscribe();
+
+		application.set_bot_public(&db, &connected_users, false).await.unwrap();
+
+		let reloaded = Application::get_by_id(&db, &application.id).await.unwrap().unwrap();
+		assert!(!reloaded.bot_public);
+		let Event::Dispatch(DispatchEvent::ApplicationUpdate(payload)) =
+			inbox.recv().await.unwrap()
+		else {
+			panic!("expected an APPLICATION_UPDATE event");
+		};
+		assert!(!payload.event_data.unwrap().bot_public);
+	}
+}
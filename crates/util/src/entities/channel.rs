@@ -6,7 +6,7 @@ use std::ops::{Deref, DerefMut};
 
 use chorus::types::{
 	ChannelMessagesAnchor, ChannelModifySchema, ChannelType, CreateChannelInviteSchema, InviteType,
-	MessageSendSchema, PermissionOverwrite, Snowflake,
+	MessageSendSchema, PermissionFlags, PermissionOverwrite, PermissionOverwriteType, Snowflake,
 };
 use futures::executor::block_on;
 use itertools::Itertools;
@@ -101,6 +101,7 @@ impl Channel {
 				name,
 				nsfw: Some(nsfw),
 				guild_id,
+				permission_overwrites: Some(Json(permission_overwrites)),
 				..Default::default()
 			},
 			..Default::default()
@@ -114,7 +115,7 @@ impl Channel {
             .bind(channel.guild_id)
             .bind(channel.parent_id)
             .bind(0)
-            .bind(Json(permission_overwrites))
+            .bind(&channel.permission_overwrites)
             .bind(0)
             .execute(db)
             .await?;
@@ -175,7 +176,7 @@ impl Channel {
 	}
 
 	pub async fn get_by_id(db: &PgPool, id: Snowflake) -> Result<Option<Self>, Error> {
-		sqlx::query_as("SELECT * FROM channels WHERE id = ?")
+		sqlx::query_as("SELECT * FROM channels WHERE id = $1")
 			.bind(id)
 			.fetch_optional(db)
 			.await
@@ -183,7 +184,7 @@ impl Channel {
 	}
 
 	pub async fn get_by_guild_id(db: &PgPool, guild_id: Snowflake) -> Result<Vec<Self>, Error> {
-		sqlx::query_as("SELECT * FROM channels WHERE guild_id = ?")
+		sqlx::query_as("SELECT * FROM channels WHERE guild_id = $1")
 			.bind(guild_id)
 			.fetch_all(db)
 			.await
@@ -373,6 +374,96 @@ impl Channel {
 			.map_err(Error::from)
 	}
 
+	/// Whether the channel has any permission overwrites. Channels without
+	/// overwrites can be seen by everyone who can see the rest of the guild.
+	pub fn has_permission_overwrites(&self) -> bool {
+		self.permission_overwrites.as_ref().is_some_and(|overwrites| !overwrites.is_empty())
+	}
+
+	/// Whether `member` may view this guild channel, taking the permission
+	/// overwrites of the channel into account. The owner of the guild and
+	/// administrators can view every channel. The roles and guild-wide
+	/// permissions of `member` must have been loaded, for example with
+	/// [GuildMember::populate_permissions].
+	pub fn is_visible_to(&self, member: &GuildMember, owner_id: Option<Snowflake>) -> bool {
+		let mut permissions = member.permissions;
+		if Some(member.id) == owner_id || permissions.contains(PermissionFlags::ADMINISTRATOR) {
+			return true;
+		}
+		let overwrites = self
+			.permission_overwrites
+			.as_ref()
+			.map(|overwrites| overwrites.as_slice())
+			.unwrap_or(&[]);
+		let mut apply = |allow: PermissionFlags, deny: PermissionFlags| {
+			permissions.remove(deny);
+			permissions.insert(allow);
+		};
+
+		// The overwrite of the @everyone role, which shares its ID with the guild,
+		// applies first, then those of the roles of the member, then the one of the
+		// member itself.
+		if let Some(everyone) = overwrites.iter().find(|overwrite| {
+			overwrite.overwrite_type == PermissionOverwriteType::Role
+				&& Some(overwrite.id) == self.guild_id
+		}) {
+			apply(everyone.allow, everyone.deny);
+		}
+		let (allow, deny) = overwrites
+			.iter()
+			.filter(|overwrite| {
+				overwrite.overwrite_type == PermissionOverwriteType::Role
+					&& Some(overwrite.id) != self.guild_id
+					&& member.roles.contains(&overwrite.id)
+			})
+			.fold(
+				(PermissionFlags::empty(), PermissionFlags::empty()),
+				|(allow, deny), overwrite| (allow | overwrite.allow, deny | overwrite.deny),
+			);
+		apply(allow, deny);
+		if let Some(own) = overwrites.iter().find(|overwrite| {
+			overwrite.overwrite_type == PermissionOverwriteType::Member && overwrite.id == member.id
+		}) {
+			apply(own.allow, own.deny);
+		}
+
+		permissions.contains(PermissionFlags::VIEW_CHANNEL)
+	}
+
+	pub fn is_thread(&self) -> bool {
+		self.channel_type == ChannelType::GuildNewsThread
+			|| self.channel_type == ChannelType::GuildPublicThread
+			|| self.channel_type == ChannelType::GuildPrivateThread
+	}
+
+	/// Whether this thread has not been archived. Threads without metadata are
+	/// considered active.
+	pub fn is_active_thread(&self) -> bool {
+		self.is_thread() && !self.thread_metadata.as_ref().is_some_and(|metadata| metadata.archived)
+	}
+
+	/// Whether `member` can see this thread, which has been started in
+	/// `parent`. Threads are visible to everyone who can see their parent,
+	/// except for private threads, which are only visible to the member who
+	/// started them, the owner of the guild and members allowed to manage
+	/// threads.
+	pub fn is_thread_visible_to(
+		&self,
+		parent: &Channel,
+		member: &GuildMember,
+		owner_id: Option<Snowflake>,
+	) -> bool {
+		if !parent.is_visible_to(member, owner_id) {
+			return false;
+		}
+		self.channel_type != ChannelType::GuildPrivateThread
+			|| self.owner_id == Some(member.id)
+			|| Some(member.id) == owner_id
+			|| member
+				.permissions
+				.intersects(PermissionFlags::ADMINISTRATOR | PermissionFlags::MANAGE_THREADS)
+	}
+
 	/// Get all private channels of a user. Only queries channels which are not
 	/// marked as closed.
 	pub async fn get_private_of_user(user_id: Snowflake, db: &PgPool) -> Result<Vec<Self>, Error> {
@@ -388,6 +479,97 @@ impl Channel {
 	}
 }
 
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn member(id: u64, roles: Vec<Snowflake>, permissions: PermissionFlags) -> GuildMember {
+		let mut member = GuildMember { id: Snowflake::from(id), ..Default::default() };
+		member.roles = roles;
+		member.permissions = permissions;
+		member
+	}
+
+	fn overwrite(
+		id: Snowflake,
+		overwrite_type: PermissionOverwriteType,
+		allow: PermissionFlags,
+		deny: PermissionFlags,
+	) -> PermissionOverwrite {
+		PermissionOverwrite { id, overwrite_type, allow, deny }
+	}
+
+	#[test]
+	fn overwrites_decide_channel_visibility() {
+		let guild_id = Snowflake::from(100u64);
+		let moderators = Snowflake::from(200u64);
+		let mut channel = Channel::default();
+		channel.guild_id = Some(guild_id);
+		channel.permission_overwrites = Some(Json(vec![
+			overwrite(
+				guild_id,
+				PermissionOverwriteType::Role,
+				PermissionFlags::empty(),
+				PermissionFlags::VIEW_CHANNEL,
+			),
+			overwrite(
+				moderators,
+				PermissionOverwriteType::Role,
+				PermissionFlags::VIEW_CHANNEL,
+				PermissionFlags::empty(),
+			),
+			overwrite(
+				Snowflake::from(3u64),
+				PermissionOverwriteType::Member,
+				PermissionFlags::empty(),
+				PermissionFlags::VIEW_CHANNEL,
+			),
+		]));
+
+		let everyone = member(1, vec![guild_id], PermissionFlags::VIEW_CHANNEL);
+		let moderator = member(2, vec![guild_id, moderators], PermissionFlags::VIEW_CHANNEL);
+		let muted_moderator = member(3, vec![guild_id, moderators], PermissionFlags::VIEW_CHANNEL);
+		let admin = member(4, vec![guild_id], PermissionFlags::ADMINISTRATOR);
+
+		assert!(channel.has_permission_overwrites());
+		assert!(!channel.is_visible_to(&everyone, None));
+		assert!(channel.is_visible_to(&moderator, None));
+		assert!(!channel.is_visible_to(&muted_moderator, None));
+		assert!(channel.is_visible_to(&admin, None));
+		assert!(channel.is_visible_to(&everyone, Some(everyone.id)));
+	}
+
+	#[test]
+	fn private_threads_are_only_visible_to_their_members() {
+		let guild_id = Snowflake::from(100u64);
+		let mut parent = Channel::default();
+		parent.id = Snowflake::from(10u64);
+		parent.guild_id = Some(guild_id);
+		let mut thread = Channel::default();
+		thread.id = Snowflake::from(11u64);
+		thread.guild_id = Some(guild_id);
+		thread.parent_id = Some(parent.id);
+		thread.channel_type = ChannelType::GuildPrivateThread;
+		thread.owner_id = Some(Snowflake::from(1u64));
+
+		let starter = member(1, vec![guild_id], PermissionFlags::VIEW_CHANNEL);
+		let everyone = member(2, vec![guild_id], PermissionFlags::VIEW_CHANNEL);
+		let moderator = member(
+			3,
+			vec![guild_id],
+			PermissionFlags::VIEW_CHANNEL | PermissionFlags::MANAGE_THREADS,
+		);
+
+		assert!(thread.is_active_thread());
+		assert!(thread.is_thread_visible_to(&parent, &starter, None));
+		assert!(!thread.is_thread_visible_to(&parent, &everyone, None));
+		assert!(thread.is_thread_visible_to(&parent, &moderator, None));
+
+		thread.channel_type = ChannelType::GuildPublicThread;
+		assert!(thread.is_thread_visible_to(&parent, &everyone, None));
+	}
+}
+
 // TODO: Move to symfonia again
 // #[cfg(test)]
 // mod channel_unit_tests {
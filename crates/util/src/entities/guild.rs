@@ -198,7 +198,7 @@ impl Guild {
 	}
 
 	pub async fn get_by_id(db: &PgPool, id: Snowflake) -> Result<Option<Self>, Error> {
-		sqlx::query_as("SELECT * FROM guilds WHERE id = ?")
+		sqlx::query_as("SELECT * FROM guilds WHERE id = $1")
 			.bind(id)
 			.fetch_optional(db)
 			.await
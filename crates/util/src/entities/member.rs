@@ -2,18 +2,26 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::ops::{Deref, DerefMut};
+use std::{
+	collections::HashMap,
+	ops::{Deref, DerefMut},
+};
 
-use chorus::types::{Snowflake, UserGuildSettingsUpdate};
+use chorus::types::{PermissionFlags, Snowflake, UserGuildSettingsUpdate};
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, Row};
+use sqlx::{FromRow, Postgres, QueryBuilder, Row};
 use sqlx_pg_uint::{PgU16, PgU64};
 
 use crate::{
-	entities::{Guild, User},
+	entities::{Guild, Role, User},
 	errors::{Error, GuildError, UserError},
 };
 
+/// Most IDs bound to a single query by
+/// [GuildMember::get_by_ids_with_permissions]. Postgres accepts at most 65535
+/// parameters per query.
+const MAX_IDS_PER_QUERY: usize = 1000;
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize, FromRow)]
 pub struct GuildMember {
 	#[serde(flatten)]
@@ -101,7 +109,7 @@ impl GuildMember {
 		guild_id: Snowflake,
 	) -> Result<Option<Self>, Error> {
 		let mut member: Self =
-			sqlx::query_as("SELECT * FROM members WHERE id = ? AND guild_id = ?")
+			sqlx::query_as("SELECT * FROM members WHERE id = $1 AND guild_id = $2")
 				.bind(id)
 				.bind(guild_id)
 				.fetch_optional(db)
@@ -134,6 +142,119 @@ impl GuildMember {
 			.map_err(Error::from)
 	}
 
+	/// Retrieve all members of the guild with the given ID.
+	pub async fn get_all_by_guild_id(
+		db: &sqlx::PgPool,
+		guild_id: Snowflake,
+	) -> Result<Vec<Self>, Error> {
+		sqlx::query_as("SELECT * FROM members WHERE guild_id = $1")
+			.bind(guild_id)
+			.fetch_all(db)
+			.await
+			.map_err(Error::from)
+	}
+
+	/// Retrieve all members of the guild with the given ID, with their roles
+	/// and the permissions these roles grant them. See
+	/// [GuildMember::set_roles].
+	pub async fn get_all_by_guild_id_with_permissions(
+		db: &sqlx::PgPool,
+		guild_id: Snowflake,
+	) -> Result<Vec<Self>, Error> {
+		let mut members = Self::get_all_by_guild_id(db, guild_id).await?;
+		let guild_roles = Role::get_by_guild(db, guild_id).await?;
+		let mut member_roles: HashMap<Snowflake, Vec<Snowflake>> = HashMap::new();
+		let rows: Vec<(Snowflake, Snowflake)> = sqlx::query_as(
+			"SELECT m.id, mr.role_id FROM member_roles mr JOIN members m ON m.index = mr.index WHERE m.guild_id = $1",
+		)
+		.bind(guild_id)
+		.fetch_all(db)
+		.await?;
+		for (user_id, role_id) in rows {
+			member_roles.entry(user_id).or_default().push(role_id);
+		}
+		for member in members.iter_mut() {
+			let role_ids = member_roles.remove(&member.id).unwrap_or_default();
+			member.set_roles(role_ids, &guild_roles);
+		}
+		Ok(members)
+	}
+
+	/// Retrieve the user IDs of all members of the guild with the given ID.
+	pub async fn get_ids_by_guild_id(
+		db: &sqlx::PgPool,
+		guild_id: Snowflake,
+	) -> Result<Vec<Snowflake>, Error> {
+		sqlx::query_scalar("SELECT id FROM members WHERE guild_id = $1")
+			.bind(guild_id)
+			.fetch_all(db)
+			.await
+			.map_err(Error::from)
+	}
+
+	/// Retrieve the members of the guild with the given ID whose user IDs are
+	/// among `ids`, with their roles and the permissions these roles grant
+	/// them, like [GuildMember::get_all_by_guild_id_with_permissions].
+	pub async fn get_by_ids_with_permissions(
+		db: &sqlx::PgPool,
+		guild_id: Snowflake,
+		ids: &[Snowflake],
+	) -> Result<Vec<Self>, Error> {
+		if ids.is_empty() {
+			return Ok(Vec::new());
+		}
+		let guild_roles = Role::get_by_guild(db, guild_id).await?;
+		let mut members: Vec<Self> = Vec::with_capacity(ids.len());
+		let mut member_roles: HashMap<Snowflake, Vec<Snowflake>> = HashMap::new();
+		for ids in ids.chunks(MAX_IDS_PER_QUERY) {
+			let mut query_builder = QueryBuilder::new("SELECT * FROM members WHERE guild_id = ");
+			push_id_filter(&mut query_builder, guild_id, "id", ids);
+			members.extend(query_builder.build_query_as::<Self>().fetch_all(db).await?);
+
+			let mut query_builder = QueryBuilder::new(
+				"SELECT m.id, mr.role_id FROM member_roles mr JOIN members m ON m.index = mr.index WHERE m.guild_id = ",
+			);
+			push_id_filter(&mut query_builder, guild_id, "m.id", ids);
+			let rows: Vec<(Snowflake, Snowflake)> =
+				query_builder.build_query_as().fetch_all(db).await?;
+			for (user_id, role_id) in rows {
+				member_roles.entry(user_id).or_default().push(role_id);
+			}
+		}
+		for member in members.iter_mut() {
+			let role_ids = member_roles.remove(&member.id).unwrap_or_default();
+			member.set_roles(role_ids, &guild_roles);
+		}
+		Ok(members)
+	}
+
+	/// Load the roles of this member, and the permissions they grant, from the
+	/// database. See [GuildMember::set_roles].
+	pub async fn populate_permissions(&mut self, db: &sqlx::PgPool) -> Result<(), Error> {
+		let guild_roles = Role::get_by_guild(db, self.guild_id).await?;
+		let role_ids: Vec<Snowflake> =
+			sqlx::query_scalar("SELECT role_id FROM member_roles WHERE index = $1")
+				.bind(&self.index)
+				.fetch_all(db)
+				.await?;
+		self.set_roles(role_ids, &guild_roles);
+		Ok(())
+	}
+
+	/// Give this member the roles `role_ids`, out of the roles of its guild,
+	/// `guild_roles`. The members table does not store permissions, so the
+	/// guild-wide permissions of the member are set to those granted by the
+	/// @everyone role, which shares its ID with the guild, and by `role_ids`.
+	/// Permission overwrites of channels are applied on top of these, see
+	/// [crate::entities::Channel::is_visible_to].
+	pub fn set_roles(&mut self, role_ids: Vec<Snowflake>, guild_roles: &[Role]) {
+		self.permissions = guild_roles
+			.iter()
+			.filter(|role| role.id == self.guild_id || role_ids.contains(&role.id))
+			.fold(PermissionFlags::empty(), |permissions, role| permissions | role.permissions);
+		self.roles = role_ids;
+	}
+
 	pub async fn get_by_role_id(
 		db: &sqlx::PgPool,
 		guild_id: Snowflake,
@@ -295,3 +416,108 @@ impl GuildMember {
 		self.inner
 	}
 }
+
+/// Complete `query_builder`, which ends with a comparison against a guild ID,
+/// with `guild_id` and the condition that `column` is one of `ids`.
+fn push_id_filter(
+	query_builder: &mut QueryBuilder<'_, Postgres>,
+	guild_id: Snowflake,
+	column: &str,
+	ids: &[Snowflake],
+) {
+	query_builder.push_bind(guild_id);
+	query_builder.push(format!(" AND {column} IN ("));
+	let mut separated = query_builder.separated(", ");
+	for id in ids {
+		separated.push_bind(*id);
+	}
+	separated.push_unseparated(")");
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use chorus::types::{PermissionOverwrite, PermissionOverwriteType};
+	use sqlx::PgPool;
+
+	use super::*;
+	use crate::{
+		entities::Channel,
+		gateway::testing::{insert_member, insert_role},
+	};
+
+	#[sqlx::test(fixtures(path = "../../fixtures", scripts("users", "guilds")))]
+	async fn permissions_are_computed_from_roles(db: PgPool) {
+		let guild_id = Snowflake::from(7249086638293258240u64);
+		let muted = Snowflake::from(1u64);
+		insert_role(&db, guild_id, guild_id, PermissionFlags::VIEW_CHANNEL).await;
+		insert_role(&db, muted, guild_id, PermissionFlags::empty()).await;
+		let member_id = Snowflake::from(7248639845155737600u64);
+		let muted_member_id = Snowflake::from(7248639891561517057u64);
+		insert_member(&db, member_id, guild_id, guild_id).await;
+		insert_member(&db, muted_member_id, guild_id, muted).await;
+
+		let members =
+			GuildMember::get_all_by_guild_id_with_permissions(&db, guild_id).await.unwrap();
+		let member = members.iter().find(|member| member.id == member_id).unwrap();
+		let muted_member = members.iter().find(|member| member.id == muted_member_id).unwrap();
+		// Everyone is granted the permissions of the @everyone role.
+		assert_eq!(member.permissions, PermissionFlags::VIEW_CHANNEL);
+		assert_eq!(muted_member.permissions, PermissionFlags::VIEW_CHANNEL);
+		assert_eq!(muted_member.roles, vec![muted]);
+
+		let mut channel = Channel::default();
+		channel.guild_id = Some(guild_id);
+		channel.permission_overwrites = Some(sqlx::types::Json(vec![PermissionOverwrite {
+			id: muted,
+			overwrite_type: PermissionOverwriteType::Role,
+			allow: PermissionFlags::empty(),
+			deny: PermissionFlags::VIEW_CHANNEL,
+		}]));
+		assert!(channel.is_visible_to(member, None));
+		assert!(!channel.is_visible_to(muted_member, None));
+
+		let mut reloaded = GuildMember::get_all_by_guild_id(&db, guild_id)
+			.await
+			.unwrap()
+			.into_iter()
+			.find(|member| member.id == muted_member_id)
+			.unwrap();
+		reloaded.populate_permissions(&db).await.unwrap();
+		assert_eq!(reloaded.permissions, muted_member.permissions);
+		assert_eq!(reloaded.roles, muted_member.roles);
+	}
+
+	#[sqlx::test(fixtures(path = "../../fixtures", scripts("users", "guilds")))]
+	async fn only_requested_members_are_loaded(db: PgPool) {
+		let guild_id = Snowflake::from(7249086638293258240u64);
+		let role_id = Snowflake::from(1u64);
+		insert_role(&db, guild_id, guild_id, PermissionFlags::VIEW_CHANNEL).await;
+		insert_role(&db, role_id, guild_id, PermissionFlags::SEND_MESSAGES).await;
+		let requested = Snowflake::from(7248639845155737600u64);
+		let other = Snowflake::from(7248639891561517057u64);
+		insert_member(&db, requested, guild_id, role_id).await;
+		insert_member(&db, other, guild_id, role_id).await;
+
+		let mut ids = GuildMember::get_ids_by_guild_id(&db, guild_id).await.unwrap();
+		ids.sort();
+		let mut expected = vec![requested, other];
+		expected.sort();
+		assert_eq!(ids, expected);
+
+		let members = GuildMember::get_by_ids_with_permissions(
+			&db,
+			guild_id,
+			&[requested, Snowflake::from(2u64)],
+		)
+		.await
+		.unwrap();
+		assert_eq!(members.len(), 1);
+		assert_eq!(members[0].id, requested);
+		assert_eq!(members[0].roles, vec![role_id]);
+		assert_eq!(
+			members[0].permissions,
+			PermissionFlags::VIEW_CHANNEL | PermissionFlags::SEND_MESSAGES
+		);
+	}
+}
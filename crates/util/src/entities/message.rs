@@ -2,10 +2,13 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::ops::{Deref, DerefMut};
+use std::{
+	collections::HashMap,
+	ops::{Deref, DerefMut},
+};
 
 use chorus::types::{
-	ChannelMessagesAnchor, MessageFlags, MessageModifySchema, MessageSearchQuery,
+	ChannelMessagesAnchor, MessageCreate, MessageFlags, MessageModifySchema, MessageSearchQuery,
 	MessageSendSchema, MessageType, PartialEmoji, Reaction, Snowflake,
 };
 use chrono::Utc;
@@ -16,6 +19,7 @@ use sqlx_pg_uint::PgU64;
 use crate::{
 	entities::User,
 	errors::{ChannelError, Error, ReactionError},
+	gateway::{GatewayPayload, dispatchevent::DispatchEvent, event::Event},
 };
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize, sqlx::FromRow)]
@@ -126,6 +130,66 @@ impl Message {
 		})
 	}
 
+	/// The `MESSAGE_CREATE` event announcing this message, for example right
+	/// after it has been created with [Self::create].
+	pub fn create_event(&self) -> Event {
+		Event::Dispatch(DispatchEvent::MessageCreate(GatewayPayload::dispatch(
+			"MESSAGE_CREATE",
+			MessageCreate {
+				message: self.inner.clone(),
+				guild_id: self.guild_id,
+				..Default::default()
+			},
+		)))
+	}
+
+	/// A lightweight `MESSAGE_CREATE` telling the users mentioned in this
+	/// message about it, even if they cannot read its channel. Only the ID,
+	/// channel, author, timestamp and type of the message are included, not
+	/// its content, embeds or attachments.
+	pub fn mention_notification_event(&self) -> Event {
+		Event::Dispatch(DispatchEvent::MessageCreate(GatewayPayload::dispatch(
+			"MESSAGE_CREATE",
+			MessageCreate {
+				message: chorus::types::Message {
+					id: self.id,
+					channel_id: self.channel_id,
+					author: self.author.clone(),
+					timestamp: self.timestamp,
+					message_type: self.message_type,
+					flags: self.flags,
+					..Default::default()
+				},
+				guild_id: self.guild_id,
+				..Default::default()
+			},
+		)))
+	}
+
+	/// The Snowflake IDs of the users mentioned in the content of this message
+	/// with `<@id>` or `<@!id>`, in order of appearance and without duplicates.
+	pub fn mentioned_user_ids(&self) -> Vec<Snowflake> {
+		let mut mentioned = Vec::new();
+		let Some(content) = self.content.as_deref() else {
+			return mentioned;
+		};
+		for (start, _) in content.match_indices("<@") {
+			let rest = &content[start + 2..];
+			let rest = rest.strip_prefix('!').unwrap_or(rest);
+			let Some(end) = rest.find('>') else {
+				continue;
+			};
+			// Role mentions, `<@&id>`, do not parse.
+			if let Ok(id) = rest[..end].parse::<u64>() {
+				let id = Snowflake::from(id);
+				if !mentioned.contains(&id) {
+					mentioned.push(id);
+				}
+			}
+		}
+		mentioned
+	}
+
 	pub async fn get_by_nonce(
 		db: &PgPool,
 		channel_id: Snowflake,
@@ -156,6 +220,41 @@ impl Message {
 			.map_err(Error::Sqlx)
 	}
 
+	/// Get the messages of the channel `channel_id` with the given `ids` in a
+	/// single query. The messages are returned in the order of `ids`; IDs of
+	/// messages which do not exist in the channel are skipped, and duplicate
+	/// IDs yield their message only once.
+	pub async fn get_by_ids(
+		db: &PgPool,
+		channel_id: Snowflake,
+		ids: &[Snowflake],
+	) -> Result<Vec<Self>, Error> {
+		if ids.is_empty() {
+			return Ok(Vec::new());
+		}
+
+		let mut query_builder = QueryBuilder::new("SELECT * FROM messages WHERE channel_id = ");
+		query_builder.push_bind(channel_id);
+		query_builder.push(" AND id IN (");
+		let mut separated = query_builder.separated(", ");
+		for id in ids {
+			separated.push_bind(*id);
+		}
+		separated.push_unseparated(")");
+
+		let messages =
+			query_builder.build_query_as::<Self>().fetch_all(db).await.map_err(Error::Sqlx)?;
+		Ok(Self::in_order_of(ids, messages))
+	}
+
+	/// Sort `messages` into the order of `ids`, dropping those whose ID is not
+	/// in `ids` and keeping only the first occurrence of duplicate IDs.
+	fn in_order_of(ids: &[Snowflake], messages: Vec<Self>) -> Vec<Self> {
+		let mut messages: HashMap<Snowflake, Self> =
+			messages.into_iter().map(|message| (message.id, message)).collect();
+		ids.iter().filter_map(|id| messages.remove(id)).collect()
+	}
+
 	pub async fn get_by_channel_id(
 		db: &PgPool,
 		channel_id: Snowflake,
@@ -458,3 +557,99 @@ impl Message {
 		Ok(res.into_iter().flat_map(|r| Message::from_row(&r)).collect::<Vec<_>>())
 	}
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+	use crate::gateway::testing::unreachable_db;
+
+	fn message(id: u64) -> Message {
+		Message {
+			inner: chorus::types::Message { id: Snowflake::from(id), ..Default::default() },
+			author_id: Snowflake::from(1u64),
+			guild_id: None,
+			message_reference_id: None,
+		}
+	}
+
+	fn ids(messages: &[Message]) -> Vec<Snowflake> {
+		messages.iter().map(|message| message.id).collect()
+	}
+
+	#[test]
+	fn fetched_messages_keep_the_order_of_the_ids() {
+		let requested = [3u64, 1, 4, 1, 5].map(Snowflake::from);
+		// The database returns rows in no particular order, and has no message 5.
+		let fetched = vec![message(1), message(4), message(3)];
+
+		let messages = Message::in_order_of(&requested, fetched);
+		assert_eq!(ids(&messages), [3u64, 1, 4].map(Snowflake::from));
+	}
+
+	#[test]
+	fn create_event_carries_the_message() {
+		let mut message = message(7);
+		message.guild_id = Some(Snowflake::from(100u64));
+		message.channel_id = Snowflake::from(20u64);
+		message.content = Some("Hello".to_string());
+
+		let Event::Dispatch(DispatchEvent::MessageCreate(payload)) = message.create_event() else {
+			panic!("expected a MESSAGE_CREATE event");
+		};
+		assert_eq!(payload.event_name.as_deref(), Some("MESSAGE_CREATE"));
+		let data = payload.event_data.unwrap();
+		assert_eq!(data.guild_id, message.guild_id);
+		assert_eq!(&data.message, message.deref());
+	}
+
+	#[test]
+	fn user_mentions_are_found_in_the_content() {
+		let mut message = message(7);
+		message.content = Some("<@2> and <@!3>, not <@&4> or <@5, but <@2> again".to_string());
+		assert_eq!(message.mentioned_user_ids(), [2u64, 3].map(Snowflake::from));
+
+		let Event::Dispatch(DispatchEvent::MessageCreate(payload)) =
+			message.mention_notification_event()
+		else {
+			panic!("expected a MESSAGE_CREATE event");
+		};
+		let data = payload.event_data.unwrap();
+		assert_eq!(data.message.id, message.id);
+		assert_eq!(data.message.content, None);
+	}
+
+	#[tokio::test]
+	async fn no_ids_fetch_no_messages() {
+		// Never connected to, as no query is made.
+		let db = unreachable_db();
+		let messages = Message::get_by_ids(&db, Snowflake::from(1u64), &[]).await.unwrap();
+		assert!(messages.is_empty());
+	}
+
+	#[sqlx::test(fixtures(path = "../../fixtures", scripts("users", "guilds")))]
+	async fn messages_are_fetched_by_ids_in_their_order(db: PgPool) {
+		let guild_id = Snowflake::from(7249086638293258240u64);
+		let channel_id = Snowflake::from(7249086862017433600u64);
+		for id in [1u64, 2, 3] {
+			sqlx::query(
+				"INSERT INTO messages (id, channel_id, guild_id, author_id, content, embeds, reactions, type) VALUES ($1, $2, $3, $4, 'Hello', '[]', '[]', 0)",
+			)
+			.bind(Snowflake::from(id))
+			.bind(channel_id)
+			.bind(guild_id)
+			.bind(Snowflake::from(7248639845155737600u64))
+			.execute(&db)
+			.await
+			.unwrap();
+		}
+
+		let requested = [3u64, 99, 1, 3].map(Snowflake::from);
+		let messages = Message::get_by_ids(&db, channel_id, &requested).await.unwrap();
+		assert_eq!(ids(&messages), [3u64, 1].map(Snowflake::from));
+
+		// Messages of other channels are not found by their ID alone.
+		let elsewhere = Message::get_by_ids(&db, Snowflake::from(5u64), &requested).await.unwrap();
+		assert!(elsewhere.is_empty());
+	}
+}
@@ -2,6 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+pub use application::*;
 pub use audit_log::*;
 pub use channel::*;
 pub use config::*;
@@ -18,6 +19,7 @@ pub use relationship::*;
 pub use role::*;
 pub use sticker::*;
 pub use user::*;
+pub use user_cache::UserCache;
 pub use user_settings::*;
 pub use voice_state::*;
 pub use webhook::*;
@@ -43,6 +45,7 @@ mod role;
 mod sticker;
 mod template;
 mod user;
+mod user_cache;
 mod user_settings;
 mod voice_state;
 mod webhook;
@@ -78,6 +78,21 @@ impl Recipient {
 			.map_err(Error::from)
 	}
 
+	/// The Snowflake IDs of the users sharing a private channel with the user
+	/// with the ID `user_id`.
+	pub async fn get_contact_ids(
+		db: &sqlx::PgPool,
+		user_id: Snowflake,
+	) -> Result<Vec<Snowflake>, Error> {
+		sqlx::query_scalar(
+			"SELECT DISTINCT other.user_id FROM recipients own JOIN recipients other ON other.channel_id = own.channel_id WHERE own.user_id = $1 AND other.user_id <> $1",
+		)
+		.bind(user_id)
+		.fetch_all(db)
+		.await
+		.map_err(Error::from)
+	}
+
 	pub async fn get_by_channel_and_user_id(
 		db: &sqlx::PgPool,
 		channel_id: Snowflake,
@@ -98,7 +98,7 @@ impl Role {
 	}
 
 	pub async fn get_by_id(db: &PgPool, id: Snowflake) -> Result<Option<Self>, Error> {
-		sqlx::query_as("SELECT * FROM roles WHERE id = ?")
+		sqlx::query_as("SELECT * FROM roles WHERE id = $1")
 			.bind(id)
 			.fetch_optional(db)
 			.await
@@ -106,7 +106,7 @@ impl Role {
 	}
 
 	pub async fn get_by_guild(db: &PgPool, guild_id: Snowflake) -> Result<Vec<Self>, Error> {
-		sqlx::query_as("SELECT * FROM roles WHERE guild_id = ?")
+		sqlx::query_as("SELECT * FROM roles WHERE guild_id = $1")
 			.bind(guild_id)
 			.fetch_all(db)
 			.await
@@ -177,7 +177,7 @@ impl Role {
 	}
 
 	pub async fn delete(&self, db: &PgPool) -> Result<(), Error> {
-		sqlx::query("DELETE FROM roles WHERE id = ?")
+		sqlx::query("DELETE FROM roles WHERE id = $1")
 			.bind(self.id)
 			.execute(db)
 			.await
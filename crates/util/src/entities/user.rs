@@ -21,7 +21,7 @@ use sqlx_pg_uint::{PgU32, PgU64};
 
 use super::*;
 use crate::{
-	entities::{Config, Guild, GuildMember, UserSettings},
+	entities::{Config, Guild, GuildMember, UserCache, UserSettings},
 	errors::{Error, GuildError},
 };
 
@@ -130,12 +130,38 @@ impl User {
 		todo!()
 	}
 
+	/// Get the user with the given `id`. If the [UserCache] has been enabled,
+	/// recently fetched users are served from it instead of the database.
 	pub async fn get_by_id(db: &PgPool, id: Snowflake) -> Result<Option<Self>, Error> {
-		sqlx::query_as("SELECT * FROM users WHERE id = $1")
+		Self::get_by_id_through(UserCache::global(), db, id).await
+	}
+
+	async fn get_by_id_through(
+		cache: Option<&UserCache>,
+		db: &PgPool,
+		id: Snowflake,
+	) -> Result<Option<Self>, Error> {
+		if let Some(user) = cache.and_then(|cache| cache.get(id)) {
+			return Ok(Some(user));
+		}
+		let user: Option<Self> = sqlx::query_as("SELECT * FROM users WHERE id = $1")
 			.bind(id)
 			.fetch_optional(db)
 			.await
-			.map_err(Error::Sqlx)
+			.map_err(Error::Sqlx)?;
+		if let (Some(cache), Some(user)) = (cache, &user) {
+			cache.insert(user.clone());
+		}
+		Ok(user)
+	}
+
+	/// Drop the user with the given `id` from the [UserCache], so that the next
+	/// [User::get_by_id] reads it from the database. Has to be called after
+	/// changing a user.
+	pub fn invalidate_cached(id: Snowflake) {
+		if let Some(cache) = UserCache::global() {
+			cache.invalidate(id);
+		}
 	}
 
 	pub async fn get_by_id_list(
@@ -247,6 +273,42 @@ impl User {
 	}
 }
 
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::gateway::testing::unreachable_db;
+
+	fn cached_user(cache: &UserCache, id: Snowflake) {
+		let mut user = User::default();
+		user.id = id;
+		user.username = "cached".to_string();
+		cache.insert(user);
+	}
+
+	#[tokio::test]
+	async fn cached_user_is_served_without_the_database() {
+		let cache = UserCache::new(8, Duration::from_secs(60));
+		let id = Snowflake::from(1u64);
+		cached_user(&cache, id);
+
+		let user = User::get_by_id_through(Some(&cache), &unreachable_db(), id).await.unwrap();
+		assert_eq!(user.unwrap().username, "cached");
+	}
+
+	#[tokio::test]
+	async fn invalidated_user_is_read_from_the_database() {
+		let cache = UserCache::new(8, Duration::from_secs(60));
+		let id = Snowflake::from(1u64);
+		cached_user(&cache, id);
+		cache.invalidate(id);
+
+		assert!(User::get_by_id_through(Some(&cache), &unreachable_db(), id).await.is_err());
+	}
+}
+
 // TODO: move these back to symfonia
 // #[cfg(test)]
 // mod user_unit_tests {
@@ -0,0 +1,163 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{
+	collections::{BTreeMap, HashMap},
+	sync::OnceLock,
+	time::{Duration, Instant},
+};
+
+use chorus::types::Snowflake;
+use parking_lot::Mutex;
+
+use super::User;
+
+static USER_CACHE: OnceLock<UserCache> = OnceLock::new();
+
+/// A least recently used cache of [User]s, sitting in front of
+/// [User::get_by_id]. Entries older than the configured time to live are
+/// treated as absent, so that changes made by other nodes are picked up
+/// eventually.
+pub struct UserCache {
+	capacity: usize,
+	ttl: Duration,
+	entries: Mutex<UserCacheEntries>,
+}
+
+#[derive(Default)]
+struct UserCacheEntries {
+	/// Cached users, along with the tick they have last been used at and the
+	/// time they have been cached at.
+	users: HashMap<Snowflake, (u64, Instant, User)>,
+	/// The ids of the cached users, ordered by the tick they have last been
+	/// used at.
+	recency: BTreeMap<u64, Snowflake>,
+	tick: u64,
+}
+
+impl UserCacheEntries {
+	fn next_tick(&mut self) -> u64 {
+		self.tick += 1;
+		self.tick
+	}
+
+	fn remove(&mut self, id: Snowflake) -> Option<(u64, Instant, User)> {
+		let entry = self.users.remove(&id)?;
+		self.recency.remove(&entry.0);
+		Some(entry)
+	}
+}
+
+impl UserCache {
+	/// Create an empty cache holding up to `capacity` users for `ttl` each.
+	pub fn new(capacity: usize, ttl: Duration) -> Self {
+		Self { capacity, ttl, entries: Mutex::new(UserCacheEntries::default()) }
+	}
+
+	/// Enable the cache used by [User::get_by_id]. Until this is called, every
+	/// lookup queries the database. Calling this more than once has no effect.
+	pub fn init(capacity: usize, ttl: Duration) {
+		if USER_CACHE.set(Self::new(capacity, ttl)).is_err() {
+			log::warn!(target: "symfonia::db", "The user cache has already been initialized");
+		}
+	}
+
+	/// The cache used by [User::get_by_id], if it has been enabled.
+	pub fn global() -> Option<&'static UserCache> {
+		USER_CACHE.get()
+	}
+
+	/// The cached user with the given `id`, unless it is absent or expired.
+	pub fn get(&self, id: Snowflake) -> Option<User> {
+		let mut entries = self.entries.lock();
+		let (last_used, cached_at, user) = entries.remove(id)?;
+		if cached_at.elapsed() > self.ttl {
+			return None;
+		}
+		entries.recency.remove(&last_used);
+		let tick = entries.next_tick();
+		entries.recency.insert(tick, id);
+		entries.users.insert(id, (tick, cached_at, user.clone()));
+		Some(user)
+	}
+
+	/// Cache `user`, replacing any previous entry for it. If the cache is
+	/// full, the least recently used user is evicted.
+	pub fn insert(&self, user: User) {
+		if self.capacity == 0 {
+			return;
+		}
+		let mut entries = self.entries.lock();
+		entries.remove(user.id);
+		while entries.users.len() >= self.capacity {
+			let Some((_, evicted)) = entries.recency.pop_first() else {
+				break;
+			};
+			entries.users.remove(&evicted);
+		}
+		let tick = entries.next_tick();
+		entries.recency.insert(tick, user.id);
+		entries.users.insert(user.id, (tick, Instant::now(), user));
+	}
+
+	/// Drop the cached user with the given `id`. Has to be called whenever the
+	/// user is changed.
+	pub fn invalidate(&self, id: Snowflake) {
+		self.entries.lock().remove(id);
+	}
+
+	/// Number of users currently cached, including expired ones.
+	pub fn len(&self) -> usize {
+		self.entries.lock().users.len()
+	}
+
+	/// Whether no users are currently cached.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+
+	fn user(id: u64) -> User {
+		let mut user = User::default();
+		user.id = Snowflake::from(id);
+		user
+	}
+
+	#[test]
+	fn least_recently_used_user_is_evicted() {
+		let cache = UserCache::new(2, Duration::from_secs(60));
+		cache.insert(user(1));
+		cache.insert(user(2));
+		// Using the first user makes the second one the least recently used.
+		assert!(cache.get(Snowflake::from(1u64)).is_some());
+		cache.insert(user(3));
+
+		assert_eq!(cache.len(), 2);
+		assert!(cache.get(Snowflake::from(1u64)).is_some());
+		assert!(cache.get(Snowflake::from(2u64)).is_none());
+		assert!(cache.get(Snowflake::from(3u64)).is_some());
+	}
+
+	#[test]
+	fn expired_users_are_absent() {
+		let cache = UserCache::new(2, Duration::ZERO);
+		cache.insert(user(1));
+		std::thread::sleep(Duration::from_millis(1));
+
+		assert!(cache.get(Snowflake::from(1u64)).is_none());
+		assert!(cache.is_empty());
+	}
+
+	#[test]
+	fn zero_capacity_caches_nothing() {
+		let cache = UserCache::new(0, Duration::from_secs(60));
+		cache.insert(user(1));
+		assert!(cache.is_empty());
+	}
+}
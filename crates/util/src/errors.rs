@@ -6,6 +6,8 @@ use std::{error::Error as StdError, fmt::Display};
 use chorus::types::{APIError, AuthError, Rights};
 use tokio::sync::broadcast::error::SendError;
 
+use crate::gateway::{connection_state::ConnectionState, event::EventType};
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
 	#[error(transparent)]
@@ -26,6 +28,9 @@ pub enum Error {
 	#[error(transparent)]
 	Reaction(#[from] ReactionError),
 
+	#[error(transparent)]
+	Application(#[from] ApplicationError),
+
 	#[error("SQLX error: {0}")]
 	Sqlx(#[from] sqlx::Error),
 
@@ -65,6 +70,9 @@ pub enum Error {
 	#[error("Password hashing error: {0}")]
 	PasswordHash(argon2::password_hash::Error),
 
+	#[error(transparent)]
+	Configuration(#[from] ConfigurationError),
+
 	#[error("{0}")]
 	Custom(String),
 }
@@ -83,15 +91,75 @@ pub enum GatewayError {
 	UnexpectedOpcode(u32),
 	#[error("TIMEOUT")]
 	Timeout,
+	/// The client identified while the gateway was already processing as
+	/// many identifies as it allows at once.
+	#[error("RATE_LIMITED")]
+	RateLimited,
+	/// The connection has been closed, so nothing can be sent through it
+	/// anymore.
 	#[error("CLOSED")]
 	Closed,
 	#[error("INTERNAL_SERVER_ERROR")]
 	Internal,
+	/// The kill signal of a connection could not be sent, because none of its
+	/// tasks are listening for it anymore.
+	#[error("KILL_SIGNAL_FAILED")]
+	KillSignalFailed,
+	/// An [Event](crate::gateway::event::Event) could not be broadcast to the
+	/// inbox of a user.
+	#[error("BROADCAST_FAILED: {0}")]
+	BroadcastFailed(String),
+	/// The [GatewayUser](crate::gateway::GatewayUser) a
+	/// [GatewayClient](crate::gateway::GatewayClient) belongs to has already
+	/// been dropped.
+	#[error("PARENT_DROPPED")]
+	ParentDropped,
+	#[error("INVALID_SHARD")]
+	InvalidShard,
+	#[error("SHARDING_REQUIRED")]
+	ShardingRequired,
+	/// A session cannot be resumed, for example because it does not exist,
+	/// because events it missed are no longer retained or because the client
+	/// claims to have received events which have never been dispatched.
+	#[error("SESSION_NOT_RESUMABLE")]
+	SessionNotResumable,
+	/// A connection cannot move from the first
+	/// [ConnectionState](crate::gateway::connection_state::ConnectionState) to
+	/// the second, for example because the client identified twice.
+	#[error("INVALID_STATE_TRANSITION: {0:?} -> {1:?}")]
+	InvalidStateTransition(ConnectionState, ConnectionState),
+	/// A dispatch event cannot be sent to a session which is not
+	/// [ConnectionState::Ready] yet.
+	#[error("NOT_READY")]
+	NotReady,
+	/// A bot requested privileged intents its application may not use. Holds
+	/// the offending intents.
+	#[error("DISALLOWED_INTENTS: {0}")]
+	DisallowedIntents(u64),
+	/// An event was about to be delivered through a
+	/// [Route](crate::gateway::routing::Route) its type does not take, for
+	/// example a `READY` to all sessions of a user.
+	#[error("MISROUTED: {0:?}")]
+	Misrouted(EventType),
+}
+
+impl From<SendError<crate::gateway::kill_reason::KillReason>> for GatewayError {
+	fn from(_: SendError<crate::gateway::kill_reason::KillReason>) -> Self {
+		Self::KillSignalFailed
+	}
+}
+
+impl From<SendError<crate::gateway::event::Event>> for GatewayError {
+	fn from(value: SendError<crate::gateway::event::Event>) -> Self {
+		Self::BroadcastFailed(value.to_string())
+	}
 }
 
 impl From<SendError<tokio_tungstenite::tungstenite::Message>> for GatewayError {
 	fn from(value: SendError<tokio_tungstenite::tungstenite::Message>) -> Self {
-		Self::Internal
+		// Sending only fails if the task forwarding messages to the WebSocket has
+		// stopped.
+		Self::Closed
 	}
 }
 
@@ -101,6 +169,14 @@ impl From<SendError<tokio_tungstenite::tungstenite::Message>> for Error {
 	}
 }
 
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+/// A value of the configuration file which cannot be used.
+pub enum ConfigurationError {
+	/// `field` has been set to `value`, which does not meet `requirement`.
+	#[error("Invalid configuration: {field} is {value}, but must be {requirement}")]
+	Invalid { field: &'static str, value: String, requirement: String },
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum UserError {
 	#[error("EMAIL_INVALID")]
@@ -167,12 +243,18 @@ pub enum ChannelError {
 	InvalidMessage,
 	#[error("You cannot delete more than {0} messages")]
 	TooManyMessages(u32),
+	#[error("A message cannot have more than {0} embeds")]
+	TooManyEmbeds(usize),
+	#[error("A message cannot have more than {0} attachments")]
+	TooManyAttachments(usize),
 	#[error("Maxmimum pins reached")]
 	MaxPinsReached,
 	#[error("Maxmimum webhooks reached")]
 	MaxWebhooksReached,
 	#[error("User is already a recipient of this channel")]
 	InvalidRecipient,
+	#[error("Missing Access")]
+	MissingAccess,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -197,6 +279,26 @@ pub enum ReactionError {
 	NotFound,
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum ApplicationError {
+	#[error("INVALID_ICON")]
+	InvalidIcon,
+	#[error("ICON_TOO_LARGE({0})")]
+	IconTooLarge(usize),
+	#[error("MAX_COMMANDS_REACHED({0})")]
+	MaxCommandsReached(usize),
+	#[error("UNKNOWN_COMMAND")]
+	UnknownCommand,
+	#[error("DUPLICATE_COMMAND_NAME")]
+	DuplicateCommandName,
+	#[error("NO_BOT_USER")]
+	NoBotUser,
+	#[error("INVALID_NAME_LENGTH({0}, {1})")]
+	InvalidNameLength(usize, usize),
+	#[error("SUMMARY_TOO_LONG({0})")]
+	SummaryTooLong(usize),
+}
+
 #[cfg(feature = "poem")]
 mod poem {
 	use ::poem::{IntoResponse, Response, error::ResponseError, http::StatusCode, web::Json};
@@ -238,9 +340,12 @@ mod poem {
 					ChannelError::EmptyMessage => StatusCode::BAD_REQUEST,
 					ChannelError::InvalidMessage => StatusCode::NOT_FOUND,
 					ChannelError::TooManyMessages(_) => StatusCode::BAD_REQUEST,
+					ChannelError::TooManyEmbeds(_) => StatusCode::BAD_REQUEST,
+					ChannelError::TooManyAttachments(_) => StatusCode::BAD_REQUEST,
 					ChannelError::MaxPinsReached => StatusCode::BAD_REQUEST,
 					ChannelError::MaxWebhooksReached => StatusCode::BAD_REQUEST,
 					ChannelError::InvalidRecipient => StatusCode::NOT_FOUND,
+					ChannelError::MissingAccess => StatusCode::FORBIDDEN,
 				},
 				Error::Invite(err) => match err {
 					InviteError::InvalidInvite => StatusCode::NOT_FOUND,
@@ -253,6 +358,16 @@ mod poem {
 					ReactionError::AlreadyExists => StatusCode::BAD_REQUEST,
 					ReactionError::NotFound => StatusCode::NOT_FOUND,
 				},
+				Error::Application(err) => match err {
+					ApplicationError::InvalidIcon => StatusCode::BAD_REQUEST,
+					ApplicationError::IconTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+					ApplicationError::MaxCommandsReached(_) => StatusCode::BAD_REQUEST,
+					ApplicationError::UnknownCommand => StatusCode::NOT_FOUND,
+					ApplicationError::DuplicateCommandName => StatusCode::BAD_REQUEST,
+					ApplicationError::NoBotUser => StatusCode::BAD_REQUEST,
+					ApplicationError::InvalidNameLength(_, _) => StatusCode::BAD_REQUEST,
+					ApplicationError::SummaryTooLong(_) => StatusCode::BAD_REQUEST,
+				},
 				Error::Sqlx(_) => StatusCode::INTERNAL_SERVER_ERROR,
 				Error::SQLXMigration(_) => StatusCode::INTERNAL_SERVER_ERROR,
 				Error::Serde(_) => StatusCode::INTERNAL_SERVER_ERROR,
@@ -272,8 +387,19 @@ mod poem {
 					GatewayError::UnexpectedMessage(_) => StatusCode::BAD_REQUEST,
 					GatewayError::UnexpectedOpcode(_) => StatusCode::BAD_REQUEST,
 					GatewayError::Timeout => StatusCode::BAD_REQUEST,
+					GatewayError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
 					GatewayError::Closed => StatusCode::BAD_REQUEST,
 					GatewayError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+					GatewayError::KillSignalFailed => StatusCode::INTERNAL_SERVER_ERROR,
+					GatewayError::BroadcastFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+					GatewayError::ParentDropped => StatusCode::INTERNAL_SERVER_ERROR,
+					GatewayError::InvalidShard => StatusCode::BAD_REQUEST,
+					GatewayError::ShardingRequired => StatusCode::BAD_REQUEST,
+					GatewayError::SessionNotResumable => StatusCode::BAD_REQUEST,
+					GatewayError::InvalidStateTransition(..) => StatusCode::BAD_REQUEST,
+					GatewayError::NotReady => StatusCode::INTERNAL_SERVER_ERROR,
+					GatewayError::DisallowedIntents(_) => StatusCode::BAD_REQUEST,
+					GatewayError::Misrouted(_) => StatusCode::INTERNAL_SERVER_ERROR,
 				},
 				Error::SqlxPgUint(_) => StatusCode::BAD_REQUEST,
 				Error::Custom(_) => StatusCode::BAD_REQUEST,
@@ -281,6 +407,7 @@ mod poem {
 					"This should never trigger, as toml is only used before the api is started"
 				),
 				Error::PasswordHash(_) => StatusCode::INTERNAL_SERVER_ERROR,
+				Error::Configuration(_) => StatusCode::INTERNAL_SERVER_ERROR,
 			}
 		}
 
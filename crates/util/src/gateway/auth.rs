@@ -0,0 +1,140 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Authentication of the tokens clients identify and resume with. The
+//! authenticator is a trait, so that deployments integrating external
+//! authentication, such as OAuth or SSO, can plug in their own without
+//! forking.
+
+use std::sync::Arc;
+
+use chorus::types::Snowflake;
+use futures::future::BoxFuture;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use sqlx::PgPool;
+
+use crate::{
+	configuration::GatewayAuthentication,
+	errors::{Error, GatewayError, UserError},
+	util::token::check_token,
+};
+
+#[derive(Debug, thiserror::Error)]
+/// Why a [GatewayAuthenticator] did not accept a token.
+pub enum AuthError {
+	/// The token is malformed, has expired or has been revoked.
+	#[error("INVALID_TOKEN")]
+	InvalidToken,
+	/// The authentication backend failed, so it is unknown whether the token
+	/// is valid.
+	#[error("Authentication backend failed: {0}")]
+	Backend(String),
+}
+
+impl From<AuthError> for Error {
+	fn from(value: AuthError) -> Self {
+		match value {
+			AuthError::InvalidToken => UserError::InvalidToken.into(),
+			AuthError::Backend(_) => GatewayError::Internal.into(),
+		}
+	}
+}
+
+/// Checks the tokens clients identify and resume with.
+pub trait GatewayAuthenticator: Send + Sync {
+	/// The Snowflake ID of the user `token` belongs to. Tokens of bots are
+	/// passed without their `Bot ` prefix.
+	fn authenticate<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Result<Snowflake, AuthError>>;
+}
+
+/// Create the [GatewayAuthenticator] selected by `config`.
+pub fn from_config(
+	config: &GatewayAuthentication,
+	db: PgPool,
+	jwt_secret: String,
+) -> Arc<dyn GatewayAuthenticator> {
+	match config {
+		GatewayAuthentication::Jwt => Arc::new(JwtAuthenticator::new(db, jwt_secret)),
+		GatewayAuthentication::Http { url } => Arc::new(HttpAuthenticator::new(url.clone())),
+	}
+}
+
+/// Accepts the JWTs issued by this instance, as long as they have not been
+/// revoked by their user. Used unless another [GatewayAuthenticator] is
+/// configured.
+pub struct JwtAuthenticator {
+	db: PgPool,
+	jwt_secret: String,
+}
+
+impl JwtAuthenticator {
+	/// Create a [JwtAuthenticator] checking tokens signed with `jwt_secret`
+	/// against the users in `db`.
+	pub fn new(db: PgPool, jwt_secret: String) -> Self {
+		Self { db, jwt_secret }
+	}
+}
+
+impl GatewayAuthenticator for JwtAuthenticator {
+	fn authenticate<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Result<Snowflake, AuthError>> {
+		Box::pin(async move {
+			match check_token(&self.db, token, &self.jwt_secret).await {
+				Ok(claims) => Ok(claims.id),
+				Err(Error::User(_)) => Err(AuthError::InvalidToken),
+				Err(e) => Err(AuthError::Backend(e.to_string())),
+			}
+		})
+	}
+}
+
+/// Asks an external service whether a token is valid. The token is posted to
+/// the service as `{"token": "..."}`. The service answers valid tokens with
+/// status 200 and `{"id": "<user ID>"}`, and invalid ones with status 401 or
+/// 403.
+pub struct HttpAuthenticator {
+	client: reqwest::Client,
+	url: String,
+}
+
+/// Answer of the service behind an [HttpAuthenticator] to a valid token.
+#[derive(Deserialize)]
+struct Authenticated {
+	id: Snowflake,
+}
+
+impl HttpAuthenticator {
+	/// Create an [HttpAuthenticator] asking the service at `url`.
+	pub fn new(url: String) -> Self {
+		Self { client: reqwest::Client::new(), url }
+	}
+}
+
+impl GatewayAuthenticator for HttpAuthenticator {
+	fn authenticate<'a>(&'a self, token: &'a str) -> BoxFuture<'a, Result<Snowflake, AuthError>> {
+		Box::pin(async move {
+			let response = self
+				.client
+				.post(&self.url)
+				.header(reqwest::header::CONTENT_TYPE, "application/json")
+				.body(serde_json::json!({ "token": token }).to_string())
+				.send()
+				.await
+				.map_err(|e| AuthError::Backend(e.to_string()))?;
+			match response.status() {
+				StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+					return Err(AuthError::InvalidToken);
+				}
+				status if !status.is_success() => {
+					return Err(AuthError::Backend(format!("Unexpected status {status}")));
+				}
+				_ => (),
+			}
+			let body = response.text().await.map_err(|e| AuthError::Backend(e.to_string()))?;
+			let authenticated: Authenticated =
+				serde_json::from_str(&body).map_err(|e| AuthError::Backend(e.to_string()))?;
+			Ok(authenticated.id)
+		})
+	}
+}
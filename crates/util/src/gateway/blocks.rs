@@ -0,0 +1,96 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::{HashMap, HashSet};
+
+use chorus::types::{RelationshipType, Snowflake};
+use sqlx::PgPool;
+
+use crate::{entities::Relationship, errors::Error};
+
+#[derive(Debug, Default)]
+/// The users each connected user has blocked. Messages, typing indicators and
+/// presences of blocked users are not delivered to the users blocking them,
+/// see [Event::author_id](super::event::Event::author_id).
+pub struct BlockList {
+	/// Map of the Snowflake ID of a user to the Snowflake IDs of the users they
+	/// have blocked.
+	blocked: HashMap<Snowflake, HashSet<Snowflake>>,
+}
+
+impl BlockList {
+	/// Record that the user with the ID `blocker` has blocked the user with the
+	/// ID `blocked`.
+	pub fn block(&mut self, blocker: Snowflake, blocked: Snowflake) {
+		self.blocked.entry(blocker).or_default().insert(blocked);
+	}
+
+	/// Record that the user with the ID `blocker` no longer blocks the user
+	/// with the ID `blocked`.
+	pub fn unblock(&mut self, blocker: Snowflake, blocked: Snowflake) {
+		if let Some(users) = self.blocked.get_mut(&blocker) {
+			users.remove(&blocked);
+			if users.is_empty() {
+				self.blocked.remove(&blocker);
+			}
+		}
+	}
+
+	/// Replace the users the user with the ID `blocker` has blocked with
+	/// `blocked`.
+	pub fn set_blocked(
+		&mut self,
+		blocker: Snowflake,
+		blocked: impl IntoIterator<Item = Snowflake>,
+	) {
+		let blocked: HashSet<Snowflake> = blocked.into_iter().collect();
+		if blocked.is_empty() {
+			self.blocked.remove(&blocker);
+		} else {
+			self.blocked.insert(blocker, blocked);
+		}
+	}
+
+	/// Drop the users the user with the ID `blocker` has blocked, for example
+	/// once they are no longer connected.
+	pub fn forget(&mut self, blocker: Snowflake) {
+		self.blocked.remove(&blocker);
+	}
+
+	/// Whether the user with the ID `blocker` has blocked the user with the ID
+	/// `user`.
+	pub fn has_blocked(&self, blocker: Snowflake, user: Snowflake) -> bool {
+		self.blocked.get(&blocker).is_some_and(|blocked| blocked.contains(&user))
+	}
+}
+
+/// The Snowflake IDs of the users the user with the ID `user_id` has blocked,
+/// as stored in the database.
+pub async fn blocked_users(db: &PgPool, user_id: Snowflake) -> Result<Vec<Snowflake>, Error> {
+	Ok(Relationship::get_by_from_id(user_id, db)
+		.await?
+		.into_iter()
+		.filter(|relationship| relationship.relationship_type == RelationshipType::Blocked)
+		.map(|relationship| relationship.id)
+		.collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn blocks_are_one_sided() {
+		let mut blocks = BlockList::default();
+		let blocker = Snowflake::from(1u64);
+		let blocked = Snowflake::from(2u64);
+		blocks.block(blocker, blocked);
+
+		assert!(blocks.has_blocked(blocker, blocked));
+		assert!(!blocks.has_blocked(blocked, blocker));
+
+		blocks.unblock(blocker, blocked);
+		assert!(!blocks.has_blocked(blocker, blocked));
+	}
+}
@@ -0,0 +1,106 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Encoding of gateway payloads into WebSocket messages. Each
+//! [WebSocketConnection](super::WebSocketConnection) encodes its payloads with
+//! a [PayloadCodec], so that formats other than JSON, such as ETF, can be
+//! supported per connection.
+
+use std::io::Write;
+
+use flate2::{Compression, write::ZlibEncoder};
+use serde_json::Value;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{configuration::CompressionAlgorithm, errors::GatewayError};
+
+/// Turns gateway payloads into the [Message]s sent to a client.
+///
+/// Payloads are handed to the codec as a [Value], which keeps the trait object
+/// safe, so that codecs can be chosen at runtime.
+pub trait PayloadCodec: Send + Sync {
+	/// Encode `payload` into a [Message].
+	fn encode(&self, payload: &Value) -> Result<Message, GatewayError>;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+/// Encodes payloads as JSON text messages. Used unless a connection is given
+/// another codec.
+pub struct JsonCodec;
+
+impl PayloadCodec for JsonCodec {
+	fn encode(&self, payload: &Value) -> Result<Message, GatewayError> {
+		Ok(Message::Text(payload.to_string().into()))
+	}
+}
+
+/// Size, in bytes, above which encoded payloads are compressed by
+/// [PayloadCompression]. Compressing smaller payloads costs more than it
+/// saves.
+pub const PAYLOAD_COMPRESSION_THRESHOLD: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Compression of individual payloads, requested by a client by identifying
+/// with `compress` set. Unlike transport compression, which is negotiated
+/// through the gateway URL and compresses the whole stream, each large payload
+/// is compressed on its own and sent as a binary message.
+pub struct PayloadCompression {
+	level: u32,
+}
+
+impl PayloadCompression {
+	/// Compress payloads with zlib at `level`, which is clamped to the levels
+	/// zlib supports.
+	pub fn new(level: i32) -> Self {
+		Self { level: CompressionAlgorithm::Zlib.clamp_level(level) as u32 }
+	}
+
+	/// Compress `message` if it is a text message larger than
+	/// [PAYLOAD_COMPRESSION_THRESHOLD]. Other messages are returned unchanged.
+	pub fn compress(&self, message: Message) -> Result<Message, GatewayError> {
+		let Message::Text(text) = &message else {
+			return Ok(message);
+		};
+		if text.len() <= PAYLOAD_COMPRESSION_THRESHOLD {
+			return Ok(message);
+		}
+		let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(self.level));
+		encoder.write_all(text.as_bytes()).map_err(|_| GatewayError::Internal)?;
+		let compressed = encoder.finish().map_err(|_| GatewayError::Internal)?;
+		Ok(Message::Binary(compressed.into()))
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::io::Read;
+
+	use flate2::read::ZlibDecoder;
+
+	use super::*;
+
+	#[test]
+	fn json_codec_encodes_text() {
+		let payload = serde_json::json!({ "op": 11 });
+		assert_eq!(JsonCodec.encode(&payload).unwrap(), Message::Text(r#"{"op":11}"#.into()));
+	}
+
+	#[test]
+	fn only_large_payloads_are_compressed() {
+		let compression = PayloadCompression::new(6);
+		let small = JsonCodec.encode(&serde_json::json!({ "op": 11 })).unwrap();
+		assert_eq!(compression.compress(small.clone()).unwrap(), small);
+
+		let large = serde_json::json!({ "op": 0, "d": "a".repeat(PAYLOAD_COMPRESSION_THRESHOLD) });
+		let Message::Binary(compressed) =
+			compression.compress(JsonCodec.encode(&large).unwrap()).unwrap()
+		else {
+			panic!("expected a binary message");
+		};
+		let mut decompressed = String::new();
+		ZlibDecoder::new(&compressed[..]).read_to_string(&mut decompressed).unwrap();
+		assert_eq!(serde_json::from_str::<Value>(&decompressed).unwrap(), large);
+	}
+}
@@ -0,0 +1,110 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::errors::GatewayError;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// The stages a gateway connection goes through. A connection only ever moves
+/// forward, as allowed by [ConnectionState::transition]:
+///
+/// ```text
+/// Connecting -> HelloSent -> Identified -> Ready
+///                         \-> Resuming  -/
+/// ```
+///
+/// Any state may move to [ConnectionState::Dead].
+pub enum ConnectionState {
+	/// The WebSocket handshake is in progress.
+	#[default]
+	Connecting,
+	/// The client has been sent the hello payload and has to identify or
+	/// resume next.
+	HelloSent,
+	/// The client has identified, but has not been sent `READY` yet.
+	Identified,
+	/// The client is resuming a session, and missed dispatches are being
+	/// replayed.
+	Resuming,
+	/// The session is established. Only now may it receive dispatches.
+	Ready,
+	/// The session has ended.
+	Dead,
+}
+
+impl ConnectionState {
+	/// Move from this state to `to`.
+	///
+	/// ## Errors
+	///
+	/// Returns [GatewayError::InvalidStateTransition] if a connection in this
+	/// state may not move to `to`, for example when a client identifies a
+	/// second time. The state is left unchanged in that case.
+	pub fn transition(&mut self, to: ConnectionState) -> Result<(), GatewayError> {
+		use ConnectionState::*;
+		match (*self, to) {
+			(Connecting, HelloSent)
+			| (HelloSent, Identified)
+			| (HelloSent, Resuming)
+			| (Identified, Ready)
+			| (Resuming, Ready)
+			| (_, Dead) => {
+				*self = to;
+				Ok(())
+			}
+			(from, to) => Err(GatewayError::InvalidStateTransition(from, to)),
+		}
+	}
+
+	/// Whether a connection in this state may be sent dispatch events.
+	pub fn accepts_dispatches(&self) -> bool {
+		*self == ConnectionState::Ready
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn identify_is_only_accepted_after_hello() {
+		let mut state = ConnectionState::HelloSent;
+		assert!(state.transition(ConnectionState::Identified).is_ok());
+		assert!(state.transition(ConnectionState::Ready).is_ok());
+
+		// A second identify on an established session is rejected.
+		assert!(matches!(
+			state.transition(ConnectionState::Identified),
+			Err(GatewayError::InvalidStateTransition(
+				ConnectionState::Ready,
+				ConnectionState::Identified
+			))
+		));
+		assert!(state.transition(ConnectionState::Resuming).is_err());
+		assert_eq!(state, ConnectionState::Ready);
+	}
+
+	#[test]
+	fn ready_requires_identify_or_resume() {
+		let mut state = ConnectionState::HelloSent;
+		assert!(state.transition(ConnectionState::Ready).is_err());
+		assert!(!state.accepts_dispatches());
+		assert!(state.transition(ConnectionState::Resuming).is_ok());
+		assert!(state.transition(ConnectionState::Ready).is_ok());
+		assert!(state.accepts_dispatches());
+	}
+
+	#[test]
+	fn any_state_may_die() {
+		for mut state in [
+			ConnectionState::Connecting,
+			ConnectionState::HelloSent,
+			ConnectionState::Identified,
+			ConnectionState::Resuming,
+			ConnectionState::Ready,
+		] {
+			assert!(state.transition(ConnectionState::Dead).is_ok());
+			assert!(state.transition(ConnectionState::HelloSent).is_err());
+		}
+	}
+}
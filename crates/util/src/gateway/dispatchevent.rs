@@ -2,7 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use super::*;
+use super::{event::EventType, *};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 /// This enum is supposed to represent all possible dispatch events that can be
@@ -18,6 +18,7 @@ pub enum DispatchEvent {
 	AuthenticatorUpdate(GatewayPayload<()>),
 	AuthenticatorDelete(GatewayPayload<()>),
 	ApplicationCommandPermissionsUpdate(GatewayPayload<()>),
+	ApplicationUpdate(GatewayPayload<chorus::types::Application>),
 	AutoModerationRuleCreate(GatewayPayload<()>),
 	AutoModerationRuleUpdate(GatewayPayload<()>),
 	AutoModerationRuleDelete(GatewayPayload<()>),
@@ -59,7 +60,7 @@ pub enum DispatchEvent {
 	GuildMemberUpdate(GatewayPayload<GuildMemberUpdate>),
 	GuildMembersChunk(GatewayPayload<GuildMembersChunk>),
 	GuildMembersRequest(GatewayPayload<GatewayRequestGuildMembers>),
-	GuildRoleCreate(GatewayPayload<()>),
+	GuildRoleCreate(GatewayPayload<GuildRoleCreate>),
 	GuildRoleUpdate(GatewayPayload<()>),
 	GuildRoleDelete(GatewayPayload<()>),
 	GuildScheduledEventCreate(GatewayPayload<()>),
@@ -105,13 +106,162 @@ pub enum DispatchEvent {
 	UserConnectionsUpdate(GatewayPayload<()>),
 	UserNoteUpdate(GatewayPayload<()>),
 	UserRequiredActionUpdate(GatewayPayload<()>),
-	UserSettingsUpdate(GatewayPayload<()>),
+	UserSettingsUpdate(GatewayPayload<UserSettings>),
 	VoiceStateUpdate(GatewayPayload<VoiceStateUpdate>),
 	VoiceServerUpdate(GatewayPayload<VoiceServerUpdate>),
 	VoiceChannelEffectSend(GatewayPayload<()>),
 	WebhooksUpdate(GatewayPayload<WebhooksUpdate>),
 }
 
+impl DispatchEvent {
+	/// The [EventType] of this event. Guild member requests are not dispatched
+	/// by the server, so they are of type [EventType::RequestGuildMembers].
+	pub fn event_type(&self) -> EventType {
+		let dispatch_event_type = match self {
+			DispatchEvent::GuildMembersRequest(_) => return EventType::RequestGuildMembers,
+			DispatchEvent::Ready(_) => DispatchEventType::Ready,
+			DispatchEvent::ReadySupplemental(_) => DispatchEventType::ReadySupplemental,
+			DispatchEvent::Resumed(_) => DispatchEventType::Resumed,
+			DispatchEvent::AuthSessionChange(_) => DispatchEventType::AuthSessionChange,
+			DispatchEvent::AuthenticatorCreate(_) => DispatchEventType::AuthenticatorCreate,
+			DispatchEvent::AuthenticatorUpdate(_) => DispatchEventType::AuthenticatorUpdate,
+			DispatchEvent::AuthenticatorDelete(_) => DispatchEventType::AuthenticatorDelete,
+			DispatchEvent::ApplicationCommandPermissionsUpdate(_) => {
+				DispatchEventType::ApplicationCommandPermissionsUpdate
+			}
+			DispatchEvent::ApplicationUpdate(_) => DispatchEventType::ApplicationUpdate,
+			DispatchEvent::AutoModerationRuleCreate(_) => {
+				DispatchEventType::AutoModerationRuleCreate
+			}
+			DispatchEvent::AutoModerationRuleUpdate(_) => {
+				DispatchEventType::AutoModerationRuleUpdate
+			}
+			DispatchEvent::AutoModerationRuleDelete(_) => {
+				DispatchEventType::AutoModerationRuleDelete
+			}
+			DispatchEvent::AutoModerationActionExecution(_) => {
+				DispatchEventType::AutoModerationActionExecution
+			}
+			DispatchEvent::AutoModerationMentionRaidDetection(_) => {
+				DispatchEventType::AutoModerationMentionRaidDetection
+			}
+			DispatchEvent::CallCreate(_) => DispatchEventType::CallCreate,
+			DispatchEvent::CallUpdate(_) => DispatchEventType::CallUpdate,
+			DispatchEvent::CallDelete(_) => DispatchEventType::CallDelete,
+			DispatchEvent::ChannelCreate(_) => DispatchEventType::ChannelCreate,
+			DispatchEvent::ChannelUpdate(_) => DispatchEventType::ChannelUpdate,
+			DispatchEvent::ChannelDelete(_) => DispatchEventType::ChannelDelete,
+			DispatchEvent::ChannelStatuses(_) => DispatchEventType::ChannelStatuses,
+			DispatchEvent::VoiceChannelStatusUpdate(_) => {
+				DispatchEventType::VoiceChannelStatusUpdate
+			}
+			DispatchEvent::ChannelPinsUpdate(_) => DispatchEventType::ChannelPinsUpdate,
+			DispatchEvent::ChannelRecipientAdd(_) => DispatchEventType::ChannelRecipientAdd,
+			DispatchEvent::ChannelRecipientRemove(_) => DispatchEventType::ChannelRecipientRemove,
+			DispatchEvent::DmSettingsUpsellShow(_) => DispatchEventType::DmSettingsUpsellShow,
+			DispatchEvent::ThreadCreate(_) => DispatchEventType::ThreadCreate,
+			DispatchEvent::ThreadUpdate(_) => DispatchEventType::ThreadUpdate,
+			DispatchEvent::ThreadDelete(_) => DispatchEventType::ThreadDelete,
+			DispatchEvent::ThreadListSync(_) => DispatchEventType::ThreadListSync,
+			DispatchEvent::ThreadMemberUpdate(_) => DispatchEventType::ThreadMemberUpdate,
+			DispatchEvent::ThreadMembersUpdate(_) => DispatchEventType::ThreadMembersUpdate,
+			DispatchEvent::FriendSuggestionCreate(_) => DispatchEventType::FriendSuggestionCreate,
+			DispatchEvent::FriendSuggestionDelete(_) => DispatchEventType::FriendSuggestionDelete,
+			DispatchEvent::GuildCreate(_) => DispatchEventType::GuildCreate,
+			DispatchEvent::GuildUpdate(_) => DispatchEventType::GuildUpdate,
+			DispatchEvent::GuildDelete(_) => DispatchEventType::GuildDelete,
+			DispatchEvent::GuildAuditLogEntryCreate(_) => {
+				DispatchEventType::GuildAuditLogEntryCreate
+			}
+			DispatchEvent::GuildBanAdd(_) => DispatchEventType::GuildBanAdd,
+			DispatchEvent::GuildBanRemove(_) => DispatchEventType::GuildBanRemove,
+			DispatchEvent::GuildEmojisUpdate(_) => DispatchEventType::GuildEmojisUpdate,
+			DispatchEvent::GuildStickersUpdate(_) => DispatchEventType::GuildStickersUpdate,
+			DispatchEvent::GuildJoinRequestCreate(_) => DispatchEventType::GuildJoinRequestCreate,
+			DispatchEvent::GuildJoinRequestUpdate(_) => DispatchEventType::GuildJoinRequestUpdate,
+			DispatchEvent::GuildJoinRequestDelete(_) => DispatchEventType::GuildJoinRequestDelete,
+			DispatchEvent::GuildMemberAdd(_) => DispatchEventType::GuildMemberAdd,
+			DispatchEvent::GuildMemberRemove(_) => DispatchEventType::GuildMemberRemove,
+			DispatchEvent::GuildMemberUpdate(_) => DispatchEventType::GuildMemberUpdate,
+			DispatchEvent::GuildMembersChunk(_) => DispatchEventType::GuildMembersChunk,
+			DispatchEvent::GuildRoleCreate(_) => DispatchEventType::GuildRoleCreate,
+			DispatchEvent::GuildRoleUpdate(_) => DispatchEventType::GuildRoleUpdate,
+			DispatchEvent::GuildRoleDelete(_) => DispatchEventType::GuildRoleDelete,
+			DispatchEvent::GuildScheduledEventCreate(_) => {
+				DispatchEventType::GuildScheduledEventCreate
+			}
+			DispatchEvent::GuildScheduledEventUpdate(_) => {
+				DispatchEventType::GuildScheduledEventUpdate
+			}
+			DispatchEvent::GuildScheduledEventDelete(_) => {
+				DispatchEventType::GuildScheduledEventDelete
+			}
+			DispatchEvent::GuildScheduledEventUserAdd(_) => {
+				DispatchEventType::GuildScheduledEventUserAdd
+			}
+			DispatchEvent::GuildScheduledEventUserRemove(_) => {
+				DispatchEventType::GuildScheduledEventUserRemove
+			}
+			DispatchEvent::GuildSoundboardSoundCreate(_) => {
+				DispatchEventType::GuildSoundboardSoundCreate
+			}
+			DispatchEvent::GuildSoundboardSoundUpdate(_) => {
+				DispatchEventType::GuildSoundboardSoundUpdate
+			}
+			DispatchEvent::GuildSoundboardSoundDelete(_) => {
+				DispatchEventType::GuildSoundboardSoundDelete
+			}
+			DispatchEvent::SoundboardSounds(_) => DispatchEventType::SoundboardSounds,
+			DispatchEvent::GuildIntegrationsUpdate(_) => DispatchEventType::GuildIntegrationsUpdate,
+			DispatchEvent::IntegrationCreate(_) => DispatchEventType::IntegrationCreate,
+			DispatchEvent::IntegrationUpdate(_) => DispatchEventType::IntegrationUpdate,
+			DispatchEvent::IntegrationDelete(_) => DispatchEventType::IntegrationDelete,
+			DispatchEvent::InteractionCreate(_) => DispatchEventType::InteractionCreate,
+			DispatchEvent::InviteCreate(_) => DispatchEventType::InviteCreate,
+			DispatchEvent::InviteDelete(_) => DispatchEventType::InviteDelete,
+			DispatchEvent::MessageCreate(_) => DispatchEventType::MessageCreate,
+			DispatchEvent::MessageUpdate(_) => DispatchEventType::MessageUpdate,
+			DispatchEvent::MessageDelete(_) => DispatchEventType::MessageDelete,
+			DispatchEvent::MessageDeleteBulk(_) => DispatchEventType::MessageDeleteBulk,
+			DispatchEvent::MessagePollVoteAdd(_) => DispatchEventType::MessagePollVoteAdd,
+			DispatchEvent::MessagePollVoteRemove(_) => DispatchEventType::MessagePollVoteRemove,
+			DispatchEvent::MessageReactionAdd(_) => DispatchEventType::MessageReactionAdd,
+			DispatchEvent::MessageReactionAddMany(_) => DispatchEventType::MessageReactionAddMany,
+			DispatchEvent::MessageReactionRemove(_) => DispatchEventType::MessageReactionRemove,
+			DispatchEvent::MessageReactionRemoveAll(_) => {
+				DispatchEventType::MessageReactionRemoveAll
+			}
+			DispatchEvent::MessageReactionRemoveEmoji(_) => {
+				DispatchEventType::MessageReactionRemoveEmoji
+			}
+			DispatchEvent::RecentMentionDelete(_) => DispatchEventType::RecentMentionDelete,
+			DispatchEvent::LastMessages(_) => DispatchEventType::LastMessages,
+			DispatchEvent::Oauth2TokenRevoke(_) => DispatchEventType::Oauth2TokenRevoke,
+			DispatchEvent::PresenceUpdate(_) => DispatchEventType::PresenceUpdate,
+			DispatchEvent::RelationshipAdd(_) => DispatchEventType::RelationshipAdd,
+			DispatchEvent::RelationshipUpdate(_) => DispatchEventType::RelationshipUpdate,
+			DispatchEvent::RelationshipRemove(_) => DispatchEventType::RelationshipRemove,
+			DispatchEvent::StageInstanceCreate(_) => DispatchEventType::StageInstanceCreate,
+			DispatchEvent::StageInstanceUpdate(_) => DispatchEventType::StageInstanceUpdate,
+			DispatchEvent::StageInstanceDelete(_) => DispatchEventType::StageInstanceDelete,
+			DispatchEvent::TypingStart(_) => DispatchEventType::TypingStart,
+			DispatchEvent::UserUpdate(_) => DispatchEventType::UserUpdate,
+			DispatchEvent::UserApplicationRemove(_) => DispatchEventType::UserApplicationRemove,
+			DispatchEvent::UserConnectionsUpdate(_) => DispatchEventType::UserConnectionsUpdate,
+			DispatchEvent::UserNoteUpdate(_) => DispatchEventType::UserNoteUpdate,
+			DispatchEvent::UserRequiredActionUpdate(_) => {
+				DispatchEventType::UserRequiredActionUpdate
+			}
+			DispatchEvent::UserSettingsUpdate(_) => DispatchEventType::UserSettingsUpdate,
+			DispatchEvent::VoiceStateUpdate(_) => DispatchEventType::VoiceStateUpdate,
+			DispatchEvent::VoiceServerUpdate(_) => DispatchEventType::VoiceServerUpdate,
+			DispatchEvent::VoiceChannelEffectSend(_) => DispatchEventType::VoiceChannelEffectSend,
+			DispatchEvent::WebhooksUpdate(_) => DispatchEventType::WebhooksUpdate,
+		};
+		EventType::Dispatch(dispatch_event_type)
+	}
+}
+
 impl From<DispatchEvent> for Event {
 	fn from(value: DispatchEvent) -> Self {
 		Self::Dispatch(value)
@@ -132,6 +282,7 @@ pub enum DispatchEventType {
 	AuthenticatorUpdate,
 	AuthenticatorDelete,
 	ApplicationCommandPermissionsUpdate,
+	ApplicationUpdate,
 	AutoModerationRuleCreate,
 	AutoModerationRuleUpdate,
 	AutoModerationRuleDelete,
@@ -334,6 +485,13 @@ mod dispatch_event_type_tests {
 		);
 	}
 
+	#[test]
+	fn test_application_update() {
+		let event = DispatchEventType::ApplicationUpdate;
+		assert_eq!(event.to_string(), "APPLICATION_UPDATE");
+		assert_eq!(DispatchEventType::try_from("APPLICATION_UPDATE".to_string()).unwrap(), event);
+	}
+
 	#[test]
 	fn test_auto_moderation_rule_create() {
 		let event = DispatchEventType::AutoModerationRuleCreate;
@@ -2,11 +2,12 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use chorus::types::{GatewayHello, Opcode};
+use chorus::types::{GatewayHello, GuildCreateDataOption, Opcode};
 use serde::{Deserialize, Serialize};
 
 use super::{
 	dispatchevent::{DispatchEvent, DispatchEventType},
+	presence_subscriptions::LazyRequest,
 	*,
 };
 
@@ -132,7 +133,7 @@ pub enum Event {
 	RequestGuildMembers(GatewayPayload<GatewayRequestGuildMembers>),
 	HeartbeatAck(GatewayPayload<GatewayHeartbeatAck>),
 	CallConnect(GatewayPayload<()>),
-	GuildSubscriptions(GatewayPayload<()>),
+	GuildSubscriptions(GatewayPayload<LazyRequest>),
 	LobbyConnect(GatewayPayload<()>),
 	LobbyDisconnect(GatewayPayload<()>),
 	LobbyVoiceStates(GatewayPayload<()>),
@@ -156,6 +157,143 @@ pub enum Event {
 }
 
 impl Event {
+	/// The Snowflake ID of the guild this event is scoped to, if it is a
+	/// dispatch event concerning a guild.
+	pub fn guild_id(&self) -> Option<Snowflake> {
+		let Event::Dispatch(dispatch_event) = self else {
+			return None;
+		};
+		match dispatch_event {
+			DispatchEvent::GuildCreate(payload) => match &payload.event_data.as_ref()?.d {
+				GuildCreateDataOption::Guild(guild) => Some(guild.id),
+				GuildCreateDataOption::UnavailableGuild(guild) => Some(guild.id),
+			},
+			DispatchEvent::GuildUpdate(payload) => Some(payload.event_data.as_ref()?.guild.id),
+			DispatchEvent::GuildDelete(payload) => Some(payload.event_data.as_ref()?.guild.id),
+			DispatchEvent::GuildBanAdd(payload) => Some(payload.event_data.as_ref()?.guild_id),
+			DispatchEvent::GuildBanRemove(payload) => Some(payload.event_data.as_ref()?.guild_id),
+			DispatchEvent::GuildEmojisUpdate(payload) => {
+				Some(payload.event_data.as_ref()?.guild_id)
+			}
+			DispatchEvent::GuildIntegrationsUpdate(payload) => {
+				Some(payload.event_data.as_ref()?.guild_id)
+			}
+			DispatchEvent::GuildMemberAdd(payload) => Some(payload.event_data.as_ref()?.guild_id),
+			DispatchEvent::GuildMemberRemove(payload) => {
+				Some(payload.event_data.as_ref()?.guild_id)
+			}
+			DispatchEvent::GuildMemberUpdate(payload) => {
+				Some(payload.event_data.as_ref()?.guild_id)
+			}
+			DispatchEvent::GuildMembersChunk(payload) => {
+				Some(payload.event_data.as_ref()?.guild_id)
+			}
+			DispatchEvent::GuildRoleCreate(payload) => Some(payload.event_data.as_ref()?.guild_id),
+			DispatchEvent::ChannelCreate(payload) => payload.event_data.as_ref()?.channel.guild_id,
+			DispatchEvent::ChannelUpdate(payload) => payload.event_data.as_ref()?.channel.guild_id,
+			DispatchEvent::ChannelDelete(payload) => payload.event_data.as_ref()?.channel.guild_id,
+			DispatchEvent::ThreadCreate(payload) => payload.event_data.as_ref()?.thread.guild_id,
+			DispatchEvent::ThreadUpdate(payload) => payload.event_data.as_ref()?.thread.guild_id,
+			DispatchEvent::ThreadDelete(payload) => payload.event_data.as_ref()?.thread.guild_id,
+			DispatchEvent::ThreadListSync(payload) => Some(payload.event_data.as_ref()?.guild_id),
+			DispatchEvent::ThreadMemberUpdate(payload) => {
+				Some(payload.event_data.as_ref()?.guild_id)
+			}
+			DispatchEvent::ThreadMembersUpdate(payload) => {
+				Some(payload.event_data.as_ref()?.guild_id)
+			}
+			DispatchEvent::InteractionCreate(payload) => {
+				Some(payload.event_data.as_ref()?.interaction.guild_id)
+			}
+			DispatchEvent::InviteCreate(payload) => payload.event_data.as_ref()?.invite.guild_id,
+			DispatchEvent::InviteDelete(payload) => payload.event_data.as_ref()?.guild_id,
+			DispatchEvent::MessageCreate(payload) => payload.event_data.as_ref()?.guild_id,
+			DispatchEvent::MessageUpdate(payload) => payload.event_data.as_ref()?.guild_id,
+			DispatchEvent::MessageDelete(payload) => payload.event_data.as_ref()?.guild_id,
+			DispatchEvent::MessageDeleteBulk(payload) => payload.event_data.as_ref()?.guild_id,
+			DispatchEvent::MessageReactionAdd(payload) => payload.event_data.as_ref()?.guild_id,
+			DispatchEvent::MessageReactionRemove(payload) => payload.event_data.as_ref()?.guild_id,
+			DispatchEvent::MessageReactionRemoveAll(payload) => {
+				payload.event_data.as_ref()?.guild_id
+			}
+			DispatchEvent::MessageReactionRemoveEmoji(payload) => {
+				payload.event_data.as_ref()?.guild_id
+			}
+			DispatchEvent::PresenceUpdate(payload) => payload.event_data.as_ref()?.guild_id,
+			DispatchEvent::StageInstanceCreate(payload) => {
+				Some(payload.event_data.as_ref()?.stage_instance.guild_id)
+			}
+			DispatchEvent::StageInstanceUpdate(payload) => {
+				Some(payload.event_data.as_ref()?.stage_instance.guild_id)
+			}
+			DispatchEvent::StageInstanceDelete(payload) => {
+				Some(payload.event_data.as_ref()?.stage_instance.guild_id)
+			}
+			DispatchEvent::TypingStart(payload) => payload.event_data.as_ref()?.guild_id,
+			DispatchEvent::VoiceStateUpdate(payload) => payload.event_data.as_ref()?.state.guild_id,
+			DispatchEvent::WebhooksUpdate(payload) => Some(payload.event_data.as_ref()?.guild_id),
+			// The remaining events are either not scoped to a guild or carry no data.
+			_ => None,
+		}
+	}
+
+	/// The Snowflake ID of the user who caused this event, if it is a message,
+	/// typing indicator or presence, which the users blocking its author should
+	/// not receive.
+	pub fn author_id(&self) -> Option<Snowflake> {
+		let Event::Dispatch(dispatch_event) = self else {
+			return None;
+		};
+		match dispatch_event {
+			DispatchEvent::MessageCreate(payload) => {
+				payload.event_data.as_ref()?.message.author.as_ref().map(|author| author.id)
+			}
+			DispatchEvent::TypingStart(payload) => Some(payload.event_data.as_ref()?.user_id),
+			DispatchEvent::PresenceUpdate(payload) => Some(payload.event_data.as_ref()?.user.id),
+			_ => None,
+		}
+	}
+
+	/// The [EventType] of this event.
+	pub fn event_type(&self) -> EventType {
+		match self {
+			Event::Hello(_) => EventType::Hello,
+			Event::Heartbeat(_) => EventType::Heartbeat,
+			Event::Dispatch(dispatch_event) => dispatch_event.event_type(),
+			Event::Identify(_) => EventType::Identify,
+			Event::Resume(_) => EventType::Resume,
+			Event::InvalidSession(_) => EventType::InvalidSession,
+			Event::PresenceUpdate(_) => EventType::PresenceUpdate,
+			Event::VoiceStateUpdate(_) => EventType::VoiceStateUpdate,
+			Event::VoiceServerPing(_) => EventType::VoiceServerPing,
+			Event::Reconnect(_) => EventType::Reconnect,
+			Event::RequestGuildMembers(_) => EventType::RequestGuildMembers,
+			Event::HeartbeatAck(_) => EventType::HeartbeatAck,
+			Event::CallConnect(_) => EventType::CallConnect,
+			Event::GuildSubscriptions(_) => EventType::GuildSubscriptions,
+			Event::LobbyConnect(_) => EventType::LobbyConnect,
+			Event::LobbyDisconnect(_) => EventType::LobbyDisconnect,
+			Event::LobbyVoiceStates(_) => EventType::LobbyVoiceStates,
+			Event::StreamCreate(_) => EventType::StreamCreate,
+			Event::StreamDelete(_) => EventType::StreamDelete,
+			Event::StreamWatch(_) => EventType::StreamWatch,
+			Event::StreamPing(_) => EventType::StreamPing,
+			Event::StreamSetPaused(_) => EventType::StreamSetPaused,
+			Event::EmbeddedActivityCreate(_) => EventType::EmbeddedActivityCreate,
+			Event::EmbeddedActivityUpdate(_) => EventType::EmbeddedActivityUpdate,
+			Event::EmbeddedActivityDelete(_) => EventType::EmbeddedActivityDelete,
+			Event::RequestForumUnreads(_) => EventType::RequestForumUnreads,
+			Event::RemoteCommand(_) => EventType::RemoteCommand,
+			Event::RequestDeletedEntityIDs(_) => EventType::RequestDeletedEntityIDs,
+			Event::RequestSoundboardSounds(_) => EventType::RequestSoundboardSounds,
+			Event::SpeedTestCreate(_) => EventType::SpeedTestCreate,
+			Event::SpeedTestDelete(_) => EventType::SpeedTestDelete,
+			Event::RequestLastMessages(_) => EventType::RequestLastMessages,
+			Event::SearchRecentMembers(_) => EventType::SearchRecentMembers,
+			Event::RequestChannelStatuses(_) => EventType::RequestChannelStatuses,
+		}
+	}
+
 	pub fn op_code(&self) -> Opcode {
 		match self {
 			Event::Hello(gateway_hello) => Opcode::Hello,
@@ -383,6 +521,10 @@ impl TryFrom<tokio_tungstenite::tungstenite::Message> for Event {
 				convert_to!(DispatchEvent::ApplicationCommandPermissionsUpdate, message_as_string)
 					.map(Event::Dispatch)
 			}
+			DispatchEventType::ApplicationUpdate => {
+				convert_to!(DispatchEvent::ApplicationUpdate, message_as_string)
+					.map(Event::Dispatch)
+			}
 			DispatchEventType::AutoModerationRuleCreate => {
 				convert_to!(DispatchEvent::AutoModerationRuleCreate, message_as_string)
 					.map(Event::Dispatch)
@@ -734,6 +876,64 @@ mod tests {
 		dbg!(event);
 	}
 
+	#[test]
+	fn guild_id_is_read_from_the_event_data() {
+		let guild_id = Snowflake::from(7u64);
+		let guild_create = Event::Dispatch(DispatchEvent::GuildCreate(GatewayPayload::dispatch(
+			"GUILD_CREATE",
+			GuildCreate {
+				d: GuildCreateDataOption::Guild(chorus::types::Guild {
+					id: guild_id,
+					..Default::default()
+				}),
+				..Default::default()
+			},
+		)));
+		assert_eq!(guild_create.guild_id(), Some(guild_id));
+
+		let message_create = |guild_id| {
+			Event::Dispatch(DispatchEvent::MessageCreate(GatewayPayload::dispatch(
+				"MESSAGE_CREATE",
+				MessageCreate { guild_id, ..Default::default() },
+			)))
+		};
+		assert_eq!(message_create(Some(guild_id)).guild_id(), Some(guild_id));
+		// Messages in DMs are not scoped to a guild.
+		assert_eq!(message_create(None).guild_id(), None);
+	}
+
+	#[test]
+	fn author_id_is_read_from_the_event_data() {
+		let author_id = Snowflake::from(3u64);
+		let typing_start = Event::Dispatch(DispatchEvent::TypingStart(GatewayPayload::dispatch(
+			"TYPING_START",
+			TypingStartEvent {
+				channel_id: Snowflake::from(2u64),
+				guild_id: None,
+				user_id: author_id,
+				timestamp: 1_700_000_000,
+				member: None,
+			},
+		)));
+		assert_eq!(typing_start.author_id(), Some(author_id));
+
+		let presence_update =
+			Event::Dispatch(DispatchEvent::PresenceUpdate(GatewayPayload::dispatch(
+				"PRESENCE_UPDATE",
+				PresenceUpdate {
+					user: PublicUser { id: author_id, ..Default::default() },
+					..Default::default()
+				},
+			)));
+		assert_eq!(presence_update.author_id(), Some(author_id));
+
+		// Messages without an author, such as system messages, have none.
+		let message_create = Event::Dispatch(DispatchEvent::MessageCreate(
+			GatewayPayload::dispatch("MESSAGE_CREATE", MessageCreate::default()),
+		));
+		assert_eq!(message_create.author_id(), None);
+	}
+
 	#[test]
 	fn heartbeat_from_raw_json() {
 		let json = r#"{"op":1}"#;
@@ -0,0 +1,131 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A user is often only interested in a few of the events a
+//! [Publisher](pubserve::Publisher) publishes, for example those of a single
+//! guild. Subscribing the user to the publisher with a [FilteredSubscriber]
+//! keeps the other events out of the inbox of the user, instead of sending them
+//! to all of its sessions only for them to be discarded there.
+
+use std::{collections::HashSet, fmt};
+
+use chorus::types::Snowflake;
+use pubserve::Subscriber;
+
+use super::{
+	GatewayUser,
+	event::{Event, EventType},
+};
+
+/// A [Subscriber] forwarding the events matching its filter to the inbox of a
+/// [GatewayUser], and dropping all others.
+pub struct FilteredSubscriber {
+	/// The sender of the inbox events are forwarded to.
+	outbox: tokio::sync::broadcast::Sender<Event>,
+	filter: Box<dyn Fn(&Event) -> bool + Send + Sync>,
+}
+
+impl fmt::Debug for FilteredSubscriber {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("FilteredSubscriber").finish_non_exhaustive()
+	}
+}
+
+impl FilteredSubscriber {
+	/// Create a subscriber forwarding the events for which `filter` returns
+	/// `true` to the inbox of `user`.
+	pub fn new(
+		user: &GatewayUser,
+		filter: impl Fn(&Event) -> bool + Send + Sync + 'static,
+	) -> Self {
+		Self { outbox: user.outbox.clone(), filter: Box::new(filter) }
+	}
+
+	/// Create a subscriber forwarding the events of the types `event_types` to
+	/// the inbox of `user`.
+	pub fn event_types(
+		user: &GatewayUser,
+		event_types: impl IntoIterator<Item = EventType>,
+	) -> Self {
+		let event_types: HashSet<EventType> = event_types.into_iter().collect();
+		Self::new(user, move |event| event_types.contains(&event.event_type()))
+	}
+
+	/// Create a subscriber forwarding the events scoped to the guild with the
+	/// ID `guild_id` to the inbox of `user`, see [Event::guild_id].
+	pub fn guild(user: &GatewayUser, guild_id: Snowflake) -> Self {
+		Self::new(user, move |event| event.guild_id() == Some(guild_id))
+	}
+
+	/// Whether `event` passes the filter of this subscriber.
+	pub fn matches(&self, event: &Event) -> bool {
+		(self.filter)(event)
+	}
+}
+
+#[async_trait::async_trait]
+impl Subscriber<Event> for FilteredSubscriber {
+	async fn update(&self, message: &Event) {
+		if !self.matches(message) {
+			return;
+		}
+		// The user has no sessions left to receive the event if sending fails,
+		// which is not a problem of the publisher.
+		if self.outbox.send(message.clone()).is_err() {
+			log::trace!(target: "symfonia::gateway::FilteredSubscriber::update", "Inbox has no receivers. Dropping event");
+		}
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::collections::HashMap;
+
+	use chorus::types::{MessageCreate, Opcode};
+
+	use super::*;
+	use crate::gateway::{
+		ConnectedUsers, GatewayPayload,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	};
+
+	fn message_create(guild_id: Snowflake) -> Event {
+		Event::Dispatch(DispatchEvent::MessageCreate(GatewayPayload::dispatch(
+			"MESSAGE_CREATE",
+			MessageCreate { guild_id: Some(guild_id), ..Default::default() },
+		)))
+	}
+
+	#[tokio::test]
+	async fn only_matching_events_reach_the_inbox() {
+		let connected_users = ConnectedUsers::default();
+		let user = connected_users.new_user(HashMap::new(), Snowflake::from(1u64), Vec::new());
+		let user = user.lock().await;
+		let mut inbox = user.inbox.resubscribe();
+		let (watched, other) = (Snowflake::from(10u64), Snowflake::from(20u64));
+		let by_guild = FilteredSubscriber::guild(&user, watched);
+		let by_type = FilteredSubscriber::event_types(
+			&user,
+			[EventType::Dispatch(DispatchEventType::MessageCreate)],
+		);
+		let reconnect = Event::Reconnect(GatewayPayload {
+			op_code: Opcode::Reconnect as u8,
+			event_data: None,
+			sequence_number: None,
+			event_name: None,
+		});
+
+		by_guild.update(&message_create(other)).await;
+		by_guild.update(&reconnect).await;
+		by_type.update(&reconnect).await;
+		assert!(inbox.try_recv().is_err());
+
+		by_guild.update(&message_create(watched)).await;
+		assert_eq!(inbox.try_recv().unwrap().guild_id(), Some(watched));
+		by_type.update(&message_create(other)).await;
+		assert_eq!(inbox.try_recv().unwrap().guild_id(), Some(other));
+		assert!(inbox.try_recv().is_err());
+	}
+}
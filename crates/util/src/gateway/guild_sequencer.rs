@@ -0,0 +1,191 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Delivering an event to the members of a guild takes a while, during which
+//! the task delivering it yields to others. Events of the same guild produced
+//! by different tasks could therefore reach some members in a different order
+//! than they have been produced in, for example a `GUILD_MEMBER_UPDATE`
+//! granting a role before the `GUILD_ROLE_CREATE` of that role. The
+//! [GuildSequencer] prevents this by delivering the events of each guild one
+//! after another.
+
+use std::{
+	sync::{Arc, Weak},
+	time::Duration,
+};
+
+use chorus::types::Snowflake;
+use tokio::sync::{mpsc, oneshot};
+
+use super::{BulkMessageBuilder, BulkSendReport, ConnectedUsers, sharded::ShardedMap};
+use crate::errors::Error;
+
+/// How long the worker of a guild waits for another event before it stops and
+/// removes the queue of the guild.
+pub const WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+type Queues = ShardedMap<mpsc::UnboundedSender<Delivery>>;
+
+/// An event waiting to be delivered by the worker of its guild.
+struct Delivery {
+	builder: BulkMessageBuilder,
+	connected_users: ConnectedUsers,
+	outcome: oneshot::Sender<Result<BulkSendReport, Error>>,
+}
+
+#[derive(Clone)]
+/// Delivers the events scoped to each guild in the order they have been
+/// queued in. Every guild which has recently been sent an event has a queue,
+/// which is drained by a worker task delivering one event at a time, so that
+/// every member receives the events of a guild in the same order. Workers
+/// which have been idle for [WORKER_IDLE_TIMEOUT] stop and remove their
+/// queue, so that only guilds with recent activity occupy a task.
+pub struct GuildSequencer {
+	queues: Arc<Queues>,
+	idle_timeout: Duration,
+}
+
+impl Default for GuildSequencer {
+	fn default() -> Self {
+		Self { queues: Arc::default(), idle_timeout: WORKER_IDLE_TIMEOUT }
+	}
+}
+
+impl GuildSequencer {
+	/// Queue the message of `builder` for delivery after all events of the
+	/// guild with the ID `guild_id` which have been queued before. The returned
+	/// receiver resolves to the outcome of the delivery, see
+	/// [BulkMessageBuilder::send_with_report].
+	///
+	/// The position of the message in the queue is decided when this method is
+	/// called, not when the receiver is awaited.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a read lock on the guild's shard of the queues
+	/// and, if the guild has no queue yet, a write lock on it afterwards. The
+	/// message is queued while the lock is held, so that the worker cannot
+	/// stop in between.
+	pub fn enqueue(
+		&self,
+		guild_id: Snowflake,
+		builder: BulkMessageBuilder,
+		connected_users: ConnectedUsers,
+	) -> oneshot::Receiver<Result<BulkSendReport, Error>> {
+		let (outcome, receiver) = oneshot::channel();
+		let delivery = Delivery { builder, connected_users, outcome };
+		let rejected = self.queues.with_or_insert_with(
+			guild_id,
+			|| self.spawn_worker(guild_id),
+			|queue| queue.send(delivery).err(),
+		);
+		if let Some(mpsc::error::SendError(delivery)) = rejected {
+			// Idle workers remove their queue before they stop, so the worker of the
+			// guild has panicked. Start over with a new one.
+			log::warn!(target: "symfonia::gateway::GuildSequencer::enqueue", "Worker of guild {guild_id} has stopped. Restarting it");
+			let queue = self.spawn_worker(guild_id);
+			self.queues.insert(guild_id, queue.clone());
+			// The worker has just been spawned, so it is still receiving.
+			let _ = queue.send(delivery);
+		}
+		receiver
+	}
+
+	/// Spawn a task delivering the events sent to the returned queue, which
+	/// belongs to the guild with the ID `guild_id`, one after another. The
+	/// task stops once the queue is dropped, or once it has been idle for
+	/// `idle_timeout` and has removed the queue from `queues`.
+	fn spawn_worker(&self, guild_id: Snowflake) -> mpsc::UnboundedSender<Delivery> {
+		let (queue, mut deliveries) = mpsc::unbounded_channel::<Delivery>();
+		let own_queue = queue.downgrade();
+		let queues = Arc::downgrade(&self.queues);
+		let idle_timeout = self.idle_timeout;
+		tokio::spawn(async move {
+			loop {
+				match tokio::time::timeout(idle_timeout, deliveries.recv()).await {
+					Ok(Some(delivery)) => {
+						let outcome = delivery.builder.deliver(delivery.connected_users).await;
+						// The producer may have stopped waiting for the outcome.
+						let _ = delivery.outcome.send(outcome);
+					}
+					Ok(None) => break,
+					Err(_) => {
+						if remove_idle_queue(&queues, guild_id, &own_queue, &deliveries) {
+							log::trace!(target: "symfonia::gateway::GuildSequencer::spawn_worker", "Worker of guild {guild_id} is idle. Stopping it");
+							break;
+						}
+					}
+				}
+			}
+		});
+		queue
+	}
+}
+
+/// Remove the queue of the guild with the ID `guild_id` from `queues` if it
+/// is still `own_queue` and nothing has been queued since `deliveries` has
+/// last been received from. Messages are queued while the lock of the shard
+/// is held, so nothing can be queued after the queue has been removed.
+///
+/// Returns whether the worker receiving from `deliveries` may stop.
+fn remove_idle_queue(
+	queues: &Weak<Queues>,
+	guild_id: Snowflake,
+	own_queue: &mpsc::WeakUnboundedSender<Delivery>,
+	deliveries: &mpsc::UnboundedReceiver<Delivery>,
+) -> bool {
+	let Some(queues) = queues.upgrade() else {
+		// The sequencer has been dropped along with all queues.
+		return true;
+	};
+	queues
+		.remove_if(guild_id, |queue| {
+			own_queue.upgrade().is_some_and(|own_queue| own_queue.same_channel(queue))
+				&& deliveries.is_empty()
+		})
+		.is_some()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+	use crate::gateway::{GatewayPayload, dispatchevent::DispatchEvent, event::Event};
+
+	async fn message(connected_users: &ConnectedUsers) -> BulkMessageBuilder {
+		let mut builder = connected_users.bulk_message_builder();
+		builder
+			.set_message(Event::Dispatch(DispatchEvent::GuildUpdate(GatewayPayload::dispatch(
+				"GUILD_UPDATE",
+				Default::default(),
+			))))
+			.await;
+		builder
+	}
+
+	#[tokio::test]
+	async fn idle_workers_remove_their_queue() {
+		let sequencer =
+			GuildSequencer { idle_timeout: Duration::from_millis(50), ..Default::default() };
+		let connected_users = ConnectedUsers::default();
+		let guild_id = Snowflake::from(1u64);
+
+		sequencer
+			.enqueue(guild_id, message(&connected_users).await, connected_users.clone())
+			.await
+			.unwrap()
+			.unwrap();
+		assert!(sequencer.queues.contains_key(guild_id));
+		while sequencer.queues.contains_key(guild_id) {
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+
+		// Events queued afterwards are delivered by a new worker.
+		sequencer
+			.enqueue(guild_id, message(&connected_users).await, connected_users.clone())
+			.await
+			.unwrap()
+			.unwrap();
+	}
+}
@@ -0,0 +1,110 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Gateway intents are a bitmask a session sends when identifying, describing
+//! which groups of events it wants to receive.
+
+use chorus::types::ApplicationFlags;
+
+use crate::errors::GatewayError;
+
+pub const GUILDS: u64 = 1 << 0;
+pub const GUILD_MEMBERS: u64 = 1 << 1;
+pub const GUILD_PRESENCES: u64 = 1 << 8;
+pub const GUILD_MESSAGES: u64 = 1 << 9;
+pub const DIRECT_MESSAGES: u64 = 1 << 12;
+pub const MESSAGE_CONTENT: u64 = 1 << 15;
+
+/// Intents which bots may only use if their application has been allowed to.
+pub const PRIVILEGED: u64 = GUILD_MEMBERS | GUILD_PRESENCES | MESSAGE_CONTENT;
+
+/// Every bit an intent may occupy.
+pub const ALL: u64 = (1 << 26) - 1;
+
+/// The privileged intents the application with the given `flags` has been
+/// allowed to use. Both the regular and the "limited" flags, which are given
+/// to unverified applications, allow an intent.
+pub fn privileged_intents_of(flags: ApplicationFlags) -> u64 {
+	let mut intents = 0;
+	if flags
+		.intersects(ApplicationFlags::GATEWAY_PRESENCE | ApplicationFlags::GATEWAY_PRESENCE_LIMITED)
+	{
+		intents |= GUILD_PRESENCES;
+	}
+	if flags.intersects(
+		ApplicationFlags::GATEWAY_GUILD_MEMBERS | ApplicationFlags::GATEWAY_GUILD_MEMBERS_LIMITED,
+	) {
+		intents |= GUILD_MEMBERS;
+	}
+	if flags.intersects(
+		ApplicationFlags::GATEWAY_MESSAGE_CONTENT
+			| ApplicationFlags::GATEWAY_MESSAGE_CONTENT_LIMITED,
+	) {
+		intents |= MESSAGE_CONTENT;
+	}
+	intents
+}
+
+/// The intents a bot session gets when requesting `requested`, with its
+/// application having the given `flags`. Privileged intents the application
+/// has not been allowed to use are left out.
+pub fn bot_intents(requested: u64, flags: ApplicationFlags) -> u64 {
+	requested & ALL & (!PRIVILEGED | privileged_intents_of(flags))
+}
+
+/// Check the intents a bot session explicitly requested when identifying, with
+/// its application having the given `flags`. Unlike [bot_intents], requesting
+/// a privileged intent the application has not been allowed to use is an error,
+/// so that the bot finds out instead of silently missing events.
+///
+/// ## Errors
+///
+/// Returns [GatewayError::DisallowedIntents] with the offending intents if
+/// `requested` contains privileged intents the application may not use.
+pub fn validate_bot_intents(requested: u64, flags: ApplicationFlags) -> Result<u64, GatewayError> {
+	let disallowed = requested & PRIVILEGED & !privileged_intents_of(flags);
+	if disallowed != 0 {
+		return Err(GatewayError::DisallowedIntents(disallowed));
+	}
+	Ok(requested & ALL)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn privileged_intents_need_application_flags() {
+		let requested = GUILDS | GUILD_MESSAGES | MESSAGE_CONTENT | GUILD_PRESENCES;
+
+		assert_eq!(bot_intents(requested, ApplicationFlags::empty()), GUILDS | GUILD_MESSAGES);
+		assert_eq!(
+			bot_intents(requested, ApplicationFlags::GATEWAY_MESSAGE_CONTENT_LIMITED),
+			GUILDS | GUILD_MESSAGES | MESSAGE_CONTENT
+		);
+	}
+
+	#[test]
+	fn privileged_request_without_application_flag_is_rejected() {
+		assert!(matches!(
+			validate_bot_intents(GUILDS | GUILD_MEMBERS, ApplicationFlags::empty()),
+			Err(GatewayError::DisallowedIntents(GUILD_MEMBERS))
+		));
+		assert_eq!(
+			validate_bot_intents(GUILDS | GUILD_MESSAGES, ApplicationFlags::empty()).unwrap(),
+			GUILDS | GUILD_MESSAGES
+		);
+		assert_eq!(
+			validate_bot_intents(GUILDS | GUILD_MEMBERS, ApplicationFlags::GATEWAY_GUILD_MEMBERS)
+				.unwrap(),
+			GUILDS | GUILD_MEMBERS
+		);
+	}
+
+	#[test]
+	fn unknown_bits_are_dropped() {
+		assert_eq!(bot_intents(u64::MAX, ApplicationFlags::all()), ALL);
+	}
+}
@@ -0,0 +1,167 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, frame::coding::CloseCode};
+
+use super::close_frame;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+/// Why the tasks of a [WebSocketConnection](super::WebSocketConnection) are
+/// being shut down. Sent through its kill switch, and mapped to the close frame
+/// the client is told about.
+pub enum KillReason {
+	/// The client has been asked to reconnect, for example because its
+	/// heartbeats are out of sync.
+	Reconnect,
+	/// The client could not be authenticated, or its credentials are no
+	/// longer valid.
+	AuthFailed,
+	/// The client has sent too many payloads, or identified too often. Closes
+	/// with 4009 rather than 4008, so that clients caught in a reconnect storm
+	/// start a new session after backing off.
+	RateLimited,
+	/// The client has not identified or heartbeated in time.
+	Timeout,
+	/// The server is shutting down.
+	ServerShutdown,
+	/// The client has sent a payload it should not have sent.
+	InvalidPayload,
+	/// The client has sent a payload with an opcode the gateway does not know.
+	UnknownOpcode,
+	/// The client has identified or resumed a session which is already
+	/// established.
+	AlreadyAuthenticated,
+	/// The client has identified with a shard which is not valid.
+	InvalidShard,
+	/// The client has to identify with a shard, because its user is in too
+	/// many guilds.
+	ShardingRequired,
+	/// A bot has requested privileged intents it may not use.
+	DisallowedIntents,
+	/// Something went wrong on the side of the server.
+	InternalError,
+	/// The client has closed the connection cleanly, with close code 1000 or
+	/// 1001, so there is nothing left to tell it. It does not intend to resume.
+	ClientClosed,
+	/// The client has closed the connection with any other close code, or
+	/// without one. The client may reconnect and resume.
+	ConnectionLost,
+}
+
+/// Why a session has been disconnected, as recorded in its
+/// [DisconnectInfo](super::DisconnectInfo).
+pub type DisconnectReason = KillReason;
+
+impl KillReason {
+	/// Whether a session ended for this reason may be resumed. Sessions whose
+	/// credentials or shards have been rejected have to identify again, and
+	/// sessions the client has closed cleanly are over.
+	pub fn is_resumable(self) -> bool {
+		!matches!(
+			self,
+			KillReason::AuthFailed
+				| KillReason::DisallowedIntents
+				| KillReason::InvalidShard
+				| KillReason::ShardingRequired
+				| KillReason::ClientClosed
+		)
+	}
+
+	/// Whether the client has ended the session, as opposed to the server.
+	pub fn is_client_initiated(self) -> bool {
+		matches!(self, KillReason::ClientClosed | KillReason::ConnectionLost)
+	}
+
+	/// The close code the client is sent, or [None] if it is not sent a close
+	/// frame at all.
+	pub fn close_code(self) -> Option<CloseCode> {
+		Some(match self {
+			KillReason::Reconnect | KillReason::InternalError => CloseCode::Library(4000),
+			KillReason::UnknownOpcode => CloseCode::Library(4001),
+			KillReason::InvalidPayload => CloseCode::Library(4002),
+			KillReason::AuthFailed => CloseCode::Library(4004),
+			KillReason::AlreadyAuthenticated => CloseCode::Library(4005),
+			KillReason::RateLimited | KillReason::Timeout => CloseCode::Library(4009),
+			KillReason::InvalidShard => CloseCode::Library(4010),
+			KillReason::ShardingRequired => CloseCode::Library(4011),
+			KillReason::DisallowedIntents => CloseCode::Library(4014),
+			KillReason::ServerShutdown => CloseCode::Away,
+			KillReason::ClientClosed | KillReason::ConnectionLost => return None,
+		})
+	}
+
+	/// The close frame the client is sent, or [None] if it is not sent one at
+	/// all.
+	pub fn close_frame(self) -> Option<CloseFrame> {
+		let reason = match self {
+			KillReason::Reconnect => "RECONNECT",
+			KillReason::AuthFailed => "AUTHENTICATION_FAILED",
+			KillReason::RateLimited => "RATE_LIMITED",
+			KillReason::Timeout => "SESSION_TIMED_OUT",
+			KillReason::ServerShutdown => "SERVER_SHUTDOWN",
+			KillReason::InvalidPayload => "DECODE_ERROR",
+			KillReason::UnknownOpcode => "UNKNOWN_OPCODE",
+			KillReason::AlreadyAuthenticated => "ALREADY_AUTHENTICATED",
+			KillReason::InvalidShard => "INVALID_SHARD",
+			KillReason::ShardingRequired => "SHARDING_REQUIRED",
+			KillReason::DisallowedIntents => "DISALLOWED_INTENTS",
+			KillReason::InternalError => "INTERNAL_SERVER_ERROR",
+			KillReason::ClientClosed | KillReason::ConnectionLost => return None,
+		};
+		Some(close_frame(self.close_code()?, reason))
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use tokio_tungstenite::tungstenite::Message;
+
+	use super::*;
+	use crate::gateway::WebSocketConnection;
+
+	#[tokio::test]
+	async fn timeout_and_auth_failure_close_differently() {
+		let mut codes = Vec::new();
+		for reason in [KillReason::Timeout, KillReason::AuthFailed] {
+			let (connection, mut client) = WebSocketConnection::from_channels();
+			let mut kill_receive = connection.kill_receive.resubscribe();
+			connection.kill(reason).unwrap();
+
+			assert_eq!(kill_receive.recv().await.unwrap(), reason);
+			match client.outgoing.recv().await.unwrap() {
+				Message::Close(Some(frame)) => codes.push(frame.code),
+				other => panic!("expected a close frame, got {other:?}"),
+			}
+		}
+		assert_eq!(codes, [CloseCode::Library(4009), CloseCode::Library(4004)]);
+	}
+
+	#[tokio::test]
+	async fn killed_connection_flushes_queued_messages_first() {
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		connection.sender.send(Message::from("last dispatch")).unwrap();
+
+		connection.kill(KillReason::Timeout).unwrap();
+
+		assert_eq!(client.outgoing.recv().await.unwrap(), Message::from("last dispatch"));
+		assert!(matches!(client.outgoing.recv().await.unwrap(), Message::Close(Some(_))));
+	}
+
+	#[test]
+	fn client_closed_sends_no_close_frame() {
+		assert!(KillReason::ClientClosed.close_frame().is_none());
+		assert!(KillReason::ConnectionLost.close_frame().is_none());
+		assert_eq!(KillReason::ServerShutdown.close_code(), Some(CloseCode::Away));
+	}
+
+	#[test]
+	fn only_abnormal_client_closes_are_resumable() {
+		assert!(!KillReason::ClientClosed.is_resumable());
+		assert!(KillReason::ConnectionLost.is_resumable());
+		assert!(KillReason::ConnectionLost.is_client_initiated());
+		assert!(!KillReason::ServerShutdown.is_client_initiated());
+	}
+}
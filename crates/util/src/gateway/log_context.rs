@@ -0,0 +1,160 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::fmt::Arguments;
+
+use chorus::types::Snowflake;
+use log::{
+	Level, Record,
+	kv::{self, Key, Source, Value, VisitSource},
+};
+
+use super::session_token::SessionToken;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Identifies the session a gateway log line belongs to. Attached to log
+/// records as the key-values `user_id` and `session` by
+/// [session_log](crate::session_log), so that all log lines of a single session
+/// can be found with one search. Keys of a connection which has not identified
+/// yet are left out.
+///
+/// The session token is masked with [SessionToken::masked], as logging the
+/// whole token would leak a credential.
+pub struct SessionLogContext {
+	user_id: Option<Snowflake>,
+	session: Option<String>,
+}
+
+impl SessionLogContext {
+	/// Create the context of the session with the given token, belonging to
+	/// the user with the given Snowflake ID.
+	pub fn new(user_id: Snowflake, session_token: &SessionToken) -> Self {
+		Self { user_id: Some(user_id), session: Some(session_token.masked()) }
+	}
+
+	/// The context of a connection which has not identified or resumed yet.
+	pub fn unidentified() -> Self {
+		Self::default()
+	}
+
+	/// Log `args` with this context attached to the record. Use
+	/// [session_log](crate::session_log) instead of calling this directly.
+	pub fn log(&self, level: Level, target: &str, args: Arguments<'_>) {
+		let record =
+			Record::builder().level(level).target(target).args(args).key_values(self).build();
+		#[cfg(test)]
+		tests::capture(&record);
+		if level > log::max_level() {
+			return;
+		}
+		let logger = log::logger();
+		if logger.enabled(record.metadata()) {
+			logger.log(&record);
+		}
+	}
+}
+
+impl Source for SessionLogContext {
+	fn visit<'kvs>(&'kvs self, visitor: &mut dyn VisitSource<'kvs>) -> Result<(), kv::Error> {
+		if let Some(user_id) = self.user_id {
+			visitor.visit_pair(Key::from("user_id"), Value::from(u64::from(user_id)))?;
+		}
+		if let Some(session) = &self.session {
+			visitor.visit_pair(Key::from("session"), Value::from(session.as_str()))?;
+		}
+		Ok(())
+	}
+}
+
+/// Log a message of a session, like [log::log], with its [SessionLogContext]
+/// attached to the record as key-values instead of being part of the message.
+#[macro_export]
+macro_rules! session_log {
+	(target: $target:expr, $level:expr, $context:expr, $($arg:tt)+) => {
+		$crate::gateway::log_context::SessionLogContext::log(
+			$context,
+			$level,
+			$target,
+			::core::format_args!($($arg)+),
+		)
+	};
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::{cell::RefCell, collections::HashMap};
+
+	use super::*;
+	use crate::gateway::{ConnectedUsers, kill_reason::KillReason, testing::test_session};
+
+	#[derive(Debug)]
+	/// A log line of a session, as seen by a logger.
+	struct CapturedLine {
+		message: String,
+		user_id: Option<String>,
+		session: Option<String>,
+	}
+
+	thread_local! {
+		/// Log lines of sessions on this thread, if a test captures them.
+		static CAPTURED: RefCell<Option<Vec<CapturedLine>>> = const { RefCell::new(None) };
+	}
+
+	/// Keep `record` if the current test captures log lines. Capturing per
+	/// thread lets tests running in parallel keep their lines apart, without
+	/// installing a global logger.
+	pub(super) fn capture(record: &Record) {
+		let get = |key| record.key_values().get(Key::from(key)).map(|value| value.to_string());
+		CAPTURED.with_borrow_mut(|lines| {
+			if let Some(lines) = lines {
+				lines.push(CapturedLine {
+					message: record.args().to_string(),
+					user_id: get("user_id"),
+					session: get("session"),
+				});
+			}
+		});
+	}
+
+	fn value(context: &SessionLogContext, key: &str) -> Option<String> {
+		context.get(Key::from(key)).map(|value| value.to_string())
+	}
+
+	#[test]
+	fn only_the_end_of_the_token_is_logged() {
+		let context =
+			SessionLogContext::new(Snowflake::from(7u64), &SessionToken::from("payload.sig1"));
+		assert_eq!(value(&context, "user_id").as_deref(), Some("7"));
+		assert_eq!(value(&context, "session").as_deref(), Some("********sig1"));
+		let short = SessionLogContext::new(Snowflake::from(7u64), &SessionToken::from("abc"));
+		assert_eq!(value(&short, "session").as_deref(), Some("abc"));
+		assert_eq!(SessionLogContext::unidentified().count(), 0);
+	}
+
+	#[tokio::test]
+	async fn session_lifecycle_is_logged_with_context() {
+		CAPTURED.set(Some(Vec::new()));
+
+		let connected_users = ConnectedUsers::default();
+		let user_id = Snowflake::from(42u64);
+		let user = connected_users.new_user(HashMap::new(), user_id, Vec::new());
+		let (client, _sent) =
+			test_session(&connected_users, &user, "header.claims.lifecycle-token").await;
+		client.lock().await.die(connected_users.clone(), KillReason::Timeout).await.unwrap();
+
+		let lines = CAPTURED.take().unwrap();
+		let session_lines: Vec<&str> = lines
+			.iter()
+			.filter(|line| {
+				line.user_id.as_deref() == Some("42")
+					&& line.session.as_deref() == Some("*************************oken")
+			})
+			.map(|line| line.message.as_str())
+			.collect();
+		// The context is not repeated in the messages themselves.
+		assert!(session_lines.contains(&"Session started"));
+		assert!(session_lines.contains(&"Session ended"));
+	}
+}
@@ -0,0 +1,196 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Counters describing the traffic of the gateway, for operators to find out
+//! which events dominate it, how much of it is retained for resuming, and why
+//! sessions end.
+
+use std::{
+	collections::{BTreeMap, HashMap},
+	sync::atomic::{AtomicU64, Ordering},
+};
+
+use parking_lot::RwLock;
+use serde::Serialize;
+
+use super::{
+	event::{Event, EventType},
+	kill_reason::KillReason,
+};
+
+#[derive(Debug, Default)]
+/// Counts the [Event]s delivered to the inboxes of connected users, by their
+/// [EventType]. An event sent to several users counts once per user.
+pub struct GatewayMetrics {
+	/// One counter per [EventType] which has been delivered at least once.
+	/// Counters are only ever added, so that the write lock is rarely taken.
+	dispatched: RwLock<HashMap<EventType, AtomicU64>>,
+	/// Number of [ResumeBuffers](super::resume::ResumeBuffer) reporting to
+	/// these metrics.
+	resume_buffers: AtomicU64,
+	/// Sum of the capacities of these buffers.
+	resume_buffer_capacity: AtomicU64,
+	/// One counter per [KillReason] sessions have ended for at least once.
+	closes: RwLock<HashMap<KillReason, AtomicU64>>,
+}
+
+impl GatewayMetrics {
+	/// Count `event` as delivered to one user.
+	pub fn record(&self, event: &Event) {
+		let event_type = event.event_type();
+		if let Some(counter) = self.dispatched.read().get(&event_type) {
+			counter.fetch_add(1, Ordering::Relaxed);
+			return;
+		}
+		self.dispatched.write().entry(event_type).or_default().fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Count a session as ended for `reason`.
+	pub fn record_close(&self, reason: KillReason) {
+		if let Some(counter) = self.closes.read().get(&reason) {
+			counter.fetch_add(1, Ordering::Relaxed);
+			return;
+		}
+		self.closes.write().entry(reason).or_default().fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Count a [ResumeBuffer](super::resume::ResumeBuffer) of capacity
+	/// `capacity`, which has started reporting to these metrics.
+	pub fn add_resume_buffer(&self, capacity: usize) {
+		self.resume_buffers.fetch_add(1, Ordering::Relaxed);
+		self.resume_buffer_capacity.fetch_add(capacity as u64, Ordering::Relaxed);
+	}
+
+	/// Stop counting a [ResumeBuffer](super::resume::ResumeBuffer) of capacity
+	/// `capacity`, for example because it has been dropped.
+	pub fn remove_resume_buffer(&self, capacity: usize) {
+		self.resume_buffers.fetch_sub(1, Ordering::Relaxed);
+		self.resume_buffer_capacity.fetch_sub(capacity as u64, Ordering::Relaxed);
+	}
+
+	/// Record that a [ResumeBuffer](super::resume::ResumeBuffer) has been
+	/// resized from `from` to `to` events.
+	pub fn resize_resume_buffer(&self, from: usize, to: usize) {
+		self.resume_buffer_capacity.fetch_add(to as u64, Ordering::Relaxed);
+		self.resume_buffer_capacity.fetch_sub(from as u64, Ordering::Relaxed);
+	}
+
+	/// The current value of all counters.
+	pub fn snapshot(&self) -> MetricsSnapshot {
+		MetricsSnapshot {
+			dispatched: self
+				.dispatched
+				.read()
+				.iter()
+				.map(|(event_type, counter)| (*event_type, counter.load(Ordering::Relaxed)))
+				.collect(),
+			resume_buffers: self.resume_buffers.load(Ordering::Relaxed),
+			resume_buffer_capacity: self.resume_buffer_capacity.load(Ordering::Relaxed),
+			closes: self
+				.closes
+				.read()
+				.iter()
+				.map(|(reason, counter)| (*reason, counter.load(Ordering::Relaxed)))
+				.collect(),
+		}
+	}
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+/// The value of the counters of [GatewayMetrics] at one point in time.
+pub struct MetricsSnapshot {
+	/// Number of deliveries of each [EventType]. Types which have never been
+	/// delivered are absent.
+	pub dispatched: BTreeMap<EventType, u64>,
+	/// Number of resume buffers of sessions, connected or resumable.
+	pub resume_buffers: u64,
+	/// Number of events all resume buffers together can currently retain.
+	pub resume_buffer_capacity: u64,
+	/// Number of sessions ended for each [KillReason]. Reasons no session has
+	/// ended for are absent.
+	pub closes: BTreeMap<KillReason, u64>,
+}
+
+impl MetricsSnapshot {
+	/// Number of deliveries of events of type `event_type`.
+	pub fn dispatched(&self, event_type: EventType) -> u64 {
+		self.dispatched.get(&event_type).copied().unwrap_or_default()
+	}
+
+	/// Number of deliveries of events of any type.
+	pub fn total_dispatched(&self) -> u64 {
+		self.dispatched.values().sum()
+	}
+
+	/// Number of sessions ended for `reason`.
+	pub fn closed(&self, reason: KillReason) -> u64 {
+		self.closes.get(&reason).copied().unwrap_or_default()
+	}
+
+	/// Number of sessions the client has ended, cleanly or not.
+	pub fn client_initiated_closes(&self) -> u64 {
+		self.closes.iter().filter(|(reason, _)| reason.is_client_initiated()).map(|(_, n)| n).sum()
+	}
+
+	/// Number of sessions the server has ended.
+	pub fn server_initiated_closes(&self) -> u64 {
+		self.closes.iter().filter(|(reason, _)| !reason.is_client_initiated()).map(|(_, n)| n).sum()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use chorus::types::{GatewayHeartbeatAck, Opcode};
+
+	use super::*;
+	use crate::gateway::{
+		GatewayPayload,
+		dispatchevent::{DispatchEvent, DispatchEventType},
+	};
+
+	fn payload<T>(event_data: T) -> GatewayPayload<T> {
+		GatewayPayload {
+			op_code: Opcode::Dispatch as u8,
+			event_data: Some(event_data),
+			sequence_number: None,
+			event_name: None,
+		}
+	}
+
+	#[test]
+	fn deliveries_are_counted_by_event_type() {
+		let metrics = GatewayMetrics::default();
+		let resumed = Event::Dispatch(DispatchEvent::Resumed(payload(())));
+		let heartbeat_ack = Event::HeartbeatAck(payload(GatewayHeartbeatAck::default()));
+		for _ in 0..3 {
+			metrics.record(&resumed);
+		}
+		metrics.record(&heartbeat_ack);
+
+		let snapshot = metrics.snapshot();
+		assert_eq!(snapshot.dispatched(EventType::Dispatch(DispatchEventType::Resumed)), 3);
+		assert_eq!(snapshot.dispatched(EventType::HeartbeatAck), 1);
+		assert_eq!(snapshot.dispatched(EventType::Dispatch(DispatchEventType::MessageCreate)), 0);
+		assert_eq!(snapshot.total_dispatched(), 4);
+	}
+
+	#[test]
+	fn closes_are_counted_by_initiator() {
+		let metrics = GatewayMetrics::default();
+		for reason in [
+			KillReason::ClientClosed,
+			KillReason::ConnectionLost,
+			KillReason::ConnectionLost,
+			KillReason::Timeout,
+		] {
+			metrics.record_close(reason);
+		}
+
+		let snapshot = metrics.snapshot();
+		assert_eq!(snapshot.closed(KillReason::ConnectionLost), 2);
+		assert_eq!(snapshot.closed(KillReason::ServerShutdown), 0);
+		assert_eq!(snapshot.client_initiated_closes(), 3);
+		assert_eq!(snapshot.server_initiated_closes(), 1);
+	}
+}
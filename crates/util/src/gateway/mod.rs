@@ -5,44 +5,105 @@
 use std::{
 	collections::{HashMap, HashSet},
 	fmt::Display,
-	ops::{Deref, DerefMut},
-	sync::{Arc, Weak},
+	ops::Deref,
+	sync::{Arc, OnceLock, Weak},
+	time::{Duration, SystemTime},
 };
 
 use ::serde::{Deserialize, Serialize, de::DeserializeOwned};
+use blocks::BlockList;
 use chorus::types::{
 	ChannelCreate, ChannelDelete, ChannelUpdate, GatewayHeartbeat, GatewayHeartbeatAck,
 	GatewayHello, GatewayIdentifyPayload, GatewayInvalidSession, GatewayReady,
 	GatewayReadySupplemental, GatewayRequestGuildMembers, GatewayResume, GuildBanAdd,
 	GuildBanRemove, GuildCreate, GuildDelete, GuildEmojisUpdate, GuildIntegrationsUpdate,
-	GuildMemberAdd, GuildMemberRemove, GuildMemberUpdate, GuildMembersChunk, GuildUpdate,
-	InteractionCreate, InviteCreate, InviteDelete, MessageCreate, MessageDelete, MessageDeleteBulk,
-	MessageReactionAdd, MessageReactionRemove, MessageReactionRemoveAll,
-	MessageReactionRemoveEmoji, MessageUpdate, Opcode, PresenceUpdate, Snowflake,
-	StageInstanceCreate, StageInstanceDelete, StageInstanceUpdate, ThreadCreate, ThreadDelete,
-	ThreadListSync, ThreadMemberUpdate, ThreadMembersUpdate, ThreadUpdate, TypingStartEvent,
-	UserUpdate, VoiceServerUpdate, VoiceStateUpdate, WebhooksUpdate,
+	GuildMemberAdd, GuildMemberRemove, GuildMemberUpdate, GuildMembersChunk, GuildRoleCreate,
+	GuildUpdate, InteractionCreate, InviteCreate, InviteDelete, MessageCreate, MessageDelete,
+	MessageDeleteBulk, MessageReactionAdd, MessageReactionRemove, MessageReactionRemoveAll,
+	MessageReactionRemoveEmoji, MessageUpdate, Opcode, PermissionFlags, PresenceUpdate, PublicUser,
+	Snowflake, StageInstance, StageInstanceCreate, StageInstanceDelete, StageInstanceUpdate,
+	ThreadCreate, ThreadDelete, ThreadListSync, ThreadMemberUpdate, ThreadMembersUpdate,
+	ThreadUpdate, TypingStartEvent, UserSettings, UserStatus, UserUpdate, VoiceServerUpdate,
+	VoiceState, VoiceStateUpdate, WebhooksUpdate,
 };
+use codec::{JsonCodec, PayloadCodec, PayloadCompression};
+use connection_state::ConnectionState;
+use dispatchevent::DispatchEvent;
 use event::Event;
 use futures::{
 	SinkExt, StreamExt,
 	stream::{SplitSink, SplitStream},
 };
+use guild_sequencer::GuildSequencer;
+use kill_reason::{DisconnectReason, KillReason};
+use log_context::SessionLogContext;
+use metrics::GatewayMetrics;
 use parking_lot::RwLock;
+use pending_interactions::PendingInteractions;
+use presence_subscriptions::{LazyRequest, MAX_LAZY_REQUEST_MEMBERS, PresenceSubscriptions};
 use pubserve::Subscriber;
+use resumable_store::SharedResumableClientsStore;
+use resume::{ResumeBuffer, ResumeSnapshot};
+use routing::{Route, RoutingTable};
 use serde_json::from_str;
+use session_info::{ClientProperties, SessionInfo};
+use session_token::SessionToken;
+use sharded::ShardedMap;
 use sqlx::PgPool;
 use sqlx_pg_uint::PgU64;
-use tokio::{net::TcpStream, sync::Mutex};
-use tokio_tungstenite::{WebSocketStream, tungstenite, tungstenite::Message};
+use stage_instance::StageInstanceEvent;
+use thread_members::ThreadMembership;
+use tokio::{
+	net::TcpStream,
+	sync::{Mutex, broadcast::error::TryRecvError},
+};
+use tokio_tungstenite::{
+	WebSocketStream, tungstenite,
+	tungstenite::{
+		Message,
+		protocol::{CloseFrame, WebSocketConfig, frame::coding::CloseCode},
+	},
+};
+use voice_state::VoiceStateMap;
 
 use crate::{
 	WebSocketReceive, WebSocketSend,
+	configuration::OfflineInteractionPolicy,
+	database::{INITIAL_RETRY_BACKOFF, retry_transient},
+	entities::{Recipient, Role},
 	errors::{Error, GatewayError},
+	session_log,
 };
 
+pub mod auth;
+pub mod blocks;
+pub mod codec;
+pub mod connection_state;
 pub mod dispatchevent;
 pub mod event;
+pub mod filtered_subscriber;
+pub mod guild_sequencer;
+pub mod intents;
+pub mod kill_reason;
+pub mod log_context;
+pub mod metrics;
+pub mod pending_interactions;
+pub mod presence_subscriptions;
+pub mod resumable_store;
+pub mod resume;
+pub mod routing;
+pub mod session_info;
+pub mod session_token;
+pub mod shard;
+pub mod sharded;
+pub mod stage_instance;
+pub mod stream_compression;
+pub mod sweep;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod testing;
+pub mod thread_members;
+pub mod thread_sync;
+pub mod voice_state;
 
 #[derive(Serialize, Clone, PartialEq, Debug)]
 /// A de-/serializable data payload for transmission over the gateway.
@@ -63,6 +124,19 @@ where
 	pub event_name: Option<String>,
 }
 
+impl<T> GatewayPayload<T> {
+	/// A dispatch of the event `event_name` carrying `event_data`. The sequence
+	/// number is assigned when the event is sent to a session.
+	pub fn dispatch(event_name: &str, event_data: T) -> Self {
+		Self {
+			op_code: Opcode::Dispatch as u8,
+			event_data: Some(event_data),
+			sequence_number: None,
+			event_name: Some(event_name.to_string()),
+		}
+	}
+}
+
 impl<T: Serialize + DeserializeOwned> GatewayPayload<T> {
 	pub fn has_data(&self) -> bool {
 		self.event_data.is_some()
@@ -101,27 +175,93 @@ impl<'de, T: DeserializeOwned + Serialize> Deserialize<'de> for GatewayPayload<T
 }
 
 #[derive(Default, Clone)]
+/// All users and sessions connected to the gateway, along with the state
+/// shared between them.
+///
+/// ## Lock ordering
+///
+/// To rule out deadlocks, the asynchronous locks are only ever acquired in
+/// the order
+///
+/// 1. the lock of a [GatewayClient],
+/// 2. the lock of the [GatewayUser] the client belongs to,
+/// 3. the lock on `role_user_map` or the lock on `voice_states`, which are
+///    never held at the same time,
+///
+/// never the reverse. Locks may be skipped, but no lock may be acquired while
+/// a lock further down the list is held. In particular, the clients of a
+/// [GatewayUser] are never locked while the user is, so that
+/// [GatewayClient::die], which is called with the lock of the client held,
+/// can always acquire the lock of its user. Methods visiting the clients of a
+/// user collect them first and release the user before locking them.
+///
+/// The synchronous locks, `store`, `blocks`, `presence_subscriptions`,
+/// `routing_table` and the shards of `users` and `inboxes`, may be acquired at
+/// any point, but are never held across an `.await` or while acquiring another
+/// lock.
 pub struct ConnectedUsers {
 	pub store: Arc<RwLock<ConnectedUsersInner>>,
+	/// Mapping of Snowflake IDs to connected [GatewayUser]s. Lives outside of
+	/// `store`, so that looking up a user does not contend with unrelated
+	/// connections.
+	pub users: Arc<ShardedMap<Arc<Mutex<GatewayUser>>>>,
+	/// A mapping of Snowflake IDs to the "inbox" of a [GatewayUser].
+	///
+	/// An "inbox" is a [tokio::sync::broadcast::Sender] that can be used to
+	/// send [Event]s to all connected clients of a [GatewayUser].
+	pub inboxes: Arc<ShardedMap<tokio::sync::broadcast::Sender<Event>>>,
 	pub role_user_map: Arc<Mutex<RoleUserMap>>,
+	pub voice_states: Arc<Mutex<VoiceStateMap>>,
+	/// Counts the events delivered to the inboxes of users.
+	pub metrics: Arc<GatewayMetrics>,
+	/// The users each connected user has blocked, consulted by
+	/// [BulkMessageBuilder::send].
+	pub blocks: Arc<RwLock<BlockList>>,
+	/// The users each session has subscribed to the presences of, consulted
+	/// by [ConnectedUsers::receives_presence].
+	pub presence_subscriptions: Arc<RwLock<PresenceSubscriptions>>,
+	/// Decides which events may be sent to the inboxes of users and which
+	/// have to be sent to a single [GatewayClient]. Consulted by every method
+	/// delivering to inboxes, see [ConnectedUsers::check_user_route].
+	pub routing_table: Arc<RwLock<RoutingTable>>,
+	/// Delivers the events scoped to a guild one after another, see
+	/// [BulkMessageBuilder::send_with_report].
+	pub guild_sequencer: GuildSequencer,
+	/// The [DisconnectInfo] of sessions which can be resumed. Kept in memory
+	/// unless another
+	/// [ResumableClientsStore](resumable_store::ResumableClientsStore)
+	/// is configured.
+	pub resumable_clients: SharedResumableClientsStore,
+	/// Inboxes registered with [ConnectedUsers::register_test_sink], which
+	/// collect the events delivered to them instead of forwarding them to a
+	/// connection.
+	#[cfg(any(test, feature = "test-utils"))]
+	test_sinks:
+		Arc<parking_lot::Mutex<HashMap<Snowflake, tokio::sync::broadcast::Receiver<Event>>>>,
 }
 
-/// A map of resumable clients. The key is the session token used
-/// for the connection. The value is a [GatewayClient] that can be resumed.
-// TODO: this is stupid. it should be a map of string and DisconnectInfo. there
-// is no need to store the whole GatewayClient, nor would it make sense to do
-// so.
-pub type ResumableClientsStore = HashMap<String, DisconnectInfo>;
-
-/// A mapping of Snowflake IDs to the "inbox" of a [GatewayUser].
-///
-/// An "inbox" is a [tokio::sync::mpsc::Sender] that can be used to send
-/// [Event]s to all connected clients of a [GatewayUser].
-#[derive(Default)]
+/// Session bookkeeping of [ConnectedUsers] which is not keyed by user.
 pub struct ConnectedUsersInner {
-	pub inboxes: HashMap<Snowflake, tokio::sync::broadcast::Sender<Event>>,
-	pub users: HashMap<Snowflake, Arc<Mutex<GatewayUser>>>,
-	pub resumeable_clients_store: ResumableClientsStore,
+	/// Index of session tokens to the Snowflake ID of the [GatewayUser] the
+	/// session belongs to. Kept up to date by [ConnectedUsers::new_client] and
+	/// [GatewayClient::die].
+	pub session_tokens: HashMap<SessionToken, Snowflake>,
+	/// Interactions for bots without a live session. Only filled if
+	/// [OfflineInteractionPolicy::Store] is configured.
+	pub pending_interactions: PendingInteractions,
+	/// Number of inboxes [BulkMessageBuilder::send_with_report] delivers to
+	/// at once. See [ConnectedUsers::set_dispatch_concurrency].
+	pub dispatch_concurrency: usize,
+}
+
+impl Default for ConnectedUsersInner {
+	fn default() -> Self {
+		Self {
+			session_tokens: HashMap::new(),
+			pending_interactions: PendingInteractions::default(),
+			dispatch_concurrency: DEFAULT_DISPATCH_CONCURRENCY,
+		}
+	}
 }
 
 /// A single identifiable User connected to the Gateway - possibly using many
@@ -136,7 +276,7 @@ pub struct GatewayUser {
 	outbox: tokio::sync::broadcast::Sender<Event>,
 	/// Sessions a User is connected with. HashMap of SessionToken ->
 	/// GatewayClient
-	clients: HashMap<String, Arc<Mutex<GatewayClient>>>,
+	clients: HashMap<SessionToken, Arc<Mutex<GatewayClient>>>,
 	/// The Snowflake ID of the User.
 	pub id: Snowflake,
 	/// A collection of [Subscribers](Subscriber) to [Event]
@@ -148,15 +288,128 @@ pub struct GatewayUser {
 	subscriptions: Vec<Box<dyn Subscriber<Event>>>,
 	/// [Weak] reference to the [ConnectedUsers] store.
 	connected_users: ConnectedUsers,
+	/// When any of the clients of this user last sent a message to the
+	/// gateway.
+	last_activity: std::time::Instant,
 }
 
 impl GatewayUser {
-	/// Kills a user by ending all of their clients' sessions.
-	pub async fn kill(&mut self) {
-		for (_, client_mutex) in self.clients.iter() {
-			let mut client = client_mutex.lock().await;
-			client.die(self.connected_users.clone()).await
+	/// When any of the clients of this user last sent a message to the
+	/// gateway. Used to tell idle users apart, for example to set their
+	/// presence to idle.
+	pub fn last_activity(&self) -> std::time::Instant {
+		self.last_activity
+	}
+
+	/// Record that one of the clients of this user has just sent a message.
+	pub fn record_activity(&mut self) {
+		self.last_activity = std::time::Instant::now();
+	}
+
+	/// The [GatewayClient]s of `user`. The lock of `user` is released before
+	/// this method returns, so that the clients can be locked without
+	/// violating the lock ordering described in [ConnectedUsers].
+	///
+	/// ## Locking
+	///
+	/// This method acquires the lock of `user` for the duration of its runtime.
+	async fn clients_of(user: &Mutex<Self>) -> Vec<Arc<Mutex<GatewayClient>>> {
+		user.lock().await.clients.values().cloned().collect()
+	}
+
+	/// Kills `user` by ending all of their clients' sessions for the given
+	/// `reason`.
+	///
+	/// ## Locking
+	///
+	/// This method acquires the lock of `user`, then the lock of each of their
+	/// [GatewayClient]s, one after another. See [GatewayClient::die] for the
+	/// locks acquired while a client is held.
+	pub async fn kill(user: &Mutex<Self>, reason: KillReason) {
+		let (user_id, connected_users) = {
+			let user = user.lock().await;
+			(user.id, user.connected_users.clone())
+		};
+		for client in Self::clients_of(user).await {
+			let mut client = client.lock().await;
+			if let Err(e) = client.die(connected_users.clone(), reason).await {
+				log::debug!(target: "symfonia::gateway::GatewayUser::kill", "Error while killing session of user {user_id}: {e}");
+			}
+		}
+	}
+
+	/// The shards all sessions of `user` identified with.
+	///
+	/// ## Locking
+	///
+	/// This method acquires the lock of `user`, then the lock of each of their
+	/// [GatewayClient]s, one after another.
+	pub async fn shards(user: &Mutex<Self>) -> Vec<Option<(u64, u64)>> {
+		let clients = Self::clients_of(user).await;
+		let mut shards = Vec::with_capacity(clients.len());
+		for client in clients {
+			shards.push(client.lock().await.shard().await);
+		}
+		shards
+	}
+
+	/// A snapshot of the metadata of all sessions of `user`, for example to
+	/// list the devices which are logged into their account.
+	///
+	/// ## Locking
+	///
+	/// This method acquires the lock of `user`, then the lock of each of their
+	/// [GatewayClient]s, one after another.
+	pub async fn sessions_info(user: &Mutex<Self>) -> Vec<SessionInfo> {
+		let clients = Self::clients_of(user).await;
+		let mut sessions = Vec::with_capacity(clients.len());
+		for client in clients {
+			sessions.push(client.lock().await.session_info().await);
+		}
+		sessions
+	}
+
+	/// Computes the effective presence of `user` across all of their
+	/// sessions. See [aggregate_presence] for how the presences of the
+	/// individual sessions are weighed against each other.
+	///
+	/// ## Locking
+	///
+	/// This method acquires the lock of `user`, then the lock of each of their
+	/// [GatewayClient]s, one after another.
+	pub async fn aggregated_presence(user: &Mutex<Self>) -> UserStatus {
+		let clients = Self::clients_of(user).await;
+		let mut statuses = Vec::with_capacity(clients.len());
+		for client in clients {
+			statuses.push(client.lock().await.presence().clone());
 		}
+		aggregate_presence(statuses)
+	}
+}
+
+/// Resolves the presences of multiple sessions into a single, effective
+/// presence. The priority is `online` > `idle` > `dnd` > `offline`, where
+/// `invisible` sessions count as `offline`. Returns [UserStatus::Offline] if
+/// no presences are given.
+pub fn aggregate_presence(statuses: impl IntoIterator<Item = UserStatus>) -> UserStatus {
+	statuses
+		.into_iter()
+		.map(|status| match status {
+			UserStatus::Invisible => UserStatus::Offline,
+			status => status,
+		})
+		.max_by_key(presence_priority)
+		.unwrap_or(UserStatus::Offline)
+}
+
+/// Priority of a [UserStatus] when aggregating the presences of multiple
+/// sessions. Higher is more important.
+fn presence_priority(status: &UserStatus) -> u8 {
+	match status {
+		UserStatus::Online => 3,
+		UserStatus::Idle => 2,
+		UserStatus::Dnd => 1,
+		UserStatus::Offline | UserStatus::Invisible => 0,
 	}
 }
 
@@ -165,15 +418,58 @@ pub struct GatewayClient {
 	connection: WebSocketConnection,
 	/// A [Weak] reference to the [GatewayUser] this client belongs to.
 	pub parent: Weak<Mutex<GatewayUser>>,
+	/// The Snowflake ID of the [GatewayUser] this client belongs to.
+	user_id: Snowflake,
 	// Handle to the main Gateway task for this client
 	main_task_handle: tokio::task::JoinHandle<()>,
 	// Handle to the heartbeat task for this client
 	heartbeat_task_handle: tokio::task::JoinHandle<()>,
 	/// Token of the session token used for this connection
-	pub session_token: String,
+	pub session_token: SessionToken,
 	/// The last sequence number received from the client. Shared between the
 	/// main task, heartbeat task, and this struct.
 	last_sequence: Arc<Mutex<u64>>,
+	/// The presence this session has set for itself. Use
+	/// [GatewayUser::aggregated_presence] to get the effective presence of a
+	/// user across all of their sessions.
+	presence: UserStatus,
+	/// The `(shard_id, shard_count)` this session identified with, if any.
+	/// Shared with the main task, which routes guild events by it, so that the
+	/// session can be moved to another shard while it is running. See
+	/// [ConnectedUsers::move_user_between_shards].
+	shard: Arc<Mutex<Option<(u64, u64)>>>,
+	/// Events recently dispatched to this session. Filled by the main task and
+	/// handed over to the [DisconnectInfo] once this client dies.
+	recent_dispatches: Arc<Mutex<ResumeBuffer>>,
+	/// Identifies this session in log lines.
+	log_context: SessionLogContext,
+	/// Guilds with more members than this are sent without their offline
+	/// members. See [large_threshold].
+	large_threshold: u64,
+	/// The [intents] of this session.
+	intents: u64,
+	/// When this session has been opened.
+	connected_at: chrono::DateTime<chrono::Utc>,
+	/// The client this session has been opened with.
+	properties: ClientProperties,
+	/// The stage of its lifecycle this session is in.
+	state: ConnectionState,
+}
+
+/// `large_threshold` used for sessions which did not request one.
+pub const DEFAULT_LARGE_THRESHOLD: u64 = 50;
+/// Largest `large_threshold` a session may request.
+pub const MAX_LARGE_THRESHOLD: u64 = 250;
+
+/// The `large_threshold` of a session which requested `requested` in its
+/// identify payload, clamped to the range Discord allows.
+pub fn large_threshold(requested: Option<i16>) -> u64 {
+	match requested {
+		Some(requested) => {
+			(requested.max(0) as u64).clamp(DEFAULT_LARGE_THRESHOLD, MAX_LARGE_THRESHOLD)
+		}
+		None => DEFAULT_LARGE_THRESHOLD,
+	}
 }
 
 impl ConnectedUsers {
@@ -186,10 +482,22 @@ impl ConnectedUsers {
 		BulkMessageBuilder::default()
 	}
 
+	/// Set how many inboxes an event sent to many users is delivered to at
+	/// once, before the sending task yields. Raised to at least 1.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a write lock on `store`.
+	pub fn set_dispatch_concurrency(&self, concurrency: usize) {
+		self.store.write().dispatch_concurrency = concurrency.max(1);
+	}
+
 	/// Initialize the [RoleUserMap] with data from the database.
 	///
 	/// This method will query the database for all roles and all users that
-	/// have these roles. The data will then populate the map.
+	/// have these roles. The data will then populate the map. Each query is
+	/// attempted up to `attempts` times if it fails with a transient error, see
+	/// [retry_transient].
 	///
 	/// Due to the possibly large number of roles and users returned by the
 	/// database, this method should only be executed once. The [RoleUserMap]
@@ -200,31 +508,29 @@ impl ConnectedUsers {
 	///
 	/// This method acquires a lock on `role_user_map` for the duration of its
 	/// runtime.
-	pub async fn init_role_user_map(&self, db: &PgPool) -> Result<(), Error> {
-		self.role_user_map.lock().await.init(db).await
+	pub async fn init_role_user_map(&self, db: &PgPool, attempts: u32) -> Result<(), Error> {
+		self.role_user_map.lock().await.init(db, attempts).await
 	}
 
 	/// Get a [GatewayUser] by its Snowflake ID if it already exists in the
 	/// store, or create a new [GatewayUser] if it does not exist using
 	/// [ConnectedUsers::new_user].
 	///
+	/// Concurrent calls for the same ID all return the same [GatewayUser].
+	///
 	/// ## Locking
 	///
-	/// This method always acquires a read lock on `store`. If the user does not
-	/// yet exist in the store, a `write` lock will be acquired additionally.
+	/// This method always acquires a read lock on the shard of `users` the user
+	/// belongs to. If the user does not yet exist in the store, the write lock
+	/// of that shard is acquired additionally. While holding it, the write lock
+	/// of the user's shard of `inboxes` is acquired to register the inbox.
 	pub fn get_user_or_new(&self, id: Snowflake) -> Arc<Mutex<GatewayUser>> {
-		let inner = self.store.clone();
-		log::trace!(target: "symfonia::gateway::types::ConnectedUsers::get_user_or_new", "Acquiring lock on ConnectedUsersInner...");
-		let mut lock = inner.read();
-		log::trace!(target: "symfonia::gateway::types::ConnectedUsers::get_user_or_new", "Lock acquired!");
-		if let Some(user) = lock.users.get(&id) {
-			log::trace!(target: "symfonia::gateway::types::ConnectedUsers::get_user_or_new", "Found user {id} in store");
-			user.clone()
-		} else {
-			drop(lock);
+		self.users.get_or_insert_with(id, || {
 			log::trace!(target: "symfonia::gateway::types::ConnectedUsers::get_user_or_new", "Creating new user {id} in store");
-			self.new_user(HashMap::new(), id, Vec::new())
-		}
+			let user = self.gateway_user(HashMap::new(), id, Vec::new());
+			self.inboxes.insert(id, user.outbox.clone());
+			Arc::new(Mutex::new(user))
+		})
 	}
 
 	pub fn inner(&self) -> Arc<RwLock<ConnectedUsersInner>> {
@@ -235,15 +541,13 @@ impl ConnectedUsers {
 	///
 	/// ## Locking
 	///
-	/// This method acquires a write lock on `store` for the duration of its
-	/// runtime.
+	/// This method acquires the write locks of the user's shards of `inboxes`
+	/// and `users`, one after another.
 	fn register(&self, user: GatewayUser) -> Arc<Mutex<GatewayUser>> {
-		log::trace!(target: "symfonia::gateway::types::ConnectedUsers::register", "Acquiring lock on ConnectedUsersInner...");
-		self.store.write().inboxes.insert(user.id, user.outbox.clone());
-		log::trace!(target: "symfonia::gateway::types::ConnectedUsers::register", "Lock acquired!");
+		self.inboxes.insert(user.id, user.outbox.clone());
 		let id = user.id;
 		let arc = Arc::new(Mutex::new(user));
-		self.store.write().users.insert(id, arc.clone());
+		self.users.insert(id, arc.clone());
 		log::trace!(target: "symfonia::gateway::types::ConnectedUsers::register", "Inserted user {id} into users store");
 		arc
 	}
@@ -252,21 +556,163 @@ impl ConnectedUsers {
 	///
 	/// ## Locking
 	///
-	/// This method acquires a write lock on `store` for the duration of its
-	/// runtime.
+	/// This method acquires the write locks of the user's shards of `inboxes`
+	/// and `users`, one after another.
 	pub fn deregister(&self, user: &GatewayUser) {
-		self.store.write().inboxes.remove(&user.id);
-		self.store.write().users.remove(&user.id);
+		self.inboxes.remove(user.id);
+		self.users.remove(user.id);
+		self.blocks.write().forget(user.id);
+	}
+
+	/// Load the users the user with the ID `user_id` has blocked from the
+	/// database, so that their messages, typing indicators and presences are
+	/// no longer delivered to them. Called when the user identifies.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a write lock on `blocks`.
+	pub async fn load_blocks(&self, db: &PgPool, user_id: Snowflake) -> Result<(), Error> {
+		let blocked = blocks::blocked_users(db, user_id).await?;
+		self.blocks.write().set_blocked(user_id, blocked);
+		Ok(())
+	}
+
+	/// Record that the user with the ID `blocker` has blocked the user with the
+	/// ID `blocked`, or no longer does if `blocking` is false. Has to be called
+	/// whenever a block is added or removed.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a write lock on `blocks`.
+	pub fn set_blocking(&self, blocker: Snowflake, blocked: Snowflake, blocking: bool) {
+		let mut blocks = self.blocks.write();
+		if blocking {
+			blocks.block(blocker, blocked);
+		} else {
+			blocks.unblock(blocker, blocked);
+		}
+	}
+
+	/// Check that `users` and `inboxes` hold the same users, which
+	/// [Self::register] and [Self::deregister] are meant to ensure. Returns
+	/// the Snowflake IDs of the users found in only one of them otherwise.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a read lock on each shard of `users` and `inboxes`,
+	/// one after another. Users registered or deregistered meanwhile may
+	/// therefore be reported, so this is only meaningful while no sessions
+	/// connect or disconnect, for example in tests.
+	pub fn verify_consistency(&self) -> Result<(), Vec<Snowflake>> {
+		let users: HashSet<Snowflake> = self.users.keys().into_iter().collect();
+		#[allow(unused_mut)]
+		let mut inboxes: HashSet<Snowflake> = self.inboxes.keys().into_iter().collect();
+		#[cfg(any(test, feature = "test-utils"))]
+		{
+			let test_sinks = self.test_sinks.lock();
+			inboxes.retain(|id| !test_sinks.contains_key(id));
+		}
+		let mut diverged: Vec<Snowflake> = users.symmetric_difference(&inboxes).copied().collect();
+		if diverged.is_empty() {
+			return Ok(());
+		}
+		diverged.sort();
+		Err(diverged)
+	}
+
+	/// Register an inbox for the user with the ID `user_id` which is not backed
+	/// by any connection, but collects the events delivered to it, so that
+	/// tests can check which users receive which events without connecting
+	/// them. Use [Self::drain_test_sink] to take the collected events. A sink
+	/// holds up to [DEFAULT_BUFFER_CAPACITY] events; older ones are dropped.
+	///
+	/// Replaces the inbox of the user, if they are connected.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a write lock on the user's shard of `inboxes` and
+	/// the lock on `test_sinks`, one after another.
+	#[cfg(any(test, feature = "test-utils"))]
+	pub fn register_test_sink(&self, user_id: Snowflake) {
+		let (inbox, sink) = tokio::sync::broadcast::channel(DEFAULT_BUFFER_CAPACITY);
+		self.inboxes.insert(user_id, inbox);
+		self.test_sinks.lock().insert(user_id, sink);
+	}
+
+	/// Take the events delivered to the sink of the user with the ID `user_id`
+	/// since it has been registered with [Self::register_test_sink] or last
+	/// drained, in the order they have been delivered in. Returns nothing if
+	/// the user has no sink.
+	///
+	/// ## Locking
+	///
+	/// This method acquires the lock on `test_sinks`.
+	#[cfg(any(test, feature = "test-utils"))]
+	pub fn drain_test_sink(&self, user_id: Snowflake) -> Vec<Event> {
+		let mut test_sinks = self.test_sinks.lock();
+		let Some(sink) = test_sinks.get_mut(&user_id) else {
+			return Vec::new();
+		};
+		let mut events = Vec::new();
+		loop {
+			match sink.try_recv() {
+				Ok(event) => events.push(event),
+				Err(tokio::sync::broadcast::error::TryRecvError::Lagged(missed)) => {
+					log::warn!(target: "symfonia::gateway::ConnectedUsers::drain_test_sink", "Test sink of user {user_id} dropped {missed} events");
+				}
+				Err(_) => return events,
+			}
+		}
+	}
+
+	/// Record that a client of the user with the given Snowflake ID has just
+	/// sent a message. Does nothing if the user is not connected.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a read lock on the user's shard of `users` and the
+	/// lock of the [GatewayUser], one after another.
+	pub async fn record_activity(&self, user_id: Snowflake) {
+		if let Some(user) = self.users.get(user_id) {
+			user.lock().await.record_activity();
+		}
+	}
+
+	/// Whether the user with the given Snowflake ID has at least one live
+	/// session. Unlike [Self::inbox], the inbox of the user is not cloned.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a read lock on the user's shard of `inboxes` for
+	/// the duration of its runtime.
+	pub fn is_online(&self, id: Snowflake) -> bool {
+		self.inboxes.contains_key(id)
+	}
+
+	/// Check that `event` may be sent to the inboxes of users, rather than to
+	/// a single [GatewayClient].
+	///
+	/// ## Errors
+	///
+	/// Returns [GatewayError::Misrouted] if the [RoutingTable] routes events of
+	/// this type to single sessions.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a read lock on `routing_table` for the duration of
+	/// its runtime.
+	pub fn check_user_route(&self, event: &Event) -> Result<(), GatewayError> {
+		self.routing_table.read().check(event.event_type(), Route::User)
 	}
 
 	/// Get the "inbox" of a [GatewayUser] by its Snowflake ID.
 	///
 	/// ## Locking
 	///
-	/// This method acquires a read lock on `store` for the duration of its
-	/// runtime.
+	/// This method acquires a read lock on the user's shard of `inboxes` for
+	/// the duration of its runtime.
 	pub async fn inbox(&self, id: Snowflake) -> Option<tokio::sync::broadcast::Sender<Event>> {
-		self.store.read().inboxes.get(&id).cloned()
+		self.inboxes.get(id)
 	}
 
 	/// Create a new [GatewayUser] with the given Snowflake ID,
@@ -279,20 +725,29 @@ impl ConnectedUsers {
 	/// on locking behavior.
 	pub fn new_user(
 		&self,
-		clients: HashMap<String, Arc<Mutex<GatewayClient>>>,
+		clients: HashMap<SessionToken, Arc<Mutex<GatewayClient>>>,
 		id: Snowflake,
 		subscriptions: Vec<Box<dyn Subscriber<Event>>>,
 	) -> Arc<Mutex<GatewayUser>> {
+		self.register(self.gateway_user(clients, id, subscriptions))
+	}
+
+	fn gateway_user(
+		&self,
+		clients: HashMap<SessionToken, Arc<Mutex<GatewayClient>>>,
+		id: Snowflake,
+		subscriptions: Vec<Box<dyn Subscriber<Event>>>,
+	) -> GatewayUser {
 		let channel = tokio::sync::broadcast::channel(20);
-		let user = GatewayUser {
+		GatewayUser {
 			inbox: channel.1,
 			outbox: channel.0.clone(),
 			clients,
 			id,
 			subscriptions,
 			connected_users: self.clone(),
-		};
-		self.register(user)
+			last_activity: std::time::Instant::now(),
+		}
 	}
 
 	/// Create a new [GatewayClient] with the given [GatewayUser], [Connection],
@@ -302,7 +757,9 @@ impl ConnectedUsers {
 	/// ## Locking
 	///
 	/// This method acquires a lock on the [Arc<Mutex<GatewayUser>>] that is
-	/// passed as `user`.
+	/// passed as `user`. While holding it, a write lock on `store` is acquired
+	/// to index the session token. The new [GatewayClient] is not locked, so
+	/// this method may be called while holding the locks of other clients.
 	#[allow(clippy::too_many_arguments)]
 	pub async fn new_client(
 		&self,
@@ -310,325 +767,3030 @@ impl ConnectedUsers {
 		connection: WebSocketConnection,
 		main_task_handle: tokio::task::JoinHandle<()>,
 		heartbeat_task_handle: tokio::task::JoinHandle<()>,
-		session_token: &str,
+		session_token: &SessionToken,
 		last_sequence: Arc<Mutex<u64>>,
+		recent_dispatches: Arc<Mutex<ResumeBuffer>>,
+		shard: Arc<Mutex<Option<(u64, u64)>>>,
 	) -> Arc<Mutex<GatewayClient>> {
+		log::trace!(target: "symfonia::gateway::ConnectedUsers::new_client", "Acquiring lock on user...");
+		let mut gateway_user = user.lock().await;
+		log::trace!(target: "symfonia::gateway::ConnectedUsers::new_client", "Lock acquired!");
+		let log_context = SessionLogContext::new(gateway_user.id, session_token);
 		let client = GatewayClient {
 			connection,
 			parent: Arc::downgrade(&user),
+			user_id: gateway_user.id,
 			main_task_handle,
 			heartbeat_task_handle,
-			session_token: session_token.to_string(),
+			session_token: session_token.clone(),
 			last_sequence,
+			presence: UserStatus::Online,
+			shard,
+			recent_dispatches,
+			log_context: log_context.clone(),
+			large_threshold: DEFAULT_LARGE_THRESHOLD,
+			intents: intents::ALL,
+			connected_at: chrono::Utc::now(),
+			properties: ClientProperties::default(),
+			// The client is registered while its identify or resume is being processed.
+			state: ConnectionState::HelloSent,
 		};
 		let arc = Arc::new(Mutex::new(client));
-		log::trace!(target: "symfonia::gateway::ConnectedUsers::new_client", "Acquiring lock on user...");
-		user.lock().await.clients.insert(session_token.to_string(), arc.clone());
-		log::trace!(target: "symfonia::gateway::ConnectedUsers::new_client", "Lock acquired!");
-		log::trace!(target: "symfonia::gateway::ConnectedUsers::new_client", "Inserted into map. Done.");
+		gateway_user.clients.insert(session_token.clone(), arc.clone());
+		self.store.write().session_tokens.insert(session_token.clone(), gateway_user.id);
+		session_log!(target: "symfonia::gateway::ConnectedUsers::new_client", log::Level::Debug, &log_context, "Session started");
 		arc
 	}
-}
 
-impl std::hash::Hash for GatewayUser {
-	fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-		self.id.hash(state);
+	/// Send `interaction` to the bot user with the ID `bot_user_id`. If the bot
+	/// is not connected, `policy` decides whether the interaction is kept
+	/// until it connects or dropped.
+	///
+	/// Returns whether the interaction has been delivered right away.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a read lock on `routing_table`, then a read lock on
+	/// `store`. If the interaction is kept, a write lock on `store` is acquired
+	/// after the read lock is released.
+	pub async fn dispatch_interaction(
+		&self,
+		bot_user_id: Snowflake,
+		interaction: InteractionCreate,
+		policy: OfflineInteractionPolicy,
+	) -> Result<bool, Error> {
+		let event = Event::Dispatch(DispatchEvent::InteractionCreate(GatewayPayload::dispatch(
+			"INTERACTION_CREATE",
+			interaction,
+		)));
+		self.check_user_route(&event)?;
+		if let Some(inbox) = self.inbox(bot_user_id).await {
+			self.metrics.record(&event);
+			inbox.send(event).map_err(GatewayError::from)?;
+			return Ok(true);
+		}
+		match policy {
+			OfflineInteractionPolicy::Drop => {
+				log::debug!(target: "symfonia::gateway::ConnectedUsers::dispatch_interaction", "Bot {bot_user_id} is not connected. Dropping interaction");
+			}
+			OfflineInteractionPolicy::Store => {
+				self.store.write().pending_interactions.push(bot_user_id, event);
+			}
+		}
+		Ok(false)
 	}
-}
 
-impl PartialEq for GatewayUser {
-	fn eq(&self, other: &Self) -> bool {
-		self.id == other.id
+	/// Remove and return the interactions kept for the bot user with the ID
+	/// `bot_user_id` while it was not connected, which have not expired yet.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a write lock on `store`.
+	pub fn take_pending_interactions(&self, bot_user_id: Snowflake) -> Vec<Event> {
+		self.store.write().pending_interactions.take(bot_user_id)
 	}
-}
 
-impl Eq for GatewayUser {}
+	/// Send `event` to all connected members of the guild with the ID
+	/// `guild_id`.
+	///
+	/// ## Locking
+	///
+	/// See [BulkMessageBuilder::send].
+	pub async fn broadcast_to_guild(&self, guild_id: Snowflake, event: Event) -> Result<(), Error> {
+		let mut builder = self.bulk_message_builder();
+		// Every member has the @everyone role, which has the same ID as the guild.
+		builder.add_role_recipients(&[guild_id]).await;
+		builder.set_message(event).await;
+		builder.send(self.clone()).await
+	}
 
-impl GatewayClient {
-	/// Disconnects a [GatewayClient] properly, including un-registering it from
-	/// the memory store and creating a resumeable session.
-	pub async fn die(&mut self, connected_users: ConnectedUsers) {
-		self.connection.kill_send.send(()).unwrap();
-		let disconnect_info = DisconnectInfo {
-			session_token: self.session_token.clone(),
-			disconnected_at_sequence: *self.last_sequence.lock().await,
-			parent: self.parent.clone(),
-		};
-		self.parent.upgrade().unwrap().lock().await.clients.remove(&self.session_token);
-		connected_users.deregister(self.parent.upgrade().unwrap().lock().await.deref());
-		connected_users
-			.store
-			.write()
-			.resumeable_clients_store
-			.insert(self.session_token.clone(), disconnect_info);
+	/// Create a role by awaiting `write`, which inserts it into the database,
+	/// then add it to the [RoleUserMap] and tell the members of its guild about
+	/// it with `GUILD_ROLE_CREATE`. If the write fails, the map is left
+	/// untouched. Grants of the role recorded before it has been added to the
+	/// map are kept. Failing to dispatch the event does not undo the creation
+	/// of the role, so it is only logged.
+	///
+	/// ## Locking
+	///
+	/// This method acquires the lock on `role_user_map` once the role has been
+	/// written, only to add the role to it, followed by the locks described in
+	/// [BulkMessageBuilder::send].
+	pub async fn create_role(
+		&self,
+		write: impl Future<Output = Result<Role, Error>>,
+	) -> Result<Role, Error> {
+		let role = write.await?;
+		self.role_user_map.lock().await.add_role(role.id, role.guild_id, role.permissions);
+		let event = Event::Dispatch(DispatchEvent::GuildRoleCreate(GatewayPayload::dispatch(
+			"GUILD_ROLE_CREATE",
+			GuildRoleCreate {
+				guild_id: role.guild_id,
+				role: role.deref().clone(),
+				..Default::default()
+			},
+		)));
+		if let Err(e) = self.broadcast_to_guild(role.guild_id, event).await {
+			log::warn!(target: "symfonia::gateway::ConnectedUsers::create_role", "Failed to dispatch GUILD_ROLE_CREATE for role {}: {e}", role.id);
+		}
+		Ok(role)
 	}
-}
 
-#[derive(Default, Clone)]
-/// `BulkMessageBuilder` can be used to build and send GatewayMessages to the
-/// inboxes of all currently connected [GatewayClients](GatewayClient).
-/// Recipients can be added either via User or Role snowflake IDs.
-pub struct BulkMessageBuilder {
-	users: Vec<Snowflake>,
-	roles: Vec<Snowflake>,
-	message: Option<Event>,
-}
+	/// Delete the role with the ID `role_id` by awaiting `write`, which deletes
+	/// it from the database, then remove it from the [RoleUserMap], along with
+	/// the records of which users have it. If the write fails, the map is left
+	/// untouched.
+	///
+	/// ## Locking
+	///
+	/// This method acquires the lock on `role_user_map` once the role has been
+	/// deleted, only to remove the role from it.
+	pub async fn delete_role<T>(
+		&self,
+		role_id: Snowflake,
+		write: impl Future<Output = Result<T, Error>>,
+	) -> Result<T, Error> {
+		let deleted = write.await?;
+		self.role_user_map.lock().await.remove_role(role_id);
+		Ok(deleted)
+	}
 
-impl BulkMessageBuilder {
-	/// Add the given list of user snowflake IDs to the list of recipients.
-	pub async fn add_user_recipients(&mut self, users: &[Snowflake]) {
-		self.users.extend_from_slice(users);
+	/// Add the user with the ID `user_id` to the guild with the ID `guild_id`
+	/// by awaiting `write`, which inserts the member into the database, then
+	/// record the roles of the new member in the [RoleUserMap]. As with
+	/// [Self::create_role], the map is left untouched if the write fails.
+	///
+	/// ## Locking
+	///
+	/// This method acquires the lock on `role_user_map` once the member has
+	/// been written, only to record its roles.
+	pub async fn add_guild_member<T>(
+		&self,
+		guild_id: Snowflake,
+		user_id: Snowflake,
+		write: impl Future<Output = Result<T, Error>>,
+	) -> Result<T, Error> {
+		let member = write.await?;
+		self.role_user_map.lock().await.add_member(guild_id, user_id);
+		Ok(member)
 	}
 
-	/// Add all members which have the given role snowflake IDs to the list of
-	/// recipients.
-	pub async fn add_role_recipients(&mut self, roles: &[Snowflake]) {
-		self.roles.extend_from_slice(roles);
+	/// Remove the user with the ID `user_id` from the guild with the ID
+	/// `guild_id` by awaiting `write`, which deletes the member from the
+	/// database, then revoke all roles of the guild the member had in the
+	/// [RoleUserMap]. If the write fails, the map is left untouched.
+	///
+	/// ## Locking
+	///
+	/// This method acquires the lock on `role_user_map` once the member has
+	/// been deleted, only to revoke its roles.
+	pub async fn remove_guild_member<T>(
+		&self,
+		guild_id: Snowflake,
+		user_id: Snowflake,
+		write: impl Future<Output = Result<T, Error>>,
+	) -> Result<T, Error> {
+		let removed = write.await?;
+		self.role_user_map.lock().await.remove_member(guild_id, user_id);
+		Ok(removed)
 	}
 
-	/// Set the message to be sent to the recipients.
-	pub async fn set_message(&mut self, message: Event) {
-		self.message = Some(message);
+	/// Tell the connected members of the guild of `stage_instance` that it has
+	/// been started, changed or ended, depending on `kind`.
+	///
+	/// ## Locking
+	///
+	/// See [BulkMessageBuilder::send].
+	pub async fn dispatch_stage_instance(
+		&self,
+		kind: StageInstanceEvent,
+		stage_instance: StageInstance,
+	) -> Result<(), Error> {
+		let guild_id = stage_instance.guild_id;
+		self.broadcast_to_guild(guild_id, kind.to_event(stage_instance)).await
 	}
 
-	/// Send the message to all recipients.
-	pub async fn send(self, connected_users: ConnectedUsers) -> Result<(), Error> {
-		if self.message.is_none() {
-			return Err(Error::Custom("No message to send".to_string()));
-		}
-		let mut recipients = HashSet::new();
-		let lock = connected_users.role_user_map.lock().await;
-		for role in self.roles.iter() {
-			if let Some(users) = lock.get(role) {
-				for user in users.iter() {
-					recipients.insert(*user);
-				}
-			}
-			for user in self.users.iter() {
-				recipients.insert(*user);
-			}
+	/// Tell the connected members of the guild `guild_id` which may manage it
+	/// that its integrations have changed, so that they fetch them again. These
+	/// are the members whose roles grant `MANAGE_GUILD` and the owner of the
+	/// guild, `owner_id`, who may always manage it.
+	///
+	/// ## Locking
+	///
+	/// See [BulkMessageBuilder::send].
+	pub async fn dispatch_guild_integrations_update(
+		&self,
+		guild_id: Snowflake,
+		owner_id: Option<Snowflake>,
+	) -> Result<(), Error> {
+		let event =
+			Event::Dispatch(DispatchEvent::GuildIntegrationsUpdate(GatewayPayload::dispatch(
+				"GUILD_INTEGRATIONS_UPDATE",
+				GuildIntegrationsUpdate { guild_id, ..Default::default() },
+			)));
+		let mut builder = self.bulk_message_builder();
+		builder.add_role_recipients(&[guild_id]).await;
+		builder.require_permission(guild_id, PermissionFlags::MANAGE_GUILD).await;
+		builder.set_message(event.clone()).await;
+		let Some(owner_id) = owner_id else {
+			return builder.send(self.clone()).await;
+		};
+		builder.exclude_user_recipients(&[owner_id]).await;
+		builder.send(self.clone()).await?;
+		let mut builder = self.bulk_message_builder();
+		builder.add_user_recipients(&[owner_id]).await;
+		builder.set_message(event).await;
+		builder.send(self.clone()).await
+	}
+
+	/// Tell the `participants` of the thread `thread_id`, which are its members
+	/// after `membership` has changed, that a user has joined or left it. The
+	/// user which has joined or left is additionally sent its own membership,
+	/// and is told about the change even if it is no longer a participant.
+	///
+	/// ## Locking
+	///
+	/// See [BulkMessageBuilder::send].
+	pub async fn dispatch_thread_membership(
+		&self,
+		guild_id: Snowflake,
+		thread_id: Snowflake,
+		participants: &[Snowflake],
+		membership: ThreadMembership,
+	) -> Result<(), Error> {
+		let mut recipients = participants.to_vec();
+		if let Some(user_id) = membership.user_id().filter(|id| !participants.contains(id)) {
+			recipients.push(user_id);
 		}
-		if recipients.is_empty() {
+		let mut builder = self.bulk_message_builder();
+		builder.add_user_recipients(&recipients).await;
+		builder
+			.set_message(membership.members_update(guild_id, thread_id, participants.len()))
+			.await;
+		builder.send(self.clone()).await?;
+
+		let Some(user_id) = membership.user_id() else {
 			return Ok(());
-		}
-		for recipient in recipients.iter() {
-			if let Some(inbox) = connected_users.inbox(*recipient).await {
-				inbox
-					.send(self.message.clone().unwrap())
-					.map_err(|e| Error::Custom(format!("tokio broadcast error: {}", e)))?;
+		};
+		let mut builder = self.bulk_message_builder();
+		builder.add_user_recipients(&[user_id]).await;
+		builder.set_message(membership.member_update(guild_id, thread_id)).await;
+		builder.send(self.clone()).await
+	}
+
+	/// Send `event` to the inbox of every connected user, for example for
+	/// announcements by an administrator. Unlike [BulkMessageBuilder], no
+	/// recipients need to be specified.
+	///
+	/// ## Errors
+	///
+	/// Returns [GatewayError::Misrouted] without sending anything if `event`
+	/// may not be sent to the inboxes of users.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a read lock on `routing_table`, then a read lock on
+	/// each shard of `inboxes` just long enough to collect its inboxes. No lock
+	/// is held while events are sent.
+	pub fn broadcast_to_all(&self, event: Event) -> Result<BulkSendReport, GatewayError> {
+		self.check_user_route(&event)?;
+		let inboxes = self.inboxes.entries();
+		let mut report = BulkSendReport::default();
+		for (id, inbox) in inboxes {
+			match inbox.send(event.clone()) {
+				Ok(_) => {
+					self.metrics.record(&event);
+					report.delivered += 1
+				}
+				Err(e) => {
+					log::debug!(target: "symfonia::gateway::ConnectedUsers::broadcast_to_all", "Failed to send event to user {id}: {e}");
+					report.failed.push(id);
+				}
 			}
 		}
-		Ok(())
+		Ok(report)
 	}
-}
 
-#[derive(Default)]
-/// Represents all existing roles on the server and the users that have these
-/// roles.
-pub struct RoleUserMap {
-	/// Map Role Snowflake ID to a list of User Snowflake IDs
-	map: HashMap<Snowflake, HashSet<Snowflake>>,
-}
-
-impl Deref for RoleUserMap {
-	type Target = HashMap<Snowflake, HashSet<Snowflake>>;
+	/// Find the [GatewayClient] with the given session token.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a read lock on `store`, a read lock on the shard of
+	/// `users` and the lock of the [GatewayUser] the session belongs to, one
+	/// after another.
+	pub async fn client_by_token(&self, token: &SessionToken) -> Option<Arc<Mutex<GatewayClient>>> {
+		let user_id = *self.store.read().session_tokens.get(token)?;
+		let user = self.users.get(user_id)?;
+		user.lock().await.clients.get(token).cloned()
+	}
 
-	fn deref(&self) -> &Self::Target {
-		&self.map
+	/// A snapshot of the metadata of all live sessions of the user with the
+	/// given Snowflake ID, for example for administrators looking into their
+	/// account. Session tokens are masked. Empty if the user is not connected.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a read lock on the user's shard of `users`, then
+	/// the locks described in [GatewayUser::sessions_info].
+	pub async fn list_sessions(&self, user_id: Snowflake) -> Vec<SessionInfo> {
+		match self.users.get(user_id) {
+			Some(user) => GatewayUser::sessions_info(&user).await,
+			None => Vec::new(),
+		}
 	}
-}
 
-impl DerefMut for RoleUserMap {
-	fn deref_mut(&mut self) -> &mut Self::Target {
-		&mut self.map
+	/// Disconnect all sessions of the user with the given Snowflake ID, for
+	/// example after a password change or a ban. Each session is killed with
+	/// [WebSocketConnection::kill] for the given `reason`. Does nothing if the
+	/// user is not connected.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a read lock on the user's shard of `users`, the
+	/// lock of the [GatewayUser] and then the lock of each of their
+	/// [GatewayClient]s, one after another.
+	pub async fn disconnect_all(&self, user_id: Snowflake, reason: KillReason) {
+		let Some(user) = self.users.get(user_id) else {
+			return;
+		};
+		let clients = GatewayUser::clients_of(&user).await;
+		for client in clients.iter() {
+			let client = client.lock().await;
+			// This only fails if the session is already shutting down, in which case
+			// there is nothing left to do.
+			let _ = client.connection.kill(reason);
+		}
+		log::debug!(target: "symfonia::gateway::ConnectedUsers::disconnect_all", "Disconnected {} session(s) of user {user_id}", clients.len());
 	}
-}
 
-impl RoleUserMap {
-	/// Initialize the [RoleUserMap] with data from the database.
+	/// Move the sessions of the user with the given Snowflake ID which are on
+	/// the shard `from` to the shard `to`, for example to rebalance shards
+	/// when scaling. Returns the number of sessions moved, which is zero if
+	/// the user is not connected.
 	///
-	/// This method will query the database for all roles and all users that
-	/// have these roles. The data will then populate the map.
+	/// If the shard count stays the same, the sessions keep running and
+	/// receive the events of the guilds of `to` from then on. Otherwise, the
+	/// guilds the clients have been sent no longer match their shard, so they
+	/// are asked to reconnect (opcode 7) and identify with the new shard count
+	/// instead. All shards of the user have to be moved to the new shard count
+	/// for them to be able to identify again.
 	///
-	/// Due to the possibly large number of roles and users returned by the
-	/// database, this method should only be executed once. The [RoleUserMap]
-	/// should be kept synchronized with the database through means that do not
-	/// involve this method.
+	/// ## Errors
 	///
-	/// TODO:
-	/// Things that need to be accounted for:
-	/// - Users who delete or deactivate their accounts
-	/// - Users who are deleted from the database
-	/// - Users creating an account while the server is running
+	/// Returns [GatewayError::InvalidShard] if `to` is not a valid shard.
 	///
-	/// If any of these are not accounted for, the RoleUserMap could get out of
-	/// sync with the database. This could result in users not receiving events
-	/// or errors when trying to send an event to a user that no longer exists.
-	pub async fn init(&mut self, db: &PgPool) -> Result<(), Error> {
-		// First, get all role ids from the roles table and insert them into the map
-		let all_role_ids: Vec<PgU64> =
-			sqlx::query_as("SELECT id FROM roles").fetch_all(db).await.map_err(Error::Sqlx)?;
-		for role_id in all_role_ids.iter() {
-			self.map.insert(Snowflake::from(role_id.to_uint()), HashSet::new());
+	/// ## Locking
+	///
+	/// This method acquires a read lock on the user's shard of `users`, the
+	/// lock of the [GatewayUser] and then the lock of each of their
+	/// [GatewayClient]s, one after another. The lock of the [GatewayUser] is
+	/// released before any session is asked to reconnect.
+	pub async fn move_user_between_shards(
+		&self,
+		user_id: Snowflake,
+		from: (u64, u64),
+		to: (u64, u64),
+	) -> Result<usize, GatewayError> {
+		shard::validate_shard(Some(to), [])?;
+		let Some(user) = self.users.get(user_id) else {
+			return Ok(0);
+		};
+		let clients = GatewayUser::clients_of(&user).await;
+		let mut moved = 0;
+		for client in clients {
+			let mut client = client.lock().await;
+			if client.shard().await != Some(from) {
+				continue;
+			}
+			moved += 1;
+			if from.1 == to.1 {
+				*client.shard.lock().await = Some(to);
+				continue;
+			}
+			if let Err(e) = client.send_reconnect(self.clone()).await {
+				session_log!(target: "symfonia::gateway::ConnectedUsers::move_user_between_shards", log::Level::Debug, &client.log_context, "Failed to ask client to reconnect: {e}");
+			}
 		}
-		// Then, query member_roles and insert the user ids into the map
-		let all_member_roles: Vec<(PgU64, PgU64)> =
-			sqlx::query_as("SELECT index, role_id FROM member_roles")
-				.fetch_all(db)
-				.await
-				.map_err(Error::Sqlx)?;
-		for (user_id, role_id) in all_member_roles.iter() {
-			// Unwrapping is fine here, as the member_roles table has a foreign key
-			// constraint which states that role_id must be a valid id in the roles
-			// table.
-			let users_for_role_id = self.map.get_mut(&role_id.to_uint().into()).unwrap();
-			users_for_role_id.insert(user_id.to_uint().into());
+		log::debug!(target: "symfonia::gateway::ConnectedUsers::move_user_between_shards", "Moved {moved} session(s) of user {user_id} from shard {from:?} to {to:?}");
+		Ok(moved)
+	}
+
+	/// Ask every connected client to reconnect (opcode 7), so that it can
+	/// connect to another node before this one shuts down. Each connection is
+	/// closed with close code 1001 once the reconnect has been flushed, and
+	/// its session is ended with [KillReason::ServerShutdown], leaving it
+	/// resumable. Waits up to [CLOSE_FLUSH_TIMEOUT] for the connections to
+	/// close.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a read lock on each shard of `users`, the lock of
+	/// each [GatewayUser] and then the lock of each of their [GatewayClient]s,
+	/// one after another.
+	pub async fn shutdown(&self) {
+		let mut clients = Vec::new();
+		for user in self.users.values() {
+			clients.extend(user.lock().await.clients.values().cloned());
+		}
+		let mut connections = Vec::with_capacity(clients.len());
+		for client in clients {
+			let mut client = client.lock().await;
+			let reconnect = client.send_payload(&GatewayPayload::<()> {
+				op_code: Opcode::Reconnect as u8,
+				event_data: None,
+				sequence_number: None,
+				event_name: None,
+			});
+			// Closing flushes the reconnect queued before it.
+			let closed = client.connection.close(KillReason::ServerShutdown.close_frame());
+			if let Err(e) = reconnect.and(closed) {
+				session_log!(target: "symfonia::gateway::ConnectedUsers::shutdown", log::Level::Debug, &client.log_context, "Failed to ask client to reconnect: {e}");
+			}
+			connections.push(client.connection.clone());
+			if let Err(e) = client.die(self.clone(), KillReason::ServerShutdown).await {
+				session_log!(target: "symfonia::gateway::ConnectedUsers::shutdown", log::Level::Debug, &client.log_context, "Error while ending session: {e}");
+			}
+		}
+		let flushed = async {
+			while !connections.iter().all(WebSocketConnection::is_closed) {
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		};
+		if tokio::time::timeout(CLOSE_FLUSH_TIMEOUT, flushed).await.is_err() {
+			log::debug!(target: "symfonia::gateway::ConnectedUsers::shutdown", "Timed out waiting for connections to close");
+		}
+		log::info!(target: "symfonia::gateway::ConnectedUsers::shutdown", "Asked {} client(s) to reconnect", connections.len());
+	}
+
+	/// Store the voice state of the user with the ID `user_id` and broadcast it
+	/// to the members of the guild the voice state belongs to.
+	///
+	/// ## Locking
+	///
+	/// This method acquires the lock on `voice_states`, followed by the locks
+	/// described in [BulkMessageBuilder::send].
+	pub async fn update_voice_state(
+		&self,
+		user_id: Snowflake,
+		mut state: VoiceState,
+	) -> Result<(), Error> {
+		state.user_id = user_id;
+		self.voice_states.lock().await.update(state.clone());
+		self.dispatch_voice_state(state).await
+	}
+
+	/// Remove all voice states of the user with the ID `user_id` and broadcast
+	/// that they left their voice channels.
+	///
+	/// ## Locking
+	///
+	/// This method acquires the lock on `voice_states`, followed by the locks
+	/// described in [BulkMessageBuilder::send].
+	pub async fn clear_voice_states(&self, user_id: Snowflake) -> Result<(), Error> {
+		let removed = self.voice_states.lock().await.remove_user(user_id);
+		for state in removed {
+			self.dispatch_voice_state(state).await?;
 		}
 		Ok(())
 	}
-}
 
-/// Connection to a WebSocket client with sending and receiving capabilities.
-///
-/// A [WebSocketConnection] is essentially an adapter from tungstenites
-/// sink/stream to a [tokio::sync::broadcast] channel. Broadcast channels are
-/// used in favor of sink/stream, because to clone a sink/stream to pass it
-/// around to different tasks which need sending/receiving capabilities, an
-/// `Arc<Mutex<T>>` has to be used. This means, that no more than one task can
-/// listen for incoming messages at a time, as a lock on the [Mutex] has to be
-/// acquired.
-///
-/// Read up on [tokio::sync::broadcast] channels if you'd like to understand how
-/// they work.
-pub struct WebSocketConnection {
-	pub sender: tokio::sync::broadcast::Sender<Message>,
-	pub receiver: tokio::sync::broadcast::Receiver<Message>,
-	pub kill_receive: tokio::sync::broadcast::Receiver<()>,
-	/// Callsites of `kill_send` are always responsible for sending a close
-	/// message to the client.
-	pub kill_send: tokio::sync::broadcast::Sender<()>,
-	sender_task: Arc<tokio::task::JoinHandle<()>>,
-	receiver_task: Arc<tokio::task::JoinHandle<()>>,
-}
+	/// Send a `VOICE_STATE_UPDATE` for `state` to the user it belongs to and,
+	/// if it belongs to a guild, to all members of that guild.
+	async fn dispatch_voice_state(&self, state: VoiceState) -> Result<(), Error> {
+		let mut builder = self.bulk_message_builder();
+		builder.add_user_recipients(&[state.user_id]).await;
+		if let Some(guild_id) = state.guild_id {
+			// The @everyone role has the same ID as the guild
+			builder.add_role_recipients(&[guild_id]).await;
+		}
+		builder
+			.set_message(Event::Dispatch(DispatchEvent::VoiceStateUpdate(
+				GatewayPayload::dispatch(
+					"VOICE_STATE_UPDATE",
+					VoiceStateUpdate { state, ..Default::default() },
+				),
+			)))
+			.await;
+		builder.send(self.clone()).await
+	}
 
-impl WebSocketConnection {
-	/// Create a new [WebSocketConnection] from a tungstenite Sink/Stream pair.
-	pub fn new(mut sink: WebSocketSend, mut stream: WebSocketReceive) -> Self {
-		// "100" is an arbitrary limit. Feel free to adjust this, if you have a good
-		// reason for it. -bitfl0wer
-		let (mut websocketsend_sender, mut websocketsend_receiver) =
-			tokio::sync::broadcast::channel(100);
-		let (mut websocketreceive_sender, mut websocketreceive_receiver) =
-			tokio::sync::broadcast::channel(100);
+	/// Update the presence of the session identified by `session_token` and
+	/// broadcast the resulting, aggregated presence of the user to themselves,
+	/// to all users sharing a role (and thus, a guild) with them and to the
+	/// users with sessions subscribed to their presence. Which of the sessions
+	/// of these users get the presence is decided by [Self::receives_presence].
+	///
+	/// ## Locking
+	///
+	/// This method acquires a read lock on the user's shard of `users`, the
+	/// lock of the [GatewayUser], the lock of the [GatewayClient] of the
+	/// session, the locks described in [GatewayUser::aggregated_presence], the
+	/// lock on `role_user_map`, a read lock on `presence_subscriptions` and a
+	/// read lock on `store`, one after another.
+	pub async fn update_presence(
+		&self,
+		user_id: Snowflake,
+		session_token: &SessionToken,
+		status: UserStatus,
+	) -> Result<(), Error> {
+		let Some(user) = self.users.get(user_id) else {
+			return Ok(());
+		};
+		let Some(client) = user.lock().await.clients.get(session_token).cloned() else {
+			return Ok(());
+		};
+		client.lock().await.set_presence(status);
+		let aggregated_presence = GatewayUser::aggregated_presence(&user).await;
 
-		// The sender task concerns itself with sending messages to the WebSocket
-		// client.
-		let sender_task = tokio::spawn(async move {
-			log::trace!(target: "symfonia::gateway::types::WebSocketConnection", "spawned sender_task");
-			loop {
-				let message: Result<Message, tokio::sync::broadcast::error::RecvError> =
-					websocketsend_receiver.recv().await;
-				match message {
-					Ok(msg) => {
-						let send_result = sink.send(msg).await;
-						match send_result {
-							Ok(_) => (),
-							Err(e) => {
-								log::debug!(target: "symfonia::gateway::types::WebSocketConnection::sender_task", "Error when sending message to WebSocket: {e}");
-								break;
-							}
-						}
-					}
-					Err(e) => {
-						log::debug!(target: "symfonia::gateway::types::WebSocketConnection::sender_task", "Error when trying to receive through websocketsend_receiver: {e}");
-						break;
-					}
-				}
+		let mut recipients = HashSet::from([user_id]);
+		let role_user_map = self.role_user_map.lock().await;
+		for role_id in role_user_map.roles_of(user_id) {
+			if let Some(users) = role_user_map.get(&role_id) {
+				recipients.extend(users.iter().copied());
 			}
-		});
+		}
+		drop(role_user_map);
+		let subscribers = self.presence_subscriptions.read().subscribers_of(user_id);
+		let store = self.store.read();
+		recipients.extend(subscribers.iter().filter_map(|token| store.session_tokens.get(token)));
+		drop(store);
+		let recipients = recipients.into_iter().collect::<Vec<_>>();
 
-		// The receiver task receives messages from the WebSocket client and sends them
-		// to the broadcast channel.
-		let receiver_task = tokio::spawn(async move {
-			log::trace!(target: "symfonia::gateway::types::WebSocketConnection", "spawned receiver_task");
-			loop {
-				let web_socket_receive_result = match stream.next().await {
-					Some(res) => res,
-					None => {
-						log::debug!(target: "symfonia::gateway::WebSocketConnection::receiver_task", "WebSocketReceive yielded None. Closing channel");
-						break;
-					}
-				};
-				let web_socket_receive_message = match web_socket_receive_result {
-					Ok(message) => message,
-					Err(e) => {
-						log::debug!(target: "symfonia::gateway::WebSocketConnection::receiver_task", "Received malformed message, closing channel: {e}");
-						break;
-					}
-				};
-				match websocketreceive_sender.send(web_socket_receive_message) {
-					Ok(_) => (),
-					Err(e) => {
-						log::debug!(target: "symfonia::gateway::WebSocketConnection::receiver_task", "Unable to send received WebSocket message to channel recipients. Closing channel: {e}");
-						break;
+		let mut builder = self.bulk_message_builder();
+		builder.add_user_recipients(&recipients).await;
+		builder
+			.set_message(Event::Dispatch(DispatchEvent::PresenceUpdate(GatewayPayload::dispatch(
+				"PRESENCE_UPDATE",
+				PresenceUpdate {
+					user: PublicUser { id: user_id, ..Default::default() },
+					status: aggregated_presence,
+					..Default::default()
+				},
+			))))
+			.await;
+		builder.send(self.clone()).await
+	}
+
+	/// Subscribe the session of the user with the ID `user_id` identified by
+	/// `session_token` to the presences of the users requested in `request`, as
+	/// sent by its client when it shows a guild or a DM.
+	///
+	/// Only the first [MAX_LAZY_REQUEST_MEMBERS] users of the request are
+	/// considered. Of these, a request for a guild only subscribes to the
+	/// members of that guild, and only if the user is a member as well. A
+	/// request without a guild only subscribes to the users sharing a private
+	/// channel with the user. Other users are ignored, so that sessions cannot
+	/// watch the presences of arbitrary users.
+	///
+	/// ## Locking
+	///
+	/// This method acquires the lock on `role_user_map` for requests for a
+	/// guild, then a write lock on `presence_subscriptions`.
+	pub async fn subscribe_to_presences(
+		&self,
+		db: &PgPool,
+		user_id: Snowflake,
+		session_token: &SessionToken,
+		request: LazyRequest,
+	) -> Result<(), Error> {
+		let mut members = request.members;
+		members.truncate(MAX_LAZY_REQUEST_MEMBERS);
+		match request.guild_id {
+			Some(guild_id) => {
+				let role_user_map = self.role_user_map.lock().await;
+				// Every member has the @everyone role, which has the same ID as the guild.
+				match role_user_map.get(&guild_id) {
+					Some(guild_members) if guild_members.contains(&user_id) => {
+						members.retain(|member| guild_members.contains(member))
 					}
+					_ => members.clear(),
 				}
 			}
-		});
-		let (kill_send, kill_receive) = tokio::sync::broadcast::channel(1);
-		Self {
-			sender: websocketsend_sender,
-			receiver: websocketreceive_receiver,
-			sender_task: Arc::new(sender_task),
-			receiver_task: Arc::new(receiver_task),
-			kill_receive,
-			kill_send,
+			None => {
+				let contacts = Recipient::get_contact_ids(db, user_id).await?;
+				members.retain(|member| contacts.contains(member));
+			}
 		}
+		self.presence_subscriptions.write().subscribe(session_token, members);
+		Ok(())
 	}
-}
 
-impl Clone for WebSocketConnection {
-	fn clone(&self) -> Self {
-		log::trace!(target: "symfonia::gateway::WebSocketConnection", "WebSocketConnection cloned!");
-		Self {
-			sender: self.sender.clone(),
-			receiver: self.receiver.resubscribe(),
-			sender_task: self.sender_task.clone(),
-			receiver_task: self.receiver_task.clone(),
-			kill_receive: self.kill_receive.resubscribe(),
-			kill_send: self.kill_send.clone(),
+	/// Whether the session of the user with the ID `user_id` identified by
+	/// `session_token` is sent the presence of the user with the ID
+	/// `presence_of`. Sessions receive their own user's presence, the
+	/// presences they have subscribed to and, if they have the
+	/// [GUILD_PRESENCES](intents::GUILD_PRESENCES) intent, the presences of the
+	/// members of their guilds.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a read lock on `presence_subscriptions`, then the
+	/// locks described in [Self::client_by_token], the lock of the
+	/// [GatewayClient] and the lock on `role_user_map`, one after another.
+	pub async fn receives_presence(
+		&self,
+		user_id: Snowflake,
+		session_token: &SessionToken,
+		presence_of: Snowflake,
+	) -> bool {
+		if presence_of == user_id
+			|| self.presence_subscriptions.read().is_subscribed(session_token, presence_of)
+		{
+			return true;
+		}
+		let Some(client) = self.client_by_token(session_token).await else {
+			return false;
+		};
+		if client.lock().await.intents() & intents::GUILD_PRESENCES == 0 {
+			return false;
 		}
+		let role_user_map = self.role_user_map.lock().await;
+		!role_user_map.roles_of(user_id).is_disjoint(&role_user_map.roles_of(presence_of))
 	}
 }
 
-#[derive(Clone)]
-pub struct DisconnectInfo {
-	/// session token that was used for this connection
-	pub session_token: String,
-	pub disconnected_at_sequence: u64,
-	pub parent: Weak<Mutex<GatewayUser>>,
+impl std::hash::Hash for GatewayUser {
+	fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+		self.id.hash(state);
+	}
 }
 
-impl
-	From<(
-		SplitSink<WebSocketStream<TcpStream>, tokio_tungstenite::tungstenite::Message>,
-		SplitStream<WebSocketStream<TcpStream>>,
-	)> for WebSocketConnection
-{
-	fn from(
-		value: (
-			SplitSink<WebSocketStream<TcpStream>, tokio_tungstenite::tungstenite::Message>,
-			SplitStream<WebSocketStream<TcpStream>>,
-		),
-	) -> Self {
-		Self::new(value.0, value.1)
+impl PartialEq for GatewayUser {
+	fn eq(&self, other: &Self) -> bool {
+		self.id == other.id
 	}
 }
 
-/// Represents a new successful connection to the gateway. The user is already
-/// part of the [ConnectedUsers] and the client is already registered with the
-/// [GatewayClient] "clients" map.
-pub struct NewWebSocketConnection {
-	pub user: Arc<Mutex<GatewayUser>>,
-	pub client: Arc<Mutex<GatewayClient>>,
+impl Eq for GatewayUser {}
+
+impl Drop for GatewayUser {
+	/// Users are normally deregistered by [GatewayClient::die] once their last
+	/// session ends. If that did not happen, for example because a session
+	/// task panicked, the user stays in `users` until
+	/// [ConnectedUsers::sweep_stale_users] removes it. Once it is dropped, or
+	/// if its entry in `users` has been replaced, the inbox of this user and
+	/// the session tokens of its remaining clients are removed here, so that
+	/// they do not outlive it. Inboxes which have been replaced meanwhile, for
+	/// example by a newer [GatewayUser] with the same ID, are left alone.
+	///
+	/// ## Locking
+	///
+	/// This method acquires a write lock on the user's shard of `inboxes`, a
+	/// write lock on `store` and a write lock on `presence_subscriptions`, one
+	/// after another.
+	fn drop(&mut self) {
+		let stale_inbox = self
+			.connected_users
+			.inboxes
+			.remove_if(self.id, |inbox| inbox.same_channel(&self.outbox));
+		if stale_inbox.is_some() {
+			log::debug!(target: "symfonia::gateway::GatewayUser::drop", "Removed inbox of user {}, who has not been deregistered", self.id);
+		}
+		if self.clients.is_empty() {
+			return;
+		}
+		let mut store = self.connected_users.store.write();
+		for session_token in self.clients.keys() {
+			store.session_tokens.remove(session_token);
+		}
+		drop(store);
+		let mut presence_subscriptions = self.connected_users.presence_subscriptions.write();
+		for session_token in self.clients.keys() {
+			presence_subscriptions.forget(session_token);
+		}
+	}
+}
+
+impl GatewayClient {
+	/// The presence this session has set for itself.
+	pub fn presence(&self) -> &UserStatus {
+		&self.presence
+	}
+
+	/// The `(shard_id, shard_count)` this session is on, if any. This is the
+	/// shard it identified with, unless it has been moved since.
+	pub async fn shard(&self) -> Option<(u64, u64)> {
+		*self.shard.lock().await
+	}
+
+	/// Identifies this session in log lines.
+	pub fn log_context(&self) -> &SessionLogContext {
+		&self.log_context
+	}
+
+	/// Guilds with more members than this are sent to this session without
+	/// their offline members.
+	pub fn large_threshold(&self) -> u64 {
+		self.large_threshold
+	}
+
+	/// Set the `large_threshold` of this session, as requested when
+	/// identifying.
+	pub fn set_large_threshold(&mut self, large_threshold: u64) {
+		self.large_threshold = large_threshold;
+	}
+
+	/// The [intents] of this session, deciding which events it receives.
+	pub fn intents(&self) -> u64 {
+		self.intents
+	}
+
+	/// Set the [intents] of this session, as established when identifying.
+	pub fn set_intents(&mut self, intents: u64) {
+		self.intents = intents;
+	}
+
+	/// The client this session has been opened with.
+	pub fn properties(&self) -> &ClientProperties {
+		&self.properties
+	}
+
+	/// Set the client this session has been opened with, as reported when
+	/// identifying.
+	pub fn set_properties(&mut self, properties: ClientProperties) {
+		self.properties = properties;
+	}
+
+	/// A snapshot of the metadata of this session.
+	pub async fn session_info(&self) -> SessionInfo {
+		SessionInfo {
+			session_token: self.session_token.masked(),
+			connected_at: self.connected_at,
+			last_sequence: *self.last_sequence.lock().await,
+			shard: *self.shard.lock().await,
+			properties: self.properties.clone(),
+		}
+	}
+
+	/// Set the presence of this session.
+	pub fn set_presence(&mut self, status: UserStatus) {
+		self.presence = status;
+	}
+
+	/// The stage of its lifecycle this session is in.
+	pub fn state(&self) -> ConnectionState {
+		self.state
+	}
+
+	/// Move this session to the [ConnectionState] `to`. See
+	/// [ConnectionState::transition].
+	pub fn transition_to(&mut self, to: ConnectionState) -> Result<(), GatewayError> {
+		self.state.transition(to)
+	}
+
+	/// Send `event` to this session only.
+	///
+	/// Use this for events that concern a single client. Events which all
+	/// sessions of a user should receive, such as a new message, go to the
+	/// inbox of the [GatewayUser] instead, for example through a
+	/// [BulkMessageBuilder].
+	///
+	/// ## Errors
+	///
+	/// Dispatch events are rejected with [GatewayError::NotReady] unless the
+	/// session is [ConnectionState::Ready].
+	pub fn send(&self, event: Event) -> Result<(), GatewayError> {
+		if matches!(event, Event::Dispatch(_)) && !self.state.accepts_dispatches() {
+			return Err(GatewayError::NotReady);
+		}
+		self.connection.send_encoded(&event)
+	}
+
+	/// Send the already encoded `message` to this session only, like
+	/// [Self::send], without encoding it again. The message is passed on as
+	/// is, so it has to be encoded with the codec and compression of this
+	/// session, see [WebSocketConnection::encode]. Unlike [Self::send], the
+	/// state of the session is not checked.
+	///
+	/// ## Errors
+	///
+	/// Returns [GatewayError::Closed] if the connection of this session no
+	/// longer forwards messages to its client.
+	pub fn send_raw(&self, message: Message) -> Result<(), GatewayError> {
+		self.connection.try_send(message)
+	}
+
+	/// Ask the client to reconnect (opcode 7), then disconnect it using
+	/// [Self::die].
+	pub async fn send_reconnect(
+		&mut self,
+		connected_users: ConnectedUsers,
+	) -> Result<(), GatewayError> {
+		let sent = self.send_payload(&GatewayPayload::<()> {
+			op_code: Opcode::Reconnect as u8,
+			event_data: None,
+			sequence_number: None,
+			event_name: None,
+		});
+		// The session has to end either way.
+		let died = self.die(connected_users, KillReason::Reconnect).await;
+		sent.and(died)
+	}
+
+	/// Tell the client that its session is invalid (opcode 9). `resumable`
+	/// indicates whether the client may try to resume the session.
+	pub fn send_invalid_session(&self, resumable: bool) -> Result<(), GatewayError> {
+		self.send_payload(&GatewayPayload {
+			op_code: Opcode::InvalidSession as u8,
+			event_data: Some(resumable),
+			sequence_number: None,
+			event_name: None,
+		})
+	}
+
+	/// Serialize `payload` and push it through the connection of this client.
+	fn send_payload<T: Serialize + DeserializeOwned>(
+		&self,
+		payload: &GatewayPayload<T>,
+	) -> Result<(), GatewayError> {
+		self.connection.send_encoded(payload)
+	}
+
+	/// Disconnects a [GatewayClient] properly, including un-registering it from
+	/// the memory store and creating a resumeable session. The connection is
+	/// closed with the close frame `reason` maps to, after the messages still
+	/// queued for the client, and the tasks of the session are told about the
+	/// `reason` through the kill switch, see [WebSocketConnection::kill].
+	///
+	/// ## Errors
+	///
+	/// Returns [GatewayError::ParentDropped] if the [GatewayUser] this client
+	/// belongs to has already been dropped, for example during shutdown. The
+	/// kill switch is still fired and the resumeable session is still created
+	/// in that case; only the cleanup steps involving the parent are skipped.
+	///
+	/// ## Locking
+	///
+	/// This method is called with the lock of this client held. It acquires
+	/// the lock of the parent [GatewayUser] while removing this client from
+	/// it, which the lock ordering described in [ConnectedUsers] permits, then
+	/// a write lock on `store`, a write lock on `presence_subscriptions` and,
+	/// if this was the last session of the user, the locks described in
+	/// [ConnectedUsers::clear_voice_states], one after another.
+	pub async fn die(
+		&mut self,
+		connected_users: ConnectedUsers,
+		reason: KillReason,
+	) -> Result<(), GatewayError> {
+		self.state = ConnectionState::Dead;
+		if let Err(e) = self.connection.kill(reason) {
+			// Nobody is listening for the kill signal, meaning that the tasks of this
+			// session have already stopped. Cleaning up is still necessary.
+			session_log!(target: "symfonia::gateway::GatewayClient::die", log::Level::Debug, &self.log_context, "{e}");
+		}
+		let disconnect_info = DisconnectInfo {
+			session_token: self.session_token.clone(),
+			user_id: self.user_id,
+			disconnected_at_sequence: *self.last_sequence.lock().await,
+			shard: *self.shard.lock().await,
+			recent_dispatches: self.recent_dispatches.lock().await.snapshot(),
+			intents: self.intents,
+			compress: self.connection.compresses_payloads(),
+			properties: self.properties.clone(),
+			reason,
+			disconnected_at: SystemTime::now(),
+		};
+		let (result, last_session_of) = match self.parent.upgrade() {
+			Some(parent) => {
+				let mut parent = parent.lock().await;
+				parent.clients.remove(&self.session_token);
+				if parent.clients.is_empty() {
+					connected_users.deregister(parent.deref());
+					(Ok(()), Some(parent.id))
+				} else {
+					(Ok(()), None)
+				}
+			}
+			None => {
+				session_log!(target: "symfonia::gateway::GatewayClient::die", log::Level::Debug, &self.log_context, "Parent of session is gone. Skipping removal from the parent");
+				(Err(GatewayError::ParentDropped), None)
+			}
+		};
+		connected_users.metrics.record_close(reason);
+		connected_users.store.write().session_tokens.remove(&self.session_token);
+		connected_users.resumable_clients.insert(self.session_token.clone(), disconnect_info).await;
+		connected_users.presence_subscriptions.write().forget(&self.session_token);
+		if let Some(user_id) = last_session_of {
+			// A user without sessions cannot be in a voice channel.
+			if let Err(e) = connected_users.clear_voice_states(user_id).await {
+				session_log!(target: "symfonia::gateway::GatewayClient::die", log::Level::Debug, &self.log_context, "Failed to clear voice states: {e}");
+			}
+		}
+		session_log!(target: "symfonia::gateway::GatewayClient::die", log::Level::Debug, &self.log_context, "Session ended");
+		result
+	}
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+/// Outcome of sending an [Event] to many users at once.
+pub struct BulkSendReport {
+	/// Number of users the event has been delivered to.
+	pub delivered: usize,
+	/// Snowflake IDs of the users the event could not be delivered to.
+	pub failed: Vec<Snowflake>,
+}
+
+#[derive(Default, Clone)]
+/// `BulkMessageBuilder` can be used to build and send GatewayMessages to the
+/// inboxes of all currently connected [GatewayClients](GatewayClient).
+/// Recipients can be added either via User or Role snowflake IDs.
+pub struct BulkMessageBuilder {
+	users: Vec<Snowflake>,
+	roles: Vec<Snowflake>,
+	excluded: Vec<Snowflake>,
+	/// `(guild_id, permission)` pairs recipients must hold.
+	required_permissions: Vec<(Snowflake, PermissionFlags)>,
+	message: Option<Event>,
+}
+
+impl BulkMessageBuilder {
+	/// Add the given list of user snowflake IDs to the list of recipients.
+	pub async fn add_user_recipients(&mut self, users: &[Snowflake]) {
+		self.users.extend_from_slice(users);
+	}
+
+	/// Add all members which have the given role snowflake IDs to the list of
+	/// recipients.
+	pub async fn add_role_recipients(&mut self, roles: &[Snowflake]) {
+		self.roles.extend_from_slice(roles);
+	}
+
+	/// Exclude the given list of user snowflake IDs from the recipients, even
+	/// if they have been added directly or through one of their roles.
+	pub async fn exclude_user_recipients(&mut self, users: &[Snowflake]) {
+		self.excluded.extend_from_slice(users);
+	}
+
+	/// Only send the message to recipients which have `permission` in the
+	/// guild with the ID `guild_id`, for events not every member may see.
+	/// Permissions are computed from the roles of the recipients, as recorded
+	/// in the [RoleUserMap].
+	pub async fn require_permission(&mut self, guild_id: Snowflake, permission: PermissionFlags) {
+		self.required_permissions.push((guild_id, permission));
+	}
+
+	/// Set the message to be sent to the recipients.
+	pub async fn set_message(&mut self, message: Event) {
+		self.message = Some(message);
+	}
+
+	/// Send the message to all recipients. Messages, typing indicators and
+	/// presences are not sent to recipients who have blocked their author.
+	///
+	/// Fails if the message could not be delivered to one of the recipients.
+	/// See [Self::send_with_report] for details.
+	pub async fn send(self, connected_users: ConnectedUsers) -> Result<(), Error> {
+		let report = self.send_with_report(connected_users).await?;
+		if !report.failed.is_empty() {
+			return Err(GatewayError::BroadcastFailed(format!(
+				"Failed to deliver to {} recipients",
+				report.failed.len()
+			))
+			.into());
+		}
+		Ok(())
+	}
+
+	/// Send the message to all recipients, like [Self::send], and report to
+	/// whom it has been delivered. Recipients which are not connected are
+	/// neither counted as delivered nor as failed.
+	///
+	/// The message is delivered in batches of as many inboxes as configured
+	/// with [ConnectedUsers::set_dispatch_concurrency]. The task yields after
+	/// each batch, so that fan-outs to huge guilds do not hold up other tasks.
+	/// A failed delivery does not stop the delivery to the remaining
+	/// recipients.
+	///
+	/// Messages scoped to a guild are handed to the [GuildSequencer], which
+	/// delivers them after all messages of that guild sent before, so that
+	/// every recipient receives the events of a guild in the order they have
+	/// been produced in. Their position is taken when this method is first
+	/// polled.
+	///
+	/// ## Errors
+	///
+	/// Returns [GatewayError::Misrouted] without sending anything if the
+	/// message may not be sent to the inboxes of users.
+	///
+	/// ## Locking
+	///
+	/// This method acquires the locks described in [GuildSequencer::enqueue]
+	/// if the message is scoped to a guild. The message is then delivered by
+	/// the worker of the guild, while this method waits for it to finish.
+	///
+	/// Delivering acquires a read lock on `routing_table`, then the lock on
+	/// `role_user_map` and a read lock on `blocks` while collecting the
+	/// recipients, then a read lock on `store`. While delivering, read locks
+	/// on the shards of `inboxes` are acquired just long enough to look up
+	/// each inbox.
+	pub async fn send_with_report(
+		self,
+		connected_users: ConnectedUsers,
+	) -> Result<BulkSendReport, Error> {
+		let Some(guild_id) = self.message.as_ref().and_then(Event::guild_id) else {
+			return self.deliver(connected_users).await;
+		};
+		let outcome =
+			connected_users.guild_sequencer.enqueue(guild_id, self, connected_users.clone());
+		// The worker only drops the sender without an outcome if it panicked.
+		outcome.await.map_err(|_| GatewayError::Internal)?
+	}
+
+	/// Deliver the message to all recipients right away. See
+	/// [Self::send_with_report].
+	async fn deliver(self, connected_users: ConnectedUsers) -> Result<BulkSendReport, Error> {
+		let Some(message) = self.message.as_ref() else {
+			return Err(Error::Custom("No message to send".to_string()));
+		};
+		connected_users.check_user_route(message)?;
+		let recipients = self.recipients(&connected_users).await.into_iter().collect::<Vec<_>>();
+		let concurrency = connected_users.store.read().dispatch_concurrency;
+		let mut report = BulkSendReport::default();
+		for batch in recipients.chunks(concurrency) {
+			let outcomes = futures::future::join_all(batch.iter().map(|recipient| async {
+				let inbox = connected_users.inbox(*recipient).await?;
+				Some((*recipient, inbox.send(message.clone())))
+			}))
+			.await;
+			for (recipient, outcome) in outcomes.into_iter().flatten() {
+				match outcome {
+					Ok(_) => {
+						connected_users.metrics.record(message);
+						report.delivered += 1;
+					}
+					Err(e) => {
+						log::debug!(target: "symfonia::gateway::BulkMessageBuilder::send_with_report", "Failed to send event to user {recipient}: {e}");
+						report.failed.push(recipient);
+					}
+				}
+			}
+			tokio::task::yield_now().await;
+		}
+		Ok(report)
+	}
+
+	/// The Snowflake IDs of the users the message is sent to.
+	async fn recipients(&self, connected_users: &ConnectedUsers) -> HashSet<Snowflake> {
+		let mut recipients = HashSet::new();
+		let lock = connected_users.role_user_map.lock().await;
+		for role in self.roles.iter() {
+			if let Some(users) = lock.get(role) {
+				for user in users.iter() {
+					recipients.insert(*user);
+				}
+			}
+		}
+		for user in self.users.iter() {
+			recipients.insert(*user);
+		}
+		for user in self.excluded.iter() {
+			recipients.remove(user);
+		}
+		for (guild_id, permission) in self.required_permissions.iter() {
+			let permitted = lock.users_with_permission(*guild_id, *permission);
+			recipients.retain(|user| permitted.contains(user));
+		}
+		if let Some(author_id) = self.message.as_ref().and_then(Event::author_id) {
+			let blocks = connected_users.blocks.read();
+			recipients.retain(|user| !blocks.has_blocked(*user, author_id));
+		}
+		recipients
+	}
+}
+
+#[derive(Default)]
+/// Represents all existing roles on the server and the users that have these
+/// roles.
+pub struct RoleUserMap {
+	/// Map Role Snowflake ID to a list of User Snowflake IDs
+	map: HashMap<Snowflake, HashSet<Snowflake>>,
+	/// Map User Snowflake ID to a list of Role Snowflake IDs. The inverse of
+	/// `map`, kept in sync with it.
+	user_roles: HashMap<Snowflake, HashSet<Snowflake>>,
+	/// Map Role Snowflake ID to the Snowflake ID of the guild of the role and
+	/// the permissions the role grants.
+	role_permissions: HashMap<Snowflake, (Snowflake, PermissionFlags)>,
+	/// Whether [RoleUserMap::init] has already populated this map.
+	initialized: bool,
+}
+
+impl Deref for RoleUserMap {
+	type Target = HashMap<Snowflake, HashSet<Snowflake>>;
+
+	fn deref(&self) -> &Self::Target {
+		&self.map
+	}
+}
+
+impl RoleUserMap {
+	/// Record that the user with the ID `user_id` has the role with the ID
+	/// `role_id`. Creates the role in the map if it does not exist yet.
+	pub fn grant_role(&mut self, role_id: Snowflake, user_id: Snowflake) {
+		self.map.entry(role_id).or_default().insert(user_id);
+		self.user_roles.entry(user_id).or_default().insert(role_id);
+	}
+
+	/// Record that the user with the ID `user_id` no longer has the role with
+	/// the ID `role_id`.
+	pub fn revoke_role(&mut self, role_id: Snowflake, user_id: Snowflake) {
+		if let Some(users) = self.map.get_mut(&role_id) {
+			users.remove(&user_id);
+		}
+		if let Some(roles) = self.user_roles.get_mut(&user_id) {
+			roles.remove(&role_id);
+			if roles.is_empty() {
+				self.user_roles.remove(&user_id);
+			}
+		}
+	}
+
+	/// Record that the user with the ID `user_id` has joined the guild with the
+	/// ID `guild_id`. New members only have the @everyone role, which has the
+	/// same ID as the guild.
+	pub fn add_member(&mut self, guild_id: Snowflake, user_id: Snowflake) {
+		self.grant_role(guild_id, user_id);
+	}
+
+	/// Record that the user with the ID `user_id` has left the guild with the
+	/// ID `guild_id`, revoking every role of the guild they had. Roles of other
+	/// guilds are kept.
+	pub fn remove_member(&mut self, guild_id: Snowflake, user_id: Snowflake) {
+		let guild_roles = self
+			.roles_of(user_id)
+			.into_iter()
+			.filter(|role_id| {
+				*role_id == guild_id
+					|| self
+						.role_permissions
+						.get(role_id)
+						.is_some_and(|(role_guild_id, _)| *role_guild_id == guild_id)
+			})
+			.collect::<Vec<_>>();
+		for role_id in guild_roles {
+			self.revoke_role(role_id, user_id);
+		}
+	}
+
+	/// Add the role with the ID `role_id`, which belongs to the guild with the
+	/// ID `guild_id` and grants `permissions`, so that grants of it can be
+	/// recorded. Users already having the role keep it.
+	pub fn add_role(
+		&mut self,
+		role_id: Snowflake,
+		guild_id: Snowflake,
+		permissions: PermissionFlags,
+	) {
+		self.map.entry(role_id).or_default();
+		self.set_role_permissions(role_id, guild_id, permissions);
+	}
+
+	/// Remove the role with the ID `role_id` from the map, along with the
+	/// records of which users have it.
+	pub fn remove_role(&mut self, role_id: Snowflake) {
+		for user_id in self.map.remove(&role_id).unwrap_or_default() {
+			if let Some(roles) = self.user_roles.get_mut(&user_id) {
+				roles.remove(&role_id);
+				if roles.is_empty() {
+					self.user_roles.remove(&user_id);
+				}
+			}
+		}
+		self.role_permissions.remove(&role_id);
+	}
+
+	/// Record that the role with the ID `role_id` belongs to the guild with the
+	/// ID `guild_id` and grants `permissions`.
+	pub fn set_role_permissions(
+		&mut self,
+		role_id: Snowflake,
+		guild_id: Snowflake,
+		permissions: PermissionFlags,
+	) {
+		self.role_permissions.insert(role_id, (guild_id, permissions));
+	}
+
+	/// Get the IDs of all users having a role in the guild with the ID
+	/// `guild_id` which grants `permission`, either directly or through
+	/// `ADMINISTRATOR`. Permissions granted through channel overwrites or
+	/// guild ownership are not taken into account.
+	pub fn users_with_permission(
+		&self,
+		guild_id: Snowflake,
+		permission: PermissionFlags,
+	) -> HashSet<Snowflake> {
+		self.role_permissions
+			.iter()
+			.filter(|(_, (role_guild_id, permissions))| {
+				*role_guild_id == guild_id && permissions.has_permission(permission)
+			})
+			.filter_map(|(role_id, _)| self.map.get(role_id))
+			.flatten()
+			.copied()
+			.collect()
+	}
+
+	/// Get the IDs of all roles the user with the ID `user_id` has.
+	pub fn roles_of(&self, user_id: Snowflake) -> HashSet<Snowflake> {
+		self.user_roles.get(&user_id).cloned().unwrap_or_default()
+	}
+
+	/// Initialize the [RoleUserMap] with data from the database.
+	///
+	/// This method will query the database for all roles and all users that
+	/// have these roles. The data will then populate the map. Each query is
+	/// attempted up to `attempts` times if it fails with a transient error, see
+	/// [retry_transient].
+	///
+	/// Due to the possibly large number of roles and users returned by the
+	/// database, this method should only be executed once. The [RoleUserMap]
+	/// should be kept synchronized with the database through means that do not
+	/// involve this method, such as [Self::grant_role] or
+	/// [Self::resync_role]. Calling this method on an already initialized map
+	/// logs a warning and does nothing.
+	///
+	/// TODO:
+	/// Things that need to be accounted for:
+	/// - Users who delete or deactivate their accounts
+	/// - Users who are deleted from the database
+	/// - Users creating an account while the server is running
+	///
+	/// If any of these are not accounted for, the RoleUserMap could get out of
+	/// sync with the database. This could result in users not receiving events
+	/// or errors when trying to send an event to a user that no longer exists.
+	pub async fn init(&mut self, db: &PgPool, attempts: u32) -> Result<(), Error> {
+		if self.initialized {
+			log::warn!(target: "symfonia::gateway::RoleUserMap::init", "RoleUserMap has already been initialized. Skipping");
+			return Ok(());
+		}
+		// First, get all roles from the roles table and insert them into the map
+		let all_roles: Vec<(PgU64, PgU64, PermissionFlags)> =
+			retry_transient(attempts, INITIAL_RETRY_BACKOFF, || {
+				sqlx::query_as("SELECT id, guild_id, permissions FROM roles").fetch_all(db)
+			})
+			.await
+			.map_err(Error::Sqlx)?;
+		for (role_id, guild_id, permissions) in all_roles.iter() {
+			self.set_role_permissions(
+				role_id.to_uint().into(),
+				guild_id.to_uint().into(),
+				*permissions,
+			);
+		}
+		// Then, query member_roles and insert the user ids into the map
+		let all_member_roles: Vec<(PgU64, PgU64)> =
+			retry_transient(attempts, INITIAL_RETRY_BACKOFF, || {
+				sqlx::query_as(
+					"SELECT m.id, mr.role_id FROM member_roles mr JOIN members m ON m.index = mr.index",
+				)
+				.fetch_all(db)
+			})
+			.await
+			.map_err(Error::Sqlx)?;
+		self.populate(
+			all_roles.iter().map(|(role_id, ..)| role_id.to_uint().into()),
+			all_member_roles
+				.iter()
+				.map(|(user_id, role_id)| (role_id.to_uint().into(), user_id.to_uint().into())),
+		);
+		Ok(())
+	}
+
+	/// Fill the map with `role_ids` and the `(role_id, user_id)` pairs of
+	/// `member_roles`, and mark it as initialized.
+	fn populate(
+		&mut self,
+		role_ids: impl IntoIterator<Item = Snowflake>,
+		member_roles: impl IntoIterator<Item = (Snowflake, Snowflake)>,
+	) {
+		for role_id in role_ids {
+			self.map.entry(role_id).or_default();
+		}
+		for (role_id, user_id) in member_roles {
+			self.grant_role(role_id, user_id);
+		}
+		self.initialized = true;
+	}
+
+	/// Re-query the users having the role with the ID `role_id` from the
+	/// database and replace the users of that role with them. Unlike
+	/// [Self::init], this only touches a single role, which makes it cheap
+	/// enough to use after the `member_roles` table has been changed outside
+	/// of symfonia, for example by a migration.
+	pub async fn resync_role(&mut self, db: &PgPool, role_id: Snowflake) -> Result<(), Error> {
+		let user_ids: Vec<(PgU64,)> = sqlx::query_as(
+			"SELECT m.id
+                FROM member_roles mr
+                JOIN members m ON mr.index = m.index
+                WHERE mr.role_id = $1",
+		)
+		.bind(role_id)
+		.fetch_all(db)
+		.await
+		.map_err(Error::Sqlx)?;
+		self.replace_role_users(
+			role_id,
+			user_ids.into_iter().map(|(user_id,)| user_id.to_uint().into()).collect(),
+		);
+		Ok(())
+	}
+
+	/// Make the users in `user_ids` the only users having the role with the ID
+	/// `role_id`.
+	fn replace_role_users(&mut self, role_id: Snowflake, user_ids: HashSet<Snowflake>) {
+		let previous_user_ids = self.map.get(&role_id).cloned().unwrap_or_default();
+		for user_id in previous_user_ids.difference(&user_ids) {
+			self.revoke_role(role_id, *user_id);
+		}
+		self.map.entry(role_id).or_default();
+		for user_id in user_ids {
+			self.grant_role(role_id, user_id);
+		}
+	}
+}
+
+/// Connection to a WebSocket client with sending and receiving capabilities.
+///
+/// A [WebSocketConnection] is essentially an adapter from tungstenites
+/// sink/stream to a [tokio::sync::broadcast] channel. Broadcast channels are
+/// used in favor of sink/stream, because to clone a sink/stream to pass it
+/// around to different tasks which need sending/receiving capabilities, an
+/// `Arc<Mutex<T>>` has to be used. This means, that no more than one task can
+/// listen for incoming messages at a time, as a lock on the [Mutex] has to be
+/// acquired.
+///
+/// Read up on [tokio::sync::broadcast] channels if you'd like to understand how
+/// they work.
+pub struct WebSocketConnection {
+	pub sender: tokio::sync::broadcast::Sender<Message>,
+	/// Channel for control frames (close, ping and pong). The sender task
+	/// always drains it before `sender`, so that a close is not stuck behind
+	/// queued dispatches. Use [WebSocketConnection::try_send], which picks the
+	/// right channel.
+	control_sender: tokio::sync::broadcast::Sender<Message>,
+	/// Channel for graceful closes, see [WebSocketConnection::close].
+	close_sender: tokio::sync::broadcast::Sender<Option<CloseFrame>>,
+	pub receiver: tokio::sync::broadcast::Receiver<Message>,
+	pub kill_receive: tokio::sync::broadcast::Receiver<KillReason>,
+	/// Callsites of `kill_send` are always responsible for sending a close
+	/// message to the client. [WebSocketConnection::kill] does both.
+	pub kill_send: tokio::sync::broadcast::Sender<KillReason>,
+	sender_task: Arc<tokio::task::JoinHandle<()>>,
+	receiver_task: Arc<tokio::task::JoinHandle<()>>,
+	/// Encodes the payloads sent through this connection. Shared between
+	/// clones, so that all of them use the same format.
+	codec: Arc<dyn PayloadCodec>,
+	/// Compression of encoded payloads, once the client has asked for it when
+	/// identifying. Shared between clones, like `codec`.
+	payload_compression: Arc<OnceLock<PayloadCompression>>,
+	/// Whether all messages sent through this connection are compressed into
+	/// a single stream, see [WebSocketConnection::with_stream_compression].
+	/// Payloads are not compressed a second time on top of it then.
+	stream_compressed: bool,
+	/// Whether the session of this connection is being synced, see
+	/// [WebSocketConnection::start_sync]. Shared between clones.
+	syncing: Arc<tokio::sync::watch::Sender<bool>>,
+}
+
+/// Largest message, in bytes, a [WebSocketConnection] accepts from its client,
+/// unless configured otherwise.
+pub const DEFAULT_MAX_PAYLOAD_SIZE: usize = 4 * 1024 * 1024;
+
+/// Number of messages a [WebSocketConnection] buffers in each direction,
+/// unless configured otherwise.
+pub const DEFAULT_BUFFER_CAPACITY: usize = 100;
+
+/// Number of inboxes an event sent to many users is delivered to at once,
+/// unless configured otherwise.
+pub const DEFAULT_DISPATCH_CONCURRENCY: usize = 64;
+
+/// Smallest number of messages a [WebSocketConnection] buffers in each
+/// direction. A burst of dispatches, such as the guild creates following an
+/// identify, should not make a connection lag.
+pub const MIN_BUFFER_CAPACITY: usize = 16;
+
+/// How long a [WebSocketConnection] which is being closed keeps flushing the
+/// messages still queued for its client.
+pub const CLOSE_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Longest reason, in bytes, a close frame may carry. The payload of a control
+/// frame is limited to 125 bytes, two of which hold the close code.
+pub const MAX_CLOSE_REASON_LENGTH: usize = 123;
+
+/// Build a [CloseFrame] with `code` and `reason`, made safe to send. Control
+/// characters are removed from `reason`, and it is cut at a character boundary
+/// to at most [MAX_CLOSE_REASON_LENGTH] bytes, as WebSocket implementations
+/// reject longer reasons.
+pub fn close_frame(code: CloseCode, reason: &str) -> CloseFrame {
+	let mut sanitized = String::with_capacity(reason.len().min(MAX_CLOSE_REASON_LENGTH));
+	for character in reason.chars().filter(|character| !character.is_control()) {
+		if sanitized.len() + character.len_utf8() > MAX_CLOSE_REASON_LENGTH {
+			break;
+		}
+		sanitized.push(character);
+	}
+	CloseFrame { code, reason: sanitized.into() }
+}
+
+/// How long a [WebSocketConnection] waits for a message to be written to its
+/// client, unless configured otherwise.
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The channels the sender task of a [WebSocketConnection] drains, see
+/// [run_sender].
+struct SenderChannels {
+	messages: tokio::sync::broadcast::Receiver<Message>,
+	control: tokio::sync::broadcast::Receiver<Message>,
+	close: tokio::sync::broadcast::Receiver<Option<CloseFrame>>,
+	kill_send: tokio::sync::broadcast::Sender<KillReason>,
+}
+
+/// Send the messages queued in `channels` to `sink` until the connection is
+/// closed or fails. A client which does not take a message within
+/// `write_timeout`, for example because it stopped reading and its TCP buffer
+/// is full, is considered gone: its connection is killed with
+/// [KillReason::Timeout] and the task ends, instead of blocking forever.
+async fn run_sender<S>(mut sink: S, mut channels: SenderChannels, write_timeout: Duration)
+where
+	S: futures::Sink<Message> + Unpin,
+	S::Error: std::fmt::Display,
+{
+	log::trace!(target: "symfonia::gateway::types::WebSocketConnection", "spawned sender_task");
+	loop {
+		// `biased` makes control frames go out before any queued dispatches.
+		let message: Result<Message, tokio::sync::broadcast::error::RecvError> = tokio::select! {
+			biased;
+			// Also fails once every handle to the connection has been dropped, in
+			// which case the queued messages are flushed all the same.
+			frame = channels.close.recv() => {
+				let flush = async {
+					loop {
+						match channels.messages.try_recv() {
+							Ok(message) => {
+								if sink.send(message).await.is_err() {
+									return;
+								}
+							}
+							Err(TryRecvError::Lagged(_)) => continue,
+							Err(_) => return,
+						}
+					}
+				};
+				if tokio::time::timeout(CLOSE_FLUSH_TIMEOUT, flush).await.is_err() {
+					log::debug!(target: "symfonia::gateway::types::WebSocketConnection::sender_task", "Timed out flushing queued messages before closing");
+				}
+				if let Ok(frame) = frame {
+					let _ = tokio::time::timeout(write_timeout, sink.send(Message::Close(frame))).await;
+				}
+				break;
+			}
+			message = channels.control.recv() => message,
+			message = channels.messages.recv() => message,
+		};
+		match message {
+			Ok(msg) => match tokio::time::timeout(write_timeout, sink.send(msg)).await {
+				Ok(Ok(_)) => (),
+				Ok(Err(e)) => {
+					log::debug!(target: "symfonia::gateway::types::WebSocketConnection::sender_task", "Error when sending message to WebSocket: {e}");
+					break;
+				}
+				Err(_) => {
+					log::debug!(target: "symfonia::gateway::types::WebSocketConnection::sender_task", "Client did not take a message within {write_timeout:?}. Closing connection");
+					// Nobody listening for the kill signal means that the other tasks
+					// have already stopped.
+					let _ = channels.kill_send.send(KillReason::Timeout);
+					break;
+				}
+			},
+			Err(e) => {
+				log::debug!(target: "symfonia::gateway::types::WebSocketConnection::sender_task", "Error when trying to receive through websocketsend_receiver: {e}");
+				break;
+			}
+		}
+	}
+}
+
+/// The configuration of WebSockets accepted by a gateway which allows
+/// payloads of up to `max_payload_size` bytes. Tungstenite then refuses to
+/// buffer larger messages or frames at all, instead of the
+/// [WebSocketConnection] discarding them once they have been received.
+pub fn websocket_config(max_payload_size: usize) -> WebSocketConfig {
+	WebSocketConfig::default()
+		.max_message_size(Some(max_payload_size))
+		.max_frame_size(Some(max_payload_size))
+}
+
+impl WebSocketConnection {
+	/// Create a new [WebSocketConnection] from a tungstenite Sink/Stream pair,
+	/// accepting messages of up to [DEFAULT_MAX_PAYLOAD_SIZE] bytes.
+	pub fn new(sink: WebSocketSend, stream: WebSocketReceive) -> Self {
+		Self::with_max_payload_size(sink, stream, DEFAULT_MAX_PAYLOAD_SIZE)
+	}
+
+	/// Create a new [WebSocketConnection] from a tungstenite Sink/Stream pair.
+	/// Messages larger than `max_payload_size` bytes are not passed on. The
+	/// connection is closed with close code 4002 instead.
+	pub fn with_max_payload_size(
+		sink: WebSocketSend,
+		stream: WebSocketReceive,
+		max_payload_size: usize,
+	) -> Self {
+		Self::with_limits(
+			sink,
+			stream,
+			max_payload_size,
+			DEFAULT_BUFFER_CAPACITY,
+			DEFAULT_WRITE_TIMEOUT,
+		)
+	}
+
+	/// Create a new [WebSocketConnection] like [Self::with_max_payload_size],
+	/// which buffers up to `buffer_capacity` messages in each direction. Once
+	/// a buffer is full, the oldest messages in it are dropped, and whoever
+	/// reads from it lags behind. Capacities below [MIN_BUFFER_CAPACITY] are
+	/// raised to it. Clients which do not take a message within
+	/// `write_timeout` are disconnected.
+	pub fn with_limits(
+		sink: WebSocketSend,
+		mut stream: WebSocketReceive,
+		max_payload_size: usize,
+		buffer_capacity: usize,
+		write_timeout: Duration,
+	) -> Self {
+		let buffer_capacity = buffer_capacity.max(MIN_BUFFER_CAPACITY);
+		let (mut websocketsend_sender, websocketsend_receiver) =
+			tokio::sync::broadcast::channel(buffer_capacity);
+		let (mut websocketreceive_sender, mut websocketreceive_receiver) =
+			tokio::sync::broadcast::channel(buffer_capacity);
+		let (control_sender, control_receiver) = tokio::sync::broadcast::channel(16);
+		let (close_sender, close_receiver) = tokio::sync::broadcast::channel(1);
+
+		let (kill_send, kill_receive) = tokio::sync::broadcast::channel(1);
+
+		// The sender task concerns itself with sending messages to the WebSocket
+		// client.
+		let sender_task = tokio::spawn(run_sender(
+			sink,
+			SenderChannels {
+				messages: websocketsend_receiver,
+				control: control_receiver,
+				close: close_receiver,
+				kill_send: kill_send.clone(),
+			},
+			write_timeout,
+		));
+
+		// The receiver task receives messages from the WebSocket client and sends them
+		// to the broadcast channel.
+		let reply_sender = control_sender.clone();
+		let receiver_kill_send = kill_send.clone();
+		let receiver_task = tokio::spawn(async move {
+			log::trace!(target: "symfonia::gateway::types::WebSocketConnection", "spawned receiver_task");
+			loop {
+				let web_socket_receive_result = match stream.next().await {
+					Some(res) => res,
+					None => {
+						log::debug!(target: "symfonia::gateway::WebSocketConnection::receiver_task", "WebSocketReceive yielded None. Closing channel");
+						break;
+					}
+				};
+				let web_socket_receive_message = match web_socket_receive_result {
+					Ok(message) => message,
+					Err(e @ tokio_tungstenite::tungstenite::Error::Utf8 { .. }) => {
+						log::debug!(target: "symfonia::gateway::WebSocketConnection::receiver_task", "Received a text message which is not valid UTF-8, closing connection: {e}");
+						// Both of these only fail if the connection is already shutting down.
+						let _ = reply_sender
+							.send(Message::Close(KillReason::InvalidPayload.close_frame()));
+						let _ = receiver_kill_send.send(KillReason::InvalidPayload);
+						break;
+					}
+					Err(e @ tungstenite::Error::Capacity(_)) => {
+						log::debug!(target: "symfonia::gateway::WebSocketConnection::receiver_task", "Received a message exceeding the limits of the WebSocket, closing connection: {e}");
+						// Both of these only fail if the connection is already shutting down.
+						let _ = reply_sender
+							.send(Message::Close(KillReason::InvalidPayload.close_frame()));
+						let _ = receiver_kill_send.send(KillReason::InvalidPayload);
+						break;
+					}
+					Err(e) => {
+						log::debug!(target: "symfonia::gateway::WebSocketConnection::receiver_task", "Received malformed message, closing channel: {e}");
+						break;
+					}
+				};
+				// Control frames concern the WebSocket, not the gateway, so they are
+				// handled right here instead of being passed on.
+				match web_socket_receive_message {
+					Message::Ping(data) => {
+						if let Err(e) = reply_sender.send(Message::Pong(data)) {
+							log::debug!(target: "symfonia::gateway::WebSocketConnection::receiver_task", "Unable to answer ping. Closing channel: {e}");
+							break;
+						}
+						continue;
+					}
+					Message::Pong(_) => continue,
+					Message::Close(frame) => {
+						match CloseKind::of(frame.as_ref()) {
+							CloseKind::Clean => {
+								log::debug!(target: "symfonia::gateway::WebSocketConnection::receiver_task", "Client closed the connection")
+							}
+							CloseKind::Abnormal => {
+								log::info!(target: "symfonia::gateway::WebSocketConnection::receiver_task", "Client closed the connection abnormally: {frame:?}")
+							}
+						}
+						// Tungstenite answers the close frame itself. Nobody listening for the
+						// kill signal means that the tasks have already stopped.
+						let _ =
+							receiver_kill_send.send(CloseKind::of(frame.as_ref()).kill_reason());
+						break;
+					}
+					_ => (),
+				}
+				if web_socket_receive_message.len() > max_payload_size {
+					log::debug!(target: "symfonia::gateway::WebSocketConnection::receiver_task", "Received message of {} bytes, which exceeds the limit of {max_payload_size} bytes. Closing connection", web_socket_receive_message.len());
+					// Both of these only fail if the connection is already shutting down.
+					let _ =
+						reply_sender.send(Message::Close(KillReason::InvalidPayload.close_frame()));
+					let _ = receiver_kill_send.send(KillReason::InvalidPayload);
+					break;
+				}
+				// Payloads which are JSON but unknown to the gateway are passed on, so that
+				// they can be rejected with a more specific close code.
+				if is_undecodable(&web_socket_receive_message) {
+					log::debug!(target: "symfonia::gateway::WebSocketConnection::receiver_task", "Received a text message which is not JSON. Closing connection");
+					// Both of these only fail if the connection is already shutting down.
+					let _ =
+						reply_sender.send(Message::Close(KillReason::InvalidPayload.close_frame()));
+					let _ = receiver_kill_send.send(KillReason::InvalidPayload);
+					break;
+				}
+				match websocketreceive_sender.send(web_socket_receive_message) {
+					Ok(_) => (),
+					Err(e) => {
+						log::debug!(target: "symfonia::gateway::WebSocketConnection::receiver_task", "Unable to send received WebSocket message to channel recipients. Closing channel: {e}");
+						break;
+					}
+				}
+			}
+		});
+		Self {
+			sender: websocketsend_sender,
+			control_sender,
+			close_sender,
+			receiver: websocketreceive_receiver,
+			sender_task: Arc::new(sender_task),
+			receiver_task: Arc::new(receiver_task),
+			kill_receive,
+			kill_send,
+			codec: Arc::new(JsonCodec),
+			payload_compression: Arc::new(OnceLock::new()),
+			stream_compressed: false,
+			syncing: Arc::new(tokio::sync::watch::channel(false).0),
+		}
+	}
+
+	/// Use `codec` to encode the payloads sent through this connection. Must
+	/// be called before the connection is cloned; existing clones keep their
+	/// codec.
+	pub fn with_codec(mut self, codec: Arc<dyn PayloadCodec>) -> Self {
+		self.codec = codec;
+		self
+	}
+
+	/// Compress the payloads encoded for this connection and all of its clones
+	/// from now on with `compression`. Has no effect if payload compression has
+	/// already been enabled, or if the connection is compressed as a whole
+	/// through [Self::with_stream_compression].
+	pub fn enable_payload_compression(&self, compression: PayloadCompression) {
+		if self.stream_compressed {
+			log::debug!(target: "symfonia::gateway::WebSocketConnection", "Not compressing payloads of a compressed stream");
+			return;
+		}
+		if self.payload_compression.set(compression).is_err() {
+			log::debug!(target: "symfonia::gateway::WebSocketConnection", "Payload compression has already been enabled");
+		}
+	}
+
+	/// Whether the payloads encoded for this connection are compressed, see
+	/// [Self::enable_payload_compression].
+	pub fn compresses_payloads(&self) -> bool {
+		self.payload_compression.get().is_some()
+	}
+
+	/// Encode `payload` into a [Message] with the [PayloadCodec] of this
+	/// connection, compressing it if the client has asked for
+	/// [PayloadCompression].
+	pub fn encode<T: Serialize + ?Sized>(&self, payload: &T) -> Result<Message, GatewayError> {
+		let payload = serde_json::to_value(payload).map_err(|_| GatewayError::Internal)?;
+		let message = self.codec.encode(&payload)?;
+		match self.payload_compression.get() {
+			Some(compression) => compression.compress(message),
+			None => Ok(message),
+		}
+	}
+
+	/// Hold back the live dispatches of the session of this connection while
+	/// its initial state, such as the `READY` and the guild creates following
+	/// it, is sent, so that they do not interleave with it. Dispatches arriving
+	/// in the meantime are queued by the main task of the session and sent in
+	/// order once [Self::finish_sync] is called.
+	pub fn start_sync(&self) {
+		self.syncing.send_replace(true);
+	}
+
+	/// Send the dispatches held back since [Self::start_sync].
+	pub fn finish_sync(&self) {
+		self.syncing.send_replace(false);
+	}
+
+	/// Watch whether the session of this connection is being synced, see
+	/// [Self::start_sync].
+	pub fn syncing(&self) -> tokio::sync::watch::Receiver<bool> {
+		self.syncing.subscribe()
+	}
+
+	/// Encode `payload` with [Self::encode] and queue it to be sent to the
+	/// client with [Self::try_send].
+	pub fn send_encoded<T: Serialize + ?Sized>(&self, payload: &T) -> Result<(), GatewayError> {
+		self.try_send(self.encode(payload)?)
+	}
+
+	/// Close the connection with `frame`, after sending the messages still
+	/// queued for the client, such as a final dispatch. Flushing the queue
+	/// gives up after [CLOSE_FLUSH_TIMEOUT]. Close frames queued with
+	/// [Self::try_send] skip the queue instead, dropping its messages.
+	///
+	/// ## Errors
+	///
+	/// Returns [GatewayError::Closed] if the connection no longer forwards
+	/// messages to the client.
+	pub fn close(&self, frame: Option<CloseFrame>) -> Result<(), GatewayError> {
+		self.close_sender.send(frame).map_err(|_| GatewayError::Closed)?;
+		Ok(())
+	}
+
+	/// Whether this connection has stopped sending messages to its client,
+	/// for example because it has been closed with [Self::close].
+	pub fn is_closed(&self) -> bool {
+		self.sender_task.is_finished()
+	}
+
+	/// Queue `message` to be sent to the client. Control frames are sent
+	/// ahead of any other messages still waiting in the queue.
+	///
+	/// ## Errors
+	///
+	/// Returns [GatewayError::Closed] if the connection no longer forwards
+	/// messages to the client. Callers should tear the connection down in that
+	/// case.
+	pub fn try_send(&self, message: Message) -> Result<(), GatewayError> {
+		match message {
+			Message::Close(_) | Message::Ping(_) | Message::Pong(_) => {
+				self.control_sender.send(message)?
+			}
+			_ => self.sender.send(message)?,
+		};
+		Ok(())
+	}
+
+	/// Close the connection with the close frame `reason` maps to, if any,
+	/// after the messages still waiting in the queue, see [Self::close]. Then
+	/// fire the kill switch of this connection with `reason`.
+	///
+	/// ## Errors
+	///
+	/// Returns [GatewayError::KillSignalFailed] if nobody listens for the kill
+	/// signal anymore, meaning that the tasks of this connection have already
+	/// stopped.
+	pub fn kill(&self, reason: KillReason) -> Result<(), GatewayError> {
+		if let Some(frame) = reason.close_frame() {
+			// This only fails if the connection is already shutting down.
+			let _ = self.close(Some(frame));
+		}
+		self.kill_send.send(reason)?;
+		Ok(())
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How a client closed its connection.
+pub enum CloseKind {
+	/// The client closed the connection with close code 1000 or 1001.
+	Clean,
+	/// The client closed the connection with any other close code, or without
+	/// one.
+	Abnormal,
+}
+
+impl CloseKind {
+	/// Classify the close frame a client sent.
+	pub fn of(frame: Option<&CloseFrame>) -> Self {
+		match frame {
+			Some(frame) if matches!(frame.code, CloseCode::Normal | CloseCode::Away) => Self::Clean,
+			_ => Self::Abnormal,
+		}
+	}
+
+	/// Why the session of a client closing its connection this way ends.
+	pub fn kill_reason(self) -> KillReason {
+		match self {
+			Self::Clean => KillReason::ClientClosed,
+			Self::Abnormal => KillReason::ConnectionLost,
+		}
+	}
+}
+
+/// Whether `message` is a text message which is not JSON. Gateway payloads sent
+/// as text are always JSON, so such a message cannot be decoded at all.
+fn is_undecodable(message: &Message) -> bool {
+	match message {
+		Message::Text(text) => from_str::<::serde::de::IgnoredAny>(text).is_err(),
+		_ => false,
+	}
+}
+
+/// The client end of a [WebSocketConnection] created with
+/// [WebSocketConnection::from_channels].
+pub struct InMemoryWebSocket {
+	/// Messages sent through this channel are received by the connection, as
+	/// if they had been sent by a WebSocket client.
+	pub incoming: tokio::sync::broadcast::Sender<Message>,
+	/// Receives every message the connection sends to its client.
+	pub outgoing: tokio::sync::broadcast::Receiver<Message>,
+}
+
+impl WebSocketConnection {
+	/// Create a new [WebSocketConnection] which is not backed by an actual
+	/// WebSocket, but by in-memory channels. Messages can be injected into and
+	/// observed from the connection through the returned [InMemoryWebSocket].
+	///
+	/// Must be called from within a tokio runtime.
+	pub fn from_channels() -> (Self, InMemoryWebSocket) {
+		Self::from_channels_with_capacity(DEFAULT_BUFFER_CAPACITY)
+	}
+
+	/// Create a new in-memory [WebSocketConnection] like
+	/// [Self::from_channels], which buffers up to `buffer_capacity` messages
+	/// in each direction. Capacities below [MIN_BUFFER_CAPACITY] are raised to
+	/// it.
+	pub fn from_channels_with_capacity(buffer_capacity: usize) -> (Self, InMemoryWebSocket) {
+		let buffer_capacity = buffer_capacity.max(MIN_BUFFER_CAPACITY);
+		let (sender, outgoing) = tokio::sync::broadcast::channel(buffer_capacity);
+		let (incoming, receiver) = tokio::sync::broadcast::channel(buffer_capacity);
+		let (kill_send, kill_receive) = tokio::sync::broadcast::channel(1);
+		let (close_sender, mut close_receiver) = tokio::sync::broadcast::channel(1);
+		// Every message is already in `outgoing` in the order it has been sent in,
+		// so closing only has to append the close frame.
+		let close_forward = sender.clone();
+		let close_task = tokio::spawn(async move {
+			if let Ok(frame) = close_receiver.recv().await {
+				let _ = close_forward.send(Message::Close(frame));
+			}
+		});
+		let connection = Self {
+			// Without a sender task, control frames cannot skip the queue. Both
+			// channels lead to `outgoing`.
+			control_sender: sender.clone(),
+			close_sender,
+			sender,
+			receiver,
+			// There is no socket to shuttle messages from and to, the channels are
+			// handed out directly.
+			sender_task: Arc::new(close_task),
+			receiver_task: Arc::new(tokio::spawn(async {})),
+			kill_receive,
+			kill_send,
+			codec: Arc::new(JsonCodec),
+			payload_compression: Arc::new(OnceLock::new()),
+			stream_compressed: false,
+			syncing: Arc::new(tokio::sync::watch::channel(false).0),
+		};
+		(connection, InMemoryWebSocket { incoming, outgoing })
+	}
+}
+
+impl Clone for WebSocketConnection {
+	fn clone(&self) -> Self {
+		log::trace!(target: "symfonia::gateway::WebSocketConnection", "WebSocketConnection cloned!");
+		Self {
+			sender: self.sender.clone(),
+			control_sender: self.control_sender.clone(),
+			close_sender: self.close_sender.clone(),
+			receiver: self.receiver.resubscribe(),
+			sender_task: self.sender_task.clone(),
+			receiver_task: self.receiver_task.clone(),
+			kill_receive: self.kill_receive.resubscribe(),
+			kill_send: self.kill_send.clone(),
+			codec: self.codec.clone(),
+			payload_compression: self.payload_compression.clone(),
+			stream_compressed: self.stream_compressed,
+			syncing: self.syncing.clone(),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// What is needed to resume a disconnected session, kept in a
+/// [ResumableClientsStore](resumable_store::ResumableClientsStore). Holds no
+/// references to the state of this gateway node, so that it can be
+/// serialized and the session resumed on another node.
+pub struct DisconnectInfo {
+	/// session token that was used for this connection
+	pub session_token: SessionToken,
+	/// The Snowflake ID of the user the session belongs to.
+	pub user_id: Snowflake,
+	pub disconnected_at_sequence: u64,
+	/// The `(shard_id, shard_count)` the session identified with, if any.
+	pub shard: Option<(u64, u64)>,
+	/// Events recently dispatched to the session, to be replayed on resume.
+	pub recent_dispatches: ResumeSnapshot,
+	/// The [intents] the session identified with.
+	pub intents: u64,
+	/// Whether the session identified with `compress` set, see
+	/// [WebSocketConnection::enable_payload_compression].
+	#[serde(default)]
+	pub compress: bool,
+	/// The client the session has been opened with.
+	pub properties: ClientProperties,
+	/// Why the session has been disconnected.
+	pub reason: DisconnectReason,
+	/// When the session has been disconnected. Its resumability expires
+	/// [ResumableClientsStore::ttl](resumable_store::ResumableClientsStore::ttl)
+	/// after this.
+	pub disconnected_at: SystemTime,
+}
+
+impl DisconnectInfo {
+	/// Whether the [reason](Self::reason) the session has been disconnected
+	/// for allows resuming it. Sessions disconnected because their
+	/// authentication failed have to identify again.
+	pub fn is_resumable(&self) -> bool {
+		self.reason.is_resumable()
+	}
+}
+
+impl
+	From<(
+		SplitSink<WebSocketStream<TcpStream>, tokio_tungstenite::tungstenite::Message>,
+		SplitStream<WebSocketStream<TcpStream>>,
+	)> for WebSocketConnection
+{
+	fn from(
+		value: (
+			SplitSink<WebSocketStream<TcpStream>, tokio_tungstenite::tungstenite::Message>,
+			SplitStream<WebSocketStream<TcpStream>>,
+		),
+	) -> Self {
+		Self::new(value.0, value.1)
+	}
+}
+
+/// Represents a new successful connection to the gateway. The user is already
+/// part of the [ConnectedUsers] and the client is already registered with the
+/// [GatewayClient] "clients" map.
+pub struct NewWebSocketConnection {
+	pub user: Arc<Mutex<GatewayUser>>,
+	pub client: Arc<Mutex<GatewayClient>>,
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::{
+		testing::{insert_member, insert_role, test_client, test_session, unreachable_db},
+		*,
+	};
+
+	mod consistency;
+	mod sessions;
+
+	/// A `MESSAGE_CREATE` without an author, which is routed to the inboxes of
+	/// users.
+	fn message_create() -> Event {
+		Event::Dispatch(DispatchEvent::MessageCreate(GatewayPayload::dispatch(
+			"MESSAGE_CREATE",
+			chorus::types::MessageCreate::default(),
+		)))
+	}
+
+	#[tokio::test]
+	async fn try_send_without_receivers_fails() {
+		let (connection, client) = WebSocketConnection::from_channels();
+		drop(client);
+
+		assert!(matches!(
+			connection.try_send(Message::Text("hello".into())),
+			Err(GatewayError::Closed)
+		));
+	}
+
+	#[tokio::test]
+	async fn connection_buffers_up_to_its_capacity() {
+		let capacity = MIN_BUFFER_CAPACITY * 2;
+		let (connection, mut client) = WebSocketConnection::from_channels_with_capacity(capacity);
+
+		for index in 0..capacity {
+			connection.try_send(Message::Text(index.to_string().into())).unwrap();
+		}
+		for index in 0..capacity {
+			assert_eq!(
+				client.outgoing.try_recv().unwrap(),
+				Message::Text(index.to_string().into())
+			);
+		}
+
+		// One message more than fits into the buffer makes the client lag.
+		for index in 0..=capacity {
+			connection.try_send(Message::Text(index.to_string().into())).unwrap();
+		}
+		assert!(matches!(
+			client.outgoing.try_recv(),
+			Err(tokio::sync::broadcast::error::TryRecvError::Lagged(1))
+		));
+	}
+
+	#[tokio::test]
+	async fn buffer_capacity_has_a_minimum() {
+		let (connection, mut client) = WebSocketConnection::from_channels_with_capacity(0);
+		for index in 0..MIN_BUFFER_CAPACITY {
+			connection.try_send(Message::Text(index.to_string().into())).unwrap();
+		}
+		assert_eq!(client.outgoing.try_recv().unwrap(), Message::Text("0".into()));
+	}
+
+	/// A sink which never accepts a message, like the socket of a client which
+	/// has stopped reading.
+	struct StuckSink;
+
+	impl futures::Sink<Message> for StuckSink {
+		type Error = GatewayError;
+
+		fn poll_ready(
+			self: std::pin::Pin<&mut Self>,
+			_: &mut std::task::Context<'_>,
+		) -> std::task::Poll<Result<(), Self::Error>> {
+			std::task::Poll::Pending
+		}
+
+		fn start_send(self: std::pin::Pin<&mut Self>, _: Message) -> Result<(), Self::Error> {
+			Ok(())
+		}
+
+		fn poll_flush(
+			self: std::pin::Pin<&mut Self>,
+			_: &mut std::task::Context<'_>,
+		) -> std::task::Poll<Result<(), Self::Error>> {
+			std::task::Poll::Pending
+		}
+
+		fn poll_close(
+			self: std::pin::Pin<&mut Self>,
+			_: &mut std::task::Context<'_>,
+		) -> std::task::Poll<Result<(), Self::Error>> {
+			std::task::Poll::Pending
+		}
+	}
+
+	#[tokio::test]
+	async fn stuck_client_is_dropped_after_write_timeout() {
+		let (sender, messages) = tokio::sync::broadcast::channel(MIN_BUFFER_CAPACITY);
+		let (_control_sender, control) = tokio::sync::broadcast::channel(1);
+		let (_close_sender, close) = tokio::sync::broadcast::channel(1);
+		let (kill_send, mut kill_receive) = tokio::sync::broadcast::channel(1);
+		let task = tokio::spawn(run_sender(
+			StuckSink,
+			SenderChannels { messages, control, close, kill_send },
+			Duration::from_millis(50),
+		));
+
+		sender.send(Message::Text("never taken".into())).unwrap();
+
+		tokio::time::timeout(Duration::from_secs(5), task)
+			.await
+			.expect("the sender task hangs")
+			.unwrap();
+		assert_eq!(kill_receive.recv().await.unwrap(), KillReason::Timeout);
+	}
+
+	/// Creates a [WebSocketConnection] backed by a WebSocket on the loopback
+	/// interface, configured like the gateway configures them, along with the
+	/// client end of that WebSocket.
+	async fn loopback_connection(
+		max_payload_size: usize,
+	) -> (WebSocketConnection, WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>) {
+		use tokio_tungstenite::{accept_async_with_config, connect_async};
+
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+		let client =
+			tokio::spawn(async move { connect_async(format!("ws://{address}")).await.unwrap().0 });
+		let (stream, _) = listener.accept().await.unwrap();
+		let (sink, stream) =
+			accept_async_with_config(stream, Some(websocket_config(max_payload_size)))
+				.await
+				.unwrap()
+				.split();
+		let connection = WebSocketConnection::with_max_payload_size(sink, stream, max_payload_size);
+		(connection, client.await.unwrap())
+	}
+
+	#[tokio::test]
+	async fn ping_is_answered_and_not_passed_on() {
+		let (mut connection, mut client) = loopback_connection(DEFAULT_MAX_PAYLOAD_SIZE).await;
+
+		client.send(Message::Ping(b"hi".to_vec().into())).await.unwrap();
+		client.send(Message::Text(r#"{"op":1,"d":null}"#.into())).await.unwrap();
+
+		match client.next().await.unwrap().unwrap() {
+			Message::Pong(data) => assert_eq!(&data[..], b"hi"),
+			other => panic!("expected a pong, got {other:?}"),
+		}
+		assert_eq!(
+			connection.receiver.recv().await.unwrap(),
+			Message::Text(r#"{"op":1,"d":null}"#.into())
+		);
+	}
+
+	#[tokio::test]
+	async fn client_close_kills_connection() {
+		let (mut connection, mut client) = loopback_connection(DEFAULT_MAX_PAYLOAD_SIZE).await;
+
+		client
+			.send(Message::Close(Some(CloseFrame { code: CloseCode::Normal, reason: "".into() })))
+			.await
+			.unwrap();
+
+		assert_eq!(connection.kill_receive.recv().await.unwrap(), KillReason::ClientClosed);
+		// The close frame is not passed on as if it was a gateway message.
+		assert!(connection.receiver.recv().await.is_err());
+	}
+
+	#[test]
+	fn close_kind_distinguishes_clean_closes() {
+		let frame = |code| CloseFrame { code, reason: "".into() };
+		assert_eq!(CloseKind::of(Some(&frame(CloseCode::Normal))), CloseKind::Clean);
+		assert_eq!(CloseKind::of(Some(&frame(CloseCode::Away))), CloseKind::Clean);
+		assert_eq!(CloseKind::of(Some(&frame(CloseCode::Error))), CloseKind::Abnormal);
+		assert_eq!(CloseKind::of(None), CloseKind::Abnormal);
+	}
+
+	#[tokio::test]
+	async fn oversized_message_closes_connection() {
+		let (mut connection, mut client) = loopback_connection(16).await;
+
+		client.send(Message::Binary(vec![0; 17].into())).await.unwrap();
+
+		match client.next().await.unwrap().unwrap() {
+			Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Library(4002)),
+			other => panic!("expected a close frame, got {other:?}"),
+		}
+		// The oversized message never reached the gateway.
+		assert!(connection.receiver.recv().await.is_err());
+	}
+
+	#[tokio::test]
+	async fn oversized_fragmented_message_closes_connection() {
+		use tokio_tungstenite::tungstenite::protocol::frame::{
+			Frame,
+			coding::{Data, OpCode},
+		};
+
+		let (mut connection, mut client) = loopback_connection(16).await;
+
+		// No single frame exceeds the limit, but the message they form does.
+		let first = Frame::message(vec![b'a'; 10], OpCode::Data(Data::Text), false);
+		client.send(Message::Frame(first)).await.unwrap();
+		client
+			.send(Message::Frame(Frame::message(
+				vec![b'a'; 10],
+				OpCode::Data(Data::Continue),
+				true,
+			)))
+			.await
+			.unwrap();
+
+		match client.next().await.unwrap().unwrap() {
+			Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Library(4002)),
+			other => panic!("expected a close frame, got {other:?}"),
+		}
+		assert!(connection.receiver.recv().await.is_err());
+	}
+
+	#[tokio::test]
+	async fn invalid_utf8_closes_connection_with_decode_error() {
+		use tokio_tungstenite::tungstenite::protocol::frame::{
+			Frame,
+			coding::{Data, OpCode},
+		};
+
+		let (mut connection, mut client) = loopback_connection(DEFAULT_MAX_PAYLOAD_SIZE).await;
+
+		let frame = Frame::message(vec![b'{', 0xff, 0xfe, b'}'], OpCode::Data(Data::Text), true);
+		client.send(Message::Frame(frame)).await.unwrap();
+
+		match client.next().await.unwrap().unwrap() {
+			Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Library(4002)),
+			other => panic!("expected a close frame, got {other:?}"),
+		}
+		assert_eq!(connection.kill_receive.recv().await.unwrap(), KillReason::InvalidPayload);
+		assert!(connection.receiver.recv().await.is_err());
+	}
+
+	#[tokio::test]
+	async fn non_json_text_closes_connection_but_unknown_payloads_pass() {
+		let (mut connection, mut client) = loopback_connection(DEFAULT_MAX_PAYLOAD_SIZE).await;
+
+		// Rejecting unknown opcodes is up to the gateway task.
+		client.send(Message::Text(r#"{"op":255}"#.into())).await.unwrap();
+		client.send(Message::Text("op: 1".into())).await.unwrap();
+
+		assert_eq!(
+			connection.receiver.recv().await.unwrap(),
+			Message::Text(r#"{"op":255}"#.into())
+		);
+		match client.next().await.unwrap().unwrap() {
+			Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Library(4002)),
+			other => panic!("expected a close frame, got {other:?}"),
+		}
+		assert!(connection.receiver.recv().await.is_err());
+	}
+
+	#[tokio::test]
+	async fn over_long_close_reason_is_capped() {
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		let reason = format!("{}\n{}", "é".repeat(100), "x".repeat(100));
+
+		connection
+			.try_send(Message::Close(Some(close_frame(CloseCode::Library(4000), &reason))))
+			.unwrap();
+
+		match client.outgoing.recv().await.unwrap() {
+			Message::Close(Some(frame)) => {
+				assert!(frame.reason.len() <= MAX_CLOSE_REASON_LENGTH);
+				assert_eq!(frame.reason.as_str(), "é".repeat(61));
+			}
+			other => panic!("expected a close frame, got {other:?}"),
+		}
+	}
+
+	#[tokio::test]
+	async fn close_skips_queued_dispatches() {
+		let (connection, mut client) = loopback_connection(DEFAULT_MAX_PAYLOAD_SIZE).await;
+
+		for n in 0..50 {
+			connection.try_send(Message::Text(format!("dispatch {n}").into())).unwrap();
+		}
+		connection
+			.try_send(Message::Close(Some(CloseFrame {
+				code: CloseCode::Library(4000),
+				reason: "".into(),
+			})))
+			.unwrap();
+
+		match client.next().await.unwrap().unwrap() {
+			Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Library(4000)),
+			other => panic!("expected the close frame first, got {other:?}"),
+		}
+	}
+
+	#[tokio::test]
+	async fn close_flushes_queued_dispatches() {
+		let (connection, mut client) = loopback_connection(DEFAULT_MAX_PAYLOAD_SIZE).await;
+
+		for n in 0..50 {
+			connection.try_send(Message::Text(format!("dispatch {n}").into())).unwrap();
+		}
+		connection
+			.close(Some(CloseFrame { code: CloseCode::Library(4000), reason: "".into() }))
+			.unwrap();
+
+		for n in 0..50 {
+			assert_eq!(
+				client.next().await.unwrap().unwrap(),
+				Message::Text(format!("dispatch {n}").into())
+			);
+		}
+		match client.next().await.unwrap().unwrap() {
+			Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Library(4000)),
+			other => panic!("expected the close frame last, got {other:?}"),
+		}
+	}
+
+	#[tokio::test]
+	async fn in_memory_connection_passes_messages_through() {
+		let (mut connection, mut client) = WebSocketConnection::from_channels();
+
+		client.incoming.send(Message::Text("hello".into())).unwrap();
+		assert_eq!(connection.receiver.recv().await.unwrap(), Message::Text("hello".into()));
+
+		connection.sender.send(Message::Text("hi".into())).unwrap();
+		assert_eq!(client.outgoing.recv().await.unwrap(), Message::Text("hi".into()));
+	}
+
+	#[tokio::test]
+	async fn test_sinks_collect_guild_broadcasts() {
+		let connected_users = ConnectedUsers::default();
+		let guild_id = Snowflake::from(100u64);
+		let (member, other_member, outsider) =
+			(Snowflake::from(1u64), Snowflake::from(2u64), Snowflake::from(3u64));
+		for user_id in [member, other_member, outsider] {
+			connected_users.register_test_sink(user_id);
+		}
+		for user_id in [member, other_member] {
+			connected_users.role_user_map.lock().await.add_member(guild_id, user_id);
+		}
+
+		for _ in 0..2 {
+			connected_users.broadcast_to_guild(guild_id, message_create()).await.unwrap();
+		}
+
+		for user_id in [member, other_member] {
+			let events = connected_users.drain_test_sink(user_id);
+			assert_eq!(events.len(), 2);
+			assert!(
+				events
+					.iter()
+					.all(|event| matches!(event, Event::Dispatch(DispatchEvent::MessageCreate(_))))
+			);
+			assert!(connected_users.drain_test_sink(user_id).is_empty());
+		}
+		assert!(connected_users.drain_test_sink(outsider).is_empty());
+		assert_eq!(connected_users.verify_consistency(), Ok(()));
+	}
+
+	#[test]
+	fn broadcast_to_all_reaches_every_user() {
+		let connected_users = ConnectedUsers::default();
+		let mut inboxes = Vec::new();
+		for id in 1..=3u64 {
+			let user = connected_users.new_user(HashMap::new(), Snowflake::from(id), Vec::new());
+			inboxes.push(user.try_lock().unwrap().inbox.resubscribe());
+		}
+
+		let report = connected_users.broadcast_to_all(message_create()).unwrap();
+
+		assert_eq!(report, BulkSendReport { delivered: 3, failed: Vec::new() });
+		for mut inbox in inboxes {
+			assert!(matches!(
+				inbox.try_recv().unwrap(),
+				Event::Dispatch(DispatchEvent::MessageCreate(_))
+			));
+		}
+	}
+
+	#[tokio::test]
+	async fn large_fan_out_is_delivered_in_batches() {
+		let connected_users = ConnectedUsers::default();
+		connected_users.set_dispatch_concurrency(16);
+		let guild_id = Snowflake::from(1u64);
+		let mut inboxes = Vec::new();
+		for id in 2..=1001u64 {
+			let user = connected_users.new_user(HashMap::new(), Snowflake::from(id), Vec::new());
+			inboxes.push(user.lock().await.inbox.resubscribe());
+			connected_users.role_user_map.lock().await.grant_role(guild_id, Snowflake::from(id));
+		}
+		// A member whose inbox nobody reads from any more.
+		let stale_id = Snowflake::from(1002u64);
+		let (stale_inbox, _) = tokio::sync::broadcast::channel(1);
+		connected_users.inboxes.insert(stale_id, stale_inbox);
+		connected_users.role_user_map.lock().await.grant_role(guild_id, stale_id);
+
+		let mut builder = connected_users.bulk_message_builder();
+		builder.add_role_recipients(&[guild_id]).await;
+		builder.set_message(message_create()).await;
+		let report = builder.clone().send_with_report(connected_users.clone()).await.unwrap();
+
+		assert_eq!(report, BulkSendReport { delivered: 1000, failed: vec![stale_id] });
+		for inbox in inboxes.iter_mut() {
+			assert!(matches!(
+				inbox.try_recv().unwrap(),
+				Event::Dispatch(DispatchEvent::MessageCreate(_))
+			));
+		}
+		assert!(builder.send(connected_users).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn delivered_events_are_counted() {
+		let connected_users = ConnectedUsers::default();
+		let mut inboxes = Vec::new();
+		for id in 1..=2u64 {
+			let user = connected_users.new_user(HashMap::new(), Snowflake::from(id), Vec::new());
+			inboxes.push(user.lock().await.inbox.resubscribe());
+		}
+		connected_users.broadcast_to_all(message_create()).unwrap();
+		let mut builder = connected_users.bulk_message_builder();
+		builder.add_user_recipients(&[Snowflake::from(1u64)]).await;
+		builder.set_message(message_create()).await;
+		builder.send(connected_users.clone()).await.unwrap();
+
+		let snapshot = connected_users.metrics.snapshot();
+		assert_eq!(
+			snapshot.dispatched(event::EventType::Dispatch(
+				dispatchevent::DispatchEventType::MessageCreate
+			)),
+			3
+		);
+		assert_eq!(snapshot.total_dispatched(), 3);
+	}
+
+	#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+	async fn guild_events_arrive_in_production_order() {
+		let connected_users = ConnectedUsers::default();
+		// Deliver to one inbox at a time, so that unordered fan-outs would interleave.
+		connected_users.set_dispatch_concurrency(1);
+		let guild_id = Snowflake::from(100u64);
+		let mut inboxes = Vec::new();
+		for id in 1..=8u64 {
+			let user = connected_users.new_user(HashMap::new(), Snowflake::from(id), Vec::new());
+			inboxes.push(user.lock().await.inbox.resubscribe());
+			connected_users.role_user_map.lock().await.add_member(guild_id, Snowflake::from(id));
+		}
+
+		// Each task produces its event once the task before it has produced its own,
+		// but does not wait for it to be delivered.
+		let mut tasks = Vec::new();
+		let mut previous_produced: Option<tokio::sync::oneshot::Receiver<()>> = None;
+		for index in 0..16u64 {
+			let connected_users = connected_users.clone();
+			let (produced_send, produced_receive) = tokio::sync::oneshot::channel();
+			let wait_for = previous_produced.replace(produced_receive);
+			tasks.push(tokio::spawn(async move {
+				if let Some(wait_for) = wait_for {
+					wait_for.await.unwrap();
+				}
+				let mut builder = connected_users.bulk_message_builder();
+				builder.add_role_recipients(&[guild_id]).await;
+				builder
+					.set_message(Event::Dispatch(DispatchEvent::MessageCreate(
+						GatewayPayload::dispatch(
+							"MESSAGE_CREATE",
+							chorus::types::MessageCreate {
+								message: chorus::types::Message {
+									id: Snowflake::from(index),
+									..Default::default()
+								},
+								guild_id: Some(guild_id),
+								..Default::default()
+							},
+						),
+					)))
+					.await;
+				let outcome = connected_users.guild_sequencer.enqueue(
+					guild_id,
+					builder,
+					connected_users.clone(),
+				);
+				let _ = produced_send.send(());
+				outcome.await.unwrap().unwrap();
+			}));
+		}
+		futures::future::try_join_all(tasks).await.unwrap();
+
+		for mut inbox in inboxes {
+			let mut received = Vec::new();
+			while let Ok(Event::Dispatch(DispatchEvent::MessageCreate(payload))) = inbox.try_recv()
+			{
+				received.push(payload.event_data.unwrap().message.id);
+			}
+			assert_eq!(received, (0..16u64).map(Snowflake::from).collect::<Vec<_>>());
+		}
+	}
+
+	#[tokio::test]
+	async fn session_events_are_not_sent_to_inboxes() {
+		let connected_users = ConnectedUsers::default();
+		let user = connected_users.new_user(HashMap::new(), Snowflake::from(1u64), Vec::new());
+		let mut inbox = user.lock().await.inbox.resubscribe();
+		let ready = Event::Dispatch(DispatchEvent::Ready(GatewayPayload {
+			op_code: Opcode::Dispatch as u8,
+			event_data: None,
+			sequence_number: None,
+			event_name: Some("READY".to_string()),
+		}));
+
+		assert!(matches!(
+			connected_users.broadcast_to_all(ready.clone()),
+			Err(GatewayError::Misrouted(_))
+		));
+		let mut builder = connected_users.bulk_message_builder();
+		builder.add_user_recipients(&[Snowflake::from(1u64)]).await;
+		builder.set_message(ready).await;
+		assert!(matches!(
+			builder.send(connected_users.clone()).await,
+			Err(Error::Gateway(GatewayError::Misrouted(_)))
+		));
+		assert!(inbox.try_recv().is_err());
+
+		builder = connected_users.bulk_message_builder();
+		builder.add_user_recipients(&[Snowflake::from(1u64)]).await;
+		builder.set_message(message_create()).await;
+		builder.send(connected_users).await.unwrap();
+		assert!(matches!(
+			inbox.try_recv().unwrap(),
+			Event::Dispatch(DispatchEvent::MessageCreate(_))
+		));
+	}
+
+	#[tokio::test]
+	async fn required_permission_filters_recipients() {
+		let connected_users = ConnectedUsers::default();
+		let guild_id = Snowflake::from(100u64);
+		let moderators = Snowflake::from(101u64);
+		let admins = Snowflake::from(102u64);
+		let other_guild_moderators = Snowflake::from(201u64);
+		{
+			let mut role_user_map = connected_users.role_user_map.lock().await;
+			role_user_map.set_role_permissions(guild_id, guild_id, PermissionFlags::SEND_MESSAGES);
+			role_user_map.set_role_permissions(
+				moderators,
+				guild_id,
+				PermissionFlags::MANAGE_WEBHOOKS,
+			);
+			role_user_map.set_role_permissions(admins, guild_id, PermissionFlags::ADMINISTRATOR);
+			role_user_map.set_role_permissions(
+				other_guild_moderators,
+				Snowflake::from(200u64),
+				PermissionFlags::MANAGE_WEBHOOKS,
+			);
+			for user_id in 1..=4u64 {
+				role_user_map.grant_role(guild_id, Snowflake::from(user_id));
+			}
+			role_user_map.grant_role(moderators, Snowflake::from(1u64));
+			role_user_map.grant_role(admins, Snowflake::from(2u64));
+			role_user_map.grant_role(other_guild_moderators, Snowflake::from(3u64));
+		}
+
+		let mut builder = connected_users.bulk_message_builder();
+		builder.add_role_recipients(&[guild_id]).await;
+		builder.require_permission(guild_id, PermissionFlags::MANAGE_WEBHOOKS).await;
+
+		assert_eq!(
+			builder.recipients(&connected_users).await,
+			HashSet::from([Snowflake::from(1u64), Snowflake::from(2u64)])
+		);
+	}
+
+	#[tokio::test]
+	async fn integrations_update_only_reaches_guild_managers() {
+		let connected_users = ConnectedUsers::default();
+		let guild_id = Snowflake::from(100u64);
+		let managers = Snowflake::from(101u64);
+		let (manager_id, member_id, owner_id) =
+			(Snowflake::from(1u64), Snowflake::from(2u64), Snowflake::from(3u64));
+		let mut inboxes = Vec::new();
+		for user_id in [manager_id, member_id, owner_id] {
+			let user = connected_users.new_user(HashMap::new(), user_id, Vec::new());
+			inboxes.push(user.lock().await.inbox.resubscribe());
+		}
+		{
+			let mut role_user_map = connected_users.role_user_map.lock().await;
+			role_user_map.set_role_permissions(guild_id, guild_id, PermissionFlags::VIEW_CHANNEL);
+			role_user_map.set_role_permissions(managers, guild_id, PermissionFlags::MANAGE_GUILD);
+			role_user_map.grant_role(guild_id, manager_id);
+			role_user_map.grant_role(guild_id, member_id);
+			role_user_map.grant_role(guild_id, owner_id);
+			role_user_map.grant_role(managers, manager_id);
+		}
+
+		connected_users.dispatch_guild_integrations_update(guild_id, Some(owner_id)).await.unwrap();
+
+		let Event::Dispatch(DispatchEvent::GuildIntegrationsUpdate(payload)) =
+			inboxes[0].try_recv().unwrap()
+		else {
+			panic!("expected a GUILD_INTEGRATIONS_UPDATE");
+		};
+		assert_eq!(payload.event_data.unwrap().guild_id, guild_id);
+		assert!(inboxes[1].try_recv().is_err());
+		// The guild owner may manage the guild without holding the permission.
+		assert!(inboxes[2].try_recv().is_ok());
+		for inbox in inboxes.iter_mut() {
+			assert!(inbox.try_recv().is_err());
+		}
+	}
+
+	#[tokio::test]
+	async fn only_users_with_live_sessions_are_online() {
+		let connected_users = ConnectedUsers::default();
+		let (user, client, _sent) = test_client(&connected_users).await;
+		let user_id = user.lock().await.id;
+
+		assert!(connected_users.is_online(user_id));
+		assert!(!connected_users.is_online(Snowflake::from(2u64)));
+
+		client.lock().await.die(connected_users.clone(), KillReason::ClientClosed).await.unwrap();
+		assert!(!connected_users.is_online(user_id));
+	}
+
+	#[tokio::test]
+	async fn presence_is_aggregated_across_sessions() {
+		let connected_users = ConnectedUsers::default();
+		let (user, _first, _first_sent) = test_client(&connected_users).await;
+		let (_second, _second_sent) = test_session(&connected_users, &user, "second").await;
+		let user_id = user.lock().await.id;
+
+		// The other session is still online, so the user stays online.
+		connected_users
+			.update_presence(user_id, &SessionToken::from("token"), UserStatus::Idle)
+			.await
+			.unwrap();
+		assert_eq!(GatewayUser::aggregated_presence(&user).await, UserStatus::Online);
+		connected_users
+			.update_presence(user_id, &SessionToken::from("second"), UserStatus::Dnd)
+			.await
+			.unwrap();
+		assert_eq!(GatewayUser::aggregated_presence(&user).await, UserStatus::Idle);
+
+		// The broadcast presences are the aggregated ones.
+		let mut user = user.lock().await;
+		for expected in [UserStatus::Online, UserStatus::Idle] {
+			let Ok(Event::Dispatch(DispatchEvent::PresenceUpdate(payload))) = user.inbox.try_recv()
+			else {
+				panic!("expected a PRESENCE_UPDATE");
+			};
+			assert_eq!(payload.event_data.unwrap().status, expected);
+		}
+	}
+
+	/// Reads the opcode of a text message sent through a connection.
+	fn sent_op_code(message: Message) -> u64 {
+		let Message::Text(text) = message else {
+			panic!("expected a text message, got {message:?}");
+		};
+		serde_json::from_str::<serde_json::Value>(&text).unwrap()["op"].as_u64().unwrap()
+	}
+
+	#[tokio::test]
+	async fn dispatch_before_ready_is_rejected() {
+		let connected_users = ConnectedUsers::default();
+		let (_user, client, mut sent) = test_client(&connected_users).await;
+		let dispatch = Event::Dispatch(DispatchEvent::Resumed(GatewayPayload {
+			op_code: Opcode::Dispatch as u8,
+			event_data: None,
+			sequence_number: None,
+			event_name: Some("RESUMED".to_string()),
+		}));
+
+		let mut client = client.lock().await;
+		assert!(matches!(client.send(dispatch.clone()), Err(GatewayError::NotReady)));
+		client.transition_to(ConnectionState::Identified).unwrap();
+		assert!(matches!(client.send(dispatch.clone()), Err(GatewayError::NotReady)));
+		assert!(sent.try_recv().is_err());
+
+		client.transition_to(ConnectionState::Ready).unwrap();
+		client.send(dispatch).unwrap();
+		assert_eq!(sent_op_code(sent.try_recv().unwrap()), Opcode::Dispatch as u64);
+	}
+
+	#[tokio::test]
+	async fn client_by_token_follows_client_lifetime() {
+		let connected_users = ConnectedUsers::default();
+		let (_user, client, _sent) = test_client(&connected_users).await;
+
+		let found = connected_users.client_by_token(&SessionToken::from("token")).await.unwrap();
+		assert!(Arc::ptr_eq(&found, &client));
+
+		client.lock().await.die(connected_users.clone(), KillReason::Timeout).await.unwrap();
+		assert!(connected_users.client_by_token(&SessionToken::from("token")).await.is_none());
+	}
+
+	#[tokio::test]
+	async fn identify_properties_are_kept_on_the_client() {
+		let connected_users = ConnectedUsers::default();
+		let (_user, client, _sent) = test_client(&connected_users).await;
+		let long_device = "d".repeat(session_info::MAX_PROPERTY_LENGTH + 1);
+
+		client.lock().await.set_properties(ClientProperties::from_value(serde_json::json!({
+			"os": "Windows",
+			"browser": "Firefox",
+			"device": long_device,
+		})));
+
+		let client = client.lock().await;
+		assert_eq!(client.properties().os.as_deref(), Some("Windows"));
+		assert_eq!(client.properties().browser.as_deref(), Some("Firefox"));
+		assert_eq!(
+			client.properties().device.as_ref().map(String::len),
+			Some(session_info::MAX_PROPERTY_LENGTH)
+		);
+	}
+
+	#[tokio::test]
+	async fn voice_state_is_broadcast_and_cleared_on_disconnect() {
+		let connected_users = ConnectedUsers::default();
+		let guild_id = Snowflake::from(100u64);
+		let (user, client, _sent) = test_client(&connected_users).await;
+		let user_id = user.lock().await.id;
+		let member = connected_users.new_user(HashMap::new(), Snowflake::from(2u64), Vec::new());
+		let mut member_inbox = member.lock().await.inbox.resubscribe();
+		{
+			let mut role_user_map = connected_users.role_user_map.lock().await;
+			role_user_map.grant_role(guild_id, user_id);
+			role_user_map.grant_role(guild_id, Snowflake::from(2u64));
+		}
+
+		let state = VoiceState {
+			guild_id: Some(guild_id),
+			channel_id: Some(Snowflake::from(101u64)),
+			..Default::default()
+		};
+		connected_users.update_voice_state(user_id, state).await.unwrap();
+		match member_inbox.try_recv().unwrap() {
+			Event::Dispatch(DispatchEvent::VoiceStateUpdate(payload)) => {
+				assert!(payload.event_data.unwrap().state.channel_id.is_some())
+			}
+			other => panic!("expected a voice state update, got {other:?}"),
+		}
+
+		client.lock().await.die(connected_users.clone(), KillReason::Timeout).await.unwrap();
+		assert!(connected_users.voice_states.lock().await.get(Some(guild_id), user_id).is_none());
+		match member_inbox.try_recv().unwrap() {
+			Event::Dispatch(DispatchEvent::VoiceStateUpdate(payload)) => {
+				assert!(payload.event_data.unwrap().state.channel_id.is_none())
+			}
+			other => panic!("expected a voice state update, got {other:?}"),
+		}
+	}
+
+	#[tokio::test]
+	async fn interaction_reaches_connected_bot() {
+		let connected_users = ConnectedUsers::default();
+		let bot_id = Snowflake::from(5u64);
+		let bot = connected_users.new_user(HashMap::new(), bot_id, Vec::new());
+		let mut inbox = bot.lock().await.inbox.resubscribe();
+
+		let delivered = connected_users
+			.dispatch_interaction(
+				bot_id,
+				InteractionCreate::default(),
+				OfflineInteractionPolicy::Drop,
+			)
+			.await
+			.unwrap();
+
+		assert!(delivered);
+		assert!(matches!(
+			inbox.try_recv().unwrap(),
+			Event::Dispatch(DispatchEvent::InteractionCreate(_))
+		));
+	}
+
+	#[tokio::test]
+	async fn interaction_for_offline_bot_follows_policy() {
+		let connected_users = ConnectedUsers::default();
+		let bot_id = Snowflake::from(5u64);
+
+		for policy in [OfflineInteractionPolicy::Drop, OfflineInteractionPolicy::Store] {
+			let delivered = connected_users
+				.dispatch_interaction(bot_id, InteractionCreate::default(), policy)
+				.await
+				.unwrap();
+			assert!(!delivered);
+		}
+
+		// Only the interaction sent with the `Store` policy has been kept.
+		assert_eq!(connected_users.take_pending_interactions(bot_id).len(), 1);
+		assert!(connected_users.take_pending_interactions(bot_id).is_empty());
+	}
+
+	#[tokio::test]
+	async fn send_reconnect_sends_op_7_and_kills() {
+		let connected_users = ConnectedUsers::default();
+		let (_user, client, mut sent) = test_client(&connected_users).await;
+		let mut kill_receive = client.lock().await.connection.kill_receive.resubscribe();
+
+		client.lock().await.send_reconnect(connected_users.clone()).await.unwrap();
+
+		assert_eq!(sent_op_code(sent.try_recv().unwrap()), 7);
+		assert!(kill_receive.try_recv().is_ok());
+	}
+
+	#[tokio::test]
+	async fn send_invalid_session_sends_op_9() {
+		let connected_users = ConnectedUsers::default();
+		let (_user, client, mut sent) = test_client(&connected_users).await;
+
+		client.lock().await.send_invalid_session(false).unwrap();
+
+		assert_eq!(sent_op_code(sent.try_recv().unwrap()), 9);
+	}
+
+	#[test]
+	fn role_user_map_stays_consistent() {
+		let mut map = RoleUserMap::default();
+		let (role_a, role_b) = (Snowflake::from(10u64), Snowflake::from(11u64));
+		let user = Snowflake::from(1u64);
+
+		map.grant_role(role_a, user);
+		map.grant_role(role_b, user);
+		assert_eq!(map.roles_of(user), HashSet::from([role_a, role_b]));
+		assert!(map.get(&role_a).unwrap().contains(&user));
+
+		map.revoke_role(role_a, user);
+		assert_eq!(map.roles_of(user), HashSet::from([role_b]));
+		assert!(!map.get(&role_a).unwrap().contains(&user));
+
+		map.revoke_role(role_b, user);
+		assert!(map.roles_of(user).is_empty());
+	}
+
+	#[sqlx::test(fixtures(path = "../../fixtures", scripts("users", "guilds")))]
+	async fn role_user_map_is_initialized_with_user_ids(db: PgPool) {
+		let guild_id = Snowflake::from(7249086638293258240u64);
+		let role_id = Snowflake::from(20u64);
+		let user_id = Snowflake::from(7248639845155737600u64);
+		insert_role(&db, role_id, guild_id, PermissionFlags::VIEW_CHANNEL).await;
+		insert_member(&db, user_id, guild_id, role_id).await;
+
+		let mut map = RoleUserMap::default();
+		map.init(&db, 1).await.unwrap();
+
+		// Rows of member_roles reference members by their index, not by user ID.
+		assert_eq!(map.get(&role_id), Some(&HashSet::from([user_id])));
+		assert_eq!(map.roles_of(user_id), HashSet::from([role_id]));
+	}
+
+	#[test]
+	fn replacing_role_users_syncs_both_directions() {
+		let mut map = RoleUserMap::default();
+		let role = Snowflake::from(10u64);
+		let (kept, removed, added) =
+			(Snowflake::from(1u64), Snowflake::from(2u64), Snowflake::from(3u64));
+		map.grant_role(role, kept);
+		map.grant_role(role, removed);
+
+		// `added` has been given the role directly in the database.
+		map.replace_role_users(role, HashSet::from([kept, added]));
+
+		assert_eq!(map.get(&role).unwrap(), &HashSet::from([kept, added]));
+		assert_eq!(map.roles_of(added), HashSet::from([role]));
+		assert!(map.roles_of(removed).is_empty());
+
+		map.replace_role_users(role, HashSet::new());
+		assert!(map.get(&role).unwrap().is_empty());
+		assert!(map.roles_of(kept).is_empty());
+	}
+
+	#[tokio::test]
+	async fn leaving_member_is_removed_from_all_roles_of_the_guild() {
+		let connected_users = ConnectedUsers::default();
+		let (guild_id, other_guild_id) = (Snowflake::from(100u64), Snowflake::from(200u64));
+		let (moderators, other_guild_role) = (Snowflake::from(101u64), Snowflake::from(201u64));
+		let (user_id, other_id) = (Snowflake::from(1u64), Snowflake::from(2u64));
+		{
+			let mut role_user_map = connected_users.role_user_map.lock().await;
+			role_user_map.add_role(moderators, guild_id, PermissionFlags::empty());
+			role_user_map.add_role(other_guild_role, other_guild_id, PermissionFlags::empty());
+			for member in [user_id, other_id] {
+				role_user_map.add_member(guild_id, member);
+				role_user_map.grant_role(moderators, member);
+			}
+			role_user_map.add_member(other_guild_id, user_id);
+			role_user_map.grant_role(other_guild_role, user_id);
+		}
+
+		connected_users
+			.remove_guild_member(guild_id, user_id, async { Ok::<_, Error>(()) })
+			.await
+			.unwrap();
+
+		let role_user_map = connected_users.role_user_map.lock().await;
+		assert_eq!(role_user_map.get(&guild_id).unwrap(), &HashSet::from([other_id]));
+		assert_eq!(role_user_map.get(&moderators).unwrap(), &HashSet::from([other_id]));
+		assert_eq!(
+			role_user_map.roles_of(user_id),
+			HashSet::from([other_guild_id, other_guild_role])
+		);
+	}
+
+	#[tokio::test]
+	async fn role_user_map_is_not_locked_while_members_are_written() {
+		let connected_users = ConnectedUsers::default();
+		let (guild_id, user_id) = (Snowflake::from(100u64), Snowflake::from(1u64));
+		let write = |connected_users: ConnectedUsers| async move {
+			assert!(connected_users.role_user_map.try_lock().is_ok());
+			Ok::<_, Error>(())
+		};
+
+		connected_users
+			.add_guild_member(guild_id, user_id, write(connected_users.clone()))
+			.await
+			.unwrap();
+		assert!(connected_users.role_user_map.lock().await.roles_of(user_id).contains(&guild_id));
+		connected_users
+			.remove_guild_member(guild_id, user_id, write(connected_users.clone()))
+			.await
+			.unwrap();
+		assert!(connected_users.role_user_map.lock().await.roles_of(user_id).is_empty());
+	}
+
+	#[test]
+	fn large_threshold_is_clamped() {
+		assert_eq!(large_threshold(None), DEFAULT_LARGE_THRESHOLD);
+		assert_eq!(large_threshold(Some(-1)), DEFAULT_LARGE_THRESHOLD);
+		assert_eq!(large_threshold(Some(100)), 100);
+		assert_eq!(large_threshold(Some(1000)), MAX_LARGE_THRESHOLD);
+	}
+
+	#[tokio::test]
+	async fn second_init_leaves_map_untouched() {
+		let mut map = RoleUserMap::default();
+		let (role, user) = (Snowflake::from(10u64), Snowflake::from(1u64));
+		map.populate([role, Snowflake::from(11u64)], [(role, user)]);
+		map.grant_role(Snowflake::from(11u64), user);
+
+		// The pool never connects; an initialized map must not query the database.
+		let db = unreachable_db();
+		map.init(&db, 1).await.unwrap();
+
+		assert_eq!(map.len(), 2);
+		assert_eq!(map.get(&role).unwrap(), &HashSet::from([user]));
+		assert_eq!(map.roles_of(user), HashSet::from([role, Snowflake::from(11u64)]));
+	}
+
+	#[test]
+	fn removed_role_is_revoked_from_its_users() {
+		let mut map = RoleUserMap::default();
+		let guild_id = Snowflake::from(100u64);
+		let role_id = Snowflake::from(10u64);
+		let user_id = Snowflake::from(1u64);
+		map.add_role(role_id, guild_id, PermissionFlags::MANAGE_GUILD);
+		assert!(map.get(&role_id).unwrap().is_empty());
+		map.grant_role(role_id, user_id);
+
+		map.remove_role(role_id);
+
+		assert!(map.get(&role_id).is_none());
+		assert!(map.roles_of(user_id).is_empty());
+		assert!(map.users_with_permission(guild_id, PermissionFlags::MANAGE_GUILD).is_empty());
+	}
+
+	#[tokio::test]
+	async fn failed_role_insert_leaves_no_map_entry() {
+		let connected_users = ConnectedUsers::default();
+
+		let result = connected_users
+			.create_role(async { Err(Error::Guild(crate::errors::GuildError::RoleNotFound)) })
+			.await;
+
+		assert!(result.is_err());
+		assert!(connected_users.role_user_map.lock().await.is_empty());
+	}
+
+	#[tokio::test]
+	async fn deleted_role_is_removed_from_the_map() {
+		let connected_users = ConnectedUsers::default();
+		let role_id = Snowflake::from(10u64);
+		let user_id = Snowflake::from(1u64);
+		connected_users.role_user_map.lock().await.grant_role(role_id, user_id);
+
+		connected_users.delete_role(role_id, async { Ok(()) }).await.unwrap();
+
+		let role_user_map = connected_users.role_user_map.lock().await;
+		assert!(role_user_map.get(&role_id).is_none());
+		assert!(role_user_map.roles_of(user_id).is_empty());
+	}
+
+	#[tokio::test]
+	async fn blocked_authors_messages_are_not_delivered() {
+		let connected_users = ConnectedUsers::default();
+		let guild_id = Snowflake::from(100u64);
+		let author_id = Snowflake::from(1u64);
+		let blocker_id = Snowflake::from(2u64);
+		let bystander_id = Snowflake::from(3u64);
+		let mut inboxes = Vec::new();
+		for id in [blocker_id, bystander_id] {
+			let user = connected_users.new_user(HashMap::new(), id, Vec::new());
+			inboxes.push(user.lock().await.inbox.resubscribe());
+			connected_users.role_user_map.lock().await.grant_role(guild_id, id);
+		}
+		connected_users.set_blocking(blocker_id, author_id, true);
+
+		let message = chorus::types::Message {
+			author: Some(chorus::types::PublicUser { id: author_id, ..Default::default() }),
+			..Default::default()
+		};
+		let event = Event::Dispatch(DispatchEvent::MessageCreate(GatewayPayload::dispatch(
+			"MESSAGE_CREATE",
+			chorus::types::MessageCreate { message, ..Default::default() },
+		)));
+		assert_eq!(event.author_id(), Some(author_id));
+		connected_users.broadcast_to_guild(guild_id, event).await.unwrap();
+
+		assert!(inboxes[0].try_recv().is_err());
+		assert!(matches!(
+			inboxes[1].try_recv().unwrap(),
+			Event::Dispatch(DispatchEvent::MessageCreate(_))
+		));
+	}
+
+	#[test]
+	fn aggregate_presence_prefers_higher_priority() {
+		assert_eq!(aggregate_presence([UserStatus::Idle, UserStatus::Online]), UserStatus::Online);
+		assert_eq!(aggregate_presence([UserStatus::Dnd, UserStatus::Idle]), UserStatus::Idle);
+		assert_eq!(aggregate_presence([UserStatus::Offline, UserStatus::Dnd]), UserStatus::Dnd);
+		assert_eq!(
+			aggregate_presence([UserStatus::Invisible, UserStatus::Offline]),
+			UserStatus::Offline
+		);
+		assert_eq!(aggregate_presence([]), UserStatus::Offline);
+	}
 }
@@ -0,0 +1,127 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{
+	collections::{HashMap, VecDeque},
+	time::{Duration, Instant},
+};
+
+use chorus::types::Snowflake;
+
+use super::event::Event;
+
+/// Most interactions kept for a single bot while it is not connected. Once a
+/// bot has this many, the oldest ones are dropped to make room for new ones.
+pub const MAX_PENDING_INTERACTIONS_PER_BOT: usize = 100;
+
+/// How long the token of an interaction can be used to respond to it. Kept
+/// interactions older than this are useless to the bot and are dropped.
+pub const INTERACTION_TOKEN_LIFETIME: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug)]
+/// Interactions for bots without a live session, kept until the bot connects
+/// if [OfflineInteractionPolicy::Store](crate::configuration::OfflineInteractionPolicy::Store)
+/// is configured. Bounded per bot by [MAX_PENDING_INTERACTIONS_PER_BOT] and
+/// [INTERACTION_TOKEN_LIFETIME].
+pub struct PendingInteractions {
+	/// Map of the Snowflake ID of a bot user to the interactions kept for it,
+	/// oldest first, along with when they have been kept.
+	interactions: HashMap<Snowflake, VecDeque<(Instant, Event)>>,
+	/// How long interactions are kept. [INTERACTION_TOKEN_LIFETIME], unless
+	/// shortened by tests.
+	token_lifetime: Duration,
+}
+
+impl Default for PendingInteractions {
+	fn default() -> Self {
+		Self { interactions: HashMap::new(), token_lifetime: INTERACTION_TOKEN_LIFETIME }
+	}
+}
+
+impl PendingInteractions {
+	/// Keep `interaction` for the bot user with the ID `bot_user_id`, dropping
+	/// its expired interactions and, if it still has too many, its oldest one.
+	pub fn push(&mut self, bot_user_id: Snowflake, interaction: Event) {
+		let pending = self.interactions.entry(bot_user_id).or_default();
+		drop_expired(pending, self.token_lifetime);
+		if pending.len() >= MAX_PENDING_INTERACTIONS_PER_BOT {
+			log::debug!(target: "symfonia::gateway::PendingInteractions::push", "Too many interactions kept for bot {bot_user_id}. Dropping the oldest one");
+			pending.pop_front();
+		}
+		pending.push_back((Instant::now(), interaction));
+	}
+
+	/// Remove and return the interactions kept for the bot user with the ID
+	/// `bot_user_id` which have not expired yet, oldest first.
+	pub fn take(&mut self, bot_user_id: Snowflake) -> Vec<Event> {
+		let Some(mut pending) = self.interactions.remove(&bot_user_id) else {
+			return Vec::new();
+		};
+		drop_expired(&mut pending, self.token_lifetime);
+		pending.into_iter().map(|(_, interaction)| interaction).collect()
+	}
+
+	/// Drop all expired interactions, along with the bots left without any.
+	pub fn purge_expired(&mut self) {
+		let token_lifetime = self.token_lifetime;
+		self.interactions.retain(|_, pending| {
+			drop_expired(pending, token_lifetime);
+			!pending.is_empty()
+		});
+	}
+}
+
+/// Drop the interactions at the front of `pending` which are older than
+/// `token_lifetime`.
+fn drop_expired(pending: &mut VecDeque<(Instant, Event)>, token_lifetime: Duration) {
+	while pending.front().is_some_and(|(kept_at, _)| kept_at.elapsed() >= token_lifetime) {
+		pending.pop_front();
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use chorus::types::InteractionCreate;
+
+	use super::*;
+	use crate::gateway::{GatewayPayload, dispatchevent::DispatchEvent};
+
+	fn interaction() -> Event {
+		Event::Dispatch(DispatchEvent::InteractionCreate(GatewayPayload::dispatch(
+			"INTERACTION_CREATE",
+			InteractionCreate::default(),
+		)))
+	}
+
+	#[test]
+	fn oldest_interactions_are_dropped_beyond_the_cap() {
+		let mut pending = PendingInteractions::default();
+		let bot_id = Snowflake::from(5u64);
+
+		for _ in 0..MAX_PENDING_INTERACTIONS_PER_BOT + 10 {
+			pending.push(bot_id, interaction());
+		}
+
+		assert_eq!(pending.take(bot_id).len(), MAX_PENDING_INTERACTIONS_PER_BOT);
+		assert!(pending.take(bot_id).is_empty());
+	}
+
+	#[test]
+	fn expired_interactions_are_dropped() {
+		let mut pending =
+			PendingInteractions { token_lifetime: Duration::from_millis(50), ..Default::default() };
+		let expired = Snowflake::from(5u64);
+		let mixed = Snowflake::from(6u64);
+		pending.push(expired, interaction());
+		pending.push(mixed, interaction());
+		std::thread::sleep(Duration::from_millis(60));
+		pending.push(mixed, interaction());
+
+		pending.purge_expired();
+
+		assert!(!pending.interactions.contains_key(&expired));
+		assert_eq!(pending.take(mixed).len(), 1);
+	}
+}
@@ -0,0 +1,186 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::{HashMap, HashSet};
+
+use chorus::types::Snowflake;
+use serde::{Deserialize, Serialize};
+
+use super::session_token::SessionToken;
+
+/// The most users a single [LazyRequest] may subscribe to. Further members of
+/// a request are ignored.
+pub const MAX_LAZY_REQUEST_MEMBERS: usize = 100;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Payload of a lazy request (opcode 14), which a client sends when it shows
+/// a guild or a DM, to receive the presences of the given `members`.
+pub struct LazyRequest {
+	/// The guild the client is looking at. Absent for DMs.
+	#[serde(default)]
+	pub guild_id: Option<Snowflake>,
+	/// Snowflake IDs of the users whose presences the client wants to receive.
+	#[serde(default)]
+	pub members: Vec<Snowflake>,
+}
+
+#[derive(Debug, Default)]
+/// The users whose presences each session has subscribed to with a
+/// [LazyRequest]. Sessions receive the presences of these users even if they
+/// do not share a guild with them, see
+/// [ConnectedUsers::receives_presence](super::ConnectedUsers::receives_presence).
+pub struct PresenceSubscriptions {
+	/// Map of a session token to the Snowflake IDs of the users the session
+	/// has subscribed to.
+	subscriptions: HashMap<SessionToken, HashSet<Snowflake>>,
+	/// Map of the Snowflake ID of a user to the session tokens of the sessions
+	/// subscribed to them.
+	subscribers: HashMap<Snowflake, HashSet<SessionToken>>,
+}
+
+impl PresenceSubscriptions {
+	/// Subscribe the session with the token `session_token` to the presences
+	/// of the users with the IDs `users`, in addition to the users it has
+	/// already subscribed to.
+	pub fn subscribe(
+		&mut self,
+		session_token: &SessionToken,
+		users: impl IntoIterator<Item = Snowflake>,
+	) {
+		for user in users {
+			self.subscriptions.entry(session_token.clone()).or_default().insert(user);
+			self.subscribers.entry(user).or_default().insert(session_token.clone());
+		}
+	}
+
+	/// Unsubscribe the session with the token `session_token` from the
+	/// presences of the users with the IDs `users`.
+	pub fn unsubscribe(
+		&mut self,
+		session_token: &SessionToken,
+		users: impl IntoIterator<Item = Snowflake>,
+	) {
+		for user in users {
+			if let Some(subscriptions) = self.subscriptions.get_mut(session_token) {
+				subscriptions.remove(&user);
+				if subscriptions.is_empty() {
+					self.subscriptions.remove(session_token);
+				}
+			}
+			self.remove_subscriber(user, session_token);
+		}
+	}
+
+	/// Drop all subscriptions of the session with the token `session_token`,
+	/// for example once it has ended.
+	pub fn forget(&mut self, session_token: &SessionToken) {
+		for user in self.subscriptions.remove(session_token).unwrap_or_default() {
+			self.remove_subscriber(user, session_token);
+		}
+	}
+
+	/// Whether the session with the token `session_token` has subscribed to
+	/// the presence of the user with the ID `user`.
+	pub fn is_subscribed(&self, session_token: &SessionToken, user: Snowflake) -> bool {
+		self.subscriptions.get(session_token).is_some_and(|users| users.contains(&user))
+	}
+
+	/// The session tokens of the sessions subscribed to the presence of the
+	/// user with the ID `user`.
+	pub fn subscribers_of(&self, user: Snowflake) -> Vec<SessionToken> {
+		self.subscribers
+			.get(&user)
+			.map(|tokens| tokens.iter().cloned().collect())
+			.unwrap_or_default()
+	}
+
+	fn remove_subscriber(&mut self, user: Snowflake, session_token: &SessionToken) {
+		if let Some(subscribers) = self.subscribers.get_mut(&user) {
+			subscribers.remove(session_token);
+			if subscribers.is_empty() {
+				self.subscribers.remove(&user);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use super::*;
+	use crate::gateway::{ConnectedUsers, testing::unreachable_db};
+
+	#[test]
+	fn forgotten_sessions_leave_no_subscribers_behind() {
+		let mut subscriptions = PresenceSubscriptions::default();
+		let session = SessionToken::from("token");
+		let user = Snowflake::from(1u64);
+		subscriptions.subscribe(&session, [user, Snowflake::from(2u64)]);
+		assert!(subscriptions.is_subscribed(&session, user));
+		assert_eq!(subscriptions.subscribers_of(user), vec![session.clone()]);
+
+		subscriptions.unsubscribe(&session, [user]);
+		assert!(!subscriptions.is_subscribed(&session, user));
+		assert!(subscriptions.subscribers_of(user).is_empty());
+
+		subscriptions.forget(&session);
+		assert!(subscriptions.subscribers_of(Snowflake::from(2u64)).is_empty());
+		assert!(subscriptions.subscriptions.is_empty());
+	}
+
+	#[tokio::test]
+	async fn only_members_of_a_shared_guild_are_subscribed_to() {
+		let connected_users = ConnectedUsers::default();
+		// Requests for a guild do not query the database.
+		let db = unreachable_db();
+		let session = SessionToken::from("token");
+		let (user, member, stranger) =
+			(Snowflake::from(1u64), Snowflake::from(2u64), Snowflake::from(3u64));
+		let (guild_id, other_guild_id) = (Snowflake::from(100u64), Snowflake::from(200u64));
+		{
+			let mut role_user_map = connected_users.role_user_map.lock().await;
+			role_user_map.grant_role(guild_id, user);
+			role_user_map.grant_role(guild_id, member);
+			role_user_map.grant_role(other_guild_id, stranger);
+		}
+
+		let request = |guild_id, members| LazyRequest { guild_id: Some(guild_id), members };
+		connected_users
+			.subscribe_to_presences(&db, user, &session, request(guild_id, vec![member, stranger]))
+			.await
+			.unwrap();
+		// The user is not a member of the other guild.
+		connected_users
+			.subscribe_to_presences(&db, user, &session, request(other_guild_id, vec![stranger]))
+			.await
+			.unwrap();
+
+		let subscriptions = connected_users.presence_subscriptions.read();
+		assert!(subscriptions.is_subscribed(&session, member));
+		assert!(!subscriptions.is_subscribed(&session, stranger));
+	}
+
+	#[tokio::test]
+	async fn lazy_requests_are_capped() {
+		let connected_users = ConnectedUsers::default();
+		let db = unreachable_db();
+		let session = SessionToken::from("token");
+		let guild_id = Snowflake::from(100u64);
+		let members =
+			(1..=MAX_LAZY_REQUEST_MEMBERS as u64 + 1).map(Snowflake::from).collect::<Vec<_>>();
+		{
+			let mut role_user_map = connected_users.role_user_map.lock().await;
+			for member in members.iter() {
+				role_user_map.grant_role(guild_id, *member);
+			}
+		}
+
+		let request = LazyRequest { guild_id: Some(guild_id), members: members.clone() };
+		connected_users.subscribe_to_presences(&db, members[0], &session, request).await.unwrap();
+
+		let subscriptions = connected_users.presence_subscriptions.read();
+		assert!(subscriptions.is_subscribed(&session, members[MAX_LAZY_REQUEST_MEMBERS - 1]));
+		assert!(!subscriptions.is_subscribed(&session, members[MAX_LAZY_REQUEST_MEMBERS]));
+	}
+}
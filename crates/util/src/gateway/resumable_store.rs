@@ -0,0 +1,235 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Storage for the [DisconnectInfo] of sessions which can still be resumed.
+//! The store is a trait, so that a deployment running several gateway nodes
+//! can share it between them, for example through Redis, and a resume landing
+//! on another node than the one the session was connected to still succeeds.
+
+use std::{collections::HashMap, ops::Deref, sync::Arc, time::Duration};
+
+use parking_lot::Mutex;
+
+use super::{DisconnectInfo, session_token::SessionToken};
+
+/// How long a disconnected session can be resumed for by default.
+pub const DEFAULT_RESUME_TTL: Duration = Duration::from_secs(90);
+
+/// A store of the [DisconnectInfo] of resumable sessions, keyed by the
+/// session token of the session. Entries expire once
+/// [ResumableClientsStore::ttl] has passed since their
+/// [DisconnectInfo::disconnected_at], after which they are treated as absent.
+///
+/// Entries are stored whatever the reason of the disconnect. Whether a session
+/// may actually be resumed is up to [DisconnectInfo::is_resumable].
+///
+/// [DisconnectInfo] can be serialized, so that stores shared between gateway
+/// nodes can keep it outside of their memory. Methods are async for the same
+/// reason, and take `&self`, so that stores can be shared without a lock
+/// around them.
+#[async_trait::async_trait]
+pub trait ResumableClientsStore: Send + Sync {
+	/// Store `disconnect_info` under `session_token`, replacing any previous
+	/// entry.
+	async fn insert(&self, session_token: SessionToken, disconnect_info: DisconnectInfo);
+
+	/// The [DisconnectInfo] stored under `session_token`, unless it has
+	/// expired.
+	async fn get(&self, session_token: &SessionToken) -> Option<DisconnectInfo>;
+
+	/// Remove and return the [DisconnectInfo] stored under `session_token`,
+	/// unless it has expired.
+	async fn remove(&self, session_token: &SessionToken) -> Option<DisconnectInfo>;
+
+	/// How long entries can be resumed for after their session has been
+	/// disconnected.
+	fn ttl(&self) -> Duration;
+
+	/// Drop all expired entries, returning how many were dropped. Stores with
+	/// native expiry may do nothing here.
+	async fn purge_expired(&self) -> usize;
+
+	/// Whether a session which has not expired is stored under
+	/// `session_token`.
+	async fn contains_key(&self, session_token: &SessionToken) -> bool {
+		self.get(session_token).await.is_some()
+	}
+}
+
+#[derive(Clone)]
+/// The [ResumableClientsStore] of a gateway node, shared between all of its
+/// sessions. Defaults to an [InMemoryResumableClientsStore].
+pub struct SharedResumableClientsStore(Arc<dyn ResumableClientsStore>);
+
+impl SharedResumableClientsStore {
+	/// Share `store` between all sessions of this gateway node.
+	pub fn new(store: impl ResumableClientsStore + 'static) -> Self {
+		Self(Arc::new(store))
+	}
+}
+
+impl Default for SharedResumableClientsStore {
+	fn default() -> Self {
+		Self::new(InMemoryResumableClientsStore::default())
+	}
+}
+
+impl Deref for SharedResumableClientsStore {
+	type Target = dyn ResumableClientsStore;
+
+	fn deref(&self) -> &Self::Target {
+		self.0.as_ref()
+	}
+}
+
+/// A [ResumableClientsStore] kept in the memory of this gateway node. Sessions
+/// can only be resumed on the node they were connected to.
+pub struct InMemoryResumableClientsStore {
+	ttl: Duration,
+	sessions: Mutex<HashMap<SessionToken, DisconnectInfo>>,
+}
+
+impl Default for InMemoryResumableClientsStore {
+	fn default() -> Self {
+		Self::new(DEFAULT_RESUME_TTL)
+	}
+}
+
+impl InMemoryResumableClientsStore {
+	/// Create an empty store, whose entries expire after `ttl`.
+	pub fn new(ttl: Duration) -> Self {
+		Self { ttl, sessions: Mutex::new(HashMap::new()) }
+	}
+}
+
+/// Whether the session of `disconnect_info` has been disconnected for longer
+/// than `ttl`. A `disconnected_at` in the future, for example after the system
+/// clock has been turned back, counts as just now.
+fn is_expired(disconnect_info: &DisconnectInfo, ttl: Duration) -> bool {
+	disconnect_info.disconnected_at.elapsed().unwrap_or_default() > ttl
+}
+
+#[async_trait::async_trait]
+impl ResumableClientsStore for InMemoryResumableClientsStore {
+	async fn insert(&self, session_token: SessionToken, disconnect_info: DisconnectInfo) {
+		self.sessions.lock().insert(session_token, disconnect_info);
+	}
+
+	async fn get(&self, session_token: &SessionToken) -> Option<DisconnectInfo> {
+		self.sessions
+			.lock()
+			.get(session_token)
+			.filter(|disconnect_info| !is_expired(disconnect_info, self.ttl))
+			.cloned()
+	}
+
+	async fn remove(&self, session_token: &SessionToken) -> Option<DisconnectInfo> {
+		let disconnect_info = self.sessions.lock().remove(session_token)?;
+		(!is_expired(&disconnect_info, self.ttl)).then_some(disconnect_info)
+	}
+
+	fn ttl(&self) -> Duration {
+		self.ttl
+	}
+
+	async fn purge_expired(&self) -> usize {
+		let ttl = self.ttl;
+		let mut sessions = self.sessions.lock();
+		let before = sessions.len();
+		sessions.retain(|_, disconnect_info| !is_expired(disconnect_info, ttl));
+		before - sessions.len()
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::time::SystemTime;
+
+	use chorus::types::Snowflake;
+
+	use super::*;
+	use crate::gateway::{
+		kill_reason::KillReason, resume::ResumeSnapshot, session_info::ClientProperties,
+	};
+
+	fn disconnect_info(session_token: &str, disconnected_at_sequence: u64) -> DisconnectInfo {
+		DisconnectInfo {
+			session_token: SessionToken::from(session_token),
+			user_id: Snowflake::from(1u64),
+			disconnected_at_sequence,
+			shard: None,
+			recent_dispatches: ResumeSnapshot::default(),
+			intents: 0,
+			compress: false,
+			properties: ClientProperties::default(),
+			reason: KillReason::ClientClosed,
+			disconnected_at: SystemTime::now(),
+		}
+	}
+
+	#[tokio::test]
+	async fn insert_get_and_remove() {
+		let store = SharedResumableClientsStore::default();
+		store.insert(SessionToken::from("first"), disconnect_info("first", 3)).await;
+		store.insert(SessionToken::from("second"), disconnect_info("second", 5)).await;
+
+		assert_eq!(
+			store.get(&SessionToken::from("first")).await.unwrap().disconnected_at_sequence,
+			3
+		);
+		assert!(store.contains_key(&SessionToken::from("second")).await);
+		assert_eq!(
+			store.remove(&SessionToken::from("second")).await.unwrap().disconnected_at_sequence,
+			5
+		);
+		assert!(store.remove(&SessionToken::from("second")).await.is_none());
+		assert!(store.get(&SessionToken::from("unknown")).await.is_none());
+		assert_eq!(store.ttl(), DEFAULT_RESUME_TTL);
+	}
+
+	#[tokio::test]
+	async fn expired_sessions_are_absent_and_purged() {
+		let store = InMemoryResumableClientsStore::new(Duration::ZERO);
+		store.insert(SessionToken::from("token"), disconnect_info("token", 1)).await;
+		std::thread::sleep(Duration::from_millis(1));
+
+		assert!(store.get(&SessionToken::from("token")).await.is_none());
+		assert!(!store.contains_key(&SessionToken::from("token")).await);
+		assert_eq!(store.purge_expired().await, 1);
+		assert_eq!(store.purge_expired().await, 0);
+	}
+
+	#[tokio::test]
+	async fn sessions_expire_relative_to_their_disconnect() {
+		let store = InMemoryResumableClientsStore::new(Duration::from_secs(60));
+		let mut stale = disconnect_info("stale", 1);
+		stale.disconnected_at = SystemTime::now() - Duration::from_secs(61);
+		store.insert(SessionToken::from("stale"), stale).await;
+		store.insert(SessionToken::from("fresh"), disconnect_info("fresh", 1)).await;
+
+		assert!(store.get(&SessionToken::from("stale")).await.is_none());
+		assert!(store.get(&SessionToken::from("fresh")).await.is_some());
+		assert_eq!(store.purge_expired().await, 1);
+	}
+
+	#[test]
+	fn disconnect_info_survives_serialization() {
+		let mut disconnected = disconnect_info("token", 7);
+		disconnected.shard = Some((1, 4));
+		disconnected.intents = 513;
+
+		let json = serde_json::to_string(&disconnected).unwrap();
+		let restored: DisconnectInfo = serde_json::from_str(&json).unwrap();
+
+		assert_eq!(restored.session_token, disconnected.session_token);
+		assert_eq!(restored.user_id, disconnected.user_id);
+		assert_eq!(restored.disconnected_at_sequence, 7);
+		assert_eq!(restored.shard, Some((1, 4)));
+		assert_eq!(restored.intents, 513);
+		assert_eq!(restored.recent_dispatches, disconnected.recent_dispatches);
+		assert_eq!(restored.reason, KillReason::ClientClosed);
+		assert_eq!(restored.disconnected_at, disconnected.disconnected_at);
+	}
+}
@@ -0,0 +1,461 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Resuming lets a client pick up a session after its connection dropped. The
+//! client reports the sequence number of the last dispatch it received, and
+//! all later dispatches are sent to it again.
+
+use std::{
+	collections::VecDeque,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{event::Event, metrics::GatewayMetrics};
+use crate::errors::GatewayError;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// An [Event] which has been dispatched to a session, serialized along with
+/// the sequence number it has been dispatched with. Being serialized already,
+/// it can be kept outside of this gateway node, see [ResumeSnapshot].
+pub struct SequencedEvent {
+	pub sequence: u64,
+	/// The serialized event, whose payload carries `sequence` as its sequence
+	/// number. Replaying the same [SequencedEvent] therefore always yields the
+	/// same sequence number.
+	pub payload: Value,
+}
+
+impl SequencedEvent {
+	/// Serialize `event`, dispatched with the sequence number `sequence`.
+	pub fn new(sequence: u64, event: &Event) -> Result<Self, GatewayError> {
+		let mut payload = serde_json::to_value(event).map_err(|_| GatewayError::Internal)?;
+		// Dispatch events serialize to `{"EventName": {"op": 0, "d": {...}, ...}}`.
+		let inner = match &mut payload {
+			Value::Object(object) if matches!(event, Event::Dispatch(_)) => {
+				object.values_mut().next()
+			}
+			_ => Some(&mut payload),
+		};
+		if let Some(Value::Object(inner)) = inner {
+			inner.insert("s".to_string(), Value::from(sequence));
+		}
+		Ok(Self { sequence, payload })
+	}
+}
+
+/// The events of `dispatched` a client resuming from `resume_sequence` has
+/// not received yet, in order. `resume_sequence` is the sequence number the
+/// client reports in its resume payload. Events up to and including it have
+/// reached the client, even if the server never learned about it, and must not
+/// be sent again.
+pub fn replay_after<'a>(
+	dispatched: impl IntoIterator<Item = &'a SequencedEvent>,
+	resume_sequence: u64,
+) -> impl Iterator<Item = &'a SequencedEvent> {
+	dispatched.into_iter().filter(move |event| event.sequence > resume_sequence)
+}
+
+/// The events of `dispatched` a client resuming from `resume_sequence` has not
+/// received yet, given that events up to `last_evicted` are no longer
+/// retained and `latest` is the last one dispatched. See
+/// [ResumeBuffer::replay_after].
+fn checked_replay_after<'a>(
+	dispatched: impl IntoIterator<Item = &'a SequencedEvent>,
+	last_evicted: Option<u64>,
+	latest: Option<u64>,
+	resume_sequence: u64,
+) -> Result<Vec<SequencedEvent>, GatewayError> {
+	let latest = latest.unwrap_or_default();
+	if resume_sequence > latest {
+		log::debug!(target: "symfonia::gateway::resume", "Rejecting resume from sequence {resume_sequence}, which is later than the latest dispatch {latest}");
+		return Err(GatewayError::SessionNotResumable);
+	}
+	if last_evicted.is_some_and(|evicted| evicted > resume_sequence) {
+		return Err(GatewayError::SessionNotResumable);
+	}
+	Ok(replay_after(dispatched, resume_sequence).cloned().collect())
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+/// The events a [ResumeBuffer] retained when its session was disconnected,
+/// taken with [ResumeBuffer::snapshot]. Unlike the buffer, it can be
+/// serialized, so that sessions can be resumed on another gateway node.
+pub struct ResumeSnapshot {
+	events: Vec<SequencedEvent>,
+	last_evicted: Option<u64>,
+	latest: Option<u64>,
+}
+
+impl ResumeSnapshot {
+	/// The retained events a client resuming from `resume_sequence` has not
+	/// received yet. See [ResumeBuffer::replay_after].
+	pub fn replay_after(&self, resume_sequence: u64) -> Result<Vec<SequencedEvent>, GatewayError> {
+		checked_replay_after(&self.events, self.last_evicted, self.latest, resume_sequence)
+	}
+}
+
+/// Length of the windows the dispatch rate of an adaptive [ResumeBuffer] is
+/// measured over.
+pub const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+/// The most recent [SequencedEvent]s dispatched to a session, retained so that
+/// they can be replayed when the session is resumed. Once the buffer is full,
+/// the oldest event is evicted for every new one.
+///
+/// Buffers created with [ResumeBuffer::adaptive] adapt their capacity to the
+/// rate of dispatches: a buffer which has been filled within a single
+/// [RATE_WINDOW] doubles in capacity, up to its ceiling, and every window in
+/// which less than half of its capacity has been dispatched halves it again,
+/// down to its floor.
+pub struct ResumeBuffer {
+	capacity: usize,
+	/// Capacity the buffer shrinks back to while the session is quiet.
+	floor: usize,
+	/// Capacity the buffer grows up to while the session is busy.
+	ceiling: usize,
+	events: VecDeque<SequencedEvent>,
+	/// Sequence number of the most recently evicted event, if any.
+	last_evicted: Option<u64>,
+	/// Sequence number of the most recently pushed event, if any. Clients
+	/// cannot have received anything later.
+	latest: Option<u64>,
+	/// Start of the current [RATE_WINDOW].
+	window_start: Instant,
+	/// Number of events pushed since `window_start`.
+	window_dispatches: usize,
+	/// Metrics the capacity of this buffer is reported to, if any.
+	metrics: Option<Arc<GatewayMetrics>>,
+}
+
+impl ResumeBuffer {
+	/// Create an empty [ResumeBuffer] retaining up to `capacity` events.
+	pub fn new(capacity: usize) -> Self {
+		Self::adaptive(capacity, capacity)
+	}
+
+	/// Create an empty [ResumeBuffer] retaining between `floor` and `ceiling`
+	/// events, depending on the rate of dispatches. A `ceiling` below `floor`
+	/// is raised to it, making the capacity fixed.
+	pub fn adaptive(floor: usize, ceiling: usize) -> Self {
+		Self {
+			capacity: floor,
+			floor,
+			ceiling: ceiling.max(floor),
+			events: VecDeque::with_capacity(floor),
+			last_evicted: None,
+			latest: None,
+			window_start: Instant::now(),
+			window_dispatches: 0,
+			metrics: None,
+		}
+	}
+
+	/// Report the capacity of this buffer to `metrics`, for as long as it is
+	/// alive.
+	pub fn with_metrics(mut self, metrics: Arc<GatewayMetrics>) -> Self {
+		metrics.add_resume_buffer(self.capacity);
+		if let Some(previous) = self.metrics.replace(metrics) {
+			previous.remove_resume_buffer(self.capacity);
+		}
+		self
+	}
+
+	/// Number of events the buffer currently retains at most.
+	pub fn capacity(&self) -> usize {
+		self.capacity
+	}
+
+	/// The events currently retained, to be stored while the session is
+	/// disconnected.
+	pub fn snapshot(&self) -> ResumeSnapshot {
+		ResumeSnapshot {
+			events: self.events.iter().cloned().collect(),
+			last_evicted: self.last_evicted,
+			latest: self.latest,
+		}
+	}
+
+	/// Retain the events of `snapshot`, taken from the buffer of the session
+	/// being resumed, replacing the retained events. The oldest events are
+	/// evicted if they do not fit.
+	pub fn restore(&mut self, snapshot: ResumeSnapshot) {
+		self.events = snapshot.events.into();
+		self.last_evicted = snapshot.last_evicted;
+		self.latest = snapshot.latest;
+		let excess = self.events.len().saturating_sub(self.capacity);
+		if let Some(evicted) = self.events.drain(..excess).last() {
+			self.last_evicted = Some(evicted.sequence);
+		}
+	}
+
+	/// Adapt the capacity of the buffer to the rate of dispatches without
+	/// retaining an event. Buffers otherwise only adapt when events are pushed,
+	/// so this is meant to be called periodically, to shrink the buffers of
+	/// sessions which have gone quiet after a burst.
+	pub fn tick(&mut self) {
+		self.end_windows(Instant::now());
+	}
+
+	/// Retain `event`, evicting the oldest event if the buffer is full.
+	pub fn push(&mut self, event: SequencedEvent) {
+		self.push_at(event, Instant::now());
+	}
+
+	/// [Self::push] `event` at the time `now`, adapting the capacity of the
+	/// buffer to the rate of dispatches first.
+	fn push_at(&mut self, event: SequencedEvent, now: Instant) {
+		self.end_windows(now);
+		self.window_dispatches += 1;
+		if self.window_dispatches > self.capacity {
+			// The whole buffer has been dispatched within one window, so even a short
+			// disconnect would leave the session unable to resume.
+			self.resize(self.capacity.saturating_mul(2).max(1));
+		}
+		self.latest = Some(event.sequence);
+		if self.capacity == 0 {
+			self.last_evicted = Some(event.sequence);
+			return;
+		}
+		if self.events.len() == self.capacity {
+			if let Some(evicted) = self.events.pop_front() {
+				self.last_evicted = Some(evicted.sequence);
+			}
+		}
+		self.events.push_back(event);
+	}
+
+	/// Shrink the buffer for every [RATE_WINDOW] which has ended before `now`
+	/// with less than half of the capacity dispatched in it, and start a new
+	/// window if any has ended.
+	fn end_windows(&mut self, now: Instant) {
+		let elapsed = now.saturating_duration_since(self.window_start);
+		if elapsed < RATE_WINDOW {
+			return;
+		}
+		// Only the first of the windows which have ended can have seen dispatches.
+		let quiet_windows = elapsed.as_nanos() / RATE_WINDOW.as_nanos() - 1
+			+ u128::from(self.window_dispatches.saturating_mul(2) < self.capacity);
+		let capacity = u32::try_from(quiet_windows)
+			.ok()
+			.and_then(|halvings| self.capacity.checked_shr(halvings))
+			.unwrap_or_default();
+		self.resize(capacity);
+		self.window_start = now;
+		self.window_dispatches = 0;
+	}
+
+	/// Change the capacity of the buffer to `capacity`, clamped between its
+	/// floor and ceiling, evicting the oldest events which no longer fit and
+	/// releasing the memory they took up.
+	fn resize(&mut self, capacity: usize) {
+		let capacity = capacity.clamp(self.floor, self.ceiling);
+		if capacity == self.capacity {
+			return;
+		}
+		let excess = self.events.len().saturating_sub(capacity);
+		if let Some(evicted) = self.events.drain(..excess).last() {
+			self.last_evicted = Some(evicted.sequence);
+		}
+		self.events.shrink_to(capacity);
+		if let Some(metrics) = &self.metrics {
+			metrics.resize_resume_buffer(self.capacity, capacity);
+		}
+		self.capacity = capacity;
+	}
+
+	/// The retained events a client resuming from `resume_sequence` has not
+	/// received yet. See [replay_after].
+	///
+	/// ## Errors
+	///
+	/// Returns [GatewayError::SessionNotResumable] if some of these events have
+	/// already been evicted, or if `resume_sequence` is later than any event
+	/// dispatched to the session. The client then has to be sent an invalid
+	/// session and identify anew. Clients can therefore neither skip events nor
+	/// have more replayed than is retained.
+	pub fn replay_after(&self, resume_sequence: u64) -> Result<Vec<SequencedEvent>, GatewayError> {
+		checked_replay_after(&self.events, self.last_evicted, self.latest, resume_sequence)
+	}
+}
+
+impl Drop for ResumeBuffer {
+	fn drop(&mut self) {
+		if let Some(metrics) = &self.metrics {
+			metrics.remove_resume_buffer(self.capacity);
+		}
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use chorus::types::Opcode;
+
+	use super::*;
+	use crate::gateway::{GatewayPayload, dispatchevent::DispatchEvent};
+
+	fn dispatched(sequence: u64) -> SequencedEvent {
+		let event = Event::Dispatch(DispatchEvent::Resumed(GatewayPayload {
+			op_code: Opcode::Dispatch as u8,
+			event_data: None,
+			sequence_number: None,
+			event_name: Some("RESUMED".to_string()),
+		}));
+		SequencedEvent::new(sequence, &event).unwrap()
+	}
+
+	fn sequence_of(event: &SequencedEvent) -> u64 {
+		event.payload.as_object().unwrap().values().next().unwrap()["s"].as_u64().unwrap()
+	}
+
+	#[test]
+	fn replay_starts_after_client_sequence() {
+		let events: Vec<SequencedEvent> = (1..=8).map(dispatched).collect();
+
+		let replayed: Vec<u64> = replay_after(&events, 5).map(sequence_of).collect();
+
+		assert_eq!(replayed, vec![6, 7, 8]);
+	}
+
+	#[test]
+	fn replay_from_latest_sequence_is_empty() {
+		let events: Vec<SequencedEvent> = (1..=3).map(dispatched).collect();
+		assert_eq!(replay_after(&events, 3).count(), 0);
+	}
+
+	#[test]
+	fn resume_buffer_evicts_oldest_events() {
+		let mut buffer = ResumeBuffer::new(3);
+		for sequence in 1..=5 {
+			buffer.push(dispatched(sequence));
+		}
+
+		let replayed: Vec<u64> =
+			buffer.replay_after(3).unwrap().iter().map(|event| event.sequence).collect();
+		assert_eq!(replayed, vec![4, 5]);
+		// Events 1 and 2 have been evicted, so resuming from 1 would miss event 2.
+		assert!(matches!(buffer.replay_after(1), Err(GatewayError::SessionNotResumable)));
+		assert!(buffer.replay_after(2).is_ok());
+	}
+
+	#[test]
+	fn too_high_resume_sequence_is_rejected() {
+		let mut buffer = ResumeBuffer::new(3);
+		for sequence in 1..=3 {
+			buffer.push(dispatched(sequence));
+		}
+
+		assert!(buffer.replay_after(3).unwrap().is_empty());
+		assert!(matches!(buffer.replay_after(4), Err(GatewayError::SessionNotResumable)));
+		assert!(matches!(buffer.replay_after(u64::MAX), Err(GatewayError::SessionNotResumable)));
+		assert!(matches!(
+			ResumeBuffer::new(3).replay_after(1),
+			Err(GatewayError::SessionNotResumable)
+		));
+	}
+
+	#[test]
+	fn too_low_resume_sequence_is_rejected() {
+		let mut buffer = ResumeBuffer::new(3);
+		for sequence in 1..=100 {
+			buffer.push(dispatched(sequence));
+		}
+
+		// Replaying everything since sequence 0 would take more than is retained.
+		assert!(matches!(buffer.replay_after(0), Err(GatewayError::SessionNotResumable)));
+		assert_eq!(buffer.replay_after(97).unwrap().len(), 3);
+	}
+
+	#[test]
+	fn adaptive_buffer_grows_under_load_and_shrinks_when_idle() {
+		let metrics = Arc::new(GatewayMetrics::default());
+		let mut buffer = ResumeBuffer::adaptive(4, 32).with_metrics(metrics.clone());
+		let start = Instant::now();
+		assert_eq!(metrics.snapshot().resume_buffer_capacity, 4);
+
+		// A burst within a single window grows the buffer up to its ceiling.
+		for sequence in 1..=100 {
+			buffer.push_at(dispatched(sequence), start);
+		}
+		assert_eq!(buffer.capacity(), 32);
+		assert_eq!(buffer.replay_after(68).unwrap().len(), 32);
+		assert_eq!(metrics.snapshot().resume_buffer_capacity, 32);
+
+		// The window of the burst was busy, the next one is quiet and halves it.
+		buffer.push_at(dispatched(101), start + RATE_WINDOW);
+		assert_eq!(buffer.capacity(), 32);
+		buffer.push_at(dispatched(102), start + RATE_WINDOW * 2);
+		assert_eq!(buffer.capacity(), 16);
+		assert!(matches!(buffer.replay_after(85), Err(GatewayError::SessionNotResumable)));
+		assert_eq!(buffer.replay_after(86).unwrap().len(), 16);
+
+		// A long quiet period shrinks it back to its floor.
+		buffer.push_at(dispatched(103), start + RATE_WINDOW * 7);
+		assert_eq!(buffer.capacity(), 4);
+		assert!(matches!(buffer.replay_after(98), Err(GatewayError::SessionNotResumable)));
+		assert_eq!(buffer.replay_after(99).unwrap().len(), 4);
+		assert_eq!(metrics.snapshot().resume_buffer_capacity, 4);
+
+		drop(buffer);
+		let snapshot = metrics.snapshot();
+		assert_eq!((snapshot.resume_buffers, snapshot.resume_buffer_capacity), (0, 0));
+	}
+
+	#[test]
+	fn quiet_buffer_shrinks_and_releases_memory_without_pushes() {
+		let mut buffer = ResumeBuffer::adaptive(4, 64);
+		let start = Instant::now();
+		for sequence in 1..=64 {
+			buffer.push_at(dispatched(sequence), start);
+		}
+		assert_eq!(buffer.capacity(), 64);
+		buffer.window_start -= RATE_WINDOW * 8;
+
+		buffer.tick();
+
+		assert_eq!(buffer.capacity(), 4);
+		assert!(buffer.events.capacity() < 64);
+		assert_eq!(buffer.replay_after(60).unwrap().len(), 4);
+	}
+
+	#[test]
+	fn non_dispatch_events_keep_their_shape() {
+		let event = SequencedEvent::new(
+			4,
+			&Event::Reconnect(GatewayPayload {
+				op_code: Opcode::Reconnect as u8,
+				event_data: None,
+				sequence_number: None,
+				event_name: None,
+			}),
+		)
+		.unwrap();
+		assert_eq!(event.payload["s"], 4);
+	}
+
+	#[test]
+	fn snapshot_survives_serialization_and_restores_into_a_smaller_buffer() {
+		let mut buffer = ResumeBuffer::new(4);
+		for sequence in 1..=6 {
+			buffer.push(dispatched(sequence));
+		}
+		let json = serde_json::to_string(&buffer.snapshot()).unwrap();
+		let snapshot: ResumeSnapshot = serde_json::from_str(&json).unwrap();
+		assert_eq!(snapshot, buffer.snapshot());
+		assert!(matches!(snapshot.replay_after(1), Err(GatewayError::SessionNotResumable)));
+		assert_eq!(snapshot.replay_after(2).unwrap().len(), 4);
+
+		let mut restored = ResumeBuffer::new(2);
+		restored.restore(snapshot);
+		assert!(matches!(restored.replay_after(3), Err(GatewayError::SessionNotResumable)));
+		let replayed: Vec<u64> =
+			restored.replay_after(4).unwrap().iter().map(|event| event.sequence).collect();
+		assert_eq!(replayed, vec![5, 6]);
+	}
+}
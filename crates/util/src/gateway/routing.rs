@@ -0,0 +1,99 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Events either concern all sessions of a user, such as a new message, or a
+//! single session, such as the `READY` it has been sent after identifying. The
+//! former go to the inbox of the user, the latter have to be sent to the
+//! [GatewayClient](super::GatewayClient) directly. The [RoutingTable] decides
+//! which is which.
+
+use std::collections::HashMap;
+
+use super::{dispatchevent::DispatchEventType, event::EventType};
+use crate::errors::GatewayError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Where an event is delivered to.
+pub enum Route {
+	/// The inbox shared by all sessions of a user.
+	User,
+	/// A single session, through
+	/// [GatewayClient::send](super::GatewayClient::send).
+	Client,
+}
+
+#[derive(Debug, Default, Clone)]
+/// The [Route] of each [EventType]. Event types without an explicit route use
+/// their [default_route].
+pub struct RoutingTable {
+	routes: HashMap<EventType, Route>,
+}
+
+impl RoutingTable {
+	/// The [Route] events of type `event_type` take.
+	pub fn route(&self, event_type: EventType) -> Route {
+		self.routes.get(&event_type).copied().unwrap_or_else(|| default_route(event_type))
+	}
+
+	/// Deliver events of type `event_type` through `route` from now on.
+	pub fn set_route(&mut self, event_type: EventType, route: Route) {
+		self.routes.insert(event_type, route);
+	}
+
+	/// Check that events of type `event_type` may be delivered through
+	/// `route`.
+	///
+	/// ## Errors
+	///
+	/// Returns [GatewayError::Misrouted] if events of this type take another
+	/// route.
+	pub fn check(&self, event_type: EventType, route: Route) -> Result<(), GatewayError> {
+		if self.route(event_type) != route {
+			return Err(GatewayError::Misrouted(event_type));
+		}
+		Ok(())
+	}
+}
+
+/// The [Route] of events of type `event_type` unless configured otherwise.
+/// Events describing the state of a connection, such as `READY`, `RESUMED` and
+/// heartbeat acknowledgements, concern a single session. Everything else is
+/// shared by all sessions of a user.
+pub fn default_route(event_type: EventType) -> Route {
+	match event_type {
+		EventType::Hello
+		| EventType::HeartbeatAck
+		| EventType::InvalidSession
+		| EventType::Reconnect
+		| EventType::Dispatch(
+			DispatchEventType::Ready
+			| DispatchEventType::ReadySupplemental
+			| DispatchEventType::Resumed,
+		) => Route::Client,
+		_ => Route::User,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn ready_is_routed_to_the_client_and_messages_to_the_user() {
+		let mut table = RoutingTable::default();
+		let ready = EventType::Dispatch(DispatchEventType::Ready);
+		let message_create = EventType::Dispatch(DispatchEventType::MessageCreate);
+
+		assert_eq!(table.route(ready), Route::Client);
+		assert_eq!(table.route(message_create), Route::User);
+		assert!(matches!(
+			table.check(ready, Route::User),
+			Err(GatewayError::Misrouted(event_type)) if event_type == ready
+		));
+		assert!(table.check(message_create, Route::User).is_ok());
+
+		table.set_route(message_create, Route::Client);
+		assert_eq!(table.route(message_create), Route::Client);
+	}
+}
@@ -0,0 +1,88 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Metadata about the sessions of a user, as shown in the list of devices
+//! which are logged into an account.
+
+use chorus::types::GatewayIdentifyConnectionProps;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Longest value of a [ClientProperties] field, in characters. Clients choose
+/// these values freely, so longer ones are truncated.
+pub const MAX_PROPERTY_LENGTH: usize = 128;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+/// The client a session has been opened with, as reported in the `properties`
+/// of its identify payload.
+pub struct ClientProperties {
+	pub os: Option<String>,
+	pub browser: Option<String>,
+	pub device: Option<String>,
+}
+
+impl ClientProperties {
+	/// The [ClientProperties] reported in the `properties` of an identify
+	/// payload. See [Self::from_value].
+	pub fn from_identify(properties: &GatewayIdentifyConnectionProps) -> Self {
+		serde_json::to_value(properties).map(Self::from_value).unwrap_or_default()
+	}
+
+	/// The [ClientProperties] in the JSON object `value`. Fields which are
+	/// missing, empty or not strings are treated as not reported. Control
+	/// characters are removed and values are truncated to
+	/// [MAX_PROPERTY_LENGTH] characters.
+	pub fn from_value(value: serde_json::Value) -> Self {
+		let property = |name: &str| {
+			let value: String = value
+				.get(name)?
+				.as_str()?
+				.chars()
+				.filter(|character| !character.is_control())
+				.take(MAX_PROPERTY_LENGTH)
+				.collect();
+			(!value.is_empty()).then_some(value)
+		};
+		Self { os: property("os"), browser: property("browser"), device: property("device") }
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+/// A snapshot of the metadata of one session of a user.
+pub struct SessionInfo {
+	/// The session token, masked with
+	/// [SessionToken::masked](super::session_token::SessionToken::masked).
+	pub session_token: String,
+	/// When the session has been opened.
+	pub connected_at: DateTime<Utc>,
+	/// The last sequence number the client has acknowledged.
+	pub last_sequence: u64,
+	/// The `[shard_id, num_shards]` the session identified with, if any.
+	pub shard: Option<(u64, u64)>,
+	pub properties: ClientProperties,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn properties_are_sanitized() {
+		let properties = ClientProperties::from_value(serde_json::json!({
+			"os": "Linux",
+			"browser": "x".repeat(MAX_PROPERTY_LENGTH * 4),
+			"device": "",
+			"system_locale": "en-US",
+		}));
+
+		assert_eq!(properties.os.as_deref(), Some("Linux"));
+		assert_eq!(properties.browser, Some("x".repeat(MAX_PROPERTY_LENGTH)));
+		assert_eq!(properties.device, None);
+		assert_eq!(
+			ClientProperties::from_value(serde_json::json!({ "os": "Li\nnux\u{0}" })).os.as_deref(),
+			Some("Linux")
+		);
+		assert_eq!(ClientProperties::from_value(serde_json::json!({ "os": 7 })).os, None);
+	}
+}
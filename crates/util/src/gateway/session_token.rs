@@ -0,0 +1,154 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{
+	borrow::Borrow,
+	fmt::{Debug, Display},
+	ops::Deref,
+	str::FromStr,
+};
+
+use rand::{Rng, distributions::Alphanumeric};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::GatewayError;
+
+/// Longest session token which is accepted, in bytes.
+pub const MAX_SESSION_TOKEN_LENGTH: usize = 4096;
+/// Number of characters of a token created by [SessionToken::generate].
+const GENERATED_SESSION_TOKEN_LENGTH: usize = 32;
+/// Number of characters of a session token left visible by
+/// [SessionToken::masked].
+const VISIBLE_TOKEN_LENGTH: usize = 4;
+
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+/// The token identifying a session of the gateway, which it is registered and
+/// can be resumed under.
+///
+/// Both [Display] and [Debug] only show the token masked with
+/// [SessionToken::masked], so that it does not end up in logs. Use
+/// [SessionToken::as_str] where the token itself is needed.
+pub struct SessionToken(String);
+
+impl SessionToken {
+	/// Create a new, random session token.
+	pub fn generate() -> Self {
+		Self(
+			rand::thread_rng()
+				.sample_iter(&Alphanumeric)
+				.take(GENERATED_SESSION_TOKEN_LENGTH)
+				.map(char::from)
+				.collect(),
+		)
+	}
+
+	/// Whether `token` could be a session token: It must not be empty or
+	/// longer than [MAX_SESSION_TOKEN_LENGTH], and may only consist of visible
+	/// ASCII characters.
+	pub fn is_valid(token: &str) -> bool {
+		!token.is_empty()
+			&& token.len() <= MAX_SESSION_TOKEN_LENGTH
+			&& token.bytes().all(|byte| byte.is_ascii_graphic())
+	}
+
+	/// The token itself. Must not be logged.
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+
+	/// The token with all but its last few characters masked, so that
+	/// sessions can be told apart without exposing the token itself. This is
+	/// how session tokens appear in logs and session lists.
+	pub fn masked(&self) -> String {
+		let visible =
+			self.0.char_indices().rev().nth(VISIBLE_TOKEN_LENGTH - 1).map_or(0, |(index, _)| index);
+		format!("{}{}", "*".repeat(self.0[..visible].chars().count()), &self.0[visible..])
+	}
+}
+
+impl FromStr for SessionToken {
+	type Err = GatewayError;
+
+	/// Parse a session token received from a client, rejecting those for
+	/// which [SessionToken::is_valid] does not hold.
+	fn from_str(token: &str) -> Result<Self, Self::Err> {
+		match Self::is_valid(token) {
+			true => Ok(Self(token.to_string())),
+			false => Err(GatewayError::UnexpectedMessage("Invalid session token".to_string())),
+		}
+	}
+}
+
+/// Wraps a token which has already been verified, or has been generated by
+/// the gateway itself, without validating it again.
+impl From<String> for SessionToken {
+	fn from(token: String) -> Self {
+		Self(token)
+	}
+}
+
+impl From<&str> for SessionToken {
+	fn from(token: &str) -> Self {
+		Self(token.to_string())
+	}
+}
+
+impl Deref for SessionToken {
+	type Target = str;
+
+	fn deref(&self) -> &Self::Target {
+		&self.0
+	}
+}
+
+impl Borrow<str> for SessionToken {
+	fn borrow(&self) -> &str {
+		&self.0
+	}
+}
+
+impl Display for SessionToken {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(&self.masked())
+	}
+}
+
+impl Debug for SessionToken {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_tuple("SessionToken").field(&self.masked()).finish()
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::collections::HashSet;
+
+	use super::*;
+
+	#[test]
+	fn generated_tokens_are_unique_and_valid() {
+		let tokens: HashSet<SessionToken> = (0..1000).map(|_| SessionToken::generate()).collect();
+		assert_eq!(tokens.len(), 1000);
+		assert!(tokens.iter().all(|token| SessionToken::is_valid(token.as_str())));
+	}
+
+	#[test]
+	fn tokens_are_masked_when_formatted() {
+		let token = SessionToken::from("header.claims.sig1");
+		assert_eq!(token.to_string(), "**************sig1");
+		assert_eq!(format!("{token:?}"), "SessionToken(\"**************sig1\")");
+		assert_eq!(token.as_str(), "header.claims.sig1");
+		assert_eq!(SessionToken::from("abc").masked(), "abc");
+	}
+
+	#[test]
+	fn invalid_tokens_are_rejected() {
+		assert!("header.claims.sig1".parse::<SessionToken>().is_ok());
+		assert!("".parse::<SessionToken>().is_err());
+		assert!("with space".parse::<SessionToken>().is_err());
+		assert!("x".repeat(MAX_SESSION_TOKEN_LENGTH + 1).parse::<SessionToken>().is_err());
+	}
+}
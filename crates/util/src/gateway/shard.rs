@@ -0,0 +1,80 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Sharding splits the guilds of a user across multiple sessions. A session
+//! identifies with a shard of `(shard_id, shard_count)` and then only receives
+//! events of the guilds belonging to that shard.
+
+use chorus::types::Snowflake;
+
+use crate::errors::GatewayError;
+
+/// Calculate the ID of the shard the guild with the ID `guild_id` belongs to.
+pub fn shard_of_guild(guild_id: Snowflake, shard_count: u64) -> u64 {
+	(u64::from(guild_id) >> 22) % shard_count
+}
+
+/// Whether a session identified with `shard` should receive events of the
+/// guild with the ID `guild_id`. Sessions without a shard receive events of all
+/// guilds.
+pub fn receives_guild(shard: Option<(u64, u64)>, guild_id: Snowflake) -> bool {
+	match shard {
+		Some((shard_id, shard_count)) => shard_of_guild(guild_id, shard_count) == shard_id,
+		None => true,
+	}
+}
+
+/// Check whether a session may identify with `shard`, given the shards of the
+/// other sessions of the same user.
+///
+/// ## Errors
+///
+/// - [GatewayError::InvalidShard] if `shard` is not a valid shard
+/// - [GatewayError::ShardingRequired] if the shard count of `shard` differs
+///   from the shard count of any of the `existing` sessions. Sessions without a
+///   shard count as having a shard count of 1.
+pub fn validate_shard(
+	shard: Option<(u64, u64)>,
+	existing: impl IntoIterator<Item = Option<(u64, u64)>>,
+) -> Result<(), GatewayError> {
+	if let Some((shard_id, shard_count)) = shard {
+		if shard_id >= shard_count {
+			return Err(GatewayError::InvalidShard);
+		}
+	}
+	let shard_count = |shard: Option<(u64, u64)>| shard.map_or(1, |(_, count)| count);
+	if existing.into_iter().any(|other| shard_count(other) != shard_count(shard)) {
+		return Err(GatewayError::ShardingRequired);
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn events_are_routed_to_the_guilds_shard() {
+		// Shard 1 of 2
+		let guild_id = Snowflake::from(1u64 << 22);
+		assert!(receives_guild(Some((1, 2)), guild_id));
+		assert!(!receives_guild(Some((0, 2)), guild_id));
+		assert!(receives_guild(None, guild_id));
+	}
+
+	#[test]
+	fn shards_are_validated() {
+		assert!(validate_shard(Some((0, 2)), [Some((1, 2))]).is_ok());
+		assert!(validate_shard(None, [None]).is_ok());
+		assert!(matches!(validate_shard(Some((2, 2)), []), Err(GatewayError::InvalidShard)));
+		assert!(matches!(
+			validate_shard(Some((0, 2)), [Some((0, 3))]),
+			Err(GatewayError::ShardingRequired)
+		));
+		assert!(matches!(
+			validate_shard(Some((0, 2)), [None]),
+			Err(GatewayError::ShardingRequired)
+		));
+	}
+}
@@ -0,0 +1,250 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+
+use chorus::types::Snowflake;
+use parking_lot::RwLock;
+
+/// Number of shards a [ShardedMap] is split into by default.
+pub const DEFAULT_SHARD_COUNT: usize = 16;
+
+/// A map of Snowflake IDs to values, split into a fixed number of shards which
+/// are each guarded by their own lock.
+///
+/// A key always lives in the shard `key % shard_count`. Operations on keys in
+/// different shards therefore never wait for one another, which keeps lookups
+/// of connected users from contending on a single lock while many sessions
+/// connect and disconnect at the same time.
+///
+/// ## Locking
+///
+/// Every method acquires the lock of at most one shard at a time, and never
+/// holds it past its own return. Methods spanning all shards, such as
+/// [ShardedMap::entries], lock the shards one after another; their result is
+/// not an atomic snapshot of the whole map.
+pub struct ShardedMap<V> {
+	shards: Box<[RwLock<HashMap<Snowflake, V>>]>,
+}
+
+impl<V> Default for ShardedMap<V> {
+	fn default() -> Self {
+		Self::new(DEFAULT_SHARD_COUNT)
+	}
+}
+
+impl<V> ShardedMap<V> {
+	/// Create an empty [ShardedMap] with `shard_count` shards. A `shard_count`
+	/// of zero is treated as one.
+	pub fn new(shard_count: usize) -> Self {
+		Self { shards: (0..shard_count.max(1)).map(|_| RwLock::new(HashMap::new())).collect() }
+	}
+
+	fn shard(&self, key: Snowflake) -> &RwLock<HashMap<Snowflake, V>> {
+		&self.shards[(u64::from(key) % self.shards.len() as u64) as usize]
+	}
+
+	/// Insert `value` under `key`, returning the value previously stored under
+	/// it, if any.
+	pub fn insert(&self, key: Snowflake, value: V) -> Option<V> {
+		self.shard(key).write().insert(key, value)
+	}
+
+	/// Remove the value stored under `key`, returning it if there was one.
+	pub fn remove(&self, key: Snowflake) -> Option<V> {
+		self.shard(key).write().remove(&key)
+	}
+
+	/// Remove the value stored under `key` if `predicate` holds for it,
+	/// returning it if it has been removed. The lock of the shard is held
+	/// while `predicate` is called, so it must not access this map.
+	pub fn remove_if(&self, key: Snowflake, predicate: impl FnOnce(&V) -> bool) -> Option<V> {
+		let mut shard = self.shard(key).write();
+		if !predicate(shard.get(&key)?) {
+			return None;
+		}
+		shard.remove(&key)
+	}
+
+	/// Whether a value is stored under `key`.
+	pub fn contains_key(&self, key: Snowflake) -> bool {
+		self.shard(key).read().contains_key(&key)
+	}
+
+	/// The number of values stored across all shards.
+	pub fn len(&self) -> usize {
+		self.shards.iter().map(|shard| shard.read().len()).sum()
+	}
+
+	/// Whether no values are stored in any shard.
+	pub fn is_empty(&self) -> bool {
+		self.shards.iter().all(|shard| shard.read().is_empty())
+	}
+
+	/// All keys stored in the map, in no particular order.
+	pub fn keys(&self) -> Vec<Snowflake> {
+		self.shards
+			.iter()
+			.flat_map(|shard| shard.read().keys().copied().collect::<Vec<_>>())
+			.collect()
+	}
+}
+
+impl<V: Clone> ShardedMap<V> {
+	/// A clone of the value stored under `key`, if any.
+	pub fn get(&self, key: Snowflake) -> Option<V> {
+		self.shard(key).read().get(&key).cloned()
+	}
+
+	/// A clone of the value stored under `key`. If there is none, the value
+	/// returned by `default` is stored first. `default` is called while the
+	/// lock of the shard is held, so it must not access this map.
+	pub fn get_or_insert_with(&self, key: Snowflake, default: impl FnOnce() -> V) -> V {
+		if let Some(value) = self.get(key) {
+			return value;
+		}
+		self.shard(key).write().entry(key).or_insert_with(default).clone()
+	}
+
+	/// Call `f` with the value stored under `key`. If there is none, the value
+	/// returned by `default` is stored first. The lock of the shard is held
+	/// while `default` and `f` are called, so they must not access this map.
+	/// In turn, the value cannot be removed or replaced before `f` returns.
+	pub fn with_or_insert_with<R>(
+		&self,
+		key: Snowflake,
+		default: impl FnOnce() -> V,
+		f: impl FnOnce(&V) -> R,
+	) -> R {
+		if let Some(value) = self.shard(key).read().get(&key) {
+			return f(value);
+		}
+		f(self.shard(key).write().entry(key).or_insert_with(default))
+	}
+
+	/// Clones of all keys and values stored in the map, in no particular order.
+	pub fn entries(&self) -> Vec<(Snowflake, V)> {
+		self.shards
+			.iter()
+			.flat_map(|shard| {
+				shard.read().iter().map(|(key, value)| (*key, value.clone())).collect::<Vec<_>>()
+			})
+			.collect()
+	}
+
+	/// Clones of all values stored in the map, in no particular order.
+	pub fn values(&self) -> Vec<V> {
+		self.entries().into_iter().map(|(_, value)| value).collect()
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::{sync::Arc, thread};
+
+	use super::*;
+
+	#[test]
+	fn keys_are_spread_across_shards() {
+		let map = ShardedMap::new(4);
+		for id in 0..8u64 {
+			map.insert(Snowflake::from(id), id);
+		}
+		assert_eq!(map.len(), 8);
+		assert!(map.shards.iter().all(|shard| shard.read().len() == 2));
+		assert_eq!(map.remove(Snowflake::from(5u64)), Some(5));
+		assert!(!map.contains_key(Snowflake::from(5u64)));
+		assert_eq!(map.get(Snowflake::from(6u64)), Some(6));
+	}
+
+	#[test]
+	fn locked_shard_does_not_block_other_shards() {
+		let map = ShardedMap::new(2);
+		map.insert(Snowflake::from(1u64), "odd");
+		map.insert(Snowflake::from(2u64), "even");
+
+		// With a single lock around the whole map, this would deadlock.
+		let _odd_shard = map.shard(Snowflake::from(1u64)).write();
+		assert_eq!(map.get(Snowflake::from(2u64)), Some("even"));
+		map.insert(Snowflake::from(4u64), "also even");
+		assert!(map.shard(Snowflake::from(3u64)).try_read().is_none());
+	}
+
+	#[test]
+	fn concurrent_access_from_many_threads() {
+		const THREADS: u64 = 8;
+		const KEYS_PER_THREAD: u64 = 1000;
+
+		let map = Arc::new(ShardedMap::default());
+		let handles: Vec<_> = (0..THREADS)
+			.map(|thread_index| {
+				let map = map.clone();
+				thread::spawn(move || {
+					for n in 0..KEYS_PER_THREAD {
+						let key = Snowflake::from(n * THREADS + thread_index);
+						map.insert(key, u64::from(key));
+						assert_eq!(map.get(key), Some(u64::from(key)));
+						if n % 2 == 0 {
+							map.remove(key);
+						}
+					}
+				})
+			})
+			.collect();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+
+		assert_eq!(map.len() as u64, THREADS * KEYS_PER_THREAD / 2);
+		assert!(map.entries().iter().all(|(key, value)| u64::from(*key) == *value));
+	}
+
+	/// Compares the time many threads take to look up and replace users in a
+	/// map with a single lock, as
+	/// [ConnectedUsers](crate::gateway::ConnectedUsers) used to have, to the
+	/// default number of shards. Timing depends on the machine, so this only
+	/// runs with `cargo test -- --ignored`.
+	#[test]
+	#[ignore = "benchmark"]
+	fn sharding_reduces_contention() {
+		const THREADS: u64 = 8;
+		const OPERATIONS_PER_THREAD: u64 = 200_000;
+
+		fn run(map: ShardedMap<u64>) -> std::time::Duration {
+			let map = Arc::new(map);
+			let start = std::time::Instant::now();
+			let handles: Vec<_> = (0..THREADS)
+				.map(|thread_index| {
+					let map = map.clone();
+					thread::spawn(move || {
+						for n in 0..OPERATIONS_PER_THREAD {
+							let key = Snowflake::from((n * THREADS + thread_index) % 4096);
+							map.get_or_insert_with(key, || n);
+							if n % 4 == 0 {
+								map.remove(key);
+							}
+						}
+					})
+				})
+				.collect();
+			for handle in handles {
+				handle.join().unwrap();
+			}
+			start.elapsed()
+		}
+
+		let single_lock = run(ShardedMap::new(1));
+		let sharded = run(ShardedMap::default());
+		println!("single lock: {single_lock:?}, sharded: {sharded:?}");
+		assert!(sharded < single_lock, "sharding did not reduce contention");
+	}
+
+	#[test]
+	fn get_or_insert_with_keeps_existing_value() {
+		let map = ShardedMap::default();
+		assert_eq!(map.get_or_insert_with(Snowflake::from(7u64), || 1), 1);
+		assert_eq!(map.get_or_insert_with(Snowflake::from(7u64), || 2), 1);
+	}
+}
@@ -0,0 +1,134 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use chorus::types::{StageInstance, StageInstanceCreate, StageInstanceDelete, StageInstanceUpdate};
+
+use super::{GatewayPayload, dispatchevent::DispatchEvent, event::Event};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What happened to a stage instance, see
+/// [ConnectedUsers::dispatch_stage_instance](super::ConnectedUsers::dispatch_stage_instance).
+pub enum StageInstanceEvent {
+	/// The stage instance has been started.
+	Create,
+	/// The topic or privacy level of the stage instance has been changed.
+	Update,
+	/// The stage instance has ended.
+	Delete,
+}
+
+impl StageInstanceEvent {
+	/// The name of the dispatch event, which is sent as `t`.
+	pub fn event_name(self) -> &'static str {
+		match self {
+			StageInstanceEvent::Create => "STAGE_INSTANCE_CREATE",
+			StageInstanceEvent::Update => "STAGE_INSTANCE_UPDATE",
+			StageInstanceEvent::Delete => "STAGE_INSTANCE_DELETE",
+		}
+	}
+
+	/// The dispatch event announcing this change of `stage_instance`.
+	pub fn to_event(self, stage_instance: StageInstance) -> Event {
+		let name = self.event_name();
+		Event::Dispatch(match self {
+			StageInstanceEvent::Create => {
+				DispatchEvent::StageInstanceCreate(GatewayPayload::dispatch(
+					name,
+					StageInstanceCreate { stage_instance, ..Default::default() },
+				))
+			}
+			StageInstanceEvent::Update => {
+				DispatchEvent::StageInstanceUpdate(GatewayPayload::dispatch(
+					name,
+					StageInstanceUpdate { stage_instance, ..Default::default() },
+				))
+			}
+			StageInstanceEvent::Delete => {
+				DispatchEvent::StageInstanceDelete(GatewayPayload::dispatch(
+					name,
+					StageInstanceDelete { stage_instance, ..Default::default() },
+				))
+			}
+		})
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::collections::HashMap;
+
+	use chorus::types::Snowflake;
+
+	use super::*;
+	use crate::gateway::{ConnectedUsers, dispatchevent::DispatchEventType, event::EventType};
+
+	fn stage_instance(guild_id: Snowflake) -> StageInstance {
+		StageInstance {
+			id: Snowflake::from(10u64),
+			guild_id,
+			channel_id: Snowflake::from(20u64),
+			topic: "Town hall".to_string(),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn events_are_named_after_their_kind() {
+		let guild_id = Snowflake::from(100u64);
+		for (kind, event_type, name) in [
+			(
+				StageInstanceEvent::Create,
+				DispatchEventType::StageInstanceCreate,
+				"STAGE_INSTANCE_CREATE",
+			),
+			(
+				StageInstanceEvent::Update,
+				DispatchEventType::StageInstanceUpdate,
+				"STAGE_INSTANCE_UPDATE",
+			),
+			(
+				StageInstanceEvent::Delete,
+				DispatchEventType::StageInstanceDelete,
+				"STAGE_INSTANCE_DELETE",
+			),
+		] {
+			let event = kind.to_event(stage_instance(guild_id));
+			assert_eq!(event.event_type(), EventType::Dispatch(event_type));
+			assert_eq!(event.guild_id(), Some(guild_id));
+			let value = serde_json::to_value(match event {
+				Event::Dispatch(dispatch_event) => dispatch_event,
+				other => panic!("expected a dispatch event, got {other:?}"),
+			})
+			.unwrap();
+			let payload = value.as_object().unwrap().values().next().unwrap();
+			assert_eq!(payload["t"], name);
+			assert_eq!(payload["d"]["topic"], "Town hall");
+		}
+	}
+
+	#[tokio::test]
+	async fn stage_instance_events_only_reach_the_guild() {
+		let connected_users = ConnectedUsers::new();
+		let guild_id = Snowflake::from(100u64);
+		let member_id = Snowflake::from(1u64);
+		let outsider_id = Snowflake::from(2u64);
+		let member = connected_users.new_user(HashMap::new(), member_id, Vec::new());
+		let outsider = connected_users.new_user(HashMap::new(), outsider_id, Vec::new());
+		let mut member_inbox = member.lock().await.inbox.resubscribe();
+		let mut outsider_inbox = outsider.lock().await.inbox.resubscribe();
+		connected_users.role_user_map.lock().await.grant_role(guild_id, member_id);
+
+		connected_users
+			.dispatch_stage_instance(StageInstanceEvent::Delete, stage_instance(guild_id))
+			.await
+			.unwrap();
+
+		assert!(matches!(
+			member_inbox.try_recv().unwrap(),
+			Event::Dispatch(DispatchEvent::StageInstanceDelete(_))
+		));
+		assert!(outsider_inbox.try_recv().is_err());
+	}
+}
@@ -0,0 +1,271 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Transport compression of gateway streams, requested by clients through the
+//! `compress` parameter of the gateway URL. With `zlib-stream`, all messages
+//! sent to a client form a single zlib stream, each message ending with a sync
+//! flush. Clients can therefore inflate every message as soon as it arrives,
+//! while the compression context is shared between all of them.
+
+use flate2::{Compress, Compression, FlushCompress};
+use tokio::sync::broadcast::error::TryRecvError;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{MIN_BUFFER_CAPACITY, WebSocketConnection};
+use crate::{configuration::CompressionAlgorithm, errors::GatewayError};
+
+/// The transport compression requested by a client connecting to a URL with
+/// the query string `query`. Algorithms which are not supported, currently
+/// everything but `zlib-stream`, are ignored, and the client is sent an
+/// uncompressed stream instead.
+pub fn requested_compression(query: &str) -> Option<CompressionAlgorithm> {
+	let (_, algorithm) = query
+		.split('&')
+		.filter_map(|parameter| parameter.split_once('='))
+		.find(|(key, _)| *key == "compress")?;
+	match algorithm {
+		"zlib-stream" => Some(CompressionAlgorithm::Zlib),
+		_ => None,
+	}
+}
+
+/// Compresses the messages sent to a client into a single zlib stream.
+pub struct ZlibStream {
+	compress: Compress,
+}
+
+impl ZlibStream {
+	/// Start a stream compressed at `level`, which is clamped to the levels
+	/// zlib supports.
+	pub fn new(level: i32) -> Self {
+		let level = CompressionAlgorithm::Zlib.clamp_level(level) as u32;
+		Self { compress: Compress::new(Compression::new(level), true) }
+	}
+
+	/// Append `message` to the stream. Text and binary messages are turned into
+	/// binary messages ending with a sync flush, control frames are returned
+	/// unchanged.
+	pub fn compress(&mut self, message: Message) -> Result<Message, GatewayError> {
+		let mut input: &[u8] = match &message {
+			Message::Text(text) => text.as_bytes(),
+			Message::Binary(data) => &data[..],
+			_ => return Ok(message),
+		};
+		let mut output = Vec::with_capacity(input.len() / 2 + 64);
+		loop {
+			let consumed_before = self.compress.total_in();
+			self.compress
+				.compress_vec(input, &mut output, FlushCompress::Sync)
+				.map_err(|_| GatewayError::Internal)?;
+			input = &input[(self.compress.total_in() - consumed_before) as usize..];
+			// zlib stops early only if it runs out of space for its output, so spare
+			// capacity means that the flush is complete.
+			if input.is_empty() && output.len() < output.capacity() {
+				break;
+			}
+			output.reserve(output.capacity().max(64));
+		}
+		Ok(Message::Binary(output.into()))
+	}
+}
+
+impl WebSocketConnection {
+	/// Compress everything sent through `sender` from now on into `stream`.
+	/// Messages are compressed in the order they have been sent, by a task
+	/// forwarding them to the sender task of the connection. Up to
+	/// `buffer_capacity` messages wait to be compressed, as with the buffers
+	/// of [Self::with_limits]. Closing the connection passes through the same
+	/// task, so that the messages still waiting are sent before the close
+	/// frame.
+	pub fn with_stream_compression(
+		mut self,
+		mut stream: ZlibStream,
+		buffer_capacity: usize,
+	) -> Self {
+		let (sender, mut uncompressed) =
+			tokio::sync::broadcast::channel(buffer_capacity.max(MIN_BUFFER_CAPACITY));
+		let compressed = std::mem::replace(&mut self.sender, sender);
+		let (close_sender, mut close) = tokio::sync::broadcast::channel(1);
+		let forward_close = std::mem::replace(&mut self.close_sender, close_sender);
+		self.stream_compressed = true;
+		tokio::spawn(async move {
+			loop {
+				let message = tokio::select! {
+					biased;
+					// Also fails once every handle to the connection has been dropped, in
+					// which case the waiting messages are sent all the same.
+					frame = close.recv() => {
+						loop {
+							match uncompressed.try_recv() {
+								Ok(message) => {
+									if !forward_compressed(&mut stream, &compressed, message) {
+										break;
+									}
+								}
+								Err(TryRecvError::Lagged(_)) => continue,
+								Err(_) => break,
+							}
+						}
+						if let Ok(frame) = frame {
+							// This only fails if the connection is already shutting down.
+							let _ = forward_close.send(frame);
+						}
+						break;
+					}
+					message = uncompressed.recv() => message,
+				};
+				match message {
+					Ok(message) if forward_compressed(&mut stream, &compressed, message) => (),
+					_ => break,
+				}
+			}
+		});
+		self
+	}
+}
+
+/// Append `message` to `stream` and pass it on to `compressed`, the sender of
+/// the connection. Returns whether the stream can go on.
+fn forward_compressed(
+	stream: &mut ZlibStream,
+	compressed: &tokio::sync::broadcast::Sender<Message>,
+	message: Message,
+) -> bool {
+	match stream.compress(message) {
+		Ok(message) => compressed.send(message).is_ok(),
+		Err(e) => {
+			log::debug!(target: "symfonia::gateway::WebSocketConnection::with_stream_compression", "Failed to compress message. Closing stream: {e}");
+			false
+		}
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use flate2::{Decompress, FlushDecompress};
+	use futures::StreamExt;
+	use tokio::net::TcpListener;
+	use tokio_tungstenite::{
+		accept_async, connect_async, tungstenite::protocol::frame::coding::CloseCode,
+	};
+
+	use super::*;
+	use crate::gateway::{DEFAULT_BUFFER_CAPACITY, close_frame, codec::PayloadCompression};
+
+	fn inflate(decompress: &mut Decompress, message: Message) -> String {
+		let Message::Binary(data) = message else {
+			panic!("expected a binary message");
+		};
+		assert!(data.ends_with(&[0x00, 0x00, 0xff, 0xff]), "message must end with a sync flush");
+		let mut output = Vec::with_capacity(data.len() * 16);
+		decompress.decompress_vec(&data, &mut output, FlushDecompress::Sync).unwrap();
+		String::from_utf8(output).unwrap()
+	}
+
+	#[test]
+	fn messages_share_one_decodable_stream() {
+		for level in [1, 9, 42, -5] {
+			let mut stream = ZlibStream::new(level);
+			let mut decompress = Decompress::new(true);
+			let hello = r#"{"op":10,"d":{"heartbeat_interval":45000}}"#;
+			let ack = r#"{"op":11}"#;
+
+			assert_eq!(
+				inflate(&mut decompress, stream.compress(Message::from(hello)).unwrap()),
+				hello
+			);
+			assert_eq!(inflate(&mut decompress, stream.compress(Message::from(ack)).unwrap()), ack);
+		}
+	}
+
+	#[tokio::test]
+	async fn compressed_connection_sends_decodable_messages() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+		let client = tokio::spawn(async move {
+			connect_async(format!("ws://{address}/?compress=zlib-stream")).await.unwrap().0
+		});
+		let (stream, _) = listener.accept().await.unwrap();
+		let (sink, stream) = accept_async(stream).await.unwrap().split();
+		let connection = WebSocketConnection::new(sink, stream)
+			.with_stream_compression(ZlibStream::new(3), DEFAULT_BUFFER_CAPACITY);
+		let mut client = client.await.unwrap();
+
+		let ack = r#"{"op":11}"#;
+		connection.sender.send(Message::from(ack)).unwrap();
+		let mut decompress = Decompress::new(true);
+		assert_eq!(inflate(&mut decompress, client.next().await.unwrap().unwrap()), ack);
+	}
+
+	#[tokio::test]
+	async fn compression_buffers_as_many_messages_as_configured() {
+		let (connection, mut client) = WebSocketConnection::from_channels_with_capacity(256);
+		let connection = connection.with_stream_compression(ZlibStream::new(6), 256);
+
+		// Nothing is compressed before the test yields, so every message waits in
+		// the buffer.
+		for sequence in 0..200 {
+			connection.sender.send(Message::from(sequence.to_string())).unwrap();
+		}
+		let mut decompress = Decompress::new(true);
+		for sequence in 0..200 {
+			assert_eq!(
+				inflate(&mut decompress, client.outgoing.recv().await.unwrap()),
+				sequence.to_string()
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn closing_sends_the_messages_waiting_to_be_compressed() {
+		let (connection, mut client) = WebSocketConnection::from_channels();
+		let connection =
+			connection.with_stream_compression(ZlibStream::new(6), DEFAULT_BUFFER_CAPACITY);
+		let frame = close_frame(CloseCode::Library(4000), "Reconnect");
+
+		// Neither the messages nor the close frame are forwarded before the test
+		// yields.
+		for sequence in 0..3 {
+			connection.sender.send(Message::from(sequence.to_string())).unwrap();
+		}
+		connection.close(Some(frame.clone())).unwrap();
+
+		let mut decompress = Decompress::new(true);
+		for sequence in 0..3 {
+			assert_eq!(
+				inflate(&mut decompress, client.outgoing.recv().await.unwrap()),
+				sequence.to_string()
+			);
+		}
+		assert_eq!(client.outgoing.recv().await.unwrap(), Message::Close(Some(frame)));
+	}
+
+	#[tokio::test]
+	async fn payloads_of_compressed_streams_are_not_compressed_again() {
+		let (connection, _client) = WebSocketConnection::from_channels();
+		let connection =
+			connection.with_stream_compression(ZlibStream::new(6), DEFAULT_BUFFER_CAPACITY);
+
+		connection.enable_payload_compression(PayloadCompression::new(6));
+
+		assert!(!connection.compresses_payloads());
+	}
+
+	#[test]
+	fn control_frames_are_not_compressed() {
+		let mut stream = ZlibStream::new(6);
+		assert_eq!(stream.compress(Message::Close(None)).unwrap(), Message::Close(None));
+	}
+
+	#[test]
+	fn only_zlib_stream_is_recognized() {
+		assert_eq!(
+			requested_compression("v=9&encoding=json&compress=zlib-stream"),
+			Some(CompressionAlgorithm::Zlib)
+		);
+		assert_eq!(requested_compression("v=9&compress=zstd-stream"), None);
+		assert_eq!(requested_compression("v=9"), None);
+	}
+}
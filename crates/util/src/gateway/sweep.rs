@@ -0,0 +1,159 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Removal of sessions whose tasks have stopped without calling
+//! [GatewayClient::die](super::GatewayClient::die), for example because they
+//! panicked or have been aborted. `users` holds strong references to every
+//! [GatewayUser], so without this, neither the user nor its inbox would ever
+//! be dropped.
+
+use std::{sync::Arc, time::Duration};
+
+use super::{ConnectedUsers, GatewayUser, session_token::SessionToken};
+
+/// How long a user may stay registered without any clients before it is
+/// considered stale. Users are registered shortly before their first client,
+/// so this has to cover the time it takes to identify.
+pub const STALE_USER_GRACE_PERIOD: Duration = Duration::from_secs(60);
+
+impl ConnectedUsers {
+	/// Remove the clients whose main task has finished or whose connection has
+	/// been closed, and deregister users left without clients for longer than
+	/// [STALE_USER_GRACE_PERIOD]. Sessions ending normally are cleaned up by
+	/// [GatewayClient::die](super::GatewayClient::die) instead; this only
+	/// catches the ones which have not. Expired interactions kept for bots
+	/// which have not connected since are dropped as well, and the resume
+	/// buffers of the remaining clients are given a
+	/// [ResumeBuffer::tick](super::resume::ResumeBuffer::tick). Meant to be
+	/// called periodically.
+	///
+	/// Returns the number of users which have been deregistered.
+	///
+	/// ## Locking
+	///
+	/// This method first acquires a write lock on `store`, which is released
+	/// right away. For every user, it then acquires the lock on each of its
+	/// clients one after another, without holding the lock on the user. While
+	/// holding the lock on a client, the lock on its resume buffer is
+	/// acquired. The user is locked afterwards to remove the stale clients,
+	/// while write locks on `store`, `presence_subscriptions` and the user's
+	/// shards of `users` and `inboxes` are acquired one after another.
+	pub async fn sweep_stale_users(&self) -> usize {
+		self.store.write().pending_interactions.purge_expired();
+		let mut deregistered = 0;
+		for (id, user) in self.users.entries() {
+			let clients: Vec<_> = user.lock().await.clients.values().cloned().collect();
+			let mut stale = Vec::new();
+			for client in clients {
+				let client = client.lock().await;
+				if client.main_task_handle.is_finished() || client.connection.is_closed() {
+					stale.push(client.session_token.clone());
+				} else {
+					// Resume buffers otherwise only shrink once the session dispatches again.
+					client.recent_dispatches.lock().await.tick();
+				}
+			}
+			let mut gateway_user = user.lock().await;
+			self.forget_stale_clients(&mut gateway_user, &stale);
+			if !gateway_user.clients.is_empty()
+				|| gateway_user.last_activity().elapsed() < STALE_USER_GRACE_PERIOD
+			{
+				continue;
+			}
+			// A newer user with the same ID may have been registered meanwhile.
+			if self.users.remove_if(id, |current| Arc::ptr_eq(current, &user)).is_none() {
+				continue;
+			}
+			self.inboxes.remove_if(id, |inbox| inbox.same_channel(&gateway_user.outbox));
+			self.blocks.write().forget(id);
+			log::debug!(target: "symfonia::gateway::ConnectedUsers::sweep_stale_users", "Deregistered user {id}, whose sessions have stopped without ending");
+			deregistered += 1;
+		}
+		deregistered
+	}
+
+	fn forget_stale_clients(&self, user: &mut GatewayUser, stale: &[SessionToken]) {
+		if stale.is_empty() {
+			return;
+		}
+		for session_token in stale {
+			user.clients.remove(session_token);
+		}
+		let mut store = self.store.write();
+		for session_token in stale {
+			store.session_tokens.remove(session_token);
+		}
+		drop(store);
+		let mut presence_subscriptions = self.presence_subscriptions.write();
+		for session_token in stale {
+			presence_subscriptions.forget(session_token);
+		}
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::collections::HashMap;
+
+	use chorus::types::Snowflake;
+	use tokio::sync::Mutex;
+
+	use super::*;
+	use crate::gateway::testing::{TestSession, test_connection};
+
+	async fn add_client(
+		connected_users: &ConnectedUsers,
+		user: &Arc<Mutex<GatewayUser>>,
+		main_task_handle: tokio::task::JoinHandle<()>,
+	) {
+		let (connection, _sent) = test_connection();
+		TestSession::new(connection)
+			.main_task(main_task_handle)
+			.connect(connected_users, user, "token")
+			.await;
+	}
+
+	fn backdate(user: &mut GatewayUser) {
+		user.last_activity -= STALE_USER_GRACE_PERIOD;
+	}
+
+	#[tokio::test]
+	async fn users_of_aborted_sessions_are_deregistered() {
+		let connected_users = ConnectedUsers::default();
+		let id = Snowflake::from(1u64);
+		let user = connected_users.new_user(HashMap::new(), id, Vec::new());
+		let main_task = tokio::spawn(std::future::pending());
+		let abort_handle = main_task.abort_handle();
+		add_client(&connected_users, &user, main_task).await;
+		backdate(&mut *user.lock().await);
+
+		abort_handle.abort();
+		while !abort_handle.is_finished() {
+			tokio::task::yield_now().await;
+		}
+
+		assert_eq!(connected_users.sweep_stale_users().await, 1);
+		assert!(!connected_users.users.contains_key(id));
+		assert!(!connected_users.inboxes.contains_key(id));
+		assert!(connected_users.store.read().session_tokens.is_empty());
+		// Nothing but this test holds on to the user anymore, so it will be dropped.
+		assert_eq!(Arc::strong_count(&user), 1);
+	}
+
+	#[tokio::test]
+	async fn running_sessions_and_new_users_are_kept() {
+		let connected_users = ConnectedUsers::default();
+		let running = connected_users.new_user(HashMap::new(), Snowflake::from(1u64), Vec::new());
+		add_client(&connected_users, &running, tokio::spawn(std::future::pending())).await;
+		backdate(&mut *running.lock().await);
+		let identifying =
+			connected_users.new_user(HashMap::new(), Snowflake::from(2u64), Vec::new());
+
+		assert_eq!(connected_users.sweep_stale_users().await, 0);
+		assert_eq!(running.lock().await.clients.len(), 1);
+		assert!(connected_users.users.contains_key(identifying.lock().await.id));
+		assert!(connected_users.verify_consistency().is_ok());
+	}
+}
@@ -0,0 +1,182 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Helpers for tests which need connected sessions without an actual
+//! WebSocket, a database which is never reached, or roles and members in a
+//! test database. Available to tests of dependent crates through the
+//! `test-utils` feature.
+
+#![allow(clippy::unwrap_used)]
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use chorus::types::{PermissionFlags, Snowflake, UserGuildSettingsUpdate};
+use sqlx::{PgPool, postgres::PgPoolOptions};
+use tokio::{sync::Mutex, task::JoinHandle};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{
+	ConnectedUsers, GatewayClient, GatewayUser, WebSocketConnection, resume::ResumeBuffer,
+	session_token::SessionToken,
+};
+
+/// Creates an in-memory [WebSocketConnection], along with a receiver for
+/// everything sent through it.
+pub fn test_connection() -> (WebSocketConnection, tokio::sync::broadcast::Receiver<Message>) {
+	let (connection, client) = WebSocketConnection::from_channels();
+	(connection, client.outgoing)
+}
+
+/// Registers the user with the ID 1 and adds a [GatewayClient] with the
+/// session token `token` to it, see [test_session].
+pub async fn test_client(
+	connected_users: &ConnectedUsers,
+) -> (Arc<Mutex<GatewayUser>>, Arc<Mutex<GatewayClient>>, tokio::sync::broadcast::Receiver<Message>)
+{
+	let user = connected_users.new_user(HashMap::new(), Snowflake::from(1u64), Vec::new());
+	let (client, sent) = test_session(connected_users, &user, "token").await;
+	(user, client, sent)
+}
+
+/// Adds a [GatewayClient] with the session token `session_token` and the
+/// defaults of [TestSession] to `user`, returning the client and a receiver
+/// for everything sent to it.
+pub async fn test_session(
+	connected_users: &ConnectedUsers,
+	user: &Arc<Mutex<GatewayUser>>,
+	session_token: impl Into<SessionToken>,
+) -> (Arc<Mutex<GatewayClient>>, tokio::sync::broadcast::Receiver<Message>) {
+	let (connection, sent) = test_connection();
+	let client = TestSession::new(connection).connect(connected_users, user, session_token).await;
+	(client, sent)
+}
+
+/// Builds the arguments of [ConnectedUsers::new_client] for tests. Unless
+/// set otherwise, the tasks of the session do nothing, its sequence number is
+/// 0, its resume buffer retains 10 dispatches and it has no shard.
+pub struct TestSession {
+	connection: WebSocketConnection,
+	main_task_handle: Option<JoinHandle<()>>,
+	last_sequence: Arc<Mutex<u64>>,
+	recent_dispatches: Arc<Mutex<ResumeBuffer>>,
+	shard: Arc<Mutex<Option<(u64, u64)>>>,
+}
+
+impl TestSession {
+	/// Start building a session connected through `connection`.
+	pub fn new(connection: WebSocketConnection) -> Self {
+		Self {
+			connection,
+			main_task_handle: None,
+			last_sequence: Arc::new(Mutex::new(0)),
+			recent_dispatches: Arc::new(Mutex::new(ResumeBuffer::new(10))),
+			shard: Arc::new(Mutex::new(None)),
+		}
+	}
+
+	/// Use `main_task_handle` as the handle of the main task of the session.
+	pub fn main_task(mut self, main_task_handle: JoinHandle<()>) -> Self {
+		self.main_task_handle = Some(main_task_handle);
+		self
+	}
+
+	/// Use `last_sequence` as the sequence number of the session. Tests which
+	/// run tasks of the session share it with them.
+	pub fn last_sequence(mut self, last_sequence: Arc<Mutex<u64>>) -> Self {
+		self.last_sequence = last_sequence;
+		self
+	}
+
+	/// Use `recent_dispatches` as the resume buffer of the session.
+	pub fn recent_dispatches(mut self, recent_dispatches: Arc<Mutex<ResumeBuffer>>) -> Self {
+		self.recent_dispatches = recent_dispatches;
+		self
+	}
+
+	/// Use `shard` as the shard of the session.
+	pub fn shard(mut self, shard: Arc<Mutex<Option<(u64, u64)>>>) -> Self {
+		self.shard = shard;
+		self
+	}
+
+	/// Add the session to `user` with the session token `session_token`, see
+	/// [ConnectedUsers::new_client].
+	pub async fn connect(
+		self,
+		connected_users: &ConnectedUsers,
+		user: &Arc<Mutex<GatewayUser>>,
+		session_token: impl Into<SessionToken>,
+	) -> Arc<Mutex<GatewayClient>> {
+		connected_users
+			.new_client(
+				user.clone(),
+				self.connection,
+				self.main_task_handle.unwrap_or_else(|| tokio::spawn(async {})),
+				tokio::spawn(async {}),
+				&session_token.into(),
+				self.last_sequence,
+				self.recent_dispatches,
+				self.shard,
+			)
+			.await
+	}
+}
+
+/// A pool whose connections can never be established, so that any query made
+/// through it fails quickly. For tests of code which should not query the
+/// database, or whose queries are expected to fail.
+pub fn unreachable_db() -> PgPool {
+	PgPoolOptions::new()
+		.acquire_timeout(Duration::from_millis(100))
+		.connect_lazy("postgres://localhost:1/symfonia")
+		.unwrap()
+}
+
+/// Inserts a role with the ID `id` and `permissions` into the guild with the ID
+/// `guild_id`. The `@everyone` role of a guild has the ID of the guild.
+pub async fn insert_role(
+	db: &PgPool,
+	id: Snowflake,
+	guild_id: Snowflake,
+	permissions: PermissionFlags,
+) {
+	sqlx::query(
+		"INSERT INTO roles (id, guild_id, color, hoist, managed, mentionable, name, permissions, position) VALUES ($1, $2, 0, false, false, false, 'role', $3, 0)",
+	)
+	.bind(id)
+	.bind(guild_id)
+	.bind(permissions)
+	.execute(db)
+	.await
+	.unwrap();
+}
+
+/// Inserts the user with the ID `id` as a member of the guild with the ID
+/// `guild_id`, holding the role with the ID `role_id`.
+pub async fn insert_member(db: &PgPool, id: Snowflake, guild_id: Snowflake, role_id: Snowflake) {
+	sqlx::query(
+		"INSERT INTO members (id, guild_id, joined_at, deaf, mute, pending, settings, bio) VALUES ($1, $2, NOW(), false, false, false, $3, '')",
+	)
+	.bind(id)
+	.bind(guild_id)
+	.bind(sqlx::types::Json(UserGuildSettingsUpdate::default()))
+	.execute(db)
+	.await
+	.unwrap();
+	grant_role(db, id, guild_id, role_id).await;
+}
+
+/// Gives the member with the ID `id` in the guild with the ID `guild_id` the
+/// role with the ID `role_id`.
+pub async fn grant_role(db: &PgPool, id: Snowflake, guild_id: Snowflake, role_id: Snowflake) {
+	sqlx::query(
+		"INSERT INTO member_roles (index, role_id) SELECT index, $3 FROM members WHERE id = $1 AND guild_id = $2",
+	)
+	.bind(id)
+	.bind(guild_id)
+	.bind(role_id)
+	.execute(db)
+	.await
+	.unwrap();
+}
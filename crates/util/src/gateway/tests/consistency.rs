@@ -0,0 +1,83 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Consistency of `users` and `inboxes`, also under concurrent access.
+
+use super::*;
+
+#[tokio::test]
+async fn users_and_inboxes_stay_consistent() {
+	let connected_users = ConnectedUsers::default();
+	let mut clients = Vec::new();
+	for id in 1..=4u64 {
+		let user = connected_users.get_user_or_new(Snowflake::from(id));
+		let (client, _sent) = test_session(&connected_users, &user, format!("token {id}")).await;
+		clients.push(client);
+	}
+	// Connecting again does not register the user a second time.
+	connected_users.get_user_or_new(Snowflake::from(1u64));
+	for client in [&clients[1], &clients[3]] {
+		client.lock().await.die(connected_users.clone(), KillReason::ClientClosed).await.unwrap();
+	}
+	let user = connected_users.new_user(HashMap::new(), Snowflake::from(5u64), Vec::new());
+	connected_users.deregister(user.lock().await.deref());
+
+	assert_eq!(connected_users.verify_consistency(), Ok(()));
+	let mut users = connected_users.users.keys();
+	users.sort();
+	assert_eq!(users, [Snowflake::from(1u64), Snowflake::from(3u64)]);
+
+	connected_users.inboxes.remove(Snowflake::from(3u64));
+	assert_eq!(connected_users.verify_consistency(), Err(vec![Snowflake::from(3u64)]));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_lookups_create_a_single_user() {
+	let connected_users = ConnectedUsers::default();
+	let id = Snowflake::from(1u64);
+	let tasks: Vec<_> = (0..64)
+		.map(|_| {
+			let connected_users = connected_users.clone();
+			tokio::spawn(async move { connected_users.get_user_or_new(id) })
+		})
+		.collect();
+
+	let users = futures::future::try_join_all(tasks).await.unwrap();
+	assert!(users.iter().all(|user| Arc::ptr_eq(user, &users[0])));
+	let outbox = users[0].lock().await.outbox.clone();
+	assert!(connected_users.inboxes.get(id).unwrap().same_channel(&outbox));
+	assert_eq!(connected_users.verify_consistency(), Ok(()));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_connects_and_disconnects_do_not_deadlock() {
+	let connected_users = ConnectedUsers::default();
+	let mut tasks = Vec::new();
+	for index in 0..64u64 {
+		let connected_users = connected_users.clone();
+		tasks.push(tokio::spawn(async move {
+			// Sessions of the same users connect and disconnect at the same time.
+			let user_id = Snowflake::from(index % 4 + 1);
+			let session_token = SessionToken::from(format!("token {index}"));
+			let user = connected_users.get_user_or_new(user_id);
+			let (client, _sent) =
+				test_session(&connected_users, &user, session_token.clone()).await;
+			// Visits the clients of the user while others are dying. Whether the
+			// presence reaches anyone does not matter here.
+			let _ =
+				connected_users.update_presence(user_id, &session_token, UserStatus::Idle).await;
+			connected_users.list_sessions(user_id).await;
+			let _ =
+				client.lock().await.die(connected_users.clone(), KillReason::ClientClosed).await;
+		}));
+	}
+
+	tokio::time::timeout(Duration::from_secs(10), futures::future::try_join_all(tasks))
+		.await
+		.expect("connecting and disconnecting sessions deadlocked")
+		.unwrap();
+	assert!(connected_users.users.is_empty());
+	assert!(connected_users.store.read().session_tokens.is_empty());
+	assert_eq!(connected_users.verify_consistency(), Ok(()));
+}
@@ -0,0 +1,252 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Lifecycle of sessions: sending to them, listing them and ending them.
+
+use super::*;
+use crate::gateway::testing::{TestSession, test_connection};
+
+#[tokio::test]
+async fn die_without_parent_returns_error() {
+	let connected_users = ConnectedUsers::default();
+	let user = connected_users.new_user(HashMap::new(), Snowflake::from(1u64), Vec::new());
+	let (client, _sent) = test_session(&connected_users, &user, "token").await;
+	connected_users.deregister(user.lock().await.deref());
+	drop(user);
+
+	let result = client.lock().await.die(connected_users.clone(), KillReason::Timeout).await;
+	assert!(matches!(result, Err(GatewayError::ParentDropped)));
+}
+
+#[tokio::test]
+async fn die_without_parent_still_kills_and_stores_session() {
+	let connected_users = ConnectedUsers::default();
+	let user = connected_users.new_user(HashMap::new(), Snowflake::from(1u64), Vec::new());
+	let (connection, _sent) = test_connection();
+	let mut kill_receive = connection.kill_receive.resubscribe();
+	let client = TestSession::new(connection).connect(&connected_users, &user, "token").await;
+	connected_users.deregister(user.lock().await.deref());
+	drop(user);
+
+	let _ = client.lock().await.die(connected_users.clone(), KillReason::Timeout).await;
+	assert!(kill_receive.try_recv().is_ok());
+	assert!(connected_users.resumable_clients.contains_key(&SessionToken::from("token")).await);
+}
+
+#[tokio::test]
+async fn auth_failure_disconnects_are_not_resumable() {
+	let connected_users = ConnectedUsers::default();
+	let user = connected_users.new_user(HashMap::new(), Snowflake::from(1u64), Vec::new());
+	let mut clients = Vec::new();
+	for token in ["failed", "timed out"] {
+		let (client, _sent) = test_session(&connected_users, &user, token).await;
+		clients.push(client);
+	}
+	let before = SystemTime::now();
+
+	clients[0].lock().await.die(connected_users.clone(), KillReason::AuthFailed).await.unwrap();
+	clients[1].lock().await.die(connected_users.clone(), KillReason::Timeout).await.unwrap();
+
+	let store = &connected_users.resumable_clients;
+	let failed = store.get(&SessionToken::from("failed")).await.unwrap();
+	assert_eq!(failed.reason, KillReason::AuthFailed);
+	assert!(failed.disconnected_at >= before);
+	assert!(!failed.is_resumable());
+	let timed_out = store.get(&SessionToken::from("timed out")).await.unwrap();
+	assert_eq!(timed_out.reason, KillReason::Timeout);
+	assert!(timed_out.is_resumable());
+	let metrics = connected_users.metrics.snapshot();
+	assert_eq!(metrics.closed(KillReason::AuthFailed), 1);
+	assert_eq!(metrics.server_initiated_closes(), 2);
+	assert_eq!(metrics.client_initiated_closes(), 0);
+}
+
+#[tokio::test]
+async fn killing_a_user_ends_all_of_their_sessions() {
+	let connected_users = ConnectedUsers::default();
+	let user = connected_users.new_user(HashMap::new(), Snowflake::from(1u64), Vec::new());
+	for session_token in ["first", "second"] {
+		let (_client, _sent) = test_session(&connected_users, &user, session_token).await;
+	}
+
+	tokio::time::timeout(Duration::from_secs(1), GatewayUser::kill(&user, KillReason::Timeout))
+		.await
+		.expect("killing the user deadlocked");
+	assert!(user.lock().await.clients.is_empty());
+	assert!(!connected_users.is_online(Snowflake::from(1u64)));
+}
+
+#[tokio::test]
+async fn dropped_user_removes_itself_from_the_store() {
+	let connected_users = ConnectedUsers::default();
+	let user_id = Snowflake::from(1u64);
+	let user = connected_users.new_user(HashMap::new(), user_id, Vec::new());
+	let (_client, _sent) = test_session(&connected_users, &user, "token").await;
+
+	// The user goes away without its session ever ending through `die`.
+	connected_users.users.remove(user_id);
+	assert!(connected_users.inboxes.contains_key(user_id));
+	drop(user);
+
+	assert!(!connected_users.inboxes.contains_key(user_id));
+	assert!(connected_users.store.read().session_tokens.is_empty());
+	assert_eq!(connected_users.verify_consistency(), Ok(()));
+}
+
+#[tokio::test]
+async fn dropped_user_keeps_the_inbox_of_its_successor() {
+	let connected_users = ConnectedUsers::default();
+	let user_id = Snowflake::from(1u64);
+	let replaced = connected_users.new_user(HashMap::new(), user_id, Vec::new());
+	let _successor = connected_users.new_user(HashMap::new(), user_id, Vec::new());
+	drop(replaced);
+
+	assert!(connected_users.is_online(user_id));
+	assert_eq!(connected_users.verify_consistency(), Ok(()));
+}
+
+#[tokio::test]
+async fn sessions_info_lists_every_session() {
+	let connected_users = ConnectedUsers::default();
+	let user = connected_users.new_user(HashMap::new(), Snowflake::from(1u64), Vec::new());
+	for (session_token, last_sequence, os) in
+		[("first-session", 3, "Linux"), ("second-session", 9, "Android")]
+	{
+		let (connection, _sent) = test_connection();
+		let client = TestSession::new(connection)
+			.last_sequence(Arc::new(Mutex::new(last_sequence)))
+			.connect(&connected_users, &user, session_token)
+			.await;
+		client
+			.lock()
+			.await
+			.set_properties(ClientProperties { os: Some(os.to_string()), ..Default::default() });
+	}
+
+	let mut sessions = GatewayUser::sessions_info(&user).await;
+	sessions.sort_by_key(|session| session.last_sequence);
+	assert_eq!(sessions.len(), 2);
+	assert_eq!(sessions[0].session_token, "*********sion");
+	assert_eq!(sessions[0].properties.os.as_deref(), Some("Linux"));
+	assert_eq!(sessions[1].last_sequence, 9);
+	assert_eq!(sessions[1].properties.os.as_deref(), Some("Android"));
+	assert!(sessions[0].connected_at <= sessions[1].connected_at);
+}
+
+#[tokio::test]
+async fn list_sessions_of_user_with_two_connections() {
+	let connected_users = ConnectedUsers::default();
+	let user_id = Snowflake::from(1u64);
+	let user = connected_users.new_user(HashMap::new(), user_id, Vec::new());
+	for (session_token, shard) in [("first-session", Some((0, 2))), ("second-session", None)] {
+		let (connection, _sent) = test_connection();
+		TestSession::new(connection)
+			.shard(Arc::new(Mutex::new(shard)))
+			.connect(&connected_users, &user, session_token)
+			.await;
+	}
+
+	let mut sessions = connected_users.list_sessions(user_id).await;
+	sessions.sort_by_key(|session| session.shard.is_none());
+	assert_eq!(sessions.len(), 2);
+	assert_eq!(sessions[0].shard, Some((0, 2)));
+	assert_eq!(sessions[1].shard, None);
+	assert!(sessions.iter().all(|session| session.session_token == "*********sion"));
+	assert!(connected_users.list_sessions(Snowflake::from(2u64)).await.is_empty());
+}
+
+#[tokio::test]
+async fn client_send_only_reaches_that_session() {
+	let connected_users = ConnectedUsers::default();
+	let user = connected_users.new_user(HashMap::new(), Snowflake::from(1u64), Vec::new());
+	let mut user_inbox = user.lock().await.inbox.resubscribe();
+	let mut sessions = Vec::new();
+	for session_token in ["first", "second"] {
+		let (client, sent) = test_session(&connected_users, &user, session_token).await;
+		sessions.push((client, sent));
+	}
+
+	sessions[0]
+		.0
+		.lock()
+		.await
+		.send(Event::HeartbeatAck(GatewayPayload {
+			op_code: Opcode::HeartbeatAck as u8,
+			event_data: None,
+			sequence_number: None,
+			event_name: None,
+		}))
+		.unwrap();
+
+	assert_eq!(sent_op_code(sessions[0].1.try_recv().unwrap()), 11);
+	assert!(sessions[1].1.try_recv().is_err());
+	assert!(user_inbox.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn raw_frame_only_reaches_that_session() {
+	let connected_users = ConnectedUsers::default();
+	let user = connected_users.new_user(HashMap::new(), Snowflake::from(1u64), Vec::new());
+	let mut sessions = Vec::new();
+	for session_token in ["first", "second"] {
+		let (client, sent) = test_session(&connected_users, &user, session_token).await;
+		sessions.push((client, sent));
+	}
+
+	let frame = Message::Text(r#"{"op":11}"#.into());
+	sessions[1].0.lock().await.send_raw(frame.clone()).unwrap();
+
+	assert!(sessions[0].1.try_recv().is_err());
+	assert_eq!(sessions[1].1.try_recv().unwrap(), frame);
+	assert!(sessions[1].1.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn disconnect_all_closes_every_session() {
+	let connected_users = ConnectedUsers::default();
+	let user_id = Snowflake::from(1u64);
+	let user = connected_users.new_user(HashMap::new(), user_id, Vec::new());
+	let mut sessions = Vec::new();
+	for session_token in ["first", "second"] {
+		let (connection, sent) = test_connection();
+		let kill_receive = connection.kill_receive.resubscribe();
+		TestSession::new(connection).connect(&connected_users, &user, session_token).await;
+		sessions.push((sent, kill_receive));
+	}
+
+	connected_users.disconnect_all(user_id, KillReason::AuthFailed).await;
+
+	for (mut sent, mut kill_receive) in sessions {
+		match sent.recv().await.unwrap() {
+			Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Library(4004)),
+			other => panic!("expected a close frame, got {other:?}"),
+		}
+		assert_eq!(kill_receive.try_recv().unwrap(), KillReason::AuthFailed);
+	}
+}
+
+#[tokio::test]
+async fn shutdown_asks_every_client_to_reconnect() {
+	let connected_users = ConnectedUsers::default();
+	let mut sessions = Vec::new();
+	for id in 1..=3u64 {
+		let user = connected_users.new_user(HashMap::new(), Snowflake::from(id), Vec::new());
+		let (connection, sent) = test_connection();
+		let kill_receive = connection.kill_receive.resubscribe();
+		TestSession::new(connection).connect(&connected_users, &user, id.to_string()).await;
+		sessions.push((sent, kill_receive));
+	}
+
+	connected_users.shutdown().await;
+
+	for (mut sent, mut kill_receive) in sessions {
+		assert_eq!(sent_op_code(sent.try_recv().unwrap()), Opcode::Reconnect as u64);
+		match sent.try_recv().unwrap() {
+			Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Away),
+			other => panic!("expected a close frame, got {other:?}"),
+		}
+		assert_eq!(kill_receive.try_recv().unwrap(), KillReason::ServerShutdown);
+	}
+	assert!(connected_users.users.is_empty());
+}
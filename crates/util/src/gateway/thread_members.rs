@@ -0,0 +1,145 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use chorus::types::{Snowflake, ThreadMember, ThreadMemberUpdate, ThreadMembersUpdate};
+
+use super::{GatewayPayload, dispatchevent::DispatchEvent, event::Event};
+
+/// The `member_count` of a thread stops counting at this many members.
+pub const MAX_THREAD_MEMBER_COUNT: usize = 50;
+
+#[derive(Debug, Clone)]
+/// A user joining or leaving a thread, see
+/// [ConnectedUsers::dispatch_thread_membership](super::ConnectedUsers::dispatch_thread_membership).
+pub enum ThreadMembership {
+	/// A user has joined the thread, with this membership.
+	Joined(ThreadMember),
+	/// The user with this Snowflake ID has left the thread.
+	Left(Snowflake),
+}
+
+impl ThreadMembership {
+	/// The Snowflake ID of the user joining or leaving the thread.
+	pub fn user_id(&self) -> Option<Snowflake> {
+		match self {
+			ThreadMembership::Joined(member) => member.user_id,
+			ThreadMembership::Left(user_id) => Some(*user_id),
+		}
+	}
+
+	/// The `THREAD_MEMBERS_UPDATE` telling the participants of the thread
+	/// `thread_id` about this change, where `member_count` is the number of
+	/// members the thread has after it.
+	pub fn members_update(
+		&self,
+		guild_id: Snowflake,
+		thread_id: Snowflake,
+		member_count: usize,
+	) -> Event {
+		let (added_members, removed_members) = match self {
+			ThreadMembership::Joined(member) => (Some(vec![member.clone()]), None),
+			ThreadMembership::Left(user_id) => (None, Some(vec![*user_id])),
+		};
+		Event::Dispatch(DispatchEvent::ThreadMembersUpdate(GatewayPayload::dispatch(
+			"THREAD_MEMBERS_UPDATE",
+			ThreadMembersUpdate {
+				id: thread_id,
+				guild_id,
+				member_count: member_count.min(MAX_THREAD_MEMBER_COUNT) as u8,
+				added_members,
+				removed_members,
+				..Default::default()
+			},
+		)))
+	}
+
+	/// The `THREAD_MEMBER_UPDATE` telling the user joining or leaving the
+	/// thread `thread_id` about its membership.
+	pub fn member_update(&self, guild_id: Snowflake, thread_id: Snowflake) -> Event {
+		let member = match self {
+			ThreadMembership::Joined(member) => member.clone(),
+			ThreadMembership::Left(user_id) => {
+				ThreadMember { id: Some(thread_id), user_id: Some(*user_id), ..Default::default() }
+			}
+		};
+		Event::Dispatch(DispatchEvent::ThreadMemberUpdate(GatewayPayload::dispatch(
+			"THREAD_MEMBER_UPDATE",
+			ThreadMemberUpdate { member, guild_id, ..Default::default() },
+		)))
+	}
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::collections::HashMap;
+
+	use super::*;
+	use crate::gateway::ConnectedUsers;
+
+	#[tokio::test]
+	async fn joining_thread_notifies_participants() {
+		let connected_users = ConnectedUsers::new();
+		let guild_id = Snowflake::from(100u64);
+		let thread_id = Snowflake::from(10u64);
+		let participant_id = Snowflake::from(1u64);
+		let joining_id = Snowflake::from(2u64);
+		let outsider_id = Snowflake::from(3u64);
+		let mut inboxes = Vec::new();
+		for id in [participant_id, joining_id, outsider_id] {
+			let user = connected_users.new_user(HashMap::new(), id, Vec::new());
+			inboxes.push(user.lock().await.inbox.resubscribe());
+		}
+
+		let member =
+			ThreadMember { id: Some(thread_id), user_id: Some(joining_id), ..Default::default() };
+		connected_users
+			.dispatch_thread_membership(
+				guild_id,
+				thread_id,
+				&[participant_id, joining_id],
+				ThreadMembership::Joined(member),
+			)
+			.await
+			.unwrap();
+
+		let Event::Dispatch(DispatchEvent::ThreadMembersUpdate(payload)) =
+			inboxes[0].try_recv().unwrap()
+		else {
+			panic!("expected a THREAD_MEMBERS_UPDATE");
+		};
+		let update = payload.event_data.unwrap();
+		assert_eq!(update.id, thread_id);
+		assert_eq!(update.member_count, 2);
+		let added = update.added_members.unwrap();
+		assert_eq!(added.len(), 1);
+		assert_eq!(added[0].user_id, Some(joining_id));
+		assert!(inboxes[0].try_recv().is_err());
+
+		let mut received = Vec::new();
+		while let Ok(Event::Dispatch(event)) = inboxes[1].try_recv() {
+			received.push(event);
+		}
+		assert!(matches!(
+			received[..],
+			[DispatchEvent::ThreadMembersUpdate(_), DispatchEvent::ThreadMemberUpdate(_)]
+		));
+		assert!(inboxes[2].try_recv().is_err());
+	}
+
+	#[test]
+	fn member_count_stops_at_fifty() {
+		let update = ThreadMembership::Left(Snowflake::from(1u64)).members_update(
+			Snowflake::from(100u64),
+			Snowflake::from(10u64),
+			120,
+		);
+		let Event::Dispatch(DispatchEvent::ThreadMembersUpdate(payload)) = update else {
+			panic!("expected a THREAD_MEMBERS_UPDATE");
+		};
+		let update = payload.event_data.unwrap();
+		assert_eq!(update.member_count as usize, MAX_THREAD_MEMBER_COUNT);
+		assert_eq!(update.removed_members, Some(vec![Snowflake::from(1u64)]));
+	}
+}
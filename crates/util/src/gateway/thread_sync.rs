@@ -0,0 +1,118 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use chorus::types::{Snowflake, ThreadListSync};
+
+use super::{GatewayPayload, dispatchevent::DispatchEvent, event::Event};
+use crate::entities::{Channel, GuildMember};
+
+/// The `THREAD_LIST_SYNC` telling `member` about the active threads of the
+/// guild `guild_id` it can see, out of the guild's `channels`. The roles and
+/// permissions of `member` must have been loaded, see
+/// [GuildMember::populate_permissions].
+///
+/// If `channel_ids` is [None], the threads of all channels are synced, as is
+/// done when the guild becomes available. Otherwise only the threads started in
+/// one of `channel_ids` are, for example because the member has just gained
+/// access to these channels.
+pub fn thread_list_sync(
+	guild_id: Snowflake,
+	channel_ids: Option<Vec<Snowflake>>,
+	channels: &[Channel],
+	member: &GuildMember,
+	owner_id: Option<Snowflake>,
+) -> ThreadListSync {
+	let threads = channels
+		.iter()
+		.filter(|thread| thread.is_active_thread())
+		.filter_map(|thread| {
+			let parent_id = thread.parent_id?;
+			if channel_ids.as_ref().is_some_and(|ids| !ids.contains(&parent_id)) {
+				return None;
+			}
+			let parent = channels.iter().find(|channel| channel.id == parent_id)?;
+			thread.is_thread_visible_to(parent, member, owner_id).then(|| thread.inner.clone())
+		})
+		.collect();
+	ThreadListSync { guild_id, channel_ids, threads, ..Default::default() }
+}
+
+/// The payload carrying `thread_list_sync`, for sending it to a connection
+/// directly.
+pub fn thread_list_sync_payload(
+	thread_list_sync: ThreadListSync,
+) -> GatewayPayload<ThreadListSync> {
+	GatewayPayload::dispatch("THREAD_LIST_SYNC", thread_list_sync)
+}
+
+/// The dispatch event carrying `thread_list_sync`, for sending it to inboxes.
+pub fn thread_list_sync_event(thread_list_sync: ThreadListSync) -> Event {
+	Event::Dispatch(DispatchEvent::ThreadListSync(thread_list_sync_payload(thread_list_sync)))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use std::collections::HashMap;
+
+	use chorus::types::{ChannelType, PermissionFlags};
+
+	use super::*;
+	use crate::gateway::ConnectedUsers;
+
+	fn channel(id: u64, channel_type: ChannelType, parent_id: Option<u64>) -> Channel {
+		let mut channel = Channel::default();
+		channel.id = Snowflake::from(id);
+		channel.guild_id = Some(Snowflake::from(100u64));
+		channel.channel_type = channel_type;
+		channel.parent_id = parent_id.map(Snowflake::from);
+		channel
+	}
+
+	#[tokio::test]
+	async fn accessing_channel_syncs_its_active_threads() {
+		let connected_users = ConnectedUsers::new();
+		let guild_id = Snowflake::from(100u64);
+		let member_id = Snowflake::from(1u64);
+		let user = connected_users.new_user(HashMap::new(), member_id, Vec::new());
+		let mut inbox = user.lock().await.inbox.resubscribe();
+		let mut member = GuildMember { id: member_id, ..Default::default() };
+		member.roles = vec![guild_id];
+		member.permissions = PermissionFlags::VIEW_CHANNEL;
+
+		let channels = vec![
+			channel(10, ChannelType::GuildText, None),
+			channel(11, ChannelType::GuildPublicThread, Some(10)),
+			// Private threads the member has not started are not listed.
+			channel(12, ChannelType::GuildPrivateThread, Some(10)),
+			// Neither are threads of other channels.
+			channel(20, ChannelType::GuildText, None),
+			channel(21, ChannelType::GuildPublicThread, Some(20)),
+		];
+		let sync = thread_list_sync(
+			guild_id,
+			Some(vec![Snowflake::from(10u64)]),
+			&channels,
+			&member,
+			None,
+		);
+
+		let mut builder = connected_users.bulk_message_builder();
+		builder.add_user_recipients(&[member_id]).await;
+		builder.set_message(thread_list_sync_event(sync)).await;
+		builder.send(connected_users.clone()).await.unwrap();
+
+		let Event::Dispatch(DispatchEvent::ThreadListSync(payload)) = inbox.try_recv().unwrap()
+		else {
+			panic!("expected a THREAD_LIST_SYNC");
+		};
+		let sync = payload.event_data.unwrap();
+		assert_eq!(sync.guild_id, guild_id);
+		assert_eq!(sync.channel_ids, Some(vec![Snowflake::from(10u64)]));
+		assert_eq!(
+			sync.threads.iter().map(|thread| thread.id).collect::<Vec<_>>(),
+			[Snowflake::from(11u64)]
+		);
+	}
+}
@@ -0,0 +1,107 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+
+use chorus::types::{Snowflake, VoiceState};
+
+/// In-memory store of the voice states of all connected users, so that clients
+/// can see who is in which voice channel.
+///
+/// Voice states are stored per guild and per channel. A user can be in at most
+/// one voice channel per guild. Voice states without a guild (calls in private
+/// channels) are stored under the guild ID `None`.
+#[derive(Default)]
+pub struct VoiceStateMap {
+	/// Map Guild ID -> Channel ID -> User ID -> [VoiceState]
+	map: HashMap<Option<Snowflake>, HashMap<Snowflake, HashMap<Snowflake, VoiceState>>>,
+}
+
+impl VoiceStateMap {
+	/// Store `state`, replacing any previous voice state of the user in the
+	/// same guild. A `state` without a `channel_id` means that the user left
+	/// the voice channel; their voice state is removed in that case.
+	pub fn update(&mut self, state: VoiceState) {
+		let channels = self.map.entry(state.guild_id).or_default();
+		channels.retain(|_, users| {
+			users.remove(&state.user_id);
+			!users.is_empty()
+		});
+		if let Some(channel_id) = state.channel_id {
+			channels.entry(channel_id).or_default().insert(state.user_id, state);
+		} else if channels.is_empty() {
+			self.map.remove(&state.guild_id);
+		}
+	}
+
+	/// Remove all voice states of the user with the ID `user_id`, for example
+	/// because their last session disconnected. Returns the removed voice
+	/// states with their `channel_id` cleared, ready to be broadcast.
+	pub fn remove_user(&mut self, user_id: Snowflake) -> Vec<VoiceState> {
+		let mut removed = Vec::new();
+		for channels in self.map.values_mut() {
+			for users in channels.values_mut() {
+				if let Some(mut state) = users.remove(&user_id) {
+					state.channel_id = None;
+					removed.push(state);
+				}
+			}
+			channels.retain(|_, users| !users.is_empty());
+		}
+		self.map.retain(|_, channels| !channels.is_empty());
+		removed
+	}
+
+	/// Get the voice states of all users in the given voice channel.
+	pub fn channel(&self, guild_id: Option<Snowflake>, channel_id: Snowflake) -> Vec<&VoiceState> {
+		self.map
+			.get(&guild_id)
+			.and_then(|channels| channels.get(&channel_id))
+			.map(|users| users.values().collect())
+			.unwrap_or_default()
+	}
+
+	/// Get the voice state of the user with the ID `user_id` in the given
+	/// guild, if they are in a voice channel there.
+	pub fn get(&self, guild_id: Option<Snowflake>, user_id: Snowflake) -> Option<&VoiceState> {
+		self.map.get(&guild_id)?.values().find_map(|users| users.get(&user_id))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn voice_state(channel_id: Option<u64>) -> VoiceState {
+		VoiceState {
+			guild_id: Some(Snowflake::from(1u64)),
+			channel_id: channel_id.map(Snowflake::from),
+			user_id: Snowflake::from(2u64),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn moving_between_channels_replaces_state() {
+		let mut map = VoiceStateMap::default();
+		map.update(voice_state(Some(10)));
+		map.update(voice_state(Some(11)));
+		let guild_id = Some(Snowflake::from(1u64));
+		assert!(map.channel(guild_id, Snowflake::from(10u64)).is_empty());
+		assert_eq!(map.channel(guild_id, Snowflake::from(11u64)).len(), 1);
+
+		map.update(voice_state(None));
+		assert!(map.get(guild_id, Snowflake::from(2u64)).is_none());
+	}
+
+	#[test]
+	fn removing_a_user_clears_their_states() {
+		let mut map = VoiceStateMap::default();
+		map.update(voice_state(Some(10)));
+		let removed = map.remove_user(Snowflake::from(2u64));
+		assert_eq!(removed.len(), 1);
+		assert!(removed[0].channel_id.is_none());
+		assert!(map.get(Some(Snowflake::from(1u64)), Snowflake::from(2u64)).is_none());
+	}
+}
@@ -11,22 +11,82 @@ use crate::{
 };
 
 pub async fn check_token(db: &PgPool, token: &str, jwt_secret: &str) -> Result<Claims, Error> {
-	let decoding_key = jsonwebtoken::DecodingKey::from_base64_secret(jwt_secret).unwrap();
-	let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256);
-	validation.insecure_disable_signature_validation(); // TODO: Remove
-	let token: TokenData<Claims> = jsonwebtoken::decode(token, &decoding_key, &validation).unwrap();
-
-	let user = User::get_by_id(db, token.claims.id)
-		.await
-		.unwrap()
-		.ok_or(Error::User(UserError::InvalidUser))?;
-
-	if chrono::DateTime::from_timestamp(token.claims.iat, 0).unwrap() < user.data.valid_tokens_since
-	{
+	let claims = decode_token(token, jwt_secret)?;
+
+	let user = User::get_by_id(db, claims.id).await?.ok_or(Error::User(UserError::InvalidUser))?;
+
+	let issued_at = chrono::DateTime::from_timestamp(claims.iat, 0)
+		.ok_or(Error::User(UserError::InvalidToken))?;
+	if issued_at < user.data.valid_tokens_since {
 		return Err(Error::User(UserError::InvalidToken));
 	}
 
 	// TODO: Check if user is banned or disabled
 
+	Ok(claims)
+}
+
+/// The token of a bot, if `token` is one. Bots send their token prefixed with
+/// `Bot `.
+pub fn strip_bot_prefix(token: &str) -> Option<&str> {
+	token.strip_prefix("Bot ")
+}
+
+/// Decodes `token` and verifies its signature and expiry. Does not check
+/// whether the token has been revoked; use [check_token] for that.
+fn decode_token(token: &str, jwt_secret: &str) -> Result<Claims, Error> {
+	let decoding_key = jsonwebtoken::DecodingKey::from_secret(jwt_secret.as_bytes());
+	let validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256);
+	let token: TokenData<Claims> = jsonwebtoken::decode(token, &decoding_key, &validation)
+		.map_err(|_| Error::User(UserError::InvalidToken))?;
 	Ok(token.claims)
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+	use chorus::types::{Snowflake, jwt::generate_token};
+
+	use super::*;
+
+	const SECRET: &str = "secret";
+
+	#[test]
+	fn valid_token_is_decoded() {
+		let token = generate_token(&Snowflake::from(1u64), "user@example.com", SECRET);
+		assert_eq!(decode_token(&token, SECRET).unwrap().id, Snowflake::from(1u64));
+	}
+
+	#[test]
+	fn bot_token_resolves_to_bot_user() {
+		let bot_user_id = Snowflake::from(2u64);
+		let token = format!("Bot {}", generate_token(&bot_user_id, "", SECRET));
+
+		let bot_token = strip_bot_prefix(&token).unwrap();
+		assert_eq!(decode_token(bot_token, SECRET).unwrap().id, bot_user_id);
+		assert!(strip_bot_prefix(bot_token).is_none());
+	}
+
+	#[test]
+	fn forged_token_is_rejected() {
+		let token = generate_token(&Snowflake::from(1u64), "user@example.com", "another secret");
+		assert!(matches!(decode_token(&token, SECRET), Err(Error::User(UserError::InvalidToken))));
+	}
+
+	#[test]
+	fn expired_token_is_rejected() {
+		let claims = Claims {
+			exp: 0,
+			iat: 0,
+			email: "user@example.com".to_string(),
+			id: Snowflake::from(1u64),
+		};
+		let token = jsonwebtoken::encode(
+			&jsonwebtoken::Header::default(),
+			&claims,
+			&jsonwebtoken::EncodingKey::from_secret(SECRET.as_bytes()),
+		)
+		.unwrap();
+		assert!(matches!(decode_token(&token, SECRET), Err(Error::User(UserError::InvalidToken))));
+	}
+}